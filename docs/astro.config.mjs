@@ -30,6 +30,7 @@ export default defineConfig({
 					items: [
 						{ label: 'SD Card Setup', slug: 'guides/sd-card-setup' },
 						{ label: 'Configuration', slug: 'guides/configuration' },
+						{ label: 'HTTPS', slug: 'guides/https' },
 						{ label: 'WiFi Setup', slug: 'guides/wifi-setup' },
 						{ label: 'Tailscale Integration', slug: 'guides/tailscale' },
 						{ label: 'Web UI Usage', slug: 'guides/web-ui' },
@@ -40,9 +41,16 @@ export default defineConfig({
 					items: [
 						{ label: 'Overview', slug: 'api/overview' },
 						{ label: 'Authentication', slug: 'api/auth' },
+						{ label: 'Passkeys', slug: 'api/passkeys' },
+						{ label: 'PIN', slug: 'api/pin' },
+						{ label: 'Sessions', slug: 'api/sessions' },
+						{ label: 'Hosts', slug: 'api/hosts' },
 						{ label: 'Status', slug: 'api/status' },
 						{ label: 'Wake-on-LAN', slug: 'api/wol' },
 						{ label: 'LUKS Unlock', slug: 'api/unlock' },
+						{ label: 'Boot Jobs', slug: 'api/boot' },
+						{ label: 'Audit Log', slug: 'api/audit' },
+						{ label: 'Access Rules', slug: 'api/access' },
 					],
 				},
 				{
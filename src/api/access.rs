@@ -0,0 +1,154 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpRequest, HttpResponse, web};
+
+use crate::apikeys::Scope;
+use crate::auth::AppState;
+use crate::config::AccessRules;
+
+use super::{client_ip, require_auth, routed_path};
+
+/// The part of the server a request is for; each has its own
+/// [`AccessRules`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Area {
+    /// The static web UI: everything outside `/api`
+    Ui,
+    Api,
+}
+
+impl Area {
+    /// The area of a [`routed_path`].
+    pub fn of(path: &str) -> Self {
+        match path.strip_prefix("/api") {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') => Area::Api,
+            _ => Area::Ui,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Area::Ui => "ui",
+            Area::Api => "api",
+        }
+    }
+}
+
+/// Requests refused by the access rules since startup.
+#[derive(Debug, Default)]
+pub struct BlockedCounts {
+    ui: AtomicU64,
+    api: AtomicU64,
+}
+
+impl BlockedCounts {
+    fn counter(&self, area: Area) -> &AtomicU64 {
+        match area {
+            Area::Ui => &self.ui,
+            Area::Api => &self.api,
+        }
+    }
+
+    pub fn record(&self, area: Area) {
+        self.counter(area).fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, area: Area) -> u64 {
+        self.counter(area).load(Ordering::Relaxed)
+    }
+}
+
+fn rules(state: &AppState, area: Area) -> &AccessRules {
+    match area {
+        Area::Ui => &state.config.access.ui,
+        Area::Api => &state.config.access.api,
+    }
+}
+
+/// Middleware that refuses clients outside the `[access]` rules with
+/// `403 Forbidden` before any handler runs. Uses the same client address as
+/// everything else, so rules see through trusted proxies.
+pub async fn enforce<B: MessageBody + 'static>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, Error> {
+    let Some(state) = req.app_data::<web::Data<AppState>>().cloned() else {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_left_body);
+    };
+    let area = Area::of(routed_path(req.request()));
+    let ip = client_ip(req.request());
+    if rules(&state, area).permits(ip) {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_left_body);
+    }
+
+    state.blocked.record(area);
+    tracing::warn!(
+        "Blocked {} {} from {} by the {} access rules",
+        req.method(),
+        req.path(),
+        ip,
+        area.name()
+    );
+    let resp = match area {
+        Area::Api => HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Access from this address is not allowed"
+        })),
+        Area::Ui => HttpResponse::Forbidden().body("Access from this address is not allowed"),
+    };
+    Ok(req.into_response(resp).map_into_right_body())
+}
+
+/// `GET /api/access`: the rules in effect and how many requests each has
+/// refused since startup.
+pub async fn get_access(req: HttpRequest, state: web::Data<AppState>) -> HttpResponse {
+    if let Err(resp) = require_auth(&req, &state, Scope::Admin) {
+        return resp;
+    }
+
+    let describe = |area: Area| {
+        let rules = rules(&state, area);
+        let list = |nets: &[ipnet::IpNet]| nets.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        serde_json::json!({
+            "allow": list(&rules.allow),
+            "deny": list(&rules.deny),
+            "blocked": state.blocked.get(area),
+        })
+    };
+    HttpResponse::Ok().json(serde_json::json!({
+        "ui": describe(Area::Ui),
+        "api": describe(Area::Api),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scope_of() {
+        assert_eq!(Area::of("/api"), Area::Api);
+        assert_eq!(Area::of("/api/status"), Area::Api);
+        assert_eq!(Area::of("/"), Area::Ui);
+        assert_eq!(Area::of("/app.js"), Area::Ui);
+        assert_eq!(Area::of("/apiary"), Area::Ui);
+    }
+
+    #[test]
+    fn test_blocked_counts() {
+        let counts = BlockedCounts::default();
+        counts.record(Area::Api);
+        counts.record(Area::Api);
+        counts.record(Area::Ui);
+        assert_eq!(counts.get(Area::Api), 2);
+        assert_eq!(counts.get(Area::Ui), 1);
+    }
+}
@@ -0,0 +1,34 @@
+use actix_web::{HttpRequest, HttpResponse, web};
+
+use crate::apikeys::Scope;
+use crate::audit::AuditQuery;
+use crate::auth::AppState;
+
+use super::require_auth;
+
+/// `GET /api/audit`: filtered, paginated audit entries, newest first.
+pub async fn get_audit(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    query: web::Query<AuditQuery>,
+) -> HttpResponse {
+    if let Err(resp) = require_auth(&req, &state, Scope::Admin) {
+        return resp;
+    }
+
+    if !state.audit.is_enabled() {
+        return HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Audit log is disabled"
+        }));
+    }
+
+    match state.audit.query(&query) {
+        Ok(page) => HttpResponse::Ok().json(page),
+        Err(e) => {
+            tracing::error!("Failed to read audit log: {}", e);
+            HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Failed to read audit log"
+            }))
+        }
+    }
+}
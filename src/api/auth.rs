@@ -1,11 +1,78 @@
-use actix_web::{HttpRequest, HttpResponse, web};
+use actix_web::{HttpRequest, HttpResponse, http::header, web};
 use serde::Deserialize;
 
-use crate::auth::{AppState, AuthError};
+use crate::apikeys::Scope;
+use crate::audit::{AuditAction, AuditEntry, AuditOutcome};
+use crate::auth::{AppState, AuthError, TOKEN_EXPIRY};
+use crate::config::CertPolicy;
+use crate::pin::PinError;
+use crate::tls::ClientCert;
+use crate::webauthn::{LoginResponse, MAX_NAME_LEN, RegistrationResponse, WebauthnError};
+
+use super::{
+    audit_entry, client_cert, client_info, client_ip, require_auth, require_login, routed_path,
+};
 
 #[derive(Deserialize)]
 pub struct AuthRequest {
+    /// Account to log in as; without one, `pin` is the shared PIN
+    #[serde(default)]
+    username: Option<String>,
     pin: String,
+    /// Current code from the authenticator app, once TOTP is enrolled
+    #[serde(default)]
+    totp: Option<String>,
+}
+
+/// The client certificate of `req` and what it counts for at login, if the
+/// login route has a [`CertPolicy`].
+fn login_certificate(req: &HttpRequest, state: &AppState) -> Option<(CertPolicy, ClientCert)> {
+    let policy = state.config.tls.cert_policy(routed_path(req))?;
+    Some((policy, client_cert(req, state)?))
+}
+
+/// Runs `check`, which hashes a PIN with Argon2, on the blocking pool so a
+/// slow hash does not hold up an actix worker. Only `max_concurrent_hashes`
+/// checks run at once; beyond that it fails at once with
+/// [`AuthError::Busy`].
+async fn with_hashing<T: Send + 'static>(
+    state: &web::Data<AppState>,
+    check: impl FnOnce(&AppState) -> Result<T, AuthError> + Send + 'static,
+) -> Result<T, AuthError> {
+    let permit = state.reserve_hash()?;
+    let state = state.clone();
+    web::block(move || {
+        let _permit = permit;
+        check(&state)
+    })
+    .await
+    .map_err(|e| AuthError::Pin(PinError::HashFailed(e.to_string())))?
+}
+
+/// 503 for a PIN check turned away by [`with_hashing`].
+fn busy(retry_after: u64) -> HttpResponse {
+    HttpResponse::ServiceUnavailable()
+        .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+        .json(serde_json::json!({
+            "error": "Too many logins in progress. Please try again.",
+            "retry_after": retry_after
+        }))
+}
+
+/// 200 with a new token and what it may do, recording `entry` with its
+/// session ID.
+fn logged_in(state: &AppState, token: String, mut entry: AuditEntry) -> HttpResponse {
+    let auth = state.validate_token(&token).ok();
+    if let Some(auth) = &auth {
+        entry = entry.session(&auth.session_id);
+    }
+    state.audit.record(entry);
+    HttpResponse::Ok().json(serde_json::json!({
+        "token": token,
+        "expires_in": TOKEN_EXPIRY.as_secs(),
+        "username": auth.as_ref().and_then(|auth| auth.username.clone()),
+        "scopes": auth.map(|auth| auth.scopes).unwrap_or_default(),
+    }))
 }
 
 pub async fn authenticate(
@@ -14,38 +81,486 @@ pub async fn authenticate(
     body: web::Json<AuthRequest>,
 ) -> HttpResponse {
     // Extract client IP for rate limiting
-    let ip = req
-        .peer_addr()
-        .map(|a| a.ip())
-        .unwrap_or_else(|| std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST));
+    let ip = client_ip(&req);
+    let cert = login_certificate(&req, &state);
+    let success = AuditEntry::new(AuditAction::Login, AuditOutcome::Success).client_ip(ip);
+
+    // Nothing to guess, so no rate limit
+    if let Some((CertPolicy::Bypass, cert)) = &cert {
+        match state.login_with_certificate(&cert.name, client_info(&req)) {
+            Ok(token) => {
+                tracing::info!("Client certificate {} logged in from {}", cert.name, ip);
+                return logged_in(
+                    &state,
+                    token,
+                    success.detail(format!("Client certificate {}", cert.name)),
+                );
+            }
+            // Not tied to a user once the shared PIN is gone; log in with a PIN
+            Err(e) => tracing::info!("Client certificate {} cannot log in: {}", cert.name, e),
+        }
+    }
+
+    // Usernames are lowercase; an empty field means the shared PIN
+    let username = body
+        .username
+        .as_deref()
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty());
+    let username = username.as_deref();
+    // Names who is logging in, in log messages
+    let who = match username {
+        Some(username) => format!("{} from {}", username, ip),
+        None => ip.to_string(),
+    };
+    let failure = |detail: &str| {
+        let entry = AuditEntry::new(AuditAction::Login, AuditOutcome::Failure)
+            .client_ip(ip)
+            .detail(detail);
+        state.audit.record(match username {
+            Some(username) => entry.user(username),
+            None => entry,
+        });
+    };
 
     // Check rate limit
-    if let Err(AuthError::RateLimited) = state.check_rate_limit(ip) {
+    if let Err(AuthError::RateLimited { retry_after }) = state.check_rate_limit(ip) {
         tracing::warn!("Rate limited auth attempt from {}", ip);
-        return HttpResponse::TooManyRequests().json(serde_json::json!({
-            "error": "Too many authentication attempts. Please wait."
-        }));
+        failure("Rate limited");
+        return too_many_attempts(
+            "Too many authentication attempts. Please wait.",
+            retry_after,
+        );
     }
 
-    // Verify PIN
-    match state.verify_pin(&body.pin) {
+    // Verify PIN and TOTP code, or PIN and certificate
+    let with_certificate = matches!(cert, Some((CertPolicy::Factor, _)));
+    let (name, pin, totp) = (
+        username.map(str::to_string),
+        body.pin.clone(),
+        body.totp.clone(),
+    );
+    let client = client_info(&req);
+    let result = match state.check_before_hashing(totp.as_deref(), with_certificate) {
+        Ok(()) => {
+            with_hashing(&state, move |state| match with_certificate {
+                true => state.verify_pin_with_certificate(name.as_deref(), &pin, client),
+                false => state.verify_pin(name.as_deref(), &pin, totp.as_deref(), client),
+            })
+            .await
+        }
+        Err(e) => Err(e),
+    };
+    match result {
         Ok(token) => {
-            tracing::info!("Successful authentication from {}", ip);
-            HttpResponse::Ok().json(serde_json::json!({
-                "token": token
+            tracing::info!("Successful authentication of {}", who);
+            let entry = match &cert {
+                Some((_, cert)) => {
+                    success.detail(format!("PIN and client certificate {}", cert.name))
+                }
+                None => success,
+            };
+            let entry = match username {
+                Some(username) => entry.user(username),
+                None => entry,
+            };
+            logged_in(&state, token, entry)
+        }
+        Err(AuthError::UsernameRequired) => {
+            failure("Username required");
+            HttpResponse::Unauthorized().json(serde_json::json!({
+                "error": "Username required",
+                "username_required": true
+            }))
+        }
+        Err(AuthError::PinLoginSuspended { retry_after }) => {
+            tracing::warn!("PIN login suspended, refused attempt from {}", ip);
+            failure("PIN login suspended");
+            too_many_attempts(
+                "Too many failed logins. PIN login is temporarily disabled.",
+                retry_after,
+            )
+        }
+        Err(AuthError::Busy { retry_after }) => {
+            // Not audited: nothing was checked, and a flood would fill the log
+            tracing::warn!(
+                "Too many PIN checks in progress, refused attempt of {}",
+                who
+            );
+            busy(retry_after)
+        }
+        Err(AuthError::LockedOut) => {
+            tracing::warn!("PIN login locked out, refused attempt from {}", ip);
+            failure("PIN login locked out");
+            HttpResponse::Forbidden().json(serde_json::json!({
+                "error": "PIN login is locked. Run `articwake clear-lockout` on the device to unlock it.",
+                "locked_out": true
             }))
         }
-        Err(AuthError::InvalidPin) => {
-            tracing::warn!("Failed authentication attempt from {}", ip);
+        Err(AuthError::TotpRequired) => {
+            failure("TOTP code required");
             HttpResponse::Unauthorized().json(serde_json::json!({
-                "error": "Invalid PIN"
+                "error": "TOTP code required",
+                "totp_required": true
+            }))
+        }
+        Err(e @ (AuthError::InvalidPin | AuthError::InvalidTotp | AuthError::TotpReplayed)) => {
+            tracing::warn!("Failed authentication attempt of {}", who);
+            failure(&e.to_string());
+            // With TOTP enrolled, don't tell which of the two factors was wrong,
+            // and never whether the username exists
+            let message = match (
+                !matches!(e, AuthError::InvalidPin) || body.totp.is_some(),
+                username.is_some(),
+            ) {
+                (true, true) => "Invalid username, PIN or TOTP code",
+                (true, false) => "Invalid PIN or TOTP code",
+                (false, true) => "Invalid username or PIN",
+                (false, false) => "Invalid PIN",
+            };
+            HttpResponse::Unauthorized().json(serde_json::json!({
+                "error": message
             }))
         }
         Err(e) => {
             tracing::error!("Auth error: {}", e);
+            failure(&e.to_string());
             HttpResponse::InternalServerError().json(serde_json::json!({
                 "error": "Authentication failed"
             }))
         }
     }
 }
+
+#[derive(Deserialize)]
+pub struct ChangePinRequest {
+    current_pin: String,
+    new_pin: String,
+    /// Needed once TOTP is enrolled, as for login
+    #[serde(default)]
+    totp: Option<String>,
+}
+
+/// `POST /api/pin`: changes the PIN the session logged in with and ends the
+/// account's other sessions.
+pub async fn change_pin(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: web::Json<ChangePinRequest>,
+) -> HttpResponse {
+    let auth = match require_login(&req, &state) {
+        Ok(auth) => auth,
+        Err(resp) => return resp,
+    };
+    if auth.session_id.starts_with("key:") {
+        return HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "API keys cannot change the PIN"
+        }));
+    }
+
+    let ip = client_ip(&req);
+    let body = body.into_inner();
+    let totp_sent = body.totp.is_some();
+    let session = auth.clone();
+    let checked = state
+        .check_rate_limit(ip)
+        .and_then(|()| state.check_before_hashing(body.totp.as_deref(), false));
+    let result = match checked {
+        Ok(()) => {
+            with_hashing(&state, move |state| {
+                state.change_pin(
+                    &session,
+                    &body.current_pin,
+                    &body.new_pin,
+                    body.totp.as_deref(),
+                    ip,
+                )
+            })
+            .await
+        }
+        Err(e) => Err(e),
+    };
+    if let Err(AuthError::Busy { retry_after }) = result {
+        // Not audited, as for login
+        return busy(retry_after);
+    }
+    let outcome = match &result {
+        Ok(_) => AuditOutcome::Success,
+        Err(_) => AuditOutcome::Failure,
+    };
+    let entry = audit_entry(&req, &auth, AuditAction::Pin, outcome);
+    match result {
+        Ok(ended) => {
+            tracing::info!("PIN changed from {}; ended {} other session(s)", ip, ended);
+            state
+                .audit
+                .record(entry.detail(format!("PIN changed, ended {} other session(s)", ended)));
+            HttpResponse::Ok().json(serde_json::json!({
+                "sessions_ended": ended
+            }))
+        }
+        Err(e) => {
+            state.audit.record(entry.detail(e.to_string()));
+            match e {
+                AuthError::RateLimited { retry_after } => too_many_attempts(
+                    "Too many authentication attempts. Please wait.",
+                    retry_after,
+                ),
+                AuthError::PinLoginSuspended { retry_after } => too_many_attempts(
+                    "Too many failed logins. PIN login is temporarily disabled.",
+                    retry_after,
+                ),
+                AuthError::LockedOut => HttpResponse::Forbidden().json(serde_json::json!({
+                    "error": "PIN login is locked. Run `articwake clear-lockout` on the device to unlock it.",
+                    "locked_out": true
+                })),
+                AuthError::TotpRequired => HttpResponse::Unauthorized().json(serde_json::json!({
+                    "error": "TOTP code required",
+                    "totp_required": true
+                })),
+                AuthError::InvalidPin | AuthError::InvalidTotp | AuthError::TotpReplayed => {
+                    tracing::warn!("Wrong current PIN in PIN change from {}", ip);
+                    // As for login, don't tell which of the two was wrong
+                    let message = match totp_sent {
+                        true => "Invalid PIN or TOTP code",
+                        false => "Invalid PIN",
+                    };
+                    HttpResponse::Unauthorized().json(serde_json::json!({
+                        "error": message
+                    }))
+                }
+                AuthError::UsernameRequired => HttpResponse::Conflict().json(serde_json::json!({
+                    "error": "There is no shared PIN to change"
+                })),
+                AuthError::Pin(
+                    e @ (PinError::TooShort(_)
+                    | PinError::TooLong
+                    | PinError::TooSimple
+                    | PinError::Unchanged),
+                ) => HttpResponse::BadRequest().json(serde_json::json!({
+                    "error": e.to_string()
+                })),
+                e => {
+                    tracing::error!("PIN change failed: {}", e);
+                    HttpResponse::InternalServerError().json(serde_json::json!({
+                        "error": "Failed to change the PIN"
+                    }))
+                }
+            }
+        }
+    }
+}
+
+/// 429 telling the client how many seconds to wait.
+fn too_many_attempts(message: &str, retry_after: u64) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+        .json(serde_json::json!({
+            "error": message,
+            "retry_after": retry_after
+        }))
+}
+
+/// `GET /api/auth`: which login methods the UI should offer.
+pub async fn auth_methods(req: HttpRequest, state: web::Data<AppState>) -> HttpResponse {
+    let client_cert = login_certificate(&req, &state).map(|(policy, _)| match policy {
+        CertPolicy::Bypass => "bypass",
+        CertPolicy::Factor => "factor",
+    });
+    HttpResponse::Ok().json(serde_json::json!({
+        "totp": state.totp_enabled().unwrap_or(true),
+        "passkeys": state.webauthn.login_available(),
+        "client_cert": client_cert,
+        "users": state.users.list().is_ok_and(|users| !users.is_empty()),
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct RegisterPasskeyRequest {
+    #[serde(flatten)]
+    credential: RegistrationResponse,
+    /// Label shown in the passkey list, e.g. "Phone"
+    #[serde(default)]
+    name: Option<String>,
+}
+
+/// Response for passkey errors outside of login.
+fn passkey_error(e: &WebauthnError) -> HttpResponse {
+    match e {
+        WebauthnError::Disabled | WebauthnError::NoCredentials => {
+            HttpResponse::NotFound().json(serde_json::json!({
+                "error": e.to_string()
+            }))
+        }
+        WebauthnError::Storage(_) => {
+            tracing::error!("Passkey error: {}", e);
+            HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Failed to access passkeys"
+            }))
+        }
+        _ => HttpResponse::BadRequest().json(serde_json::json!({
+            "error": e.to_string()
+        })),
+    }
+}
+
+fn passkey_name(name: Option<&str>) -> String {
+    let name: String = name
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_NAME_LEN)
+        .collect();
+    match name.trim() {
+        "" => "Passkey".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// `POST /api/auth/passkeys/register/start`: options for `navigator.credentials.create()`.
+pub async fn start_passkey_registration(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+) -> HttpResponse {
+    let auth = match require_auth(&req, &state, Scope::Admin) {
+        Ok(auth) => auth,
+        Err(resp) => return resp,
+    };
+
+    match state
+        .webauthn
+        .start_registration(&auth.session_id, auth.username.as_deref())
+    {
+        Ok(options) => HttpResponse::Ok().json(serde_json::json!({ "publicKey": options })),
+        Err(e) => passkey_error(&e),
+    }
+}
+
+/// `POST /api/auth/passkeys/register/finish`: stores the new passkey.
+pub async fn finish_passkey_registration(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: web::Json<RegisterPasskeyRequest>,
+) -> HttpResponse {
+    let auth = match require_auth(&req, &state, Scope::Admin) {
+        Ok(auth) => auth,
+        Err(resp) => return resp,
+    };
+
+    let name = passkey_name(body.name.as_deref());
+    let result = state
+        .webauthn
+        .finish_registration(&auth.session_id, &body.credential, &name);
+    match result {
+        Ok(credential) => {
+            tracing::info!("Registered passkey {}", credential.name);
+            state.audit.record(
+                audit_entry(&req, &auth, AuditAction::Passkey, AuditOutcome::Success)
+                    .detail(format!("Registered {}", credential.name)),
+            );
+            HttpResponse::Created().json(credential)
+        }
+        Err(e) => {
+            tracing::warn!("Passkey registration failed: {}", e);
+            state.audit.record(
+                audit_entry(&req, &auth, AuditAction::Passkey, AuditOutcome::Failure)
+                    .detail(format!("Registration failed: {}", e)),
+            );
+            passkey_error(&e)
+        }
+    }
+}
+
+/// `GET /api/auth/passkeys`
+pub async fn list_passkeys(req: HttpRequest, state: web::Data<AppState>) -> HttpResponse {
+    if let Err(resp) = require_auth(&req, &state, Scope::Admin) {
+        return resp;
+    }
+
+    match state.webauthn.credentials() {
+        Ok(passkeys) => HttpResponse::Ok().json(serde_json::json!({ "passkeys": passkeys })),
+        Err(e) => passkey_error(&e),
+    }
+}
+
+/// `DELETE /api/auth/passkeys/{id}`
+pub async fn delete_passkey(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let auth = match require_auth(&req, &state, Scope::Admin) {
+        Ok(auth) => auth,
+        Err(resp) => return resp,
+    };
+
+    match state.webauthn.remove(&path) {
+        Ok(Some(credential)) => {
+            state.audit.record(
+                audit_entry(&req, &auth, AuditAction::Passkey, AuditOutcome::Success)
+                    .detail(format!("Removed {}", credential.name)),
+            );
+            HttpResponse::NoContent().finish()
+        }
+        Ok(None) => HttpResponse::NotFound().json(serde_json::json!({
+            "error": format!("Unknown passkey: {}", path)
+        })),
+        Err(e) => passkey_error(&e),
+    }
+}
+
+/// `POST /api/auth/passkeys/login/start`: options for `navigator.credentials.get()`.
+pub async fn start_passkey_login(state: web::Data<AppState>) -> HttpResponse {
+    match state.webauthn.start_login() {
+        Ok(options) => HttpResponse::Ok().json(serde_json::json!({ "publicKey": options })),
+        Err(e) => passkey_error(&e),
+    }
+}
+
+/// `POST /api/auth/passkeys/login/finish`: checks the assertion and returns a
+/// session token, like `POST /api/auth`.
+pub async fn finish_passkey_login(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: web::Json<LoginResponse>,
+) -> HttpResponse {
+    let ip = client_ip(&req);
+    let failure = |detail: &str| {
+        state.audit.record(
+            AuditEntry::new(AuditAction::Login, AuditOutcome::Failure)
+                .client_ip(ip)
+                .detail(detail),
+        );
+    };
+
+    // Shares the backoff with PIN attempts
+    if let Err(AuthError::RateLimited { retry_after }) = state.check_rate_limit(ip) {
+        tracing::warn!("Rate limited passkey attempt from {}", ip);
+        failure("Rate limited");
+        return too_many_attempts(
+            "Too many authentication attempts. Please wait.",
+            retry_after,
+        );
+    }
+
+    match state.verify_passkey(&body, client_info(&req)) {
+        Ok((token, credential)) => {
+            tracing::info!("Successful passkey authentication from {}", ip);
+            let entry = AuditEntry::new(AuditAction::Login, AuditOutcome::Success)
+                .client_ip(ip)
+                .detail(format!("Passkey {}", credential.name));
+            logged_in(&state, token, entry)
+        }
+        Err(AuthError::Passkey(e @ (WebauthnError::Disabled | WebauthnError::Storage(_)))) => {
+            failure(&e.to_string());
+            passkey_error(&e)
+        }
+        Err(e) => {
+            tracing::warn!("Failed passkey authentication from {}: {}", ip, e);
+            failure(&format!("Passkey: {}", e));
+            HttpResponse::Unauthorized().json(serde_json::json!({
+                "error": "Passkey login failed"
+            }))
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use actix_web::{HttpRequest, HttpResponse, web};
+use serde::Deserialize;
+
+use crate::apikeys::Scope;
+use crate::audit::{AuditAction, AuditOutcome};
+use crate::auth::AppState;
+use crate::services::boot::{self, JobAudit};
+
+use super::unlock::check_passphrase;
+use super::{audit_entry, find_host, require_auth};
+
+#[derive(Deserialize)]
+pub struct BootRequest {
+    passphrase: String,
+    /// Host ID; defaults to the first configured host
+    #[serde(default)]
+    host: Option<String>,
+}
+
+pub async fn start_boot(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: web::Json<BootRequest>,
+) -> HttpResponse {
+    let auth = match require_auth(&req, &state, Scope::Unlock) {
+        Ok(auth) => auth,
+        Err(resp) => return resp,
+    };
+
+    let host = match &body.host {
+        Some(id) => match find_host(&state, id) {
+            Ok(host) => host,
+            Err(resp) => return resp,
+        },
+        None => state.config.default_host(),
+    };
+
+    if let Err(resp) = check_passphrase(&body.passphrase) {
+        return resp;
+    }
+
+    match boot::start(
+        state.jobs.clone(),
+        host.clone(),
+        state.config.boot.clone(),
+        body.passphrase.clone(),
+        JobAudit {
+            log: state.audit.clone(),
+            origin: audit_entry(&req, &auth, AuditAction::Boot, AuditOutcome::Success)
+                .host(&host.id),
+        },
+    ) {
+        Ok(job) => {
+            tracing::info!("Started boot job {} for {}", job.id, job.host);
+            HttpResponse::Accepted().json(job)
+        }
+        Err(running) => HttpResponse::Conflict().json(serde_json::json!({
+            "error": format!("A boot job is already running for {}", running.host),
+            "job": running
+        })),
+    }
+}
+
+pub async fn get_job(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    if let Err(resp) = require_auth(&req, &state, Scope::StatusRead) {
+        return resp;
+    }
+
+    match state.jobs.get(&path) {
+        Some(job) => HttpResponse::Ok().json(job),
+        None => HttpResponse::NotFound().json(serde_json::json!({
+            "error": format!("Unknown job: {}", path.as_str())
+        })),
+    }
+}
@@ -0,0 +1,192 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use actix_web::{HttpRequest, HttpResponse, http::header, web};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::apikeys::Scope;
+use crate::auth::{AppState, EVENTS_TICKET_LIFETIME};
+use crate::services::monitor::StatusEvent;
+
+use super::{check_scope, find_host, require_auth};
+
+/// Comment sent to keep proxies from closing an idle stream; the session is
+/// re-checked at the same time.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Deserialize)]
+pub struct EventsQuery {
+    /// `EventSource` cannot set headers, so a ticket from
+    /// `POST /api/events/ticket` may be passed here instead
+    ticket: Option<String>,
+    /// Only stream events for this host
+    host: Option<String>,
+}
+
+/// `POST /api/events/ticket`: a single-use ticket for opening the event
+/// stream from a browser. Only for sessions; API keys can send the header.
+pub async fn events_ticket(req: HttpRequest, state: web::Data<AppState>) -> HttpResponse {
+    let auth = match require_auth(&req, &state, Scope::StatusRead) {
+        Ok(auth) => auth,
+        Err(resp) => return resp,
+    };
+    if auth.session_id.starts_with("key:") {
+        return HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "API keys cannot get event stream tickets"
+        }));
+    }
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "ticket": state.issue_events_ticket(&auth),
+        "expires_in": EVENTS_TICKET_LIFETIME.as_secs(),
+    }))
+}
+
+/// `GET /api/events`: Server-Sent Events stream of host status changes.
+pub async fn status_events(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    query: web::Query<EventsQuery>,
+) -> HttpResponse {
+    let query = query.into_inner();
+    let auth = match query.ticket {
+        Some(ticket) if !req.headers().contains_key(header::AUTHORIZATION) => {
+            match state.redeem_events_ticket(&ticket) {
+                Ok(auth) => match check_scope(&auth, Scope::StatusRead) {
+                    Ok(()) => auth,
+                    Err(resp) => return resp,
+                },
+                Err(_) => {
+                    return HttpResponse::Unauthorized().json(serde_json::json!({
+                        "error": "Invalid or expired ticket"
+                    }));
+                }
+            }
+        }
+        _ => match require_auth(&req, &state, Scope::StatusRead) {
+            Ok(auth) => auth,
+            Err(resp) => return resp,
+        },
+    };
+
+    if let Some(id) = &query.host
+        && let Err(resp) = find_host(&state, id)
+    {
+        return resp;
+    }
+
+    // Subscribe before taking snapshots so no change falls in between
+    let events = state.monitor.subscribe();
+    let mut pending = VecDeque::new();
+    for snapshot in state.monitor.snapshots() {
+        if query.host.as_ref().is_none_or(|id| *id == snapshot.host) {
+            pending.push_back(sse_message("snapshot", &snapshot));
+        }
+    }
+
+    let stream = EventStream {
+        state: state.clone(),
+        session_id: auth.session_id,
+        host: query.host,
+        pending,
+        events,
+        keep_alive: tokio::time::interval(KEEP_ALIVE_INTERVAL),
+    };
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .streaming(futures_util::stream::unfold(stream, |mut stream| async {
+            let chunk = stream.next_chunk().await?;
+            Some((Ok::<_, actix_web::Error>(web::Bytes::from(chunk)), stream))
+        }))
+}
+
+struct EventStream {
+    state: web::Data<AppState>,
+    /// Checked without sliding its expiry, so an idle stream doesn't keep
+    /// the session alive, and refreshing the token doesn't end the stream
+    session_id: String,
+    host: Option<String>,
+    pending: VecDeque<String>,
+    events: broadcast::Receiver<StatusEvent>,
+    keep_alive: tokio::time::Interval,
+}
+
+impl EventStream {
+    /// Next SSE message, or `None` once the session ended or the monitor
+    /// went away.
+    async fn next_chunk(&mut self) -> Option<String> {
+        loop {
+            if let Some(chunk) = self.pending.pop_front() {
+                return Some(chunk);
+            }
+
+            tokio::select! {
+                event = self.events.recv() => match event {
+                    Ok(event) => {
+                        if self.host.as_ref().is_none_or(|id| *id == event.host) {
+                            return Some(sse_message("status", &event));
+                        }
+                    }
+                    // Too slow to keep up; resend the current state instead
+                    Err(RecvError::Lagged(_)) => {
+                        for snapshot in self.state.monitor.snapshots() {
+                            if self.host.as_ref().is_none_or(|id| *id == snapshot.host) {
+                                self.pending.push_back(sse_message("snapshot", &snapshot));
+                            }
+                        }
+                    }
+                    Err(RecvError::Closed) => return None,
+                },
+                _ = self.keep_alive.tick() => {
+                    if !self.state.session_active(&self.session_id) {
+                        return None;
+                    }
+                    return Some(": keep-alive\n\n".to_string());
+                }
+            }
+        }
+    }
+}
+
+fn sse_message(event: &str, data: &impl Serialize) -> String {
+    format!(
+        "event: {}\ndata: {}\n\n",
+        event,
+        serde_json::to_string(data).unwrap_or_default()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::HostPhase;
+    use crate::services::network::{HostStatus, ProbeResult};
+
+    #[test]
+    fn test_sse_message_format() {
+        let event = StatusEvent {
+            host: "nas".to_string(),
+            phase: HostPhase::Initrd,
+            previous: Some(HostPhase::Off),
+            status: HostStatus::from_probes(vec![ProbeResult {
+                name: "initrd_ssh".to_string(),
+                kind: "tcp",
+                phase: Some(HostPhase::Initrd),
+                ok: true,
+                latency_ms: Some(4),
+                error: None,
+                responded: true,
+            }]),
+        };
+
+        let message = sse_message("status", &event);
+        assert!(message.starts_with("event: status\ndata: {"));
+        assert!(message.ends_with("}\n\n"));
+        assert!(message.contains("\"phase\":\"initrd\""));
+        assert!(message.contains("\"previous\":\"off\""));
+        assert_eq!(message.matches('\n').count(), 3);
+    }
+}
@@ -0,0 +1,40 @@
+use actix_web::{HttpRequest, HttpResponse, web};
+
+use crate::apikeys::Scope;
+use crate::auth::AppState;
+
+use super::require_auth;
+
+pub async fn list_hosts(req: HttpRequest, state: web::Data<AppState>) -> HttpResponse {
+    if let Err(resp) = require_auth(&req, &state, Scope::StatusRead) {
+        return resp;
+    }
+
+    // Served from the monitor's cache; stale hosts are probed concurrently
+    let snapshots = futures_util::future::join_all(
+        state
+            .config
+            .hosts
+            .iter()
+            .map(|host| state.monitor.status(host)),
+    )
+    .await;
+
+    let hosts: Vec<_> = state
+        .config
+        .hosts
+        .iter()
+        .zip(snapshots)
+        .map(|(host, snapshot)| {
+            serde_json::json!({
+                "id": host.id,
+                "ip": host.ip,
+                "initrd_ssh_port": host.ssh_port,
+                "phase": snapshot.phase,
+                "status": snapshot.status
+            })
+        })
+        .collect();
+
+    HttpResponse::Ok().json(serde_json::json!({ "hosts": hosts }))
+}
@@ -1,12 +1,69 @@
+pub mod access;
+pub mod audit;
 pub mod auth;
+pub mod boot;
+pub mod events;
+pub mod hosts;
+pub mod sessions;
 pub mod status;
 pub mod unlock;
 pub mod wol;
 
-use crate::auth::{AppState, extract_bearer_token};
-use actix_web::{HttpRequest, HttpResponse, http::header};
+use std::net::{IpAddr, Ipv4Addr};
+
+use crate::apikeys::Scope;
+use crate::audit::{AuditAction, AuditEntry, AuditOutcome};
+use crate::auth::{AppState, AuthContext, ClientInfo, extract_bearer_token};
+use crate::config::{CertPolicy, HostConfig};
+use crate::proxy;
+use crate::tls::ClientCert;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::{Error, HttpRequest, HttpResponse, http::header, web};
+use tracing::Span;
+use tracing_actix_web::{DefaultRootSpanBuilder, RootSpanBuilder};
+
+/// [`require_login`], and that the session or API key holds `scope`.
+pub fn require_auth(
+    req: &HttpRequest,
+    state: &AppState,
+    scope: Scope,
+) -> Result<AuthContext, HttpResponse> {
+    let auth = require_login(req, state)?;
+    check_scope(&auth, scope)?;
+    Ok(auth)
+}
+
+/// `403 Forbidden` unless `auth` holds `scope`.
+pub fn check_scope(auth: &AuthContext, scope: Scope) -> Result<(), HttpResponse> {
+    if auth.allows(scope) {
+        return Ok(());
+    }
+    Err(HttpResponse::Forbidden().json(serde_json::json!({
+        "error": format!("This needs the {} scope", scope),
+        "scope": scope,
+    })))
+}
+
+/// Checks the bearer token (a session token or API key), or the client
+/// certificate on routes with a [`CertPolicy`]: `bypass` routes accept the
+/// certificate instead of a token, `factor` routes need both. Scopes are not
+/// checked, so this is only for acting on the caller's own session.
+pub fn require_login(req: &HttpRequest, state: &AppState) -> Result<AuthContext, HttpResponse> {
+    let policy = state.config.tls.cert_policy(routed_path(req));
+    let cert = policy.and_then(|_| client_cert(req, state));
+    if let (Some(CertPolicy::Bypass), Some(cert)) = (policy, &cert) {
+        // Without an account to act as, the certificate is no login
+        if let Ok((username, role)) = state.certificate_account(&cert.name) {
+            // Not a real session; identifies the certificate in the audit log
+            return Ok(AuthContext {
+                session_id: format!("cert:{}", &cert.fingerprint[..16]),
+                username,
+                scopes: role.scopes(),
+            });
+        }
+    }
 
-pub fn require_auth(req: &HttpRequest, state: &AppState) -> Result<(), HttpResponse> {
     let auth_header = req
         .headers()
         .get(header::AUTHORIZATION)
@@ -18,9 +75,133 @@ pub fn require_auth(req: &HttpRequest, state: &AppState) -> Result<(), HttpRespo
         }))
     })?;
 
-    state.validate_token(token).map_err(|_| {
+    let auth = state.validate_token(token).map_err(|_| {
         HttpResponse::Unauthorized().json(serde_json::json!({
             "error": "Invalid or expired token"
         }))
+    })?;
+
+    if policy == Some(CertPolicy::Factor) && cert.is_none() {
+        return Err(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "A client certificate is required"
+        })));
+    }
+    if let Some(username) = &auth.username {
+        Span::current().record("user", username.as_str());
+    }
+    Ok(auth)
+}
+
+/// The path the router matches routes against, with percent-escapes other
+/// than `%2F`, `%25` and `%2B` decoded. Rules keyed by path must look here,
+/// not at `req.path()`, or `/api/%75nlock` would reach the unlock handler
+/// without matching a rule for `/api/unlock`.
+pub fn routed_path(req: &HttpRequest) -> &str {
+    req.match_info().as_str()
+}
+
+/// The verified client certificate of the request's TLS connection, unless
+/// it is listed in `tls.revoked_client_certs`.
+pub fn client_cert(req: &HttpRequest, state: &AppState) -> Option<ClientCert> {
+    let cert = req.conn_data::<ClientCert>()?;
+    if state
+        .config
+        .tls
+        .revoked_client_certs
+        .contains(&cert.fingerprint)
+    {
+        tracing::warn!(
+            "Ignoring revoked client certificate {} ({})",
+            cert.name,
+            cert.fingerprint
+        );
+        return None;
+    }
+    Some(cert.clone())
+}
+
+/// Address of the client, looked up behind the proxies in
+/// `config.trusted_proxies`. Used for rate limiting, logs and the audit log.
+pub fn client_ip(req: &HttpRequest) -> IpAddr {
+    let peer = req
+        .peer_addr()
+        .map(|a| a.ip())
+        .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    match req.app_data::<web::Data<AppState>>() {
+        Some(state) => proxy::client_ip(peer, req.headers(), &state.config.trusted_proxies),
+        None => peer,
+    }
+}
+
+/// Request span for `TracingLogger`. The default one logs whatever
+/// `X-Forwarded-For` claims, from any client, and the query string; this
+/// one logs [`client_ip`] and the path.
+pub struct RequestSpan;
+
+impl RootSpanBuilder for RequestSpan {
+    fn on_request_start(request: &ServiceRequest) -> Span {
+        let user_agent = request
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or("");
+        tracing::info_span!(
+            "HTTP request",
+            http.method = %request.method(),
+            http.route = %request.match_pattern().unwrap_or_else(|| "default".to_string()),
+            // Path only: a query string may carry credentials
+            http.target = %request.uri().path(),
+            http.client_ip = %client_ip(request.request()),
+            http.user_agent = %user_agent,
+            user = tracing::field::Empty,
+            http.status_code = tracing::field::Empty,
+            otel.status_code = tracing::field::Empty,
+            exception.message = tracing::field::Empty,
+            exception.details = tracing::field::Empty,
+        )
+    }
+
+    fn on_request_end<B: MessageBody>(span: Span, outcome: &Result<ServiceResponse<B>, Error>) {
+        DefaultRootSpanBuilder::on_request_end(span, outcome);
+    }
+}
+
+/// Longest user agent kept with a session
+const MAX_USER_AGENT_LEN: usize = 256;
+
+/// Client details recorded with a new session.
+pub fn client_info(req: &HttpRequest) -> ClientInfo {
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|h| h.to_str().ok())
+        .map(|ua| ua.chars().take(MAX_USER_AGENT_LEN).collect());
+    ClientInfo {
+        ip: client_ip(req),
+        user_agent,
+    }
+}
+
+/// Audit entry for an action taken by the client of `req`.
+pub fn audit_entry(
+    req: &HttpRequest,
+    auth: &AuthContext,
+    action: AuditAction,
+    outcome: AuditOutcome,
+) -> AuditEntry {
+    let entry = AuditEntry::new(action, outcome)
+        .client_ip(client_ip(req))
+        .session(&auth.session_id);
+    match &auth.username {
+        Some(username) => entry.user(username),
+        None => entry,
+    }
+}
+
+pub fn find_host<'a>(state: &'a AppState, id: &str) -> Result<&'a HostConfig, HttpResponse> {
+    state.config.host(id).ok_or_else(|| {
+        HttpResponse::NotFound().json(serde_json::json!({
+            "error": format!("Unknown host: {}", id)
+        }))
     })
 }
@@ -0,0 +1,79 @@
+use actix_web::{HttpRequest, HttpResponse, web};
+
+use crate::apikeys::Scope;
+use crate::audit::{AuditAction, AuditOutcome};
+use crate::auth::{AppState, TOKEN_EXPIRY};
+
+use super::{audit_entry, require_auth, require_login};
+
+/// `POST /api/logout`: ends the session making the request.
+pub async fn logout(req: HttpRequest, state: web::Data<AppState>) -> HttpResponse {
+    let auth = match require_login(&req, &state) {
+        Ok(auth) => auth,
+        Err(resp) => return resp,
+    };
+
+    state.revoke_session(&auth.session_id);
+    state.audit.record(
+        audit_entry(&req, &auth, AuditAction::Logout, AuditOutcome::Success).detail("Logged out"),
+    );
+    HttpResponse::NoContent().finish()
+}
+
+/// `POST /api/refresh`: swaps the current token for a new one.
+pub async fn refresh(req: HttpRequest, state: web::Data<AppState>) -> HttpResponse {
+    let auth = match require_login(&req, &state) {
+        Ok(auth) => auth,
+        Err(resp) => return resp,
+    };
+
+    match state.refresh_session(&auth.session_id) {
+        Some(token) => HttpResponse::Ok().json(serde_json::json!({
+            "token": token,
+            "expires_in": TOKEN_EXPIRY.as_secs(),
+            "username": auth.username,
+            "scopes": auth.scopes,
+        })),
+        // Revoked between the two calls
+        None => HttpResponse::Unauthorized().json(serde_json::json!({
+            "error": "Invalid or expired token"
+        })),
+    }
+}
+
+/// `GET /api/sessions`: active sessions, newest first.
+pub async fn list_sessions(req: HttpRequest, state: web::Data<AppState>) -> HttpResponse {
+    let auth = match require_auth(&req, &state, Scope::Admin) {
+        Ok(auth) => auth,
+        Err(resp) => return resp,
+    };
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "sessions": state.sessions(&auth.session_id)
+    }))
+}
+
+/// `DELETE /api/sessions/{id}`: ends a session, e.g. one on a lost device.
+pub async fn delete_session(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let auth = match require_auth(&req, &state, Scope::Admin) {
+        Ok(auth) => auth,
+        Err(resp) => return resp,
+    };
+
+    if !state.revoke_session(&path) {
+        return HttpResponse::NotFound().json(serde_json::json!({
+            "error": format!("Unknown session: {}", path)
+        }));
+    }
+
+    tracing::info!("Revoked session {}", path);
+    state.audit.record(
+        audit_entry(&req, &auth, AuditAction::Logout, AuditOutcome::Success)
+            .detail(format!("Revoked session {}", path)),
+    );
+    HttpResponse::NoContent().finish()
+}
@@ -1,22 +1,46 @@
 use actix_web::{HttpRequest, HttpResponse, web};
 
+use crate::apikeys::Scope;
 use crate::auth::AppState;
-use crate::services::network::check_host_status;
+use crate::config::HostConfig;
 
-use super::require_auth;
+use super::{find_host, require_auth};
 
 pub async fn get_status(req: HttpRequest, state: web::Data<AppState>) -> HttpResponse {
-    if let Err(resp) = require_auth(&req, &state) {
+    if let Err(resp) = require_auth(&req, &state, Scope::StatusRead) {
         return resp;
     }
 
-    let status = check_host_status(&state.config.homelab_ip, state.config.ssh_port);
+    status_response(&state, state.config.default_host()).await
+}
+
+pub async fn get_host_status(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    if let Err(resp) = require_auth(&req, &state, Scope::StatusRead) {
+        return resp;
+    }
+
+    match find_host(&state, &path) {
+        Ok(host) => status_response(&state, host).await,
+        Err(resp) => resp,
+    }
+}
+
+async fn status_response(state: &AppState, host: &HostConfig) -> HttpResponse {
+    let snapshot = state.monitor.status(host).await;
+    let status = snapshot.status;
 
     HttpResponse::Ok().json(serde_json::json!({
-        "homelab_ip": state.config.homelab_ip,
+        "host": host.id,
+        "homelab_ip": host.ip,
+        "phase": snapshot.phase,
         "reachable": status.reachable,
         "initrd_ssh_open": status.initrd_ssh_open,
         "system_ssh_open": status.system_ssh_open,
-        "initrd_ssh_port": state.config.ssh_port
+        "initrd_ssh_port": host.ssh_port,
+        "probes": status.probes
     }))
 }
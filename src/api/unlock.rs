@@ -1,16 +1,25 @@
 use actix_web::{HttpRequest, HttpResponse, web};
 use serde::Deserialize;
 
-use crate::auth::AppState;
-use crate::services::ssh::send_passphrase;
+use crate::apikeys::Scope;
+use crate::audit::{AuditAction, AuditOutcome};
+use crate::auth::{AppState, AuthContext};
+use crate::config::HostConfig;
+use crate::config::HostPhase;
+use crate::services::network::wait_for_status;
+use crate::services::ssh::UnlockOutcome;
+use crate::services::unlock::backend_for;
 
-use super::require_auth;
+use super::{audit_entry, find_host, require_auth};
 
 const MAX_PASSPHRASE_LEN: usize = 1024;
 
 #[derive(Deserialize)]
 pub struct UnlockRequest {
     passphrase: String,
+    /// Keep the request open until the system SSH port answers
+    #[serde(default)]
+    wait_for_boot: bool,
 }
 
 #[derive(Debug, PartialEq)]
@@ -38,11 +47,34 @@ pub async fn unlock(
     state: web::Data<AppState>,
     body: web::Json<UnlockRequest>,
 ) -> HttpResponse {
-    if let Err(resp) = require_auth(&req, &state) {
-        return resp;
+    let auth = match require_auth(&req, &state, Scope::Unlock) {
+        Ok(auth) => auth,
+        Err(resp) => return resp,
+    };
+
+    unlock_host(&req, &state, &auth, state.config.default_host(), &body).await
+}
+
+pub async fn unlock_host_by_id(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    body: web::Json<UnlockRequest>,
+) -> HttpResponse {
+    let auth = match require_auth(&req, &state, Scope::Unlock) {
+        Ok(auth) => auth,
+        Err(resp) => return resp,
+    };
+
+    match find_host(&state, &path) {
+        Ok(host) => unlock_host(&req, &state, &auth, host, &body).await,
+        Err(resp) => resp,
     }
+}
 
-    if let Err(e) = validate_passphrase(&body.passphrase) {
+/// Validates a passphrase, returning a 400 response describing the problem.
+pub(super) fn check_passphrase(passphrase: &str) -> Result<(), HttpResponse> {
+    validate_passphrase(passphrase).map_err(|e| {
         let error_msg = match e {
             PassphraseValidationError::Empty => "Passphrase cannot be empty",
             PassphraseValidationError::TooLong => "Passphrase too long",
@@ -50,25 +82,64 @@ pub async fn unlock(
                 "Passphrase contains invalid characters"
             }
         };
-        return HttpResponse::BadRequest().json(serde_json::json!({
+        HttpResponse::BadRequest().json(serde_json::json!({
             "error": error_msg
-        }));
+        }))
+    })
+}
+
+async fn unlock_host(
+    req: &HttpRequest,
+    state: &AppState,
+    auth: &AuthContext,
+    host: &HostConfig,
+    body: &UnlockRequest,
+) -> HttpResponse {
+    if let Err(resp) = check_passphrase(&body.passphrase) {
+        return resp;
     }
 
-    match send_passphrase(
-        &state.config.homelab_ip,
-        state.config.ssh_port,
-        &state.config.ssh_key_path,
-        &body.passphrase,
-    )
-    .await
-    {
-        Ok(()) => HttpResponse::Ok().json(serde_json::json!({
-            "success": true,
-            "message": "Passphrase sent successfully"
-        })),
+    let backend = backend_for(&host.unlock);
+    let outcome = backend.unlock(host, &body.passphrase).await;
+    if let Ok(outcome) = &outcome {
+        tracing::info!("Unlock of {} finished: {:?}", host.id, outcome);
+    }
+    state.audit.record(
+        audit_entry(req, auth, AuditAction::Unlock, AuditOutcome::Success)
+            .host(&host.id)
+            .unlock_result(&outcome),
+    );
+
+    let outcome = match outcome {
+        Ok(UnlockOutcome::Unlocked) if body.wait_for_boot => {
+            match wait_for_status(host, state.config.boot.boot_timeout, |s| {
+                s.phase == HostPhase::Booted
+            })
+            .await
+            {
+                Some(_) => Ok(UnlockOutcome::Unlocked),
+                None => Ok(UnlockOutcome::Timeout),
+            }
+        }
+        other => other,
+    };
+
+    match outcome {
+        Ok(outcome) => {
+            let message = match outcome {
+                UnlockOutcome::Unlocked if body.wait_for_boot => "Disk unlocked and system booted",
+                UnlockOutcome::Unlocked => "Disk unlocked",
+                UnlockOutcome::Rejected => "Passphrase rejected",
+                UnlockOutcome::Timeout => "Timed out waiting for the server",
+            };
+            HttpResponse::Ok().json(serde_json::json!({
+                "success": outcome == UnlockOutcome::Unlocked,
+                "result": outcome,
+                "message": message
+            }))
+        }
         Err(e) => {
-            tracing::error!("Unlock failed: {}", e);
+            tracing::error!("Unlock failed for {}: {}", host.id, e);
             HttpResponse::InternalServerError().json(serde_json::json!({
                 "error": format!("Failed to unlock: {}", e)
             }))
@@ -1,22 +1,60 @@
 use actix_web::{HttpRequest, HttpResponse, web};
 
-use crate::auth::AppState;
+use crate::apikeys::Scope;
+use crate::audit::{AuditAction, AuditOutcome};
+use crate::auth::{AppState, AuthContext};
+use crate::config::HostConfig;
 use crate::services::wol::send_magic_packet;
 
-use super::require_auth;
+use super::{audit_entry, find_host, require_auth};
 
 pub async fn send_wol(req: HttpRequest, state: web::Data<AppState>) -> HttpResponse {
-    if let Err(resp) = require_auth(&req, &state) {
-        return resp;
+    let auth = match require_auth(&req, &state, Scope::Wol) {
+        Ok(auth) => auth,
+        Err(resp) => return resp,
+    };
+
+    wake_host(&req, &state, &auth, state.config.default_host())
+}
+
+pub async fn send_host_wol(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let auth = match require_auth(&req, &state, Scope::Wol) {
+        Ok(auth) => auth,
+        Err(resp) => return resp,
+    };
+
+    match find_host(&state, &path) {
+        Ok(host) => wake_host(&req, &state, &auth, host),
+        Err(resp) => resp,
     }
+}
+
+fn wake_host(
+    req: &HttpRequest,
+    state: &AppState,
+    auth: &AuthContext,
+    host: &HostConfig,
+) -> HttpResponse {
+    let result = send_magic_packet(&host.mac, &host.broadcast);
+    let entry = match &result {
+        Ok(()) => audit_entry(req, auth, AuditAction::Wol, AuditOutcome::Success),
+        Err(e) => {
+            audit_entry(req, auth, AuditAction::Wol, AuditOutcome::Failure).detail(e.to_string())
+        }
+    };
+    state.audit.record(entry.host(&host.id));
 
-    match send_magic_packet(&state.config.homelab_mac, &state.config.homelab_broadcast) {
+    match result {
         Ok(()) => HttpResponse::Ok().json(serde_json::json!({
             "success": true,
             "message": "Wake-on-LAN packet sent"
         })),
         Err(e) => {
-            tracing::error!("WOL failed: {}", e);
+            tracing::error!("WOL failed for {}: {}", host.id, e);
             HttpResponse::InternalServerError().json(serde_json::json!({
                 "error": format!("Failed to send WOL packet: {}", e)
             }))
@@ -0,0 +1,368 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
+
+use crate::store::hash_token;
+use crate::util::{self, FileCache, random_bytes, unix_now};
+
+/// Every API key starts with this, so it can be told apart from a session
+/// token (plain hex) and found by secret scanners
+pub const KEY_PREFIX: &str = "awk_";
+pub const MAX_NAME_LEN: usize = 64;
+
+#[derive(Debug, Error)]
+pub enum ApiKeyError {
+    #[error("Failed to read {0}: {1}")]
+    ReadFailed(PathBuf, String),
+    #[error("Failed to write {0}: {1}")]
+    WriteFailed(PathBuf, String),
+    #[error("Unknown scope {0:?} (expected status:read, wol, unlock or admin)")]
+    UnknownScope(String),
+    #[error("Invalid key name {0:?}: 1 to 64 printable characters")]
+    InvalidName(String),
+    #[error("No API key with ID {0}")]
+    NotFound(String),
+}
+
+/// What a caller may do. Sessions currently get [`Scope::Admin`]; API keys
+/// get the scopes they were created with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scope {
+    /// Host list, status, live events and boot job progress
+    StatusRead,
+    /// Send magic packets
+    Wol,
+    /// Send the passphrase, including boot jobs
+    Unlock,
+    /// Everything, including sessions, passkeys and the audit log
+    Admin,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 4] = [Scope::StatusRead, Scope::Wol, Scope::Unlock, Scope::Admin];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::StatusRead => "status:read",
+            Scope::Wol => "wol",
+            Scope::Unlock => "unlock",
+            Scope::Admin => "admin",
+        }
+    }
+
+    /// Whether holding `self` grants `needed`.
+    pub fn grants(self, needed: Scope) -> bool {
+        self == Scope::Admin || self == needed
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Scope {
+    type Err = ApiKeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Scope::ALL
+            .into_iter()
+            .find(|scope| scope.as_str() == s)
+            .ok_or_else(|| ApiKeyError::UnknownScope(s.to_string()))
+    }
+}
+
+impl Serialize for Scope {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Scope {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Parses `status:read,wol`.
+pub fn parse_scopes(value: &str) -> Result<Vec<Scope>, ApiKeyError> {
+    let mut scopes = Vec::new();
+    for scope in value.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let scope = scope.parse()?;
+        if !scopes.contains(&scope) {
+            scopes.push(scope);
+        }
+    }
+    Ok(scopes)
+}
+
+/// A stored API key. Only the SHA-256 hash of the key is kept.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    /// Identifies the key in the audit log and for revocation
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    /// Unix timestamps in seconds
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+    hash: String,
+}
+
+impl ApiKey {
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.iter().any(|held| held.grants(scope))
+    }
+
+    pub fn expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct KeyFile {
+    keys: Vec<ApiKey>,
+}
+
+/// API keys in a JSON file. The keys are cached, and read again when the
+/// file changes, so keys created or revoked with `articwake api-key` take
+/// effect within a second, without a restart.
+pub struct ApiKeys {
+    file: FileCache<KeyFile>,
+}
+
+impl ApiKeys {
+    pub fn new(path: PathBuf) -> Self {
+        ApiKeys {
+            file: FileCache::new(path),
+        }
+    }
+
+    pub fn list(&self) -> Result<Vec<ApiKey>, ApiKeyError> {
+        Ok(self.load()?.keys.clone())
+    }
+
+    /// Creates a key valid for `days` (forever if `None`). Returns the stored
+    /// entry and the key itself, which is not kept anywhere.
+    pub fn create(
+        &self,
+        name: &str,
+        scopes: Vec<Scope>,
+        days: Option<u32>,
+    ) -> Result<(ApiKey, String), ApiKeyError> {
+        let valid_name = !name.is_empty()
+            && name.chars().count() <= MAX_NAME_LEN
+            && !name.chars().any(char::is_control);
+        if !valid_name {
+            return Err(ApiKeyError::InvalidName(name.to_string()));
+        }
+
+        let mut file = KeyFile::clone(&*self.load()?);
+        let key = format!("{}{}", KEY_PREFIX, hex::encode(random_bytes::<32>()));
+        let now = unix_now();
+        let entry = ApiKey {
+            id: hex::encode(random_bytes::<4>()),
+            name: name.to_string(),
+            scopes,
+            created_at: now,
+            expires_at: days.map(|days| now + u64::from(days) * 24 * 60 * 60),
+            hash: hash_token(&key),
+        };
+        file.keys.push(entry.clone());
+        self.save(&file)?;
+        Ok((entry, key))
+    }
+
+    /// Deletes the key with `id`.
+    pub fn revoke(&self, id: &str) -> Result<ApiKey, ApiKeyError> {
+        let mut file = KeyFile::clone(&*self.load()?);
+        let index = file
+            .keys
+            .iter()
+            .position(|key| key.id == id)
+            .ok_or_else(|| ApiKeyError::NotFound(id.to_string()))?;
+        let removed = file.keys.remove(index);
+        self.save(&file)?;
+        Ok(removed)
+    }
+
+    /// The unexpired key matching `key`, if any.
+    pub fn verify(&self, key: &str) -> Result<Option<ApiKey>, ApiKeyError> {
+        let hash = hash_token(key);
+        let now = unix_now();
+        Ok(self
+            .load()?
+            .keys
+            .iter()
+            .find(|entry| entry.hash == hash && !entry.expired(now))
+            .cloned())
+    }
+
+    /// Whether the key with `id` still exists and has not expired.
+    pub fn is_active(&self, id: &str) -> Result<bool, ApiKeyError> {
+        let now = unix_now();
+        Ok(self
+            .load()?
+            .keys
+            .iter()
+            .any(|key| key.id == id && !key.expired(now)))
+    }
+
+    /// Reads the file again on the next use, e.g. on SIGHUP.
+    pub fn reload(&self) {
+        self.file.invalidate();
+    }
+
+    fn load(&self) -> Result<Arc<KeyFile>, ApiKeyError> {
+        self.file.get(|path| {
+            let read_failed = |e: String| ApiKeyError::ReadFailed(path.to_path_buf(), e);
+            match fs::read(path) {
+                Ok(content) => {
+                    serde_json::from_slice(&content).map_err(|e| read_failed(e.to_string()))
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(KeyFile::default()),
+                Err(e) => Err(read_failed(e.to_string())),
+            }
+        })
+    }
+
+    /// Replaces the file atomically, readable only by its owner.
+    fn save(&self, file: &KeyFile) -> Result<(), ApiKeyError> {
+        let path = self.file.path();
+        let write_failed =
+            |e: io::Error| ApiKeyError::WriteFailed(path.to_path_buf(), e.to_string());
+        let content = serde_json::to_vec_pretty(file).map_err(|e| write_failed(e.into()))?;
+        let saved = util::write_atomic(path, &content, 0o600).map_err(write_failed);
+        self.file.invalidate();
+        saved
+    }
+}
+
+/// Whether `token` looks like an API key rather than a session token.
+pub fn is_api_key(token: &str) -> bool {
+    token.starts_with(KEY_PREFIX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(dir: &tempfile::TempDir) -> ApiKeys {
+        ApiKeys::new(dir.path().join("api-keys.json"))
+    }
+
+    #[test]
+    fn test_create_verify_revoke() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let keys = keys(&dir);
+        assert!(keys.list().unwrap().is_empty());
+
+        let (entry, key) = keys
+            .create("home-assistant", vec![Scope::StatusRead, Scope::Wol], None)
+            .unwrap();
+        assert!(is_api_key(&key));
+        assert_eq!(entry.expires_at, None);
+
+        let found = keys.verify(&key).unwrap().unwrap();
+        assert_eq!(found.id, entry.id);
+        assert!(found.allows(Scope::Wol));
+        assert!(!found.allows(Scope::Unlock));
+        assert!(keys.verify("awk_wrong").unwrap().is_none());
+
+        // Only the hash is on disk
+        let content = fs::read_to_string(dir.path().join("api-keys.json")).unwrap();
+        assert!(!content.contains(&key));
+        assert!(content.contains("\"status:read\""));
+
+        assert!(keys.is_active(&entry.id).unwrap());
+        assert_eq!(keys.revoke(&entry.id).unwrap().name, "home-assistant");
+        assert!(keys.verify(&key).unwrap().is_none());
+        assert!(!keys.is_active(&entry.id).unwrap());
+        assert!(matches!(
+            keys.revoke(&entry.id),
+            Err(ApiKeyError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_expired_key_rejected() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let keys = keys(&dir);
+        let (entry, key) = keys.create("script", vec![Scope::Admin], Some(1)).unwrap();
+        assert!(keys.verify(&key).unwrap().is_some());
+
+        // Age it past its expiry
+        let mut file = KeyFile::clone(&keys.load().unwrap());
+        file.keys[0].expires_at = Some(unix_now() - 1);
+        keys.save(&file).unwrap();
+        assert!(keys.verify(&key).unwrap().is_none());
+        assert!(!keys.is_active(&entry.id).unwrap());
+    }
+
+    #[test]
+    fn test_sees_keys_from_other_processes() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let server = keys(&dir);
+        assert!(server.list().unwrap().is_empty());
+
+        // `articwake api-key create` writes the file behind the server's back
+        let (entry, key) = keys(&dir).create("script", vec![Scope::Wol], None).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        assert_eq!(server.verify(&key).unwrap().unwrap().id, entry.id);
+
+        keys(&dir).revoke(&entry.id).unwrap();
+        server.reload();
+        assert!(server.verify(&key).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_scopes() {
+        assert_eq!(
+            parse_scopes("status:read, wol,wol").unwrap(),
+            vec![Scope::StatusRead, Scope::Wol]
+        );
+        assert!(matches!(
+            parse_scopes("status:write"),
+            Err(ApiKeyError::UnknownScope(_))
+        ));
+        assert!(Scope::Admin.grants(Scope::Unlock));
+        assert!(!Scope::Wol.grants(Scope::Unlock));
+    }
+
+    #[test]
+    fn test_invalid_name() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let keys = keys(&dir);
+        assert!(matches!(
+            keys.create("", vec![Scope::Wol], None),
+            Err(ApiKeyError::InvalidName(_))
+        ));
+        assert!(matches!(
+            keys.create("a\nb", vec![Scope::Wol], None),
+            Err(ApiKeyError::InvalidName(_))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_file_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::TempDir::new().unwrap();
+        let keys = keys(&dir);
+        keys.create("script", vec![Scope::Wol], None).unwrap();
+        let mode = fs::metadata(dir.path().join("api-keys.json"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
@@ -0,0 +1,421 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::services::ssh::{SshError, UnlockOutcome};
+use crate::util::unix_now;
+
+/// Entries returned per page when the client does not ask for a size
+pub const DEFAULT_PAGE_SIZE: usize = 50;
+pub const MAX_PAGE_SIZE: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    /// PIN or passkey authentication
+    Login,
+    /// Session ended by logout or revocation
+    Logout,
+    Wol,
+    Unlock,
+    /// Boot job started or finished
+    Boot,
+    /// Passkey registered or removed
+    Passkey,
+    /// PIN changed through the API
+    Pin,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Success,
+    Failure,
+}
+
+/// One line of the audit log. Never holds secrets such as the PIN, the
+/// passphrase or the session token.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Unix timestamp in seconds, set when the entry is written
+    pub timestamp: u64,
+    pub action: AuditAction,
+    pub outcome: AuditOutcome,
+    pub client_ip: Option<IpAddr>,
+    pub session_id: Option<String>,
+    /// The user who acted, or the username tried at a failed login
+    #[serde(default)]
+    pub user: Option<String>,
+    pub host: Option<String>,
+    /// Why an action failed, or which boot job it belongs to
+    pub detail: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn new(action: AuditAction, outcome: AuditOutcome) -> Self {
+        AuditEntry {
+            timestamp: 0,
+            action,
+            outcome,
+            client_ip: None,
+            session_id: None,
+            user: None,
+            host: None,
+            detail: None,
+        }
+    }
+
+    pub fn client_ip(mut self, ip: IpAddr) -> Self {
+        self.client_ip = Some(ip);
+        self
+    }
+
+    pub fn session(mut self, session_id: &str) -> Self {
+        self.session_id = Some(session_id.to_string());
+        self
+    }
+
+    pub fn user(mut self, username: &str) -> Self {
+        self.user = Some(username.to_string());
+        self
+    }
+
+    pub fn host(mut self, host: &str) -> Self {
+        self.host = Some(host.to_string());
+        self
+    }
+
+    pub fn detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    /// Sets the outcome (and failure reason) from the result of an unlock.
+    pub fn unlock_result(mut self, result: &Result<UnlockOutcome, SshError>) -> Self {
+        let failure = match result {
+            Ok(UnlockOutcome::Unlocked) => None,
+            Ok(UnlockOutcome::Rejected) => Some("Passphrase rejected".to_string()),
+            Ok(UnlockOutcome::Timeout) => Some("Timed out waiting for the server".to_string()),
+            Err(e) => Some(e.to_string()),
+        };
+        match failure {
+            Some(reason) => {
+                self.outcome = AuditOutcome::Failure;
+                self.detail(reason)
+            }
+            None => {
+                self.outcome = AuditOutcome::Success;
+                self
+            }
+        }
+    }
+}
+
+/// Filters and paging for [`AuditLog::query`]. All filters must match.
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditQuery {
+    pub action: Option<AuditAction>,
+    pub outcome: Option<AuditOutcome>,
+    pub host: Option<String>,
+    pub client_ip: Option<IpAddr>,
+    pub session_id: Option<String>,
+    pub user: Option<String>,
+    /// Unix timestamps in seconds, inclusive
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+impl AuditQuery {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.action.is_none_or(|action| entry.action == action)
+            && self.outcome.is_none_or(|outcome| entry.outcome == outcome)
+            && self
+                .host
+                .as_ref()
+                .is_none_or(|host| entry.host.as_ref() == Some(host))
+            && self.client_ip.is_none_or(|ip| entry.client_ip == Some(ip))
+            && self
+                .session_id
+                .as_ref()
+                .is_none_or(|id| entry.session_id.as_ref() == Some(id))
+            && self
+                .user
+                .as_ref()
+                .is_none_or(|user| entry.user.as_ref() == Some(user))
+            && self.since.is_none_or(|since| entry.timestamp >= since)
+            && self.until.is_none_or(|until| entry.timestamp <= until)
+    }
+}
+
+/// A page of entries, newest first.
+#[derive(Debug, Serialize)]
+pub struct AuditPage {
+    pub entries: Vec<AuditEntry>,
+    /// Matching entries across all pages
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+/// Append-only JSON lines file of privileged actions. Write failures are
+/// logged but never fail the action being audited.
+pub struct AuditLog {
+    path: Option<PathBuf>,
+    file: Mutex<Option<File>>,
+}
+
+impl AuditLog {
+    /// Opens the log at `path`; `None` disables auditing.
+    pub fn new(path: Option<PathBuf>) -> Self {
+        let file = path.as_deref().and_then(|path| match open_append(path) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                tracing::error!("Failed to open audit log {}: {}", path.display(), e);
+                None
+            }
+        });
+        AuditLog {
+            path,
+            file: Mutex::new(file),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.path.is_some()
+    }
+
+    pub fn record(&self, mut entry: AuditEntry) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        entry.timestamp = unix_now();
+
+        let mut line = match serde_json::to_vec(&entry) {
+            Ok(line) => line,
+            Err(e) => {
+                tracing::error!("Failed to serialize audit entry: {}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        let mut file = self.file.lock().unwrap();
+        // Retry opening if the file was missing at startup
+        if file.is_none() {
+            *file = open_append(path).ok();
+        }
+        let result = match file.as_mut() {
+            // A single write keeps lines whole with O_APPEND
+            Some(file) => file.write_all(&line),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "not open")),
+        };
+        if let Err(e) = result {
+            tracing::error!("Failed to write audit log {}: {}", path.display(), e);
+            *file = None;
+        }
+    }
+
+    /// Reads the log and returns the matching entries, newest first. Lines
+    /// that do not parse are skipped.
+    pub fn query(&self, query: &AuditQuery) -> io::Result<AuditPage> {
+        let limit = query
+            .limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE);
+        let mut matching = Vec::new();
+
+        if let Some(path) = &self.path {
+            let file = match File::open(path) {
+                Ok(file) => Some(file),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e),
+            };
+            for line in file.into_iter().flat_map(|f| BufReader::new(f).lines()) {
+                if let Ok(entry) = serde_json::from_str::<AuditEntry>(&line?)
+                    && query.matches(&entry)
+                {
+                    matching.push(entry);
+                }
+            }
+        }
+
+        let total = matching.len();
+        let entries = matching
+            .into_iter()
+            .rev()
+            .skip(query.offset)
+            .take(limit)
+            .collect();
+        Ok(AuditPage {
+            entries,
+            total,
+            offset: query.offset,
+            limit,
+        })
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn log_in(dir: &TempDir) -> AuditLog {
+        AuditLog::new(Some(dir.path().join("audit.log")))
+    }
+
+    #[test]
+    fn test_record_appends_json_lines() {
+        let dir = TempDir::new().unwrap();
+        let log = log_in(&dir);
+        log.record(
+            AuditEntry::new(AuditAction::Login, AuditOutcome::Success)
+                .client_ip("10.0.0.5".parse().unwrap())
+                .session("abc"),
+        );
+        log.record(AuditEntry::new(AuditAction::Wol, AuditOutcome::Failure).host("nas"));
+
+        let content = std::fs::read_to_string(dir.path().join("audit.log")).unwrap();
+        let lines: Vec<_> = content.lines().collect();
+        assert_eq!(lines.len(), 2);
+
+        let first: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(first["action"], "login");
+        assert_eq!(first["outcome"], "success");
+        assert_eq!(first["client_ip"], "10.0.0.5");
+        assert_eq!(first["session_id"], "abc");
+        assert!(first["timestamp"].as_u64().unwrap() > 0);
+    }
+
+    #[test]
+    fn test_reopen_keeps_entries() {
+        let dir = TempDir::new().unwrap();
+        log_in(&dir).record(AuditEntry::new(AuditAction::Login, AuditOutcome::Success));
+        log_in(&dir).record(AuditEntry::new(AuditAction::Login, AuditOutcome::Failure));
+
+        let page = log_in(&dir).query(&AuditQuery::default()).unwrap();
+        assert_eq!(page.total, 2);
+        assert_eq!(page.entries[0].outcome, AuditOutcome::Failure);
+    }
+
+    #[test]
+    fn test_query_filters() {
+        let dir = TempDir::new().unwrap();
+        let log = log_in(&dir);
+        log.record(AuditEntry::new(AuditAction::Wol, AuditOutcome::Success).host("nas"));
+        log.record(AuditEntry::new(AuditAction::Unlock, AuditOutcome::Failure).host("nas"));
+        log.record(AuditEntry::new(AuditAction::Unlock, AuditOutcome::Success).host("gpu"));
+
+        let query = |query: AuditQuery| log.query(&query).unwrap();
+        assert_eq!(
+            query(AuditQuery {
+                action: Some(AuditAction::Unlock),
+                ..Default::default()
+            })
+            .total,
+            2
+        );
+        assert_eq!(
+            query(AuditQuery {
+                host: Some("nas".to_string()),
+                outcome: Some(AuditOutcome::Failure),
+                ..Default::default()
+            })
+            .total,
+            1
+        );
+        assert_eq!(
+            query(AuditQuery {
+                until: Some(1),
+                ..Default::default()
+            })
+            .total,
+            0
+        );
+    }
+
+    #[test]
+    fn test_query_pages_newest_first() {
+        let dir = TempDir::new().unwrap();
+        let log = log_in(&dir);
+        for host in ["a", "b", "c", "d", "e"] {
+            log.record(AuditEntry::new(AuditAction::Wol, AuditOutcome::Success).host(host));
+        }
+
+        let page = log
+            .query(&AuditQuery {
+                offset: 1,
+                limit: Some(2),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(page.total, 5);
+        let hosts: Vec<_> = page.entries.iter().map(|e| e.host.as_deref()).collect();
+        assert_eq!(hosts, [Some("d"), Some("c")]);
+
+        let page = log
+            .query(&AuditQuery {
+                limit: Some(10_000),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(page.limit, MAX_PAGE_SIZE);
+    }
+
+    #[test]
+    fn test_query_skips_malformed_lines() {
+        let dir = TempDir::new().unwrap();
+        let log = log_in(&dir);
+        log.record(AuditEntry::new(AuditAction::Login, AuditOutcome::Success));
+        let mut file = open_append(&dir.path().join("audit.log")).unwrap();
+        file.write_all(b"{\"truncated\n").unwrap();
+        log.record(AuditEntry::new(AuditAction::Login, AuditOutcome::Success));
+
+        assert_eq!(log.query(&AuditQuery::default()).unwrap().total, 2);
+    }
+
+    #[test]
+    fn test_unlock_result() {
+        let entry = |result| {
+            AuditEntry::new(AuditAction::Unlock, AuditOutcome::Success).unlock_result(&result)
+        };
+
+        let unlocked = entry(Ok(UnlockOutcome::Unlocked));
+        assert_eq!(unlocked.outcome, AuditOutcome::Success);
+        assert_eq!(unlocked.detail, None);
+
+        let rejected = entry(Ok(UnlockOutcome::Rejected));
+        assert_eq!(rejected.outcome, AuditOutcome::Failure);
+        assert_eq!(rejected.detail.as_deref(), Some("Passphrase rejected"));
+
+        let failed = entry(Err(SshError::AuthFailed));
+        assert_eq!(failed.outcome, AuditOutcome::Failure);
+        assert!(failed.detail.is_some());
+    }
+
+    #[test]
+    fn test_disabled_log() {
+        let log = AuditLog::new(None);
+        assert!(!log.is_enabled());
+        log.record(AuditEntry::new(AuditAction::Login, AuditOutcome::Success));
+        assert_eq!(log.query(&AuditQuery::default()).unwrap().total, 0);
+    }
+}
@@ -1,121 +1,984 @@
 use std::collections::HashMap;
 use std::fs;
+use std::io;
 use std::net::IpAddr;
-use std::sync::Mutex;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Duration, Instant};
 
-use argon2::{Argon2, PasswordHash, PasswordVerifier};
-use rand::Rng;
+use argon2::PasswordHash;
+use argon2::password_hash::PasswordHashString;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 
+use crate::api::access::BlockedCounts;
+use crate::apikeys::{self, ApiKeys, Scope};
+use crate::audit::AuditLog;
+use crate::audit::{AuditAction, AuditEntry, AuditOutcome};
 use crate::config::Config;
+use crate::lockout;
+use crate::pin::{self, PinError};
+use crate::services::boot::JobRegistry;
+use crate::services::monitor::StatusMonitor;
+use crate::store::{
+    self, AuthState, FailureRecord, MemoryStore, StateStore, StoreError, hash_token,
+};
+use crate::totp;
+use crate::users::{Role, UserError, Users};
+use crate::util::{random_bytes, unix_now};
+use crate::webauthn::{CredentialInfo, LoginResponse, Webauthn, WebauthnError};
 
-const TOKEN_EXPIRY: Duration = Duration::from_secs(15 * 60); // 15 minutes
-const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
-const MAX_ATTEMPTS_PER_WINDOW: usize = 10;
+/// Idle timeout: every authenticated request pushes expiry back this far
+pub const TOKEN_EXPIRY: Duration = Duration::from_secs(15 * 60); // 15 minutes
+/// Sessions end this long after login, however active they are
+pub const MAX_SESSION_LIFETIME: Duration = Duration::from_secs(12 * 60 * 60);
+/// Failed logins a client gets before it has to wait
+const FREE_FAILURES: u32 = 5;
+/// Wait after the first failure past `FREE_FAILURES`, doubled for each further one
+const BASE_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+/// A client's failures are forgotten this long after its last one
+const FAILURE_MEMORY: Duration = Duration::from_secs(24 * 60 * 60);
+/// Failed PIN logins from all clients together that suspend PIN login
+/// until the oldest of them is `GLOBAL_FAILURE_WINDOW` old
+const MAX_GLOBAL_FAILURES: usize = 50;
+const GLOBAL_FAILURE_WINDOW: Duration = Duration::from_secs(60 * 60);
+/// Using a session only rewrites the state file once it has been idle this
+/// long, so browsing doesn't turn into a write per request
+const PERSIST_ACTIVITY_AFTER: u64 = 60;
+/// How long a client is asked to wait when every hashing slot is taken
+const HASH_BUSY_RETRY_AFTER: u64 = 1;
+/// How long an event stream ticket can be redeemed for
+pub const EVENTS_TICKET_LIFETIME: Duration = Duration::from_secs(30);
+/// Unredeemed tickets kept at once; the oldest is dropped beyond this
+const MAX_EVENTS_TICKETS: usize = 32;
 
 #[derive(Debug, Error)]
 pub enum AuthError {
     #[error("Invalid PIN")]
     InvalidPin,
     #[error("Rate limited")]
-    RateLimited,
+    RateLimited { retry_after: u64 },
+    #[error("PIN login suspended after too many failures")]
+    PinLoginSuspended { retry_after: u64 },
+    #[error("PIN login locked out")]
+    LockedOut,
+    #[error("Too many PIN checks in progress")]
+    Busy { retry_after: u64 },
     #[error("Invalid or expired token")]
     InvalidToken,
     #[error("Failed to read PIN hash: {0}")]
     PinHashReadFailed(String),
     #[error("Invalid PIN hash format: {0}")]
     InvalidPinHash(String),
+    #[error("TOTP code required")]
+    TotpRequired,
+    #[error("Invalid TOTP code")]
+    InvalidTotp,
+    #[error("TOTP code already used")]
+    TotpReplayed,
+    #[error("Failed to read TOTP secret: {0}")]
+    TotpSecretReadFailed(String),
+    #[error("Username required")]
+    UsernameRequired,
+    #[error(transparent)]
+    Users(#[from] UserError),
+    #[error(transparent)]
+    Pin(#[from] PinError),
+    #[error(transparent)]
+    Passkey(#[from] WebauthnError),
+    #[error(transparent)]
+    Store(#[from] StoreError),
 }
 
-struct Session {
-    expires_at: Instant,
+/// A logged in client. Times are Unix timestamps in seconds, so sessions can
+/// be persisted and survive a restart.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    /// Identifies the session in the audit log without revealing the token
+    pub id: String,
+    #[serde(flatten)]
+    pub client: ClientInfo,
+    /// `None` for the shared account: logins with the shared PIN, or a
+    /// passkey or certificate not tied to a user
+    #[serde(default)]
+    pub username: Option<String>,
+    /// Sessions saved before user accounts existed all had full access
+    #[serde(default = "admin_role")]
+    pub role: Role,
+    pub created_at: u64,
+    pub last_used_at: u64,
+    pub expires_at: u64,
+    /// Hard limit on sliding `expires_at`
+    pub max_expires_at: u64,
+}
+
+impl Session {
+    /// Pushes expiry back after activity at `now`.
+    fn touch(&mut self, now: u64) {
+        self.expires_at = (now + TOKEN_EXPIRY.as_secs()).min(self.max_expires_at);
+        self.last_used_at = now;
+    }
+
+    fn info(&self, current: &str) -> SessionInfo {
+        SessionInfo {
+            id: self.id.clone(),
+            created_at: self.created_at,
+            last_used_at: self.last_used_at,
+            expires_at: self.expires_at,
+            client_ip: self.client.ip,
+            user_agent: self.client.user_agent.clone(),
+            username: self.username.clone(),
+            role: self.role,
+            current: self.id == current,
+        }
+    }
 }
 
-struct RateLimitEntry {
-    attempts: Vec<Instant>,
+fn admin_role() -> Role {
+    Role::Admin
+}
+
+/// Where a login came from, kept with the session it opened.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientInfo {
+    pub ip: IpAddr,
+    pub user_agent: Option<String>,
+}
+
+/// An active session as listed by `GET /api/sessions`. Never holds the token.
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    pub id: String,
+    /// Unix timestamps in seconds
+    pub created_at: u64,
+    pub last_used_at: u64,
+    pub expires_at: u64,
+    pub client_ip: IpAddr,
+    pub user_agent: Option<String>,
+    pub username: Option<String>,
+    pub role: Role,
+    /// Whether this is the session making the request
+    pub current: bool,
+}
+
+/// The session or API key behind an authenticated request.
+#[derive(Debug, Clone)]
+pub struct AuthContext {
+    /// Session ID, or `key:<id>` for an API key
+    pub session_id: String,
+    /// The user who logged in, if they logged in with a username
+    pub username: Option<String>,
+    pub scopes: Vec<Scope>,
+}
+
+impl AuthContext {
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.iter().any(|held| held.grants(scope))
+    }
 }
 
 pub struct AppState {
     pub config: Config,
-    sessions: Mutex<HashMap<String, Session>>,
-    rate_limits: Mutex<HashMap<IpAddr, RateLimitEntry>>,
+    pub jobs: Arc<JobRegistry>,
+    pub monitor: Arc<StatusMonitor>,
+    pub audit: Arc<AuditLog>,
+    pub webauthn: Webauthn,
+    pub api_keys: ApiKeys,
+    pub users: Users,
+    /// Requests refused by the `[access]` rules
+    pub blocked: BlockedCounts,
+    /// Sessions, login attempts and the last accepted TOTP step, which is
+    /// refused along with earlier steps so a code cannot be replayed
+    state: Mutex<AuthState>,
+    store: Box<dyn StateStore>,
+    /// The shared PIN hash, read at startup and on SIGHUP; `None` while
+    /// `pin_hash_path` does not exist
+    pin_hash: RwLock<Option<PasswordHashString>>,
+    /// One permit per Argon2 hash allowed to run at once
+    hashing: Arc<Semaphore>,
+    /// Single-use tickets for opening an event stream, by ticket hash
+    events_tickets: Mutex<HashMap<String, EventsTicket>>,
+}
+
+struct EventsTicket {
+    auth: AuthContext,
+    expires_at: Instant,
 }
 
 impl AppState {
+    /// Sets up the state for `config`. A PIN hash that cannot be loaded is
+    /// logged and leaves the shared PIN unusable; a state file that cannot
+    /// be loaded is logged and left alone, with nothing saved until restart.
+    /// See [`Self::open`] to fail instead.
     pub fn new(config: Config) -> Self {
+        let pin_hash = read_pin_hash(&config.pin_hash_path).unwrap_or_else(|e| {
+            tracing::error!("{}", e);
+            None
+        });
+        let (store, state) = load_state(&config).unwrap_or_else(|e| {
+            tracing::error!("{}; keeping sessions in memory only", e);
+            (Box::new(MemoryStore), AuthState::default())
+        });
+        Self::with_state(config, pin_hash, store, state)
+    }
+
+    /// Like [`Self::new`], but fails if the shared PIN hash is unreadable or
+    /// invalid, or missing while there are no user accounts to log in with,
+    /// or if the state file is unreadable or corrupt, so a broken setup
+    /// shows at startup rather than at the first login. Starting over with
+    /// an empty state would forget rate limits and the TOTP replay guard.
+    pub fn open(config: Config) -> Result<Self, AuthError> {
+        let pin_hash = read_pin_hash(&config.pin_hash_path)?;
+        let (store, auth_state) = load_state(&config)?;
+        let state = Self::with_state(config, pin_hash, store, auth_state);
+        if state.pin_hash.read().unwrap().is_none() && state.users.list()?.is_empty() {
+            return Err(state.missing_pin_hash());
+        }
+        Ok(state)
+    }
+
+    fn with_state(
+        config: Config,
+        pin_hash: Option<PasswordHashString>,
+        store: Box<dyn StateStore>,
+        state: AuthState,
+    ) -> Self {
         AppState {
+            monitor: Arc::new(StatusMonitor::new(config.status_interval)),
+            audit: Arc::new(AuditLog::new(config.audit_log_path.clone())),
+            webauthn: Webauthn::new(&config.webauthn),
+            api_keys: ApiKeys::new(config.api_keys_path.clone()),
+            users: Users::new(config.users_path.clone(), config.pin.argon2.clone()),
+            hashing: Arc::new(Semaphore::new(config.login.max_concurrent_hashes as usize)),
             config,
-            sessions: Mutex::new(HashMap::new()),
-            rate_limits: Mutex::new(HashMap::new()),
+            jobs: Arc::new(JobRegistry::new()),
+            blocked: BlockedCounts::default(),
+            state: Mutex::new(state),
+            store,
+            pin_hash: RwLock::new(pin_hash),
+            events_tickets: Mutex::new(HashMap::new()),
         }
     }
 
-    pub fn check_rate_limit(&self, ip: IpAddr) -> Result<(), AuthError> {
-        let mut limits = self.rate_limits.lock().unwrap();
-        let now = Instant::now();
+    /// Takes one of the `max_concurrent_hashes` slots for checking a PIN,
+    /// held until the permit is dropped. Fails at once when all are taken,
+    /// so a flood of logins is turned away instead of queueing up.
+    pub fn reserve_hash(&self) -> Result<OwnedSemaphorePermit, AuthError> {
+        self.hashing
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| AuthError::Busy {
+                retry_after: HASH_BUSY_RETRY_AFTER,
+            })
+    }
 
-        let entry = limits.entry(ip).or_insert(RateLimitEntry {
-            attempts: Vec::new(),
-        });
+    /// The checks of a PIN login that need no hashing: the lockout, the
+    /// global suspension and, once TOTP is enrolled and not `totp_waived`, a
+    /// missing code. Run before [`Self::reserve_hash`], so a login refused
+    /// anyway does not take a slot.
+    pub fn check_before_hashing(
+        &self,
+        totp: Option<&str>,
+        totp_waived: bool,
+    ) -> Result<(), AuthError> {
+        self.check_pin_login()?;
+        if !totp_waived && totp.is_none_or(|code| code.trim().is_empty()) && self.totp_enabled()? {
+            return Err(AuthError::TotpRequired);
+        }
+        Ok(())
+    }
+
+    /// Reads `pin_hash_path` again, e.g. after `hash-pin` replaced it. An
+    /// unreadable or invalid file is refused and the hash loaded before
+    /// stays in use; a deleted file turns the shared PIN off.
+    pub fn reload_pin_hash(&self) -> Result<(), AuthError> {
+        let pin_hash = read_pin_hash(&self.config.pin_hash_path)?;
+        if pin_hash.is_none() {
+            tracing::warn!(
+                "{} does not exist; logins need a username",
+                self.config.pin_hash_path.display()
+            );
+        }
+        *self.pin_hash.write().unwrap() = pin_hash;
+        Ok(())
+    }
+
+    /// Writes a new shared PIN hash and uses it from now on. With
+    /// `replaces`, only if that is still the hash in use, checked under the
+    /// same lock, so rehashing a PIN that was just verified never undoes a
+    /// change made meanwhile. Returns whether it was written.
+    fn replace_pin_hash(&self, hash: &str, replaces: Option<&str>) -> Result<bool, PinError> {
+        let parsed = PasswordHash::new(hash)
+            .map_err(|e| PinError::HashFailed(e.to_string()))?
+            .serialize();
+        let mut current = self.pin_hash.write().unwrap();
+        if let Some(replaces) = replaces
+            && current.as_ref().map(PasswordHashString::as_str) != Some(replaces)
+        {
+            return Ok(false);
+        }
+        pin::write_hash(&self.config.pin_hash_path, hash)?;
+        *current = Some(parsed);
+        Ok(true)
+    }
+
+    fn missing_pin_hash(&self) -> AuthError {
+        AuthError::PinHashReadFailed(format!(
+            "{}: file not found",
+            self.config.pin_hash_path.display()
+        ))
+    }
+
+    /// Writes the state to the store. Failing to persist is logged, not
+    /// fatal: the in-memory state is still correct.
+    fn persist(&self, state: &AuthState) {
+        if let Err(e) = self.store.save(state) {
+            tracing::warn!("{}", e);
+        }
+    }
 
-        // Remove old attempts outside the window
-        entry
-            .attempts
-            .retain(|t| now.duration_since(*t) < RATE_LIMIT_WINDOW);
+    /// Refuses clients that are still backing off after failed logins.
+    /// Only failures count, so checking is free.
+    pub fn check_rate_limit(&self, ip: IpAddr) -> Result<(), AuthError> {
+        let state = self.state.lock().unwrap();
+        let now = unix_now();
 
-        if entry.attempts.len() >= MAX_ATTEMPTS_PER_WINDOW {
-            return Err(AuthError::RateLimited);
+        match state.failures.get(&ip) {
+            Some(record) => {
+                let until = record.last_failure + backoff(record.count).as_secs();
+                if now < until {
+                    return Err(AuthError::RateLimited {
+                        retry_after: until - now,
+                    });
+                }
+                Ok(())
+            }
+            None => Ok(()),
         }
+    }
 
-        entry.attempts.push(now);
+    /// Refuses PIN logins while locked out, or while too many have failed
+    /// recently across all clients.
+    fn check_pin_login(&self) -> Result<(), AuthError> {
+        if lockout::is_locked(&self.config.login.lockout_path) {
+            return Err(AuthError::LockedOut);
+        }
+
+        let state = self.state.lock().unwrap();
+        let now = unix_now();
+        let window = GLOBAL_FAILURE_WINDOW.as_secs();
+        let recent: Vec<u64> = state
+            .pin_failures
+            .iter()
+            .copied()
+            .filter(|&t| t + window > now)
+            .collect();
+        if recent.len() >= MAX_GLOBAL_FAILURES {
+            // Allowed again once enough of them have aged out
+            let oldest = recent[recent.len() - MAX_GLOBAL_FAILURES];
+            return Err(AuthError::PinLoginSuspended {
+                retry_after: oldest + window - now,
+            });
+        }
         Ok(())
     }
 
-    pub fn verify_pin(&self, pin: &str) -> Result<String, AuthError> {
-        let hash_content = fs::read_to_string(&self.config.pin_hash_path)
-            .map_err(|e| AuthError::PinHashReadFailed(e.to_string()))?;
+    /// Counts a failed login from `ip`. Failed PIN logins also count
+    /// towards the global budget and the permanent lockout.
+    fn record_failure(&self, ip: IpAddr, pin: bool) {
+        let mut state = self.state.lock().unwrap();
+        let now = unix_now();
+
+        let record = state.failures.entry(ip).or_default();
+        if record.last_failure + FAILURE_MEMORY.as_secs() <= now {
+            *record = FailureRecord::default();
+        }
+        record.count += 1;
+        record.last_failure = now;
+
+        let mut lock = false;
+        if pin {
+            record.pin_count += 1;
+            let window = GLOBAL_FAILURE_WINDOW.as_secs();
+            state.pin_failures.retain(|&t| t + window > now);
+            state.pin_failures.push(now);
+            state.failures_since_login += 1;
+            if let Some(limit) = self.config.login.lockout_after
+                && state.failures_since_login >= limit
+            {
+                // The count starts over, so no client has any left in it
+                state.failures_since_login = 0;
+                for record in state.failures.values_mut() {
+                    record.pin_count = 0;
+                }
+                lock = true;
+            }
+        }
+        self.persist(&state);
+        drop(state);
+
+        if lock {
+            self.lock_out(ip);
+        }
+    }
+
+    fn lock_out(&self, ip: IpAddr) {
+        let limit = self.config.login.lockout_after.unwrap_or_default();
+        let path = &self.config.login.lockout_path;
+        tracing::error!(
+            "PIN login locked after {} failed attempts; run `articwake clear-lockout` to unlock",
+            limit
+        );
+        let reason = format!(
+            "Locked at {} after {} failed PIN logins, the last from {}",
+            unix_now(),
+            limit,
+            ip
+        );
+        if let Err(e) = lockout::lock(path, &reason) {
+            tracing::error!("Failed to write lockout file {}: {}", path.display(), e);
+        }
+        self.audit.record(
+            AuditEntry::new(AuditAction::Login, AuditOutcome::Failure)
+                .client_ip(ip)
+                .detail(format!("PIN login locked after {} failures", limit)),
+        );
+    }
 
-        let hash = PasswordHash::new(hash_content.trim())
-            .map_err(|e| AuthError::InvalidPinHash(e.to_string()))?;
+    /// Forgets the failures of `ip` after it logged in, also towards the
+    /// lockout. Those of other clients still count.
+    fn record_success(&self, ip: IpAddr) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(record) = state.failures.remove(&ip) {
+            state.failures_since_login =
+                state.failures_since_login.saturating_sub(record.pin_count);
+        }
+        self.persist(&state);
+    }
 
-        Argon2::default()
-            .verify_password(pin.as_bytes(), &hash)
-            .map_err(|_| AuthError::InvalidPin)?;
+    /// Whether logins need a TOTP code, i.e. a secret has been enrolled.
+    pub fn totp_enabled(&self) -> Result<bool, AuthError> {
+        self.totp_secret().map(|secret| secret.is_some())
+    }
+
+    fn totp_secret(&self) -> Result<Option<Vec<u8>>, AuthError> {
+        totp::read_secret(&self.config.totp_secret_path)
+            .map_err(|e| AuthError::TotpSecretReadFailed(e.to_string()))
+    }
+
+    /// Checks the PIN and, once TOTP is enrolled, the code, then opens a
+    /// session and returns its token. With a username the PIN is that
+    /// user's and the session gets their role; without one it is the shared
+    /// PIN in `pin_hash_path`, which gives full access.
+    pub fn verify_pin(
+        &self,
+        username: Option<&str>,
+        pin: &str,
+        totp: Option<&str>,
+        client: ClientInfo,
+    ) -> Result<String, AuthError> {
+        self.pin_login(username, pin, totp, false, client)
+    }
+
+    /// Like [`Self::verify_pin`] for a client that presented a certificate
+    /// counting as a second factor, so no TOTP code is needed.
+    pub fn verify_pin_with_certificate(
+        &self,
+        username: Option<&str>,
+        pin: &str,
+        client: ClientInfo,
+    ) -> Result<String, AuthError> {
+        self.pin_login(username, pin, None, true, client)
+    }
 
-        // Generate session token
+    fn pin_login(
+        &self,
+        username: Option<&str>,
+        pin: &str,
+        totp: Option<&str>,
+        totp_waived: bool,
+        client: ClientInfo,
+    ) -> Result<String, AuthError> {
+        self.check_pin_login()?;
+
+        let result = self.check_factors(username, pin, totp, totp_waived);
+        match result {
+            Ok(_) => self.record_success(client.ip),
+            Err(AuthError::InvalidPin | AuthError::InvalidTotp) => {
+                self.record_failure(client.ip, true)
+            }
+            Err(_) => {}
+        }
+        let role = result?;
+
+        Ok(self.create_session(client, username, role))
+    }
+
+    /// Checks the PIN and, once TOTP is enrolled and not `totp_waived`, the
+    /// code. Returns the role they log in with.
+    fn check_factors(
+        &self,
+        username: Option<&str>,
+        pin: &str,
+        totp: Option<&str>,
+        totp_waived: bool,
+    ) -> Result<Role, AuthError> {
+        let totp_secret = match totp_waived {
+            true => None,
+            false => self.totp_secret()?,
+        };
+        // Ask for the code before checking the PIN, so the answer does not
+        // reveal whether the PIN was right
+        if totp_secret.is_some() && totp.is_none_or(|code| code.trim().is_empty()) {
+            return Err(AuthError::TotpRequired);
+        }
+
+        let role = self.check_pin(username, pin)?;
+        if let (Some(secret), Some(code)) = (totp_secret, totp) {
+            self.check_totp(&secret, code, totp::current_step(), username)?;
+        }
+        Ok(role)
+    }
+
+    /// Replaces the PIN the session logged in with, its user's or the shared
+    /// one, after checking `current` and the TOTP code like a login. Ends the
+    /// other sessions of the same account and returns how many there were:
+    /// a user's sessions for their PIN, those without a username for the
+    /// shared PIN. Other users keep theirs, as their PINs did not change.
+    pub fn change_pin(
+        &self,
+        auth: &AuthContext,
+        current: &str,
+        new: &str,
+        totp: Option<&str>,
+        ip: IpAddr,
+    ) -> Result<usize, AuthError> {
+        self.check_rate_limit(ip)?;
+        self.check_pin_login()?;
+        pin::check_policy(new, &self.config.pin)?;
+        if new == current {
+            return Err(PinError::Unchanged.into());
+        }
+
+        let username = auth.username.as_deref();
+        match self.check_factors(username, current, totp, false) {
+            Ok(_) => {}
+            Err(e @ (AuthError::InvalidPin | AuthError::InvalidTotp)) => {
+                self.record_failure(ip, true);
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        }
+        match username {
+            Some(username) => self.users.set_pin(username, new)?,
+            None => {
+                self.replace_pin_hash(&pin::hash(new, &self.config.pin.argon2)?, None)?;
+            }
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let before = state.sessions.len();
+        state
+            .sessions
+            .retain(|_, s| s.id == auth.session_id || s.username.as_deref() != username);
+        let ended = before - state.sessions.len();
+        self.persist(&state);
+        Ok(ended)
+    }
+
+    /// Checks a passkey assertion and opens a session, like [`Self::verify_pin`].
+    /// The passkey stands in for both the PIN and the TOTP code.
+    pub fn verify_passkey(
+        &self,
+        response: &LoginResponse,
+        client: ClientInfo,
+    ) -> Result<(String, CredentialInfo), AuthError> {
+        let credential = match self.webauthn.finish_login(response) {
+            Ok(credential) => credential,
+            Err(e) => {
+                // Server-side problems are not the client's fault
+                if !matches!(
+                    e,
+                    WebauthnError::Disabled
+                        | WebauthnError::NoCredentials
+                        | WebauthnError::Storage(_)
+                ) {
+                    self.record_failure(client.ip, false);
+                }
+                return Err(e.into());
+            }
+        };
+        let role = self.account_role(credential.username.as_deref())?;
+        self.record_success(client.ip);
+        let token = self.create_session(client, credential.username.as_deref(), role);
+        Ok((token, credential))
+    }
+
+    /// Opens a session for a client whose certificate alone is enough to log
+    /// in. The TLS handshake has already verified it.
+    pub fn login_with_certificate(
+        &self,
+        cert_name: &str,
+        client: ClientInfo,
+    ) -> Result<String, AuthError> {
+        let (username, role) = self.certificate_account(cert_name)?;
+        self.record_success(client.ip);
+        Ok(self.create_session(client, username.as_deref(), role))
+    }
+
+    /// The account a certificate acts as: the user it is named after, or
+    /// else the shared one.
+    pub fn certificate_account(
+        &self,
+        cert_name: &str,
+    ) -> Result<(Option<String>, Role), AuthError> {
+        match self.users.find(cert_name)? {
+            Some(user) => Ok((Some(user.username), user.role)),
+            None => Ok((None, self.account_role(None)?)),
+        }
+    }
+
+    /// Role of a login as `username` without a PIN. The shared account
+    /// (`None`) only exists while the shared PIN is set, so deleting it
+    /// also retires passkeys and certificates not tied to a user.
+    fn account_role(&self, username: Option<&str>) -> Result<Role, AuthError> {
+        match username {
+            Some(username) => match self.users.find(username)? {
+                Some(user) => Ok(user.role),
+                None => Err(UserError::NotFound(username.to_string()).into()),
+            },
+            None if self.pin_hash.read().unwrap().is_some() => Ok(Role::Admin),
+            None => Err(AuthError::UsernameRequired),
+        }
+    }
+
+    fn create_session(&self, client: ClientInfo, username: Option<&str>, role: Role) -> String {
         let token = generate_token();
-        let mut sessions = self.sessions.lock().unwrap();
+        let mut state = self.state.lock().unwrap();
 
         // Clean expired sessions
-        let now = Instant::now();
-        sessions.retain(|_, s| s.expires_at > now);
+        let now = unix_now();
+        state.sessions.retain(|_, s| s.expires_at > now);
 
-        sessions.insert(
-            token.clone(),
+        state.sessions.insert(
+            hash_token(&token),
             Session {
-                expires_at: now + TOKEN_EXPIRY,
+                id: generate_session_id(),
+                client,
+                username: username.map(str::to_string),
+                role,
+                created_at: now,
+                last_used_at: now,
+                expires_at: now + TOKEN_EXPIRY.as_secs(),
+                max_expires_at: now + MAX_SESSION_LIFETIME.as_secs(),
             },
         );
+        self.persist(&state);
+
+        token
+    }
+
+    /// Checks a TOTP code for the account of `username`, or the shared one.
+    /// Each account may use a code, and those before it, only once.
+    fn check_totp(
+        &self,
+        secret: &[u8],
+        code: &str,
+        step: u64,
+        username: Option<&str>,
+    ) -> Result<(), AuthError> {
+        let matched = totp::verify(secret, code, step).ok_or(AuthError::InvalidTotp)?;
+        let mut state = self.state.lock().unwrap();
+        let last = state
+            .last_totp_steps
+            .entry(username.unwrap_or_default().to_string())
+            .or_default();
+        if matched <= *last {
+            return Err(AuthError::TotpReplayed);
+        }
+        *last = matched;
+        self.persist(&state);
+        Ok(())
+    }
+
+    /// Checks the PIN of `username`, or the shared PIN, and returns the
+    /// role it logs in with. A right PIN whose hash is weaker than
+    /// `[pin.argon2]` asks for is hashed again.
+    fn check_pin(&self, username: Option<&str>, pin: &str) -> Result<Role, AuthError> {
+        if let Some(username) = username {
+            let user = self
+                .users
+                .verify(username, pin)?
+                .ok_or(AuthError::InvalidPin)?;
+            match self.users.rehash_if_weaker(&user, pin) {
+                Ok(true) => tracing::info!("Rehashed the PIN of {}", username),
+                Ok(false) => {}
+                Err(e) => tracing::warn!("Failed to rehash the PIN of {}: {}", username, e),
+            }
+            return Ok(user.role);
+        }
+
+        // Cloned so the lock is not held while hashing
+        let Some(stored) = self.pin_hash.read().unwrap().clone() else {
+            // Only user accounts are set up
+            if !self.users.list()?.is_empty() {
+                return Err(AuthError::UsernameRequired);
+            }
+            return Err(self.missing_pin_hash());
+        };
+        let hash = stored.password_hash();
+
+        if !pin::verify(&hash, pin) {
+            return Err(AuthError::InvalidPin);
+        }
+        let argon2 = &self.config.pin.argon2;
+        if pin::needs_rehash(&hash, argon2) {
+            let rehashed = pin::hash(pin, argon2)
+                .and_then(|new| self.replace_pin_hash(&new, Some(stored.as_str())));
+            match rehashed {
+                Ok(true) => tracing::info!("Rehashed the shared PIN"),
+                Ok(false) => tracing::info!("Not rehashing the shared PIN, it was changed"),
+                Err(e) => tracing::warn!("Failed to rehash the shared PIN: {}", e),
+            }
+        }
+        Ok(Role::Admin)
+    }
 
-        Ok(token)
+    /// Checks a session token or API key. A session's expiry is pushed back,
+    /// since it is being used.
+    pub fn validate_token(&self, token: &str) -> Result<AuthContext, AuthError> {
+        if apikeys::is_api_key(token) {
+            return match self.api_keys.verify(token) {
+                Ok(Some(key)) => Ok(AuthContext {
+                    session_id: format!("key:{}", key.id),
+                    username: None,
+                    scopes: key.scopes,
+                }),
+                Ok(None) => Err(AuthError::InvalidToken),
+                Err(e) => {
+                    tracing::error!("{}", e);
+                    Err(AuthError::InvalidToken)
+                }
+            };
+        }
+
+        let hash = hash_token(token);
+        let now = unix_now();
+        let username = match self.state.lock().unwrap().sessions.get(&hash) {
+            Some(session) if session.expires_at > now => session.username.clone(),
+            _ => return Err(AuthError::InvalidToken),
+        };
+        // The user may have been removed or given another role since
+        // logging in
+        let role = match username.map(|name| self.users.find(&name)) {
+            Some(Ok(Some(user))) => Some(user.role),
+            Some(Ok(None)) => {
+                let mut state = self.state.lock().unwrap();
+                state.sessions.remove(&hash);
+                self.persist(&state);
+                return Err(AuthError::InvalidToken);
+            }
+            Some(Err(e)) => {
+                tracing::error!("{}", e);
+                return Err(AuthError::InvalidToken);
+            }
+            None => None,
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let session = match state.sessions.get_mut(&hash) {
+            Some(session) if session.expires_at > now => session,
+            _ => return Err(AuthError::InvalidToken),
+        };
+        let mut persist = now >= session.last_used_at + PERSIST_ACTIVITY_AFTER;
+        session.touch(now);
+        if let Some(role) = role.filter(|&role| role != session.role) {
+            session.role = role;
+            persist = true;
+        }
+        let auth = AuthContext {
+            session_id: session.id.clone(),
+            username: session.username.clone(),
+            scopes: session.role.scopes(),
+        };
+        if persist {
+            self.persist(&state);
+        }
+        Ok(auth)
     }
 
-    pub fn validate_token(&self, token: &str) -> Result<(), AuthError> {
-        let sessions = self.sessions.lock().unwrap();
+    /// Whether the session still exists, without counting as activity. For
+    /// an API key (`key:<id>`), whether it is still unrevoked and unexpired;
+    /// a certificate (`cert:<fingerprint>`) was checked by the TLS handshake
+    /// and stays good for the connection.
+    pub fn session_active(&self, session_id: &str) -> bool {
+        if let Some(id) = session_id.strip_prefix("key:") {
+            return self.api_keys.is_active(id).unwrap_or_else(|e| {
+                tracing::error!("{}", e);
+                false
+            });
+        }
+        if session_id.starts_with("cert:") {
+            return true;
+        }
+
+        let now = unix_now();
+        self.state
+            .lock()
+            .unwrap()
+            .sessions
+            .values()
+            .any(|s| s.id == session_id && s.expires_at > now)
+    }
+
+    /// Issues a ticket that opens one event stream for the session of
+    /// `auth`. `EventSource` cannot send headers, and a ticket in the URL
+    /// is less of a risk than the session token: it is only good once and
+    /// for [`EVENTS_TICKET_LIFETIME`].
+    pub fn issue_events_ticket(&self, auth: &AuthContext) -> String {
+        let ticket = generate_token();
         let now = Instant::now();
+        let mut tickets = self.events_tickets.lock().unwrap();
+        tickets.retain(|_, t| t.expires_at > now);
+        if tickets.len() >= MAX_EVENTS_TICKETS {
+            let oldest = tickets
+                .iter()
+                .min_by_key(|(_, t)| t.expires_at)
+                .map(|(hash, _)| hash.clone());
+            if let Some(oldest) = oldest {
+                tickets.remove(&oldest);
+            }
+        }
+        tickets.insert(
+            hash_token(&ticket),
+            EventsTicket {
+                auth: auth.clone(),
+                expires_at: now + EVENTS_TICKET_LIFETIME,
+            },
+        );
+        ticket
+    }
 
-        match sessions.get(token) {
-            Some(session) if session.expires_at > now => Ok(()),
+    /// Uses up an event stream ticket. Fails if it is unknown, expired or
+    /// already used, or its session has ended since.
+    pub fn redeem_events_ticket(&self, ticket: &str) -> Result<AuthContext, AuthError> {
+        let redeemed = self
+            .events_tickets
+            .lock()
+            .unwrap()
+            .remove(&hash_token(ticket));
+        match redeemed {
+            Some(t) if t.expires_at > Instant::now() && self.session_active(&t.auth.session_id) => {
+                Ok(t.auth)
+            }
             _ => Err(AuthError::InvalidToken),
         }
     }
+
+    /// Active sessions, newest first.
+    pub fn sessions(&self, current_id: &str) -> Vec<SessionInfo> {
+        let mut state = self.state.lock().unwrap();
+        let now = unix_now();
+        state.sessions.retain(|_, s| s.expires_at > now);
+
+        let mut list: Vec<SessionInfo> = state
+            .sessions
+            .values()
+            .map(|s| s.info(current_id))
+            .collect();
+        list.sort_by_key(|s| std::cmp::Reverse(s.created_at));
+        list
+    }
+
+    /// Ends a session so its token stops working. Returns `false` if there
+    /// was no such session.
+    pub fn revoke_session(&self, session_id: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let before = state.sessions.len();
+        state.sessions.retain(|_, s| s.id != session_id);
+        if state.sessions.len() == before {
+            return false;
+        }
+        self.persist(&state);
+        true
+    }
+
+    /// Replaces the session's token with a new one and resets its idle
+    /// timeout. The old token stops working.
+    pub fn refresh_session(&self, session_id: &str) -> Option<String> {
+        let mut state = self.state.lock().unwrap();
+        let now = unix_now();
+        let old_hash = state
+            .sessions
+            .iter()
+            .find(|(_, s)| s.id == session_id && s.expires_at > now)
+            .map(|(hash, _)| hash.clone())?;
+
+        let mut session = state.sessions.remove(&old_hash)?;
+        session.touch(now);
+        let token = generate_token();
+        state.sessions.insert(hash_token(&token), session);
+        self.persist(&state);
+        Some(token)
+    }
+}
+
+/// How long a client must wait after its `failures`th failed login.
+fn backoff(failures: u32) -> Duration {
+    if failures < FREE_FAILURES {
+        return Duration::ZERO;
+    }
+    let doublings = (failures - FREE_FAILURES).min(16);
+    (BASE_BACKOFF * 2u32.pow(doublings)).min(MAX_BACKOFF)
 }
 
 fn generate_token() -> String {
-    let mut rng = rand::thread_rng();
-    let bytes: [u8; 32] = rng.r#gen();
-    hex::encode(bytes)
+    hex::encode(random_bytes::<32>())
+}
+
+fn generate_session_id() -> String {
+    hex::encode(random_bytes::<8>())
+}
+
+/// The store for `config.state_path` and the state saved in it, empty if
+/// the file does not exist yet.
+fn load_state(config: &Config) -> Result<(Box<dyn StateStore>, AuthState), StoreError> {
+    let store = store::open(config.state_path.as_deref());
+    let mut state = store.load()?;
+    // Compact at startup so expired entries don't linger in the file
+    state.compact(unix_now(), FAILURE_MEMORY.as_secs());
+    if let Err(e) = store.save(&state) {
+        tracing::warn!("{}", e);
+    }
+    Ok((store, state))
+}
+
+/// Reads and checks the shared PIN hash; `None` if the file does not exist.
+/// The hash must be an Argon2 hash that [`pin::verify`] can check.
+fn read_pin_hash(path: &Path) -> Result<Option<PasswordHashString>, AuthError> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(AuthError::PinHashReadFailed(format!(
+                "{}: {}",
+                path.display(),
+                e
+            )));
+        }
+    };
+    let invalid =
+        |reason: String| AuthError::InvalidPinHash(format!("{}: {}", path.display(), reason));
+
+    let hash = PasswordHash::new(content.trim()).map_err(|e| invalid(e.to_string()))?;
+    argon2::Algorithm::try_from(hash.algorithm).map_err(|e| invalid(e.to_string()))?;
+    argon2::Params::try_from(&hash).map_err(|e| invalid(e.to_string()))?;
+    if hash.hash.is_none() {
+        return Err(invalid("no hash value".to_string()));
+    }
+    Ok(Some(hash.serialize()))
 }
 
 pub fn extract_bearer_token(auth_header: Option<&str>) -> Option<&str> {
@@ -133,16 +996,32 @@ mod tests {
         Config {
             bind_host: "127.0.0.1".to_string(),
             port: 8080,
-            homelab_mac: "aa:bb:cc:dd:ee:ff".to_string(),
-            homelab_ip: "192.168.1.100".to_string(),
-            homelab_broadcast: "255.255.255.255".to_string(),
-            ssh_port: 2222,
-            ssh_key_path: std::path::PathBuf::from("/tmp/test-key"),
+            hosts: vec![crate::config::HostConfig {
+                id: "homelab".to_string(),
+                mac: "aa:bb:cc:dd:ee:ff".to_string(),
+                ip: "192.168.1.100".to_string(),
+                broadcast: "255.255.255.255".to_string(),
+                ssh_port: 2222,
+                ssh_key_path: std::path::PathBuf::from("/tmp/test-key"),
+                ..Default::default()
+            }],
             pin_hash_path,
+            totp_secret_path: std::path::PathBuf::from("/nonexistent/totp.secret"),
+            users_path: std::path::PathBuf::from("/nonexistent/users.json"),
+            audit_log_path: None,
+            ..Default::default()
+        }
+    }
+
+    fn client() -> ClientInfo {
+        ClientInfo {
+            ip: IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10)),
+            user_agent: Some("test".to_string()),
         }
     }
 
     fn create_pin_hash(pin: &str) -> NamedTempFile {
+        use argon2::Argon2;
         use argon2::password_hash::{PasswordHasher, SaltString, rand_core::OsRng};
 
         let salt = SaltString::generate(&mut OsRng);
@@ -187,15 +1066,31 @@ mod tests {
         assert_ne!(token1, token2);
     }
 
+    #[test]
+    fn test_backoff_schedule() {
+        assert_eq!(backoff(0), Duration::ZERO);
+        assert_eq!(backoff(FREE_FAILURES - 1), Duration::ZERO);
+        assert_eq!(backoff(FREE_FAILURES), BASE_BACKOFF);
+        assert_eq!(backoff(FREE_FAILURES + 1), BASE_BACKOFF * 2);
+        assert_eq!(backoff(FREE_FAILURES + 3), BASE_BACKOFF * 8);
+        assert_eq!(backoff(FREE_FAILURES + 10), MAX_BACKOFF);
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+    }
+
     #[test]
     fn test_rate_limit_allows_under_limit() {
         let config = create_test_config(std::path::PathBuf::from("/tmp/nonexistent"));
         let state = AppState::new(config);
         let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1));
 
-        for _ in 0..MAX_ATTEMPTS_PER_WINDOW {
+        // Checking alone never counts
+        for _ in 0..100 {
             assert!(state.check_rate_limit(ip).is_ok());
         }
+        for _ in 0..FREE_FAILURES - 1 {
+            state.record_failure(ip, true);
+        }
+        assert!(state.check_rate_limit(ip).is_ok());
     }
 
     #[test]
@@ -204,14 +1099,20 @@ mod tests {
         let state = AppState::new(config);
         let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2));
 
-        for _ in 0..MAX_ATTEMPTS_PER_WINDOW {
+        for _ in 0..FREE_FAILURES {
             assert!(state.check_rate_limit(ip).is_ok());
+            state.record_failure(ip, true);
         }
+        assert!(matches!(
+            state.check_rate_limit(ip),
+            Err(AuthError::RateLimited { retry_after: 30 })
+        ));
 
-        // 11th attempt should be blocked
+        // Each further failure doubles the wait
+        state.record_failure(ip, true);
         assert!(matches!(
             state.check_rate_limit(ip),
-            Err(AuthError::RateLimited)
+            Err(AuthError::RateLimited { retry_after: 60 })
         ));
     }
 
@@ -223,8 +1124,8 @@ mod tests {
         let ip2 = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 4));
 
         // Max out ip1
-        for _ in 0..MAX_ATTEMPTS_PER_WINDOW {
-            assert!(state.check_rate_limit(ip1).is_ok());
+        for _ in 0..FREE_FAILURES {
+            state.record_failure(ip1, false);
         }
         assert!(state.check_rate_limit(ip1).is_err());
 
@@ -232,13 +1133,155 @@ mod tests {
         assert!(state.check_rate_limit(ip2).is_ok());
     }
 
+    #[test]
+    fn test_old_failures_forgotten() {
+        let config = create_test_config(std::path::PathBuf::from("/tmp/nonexistent"));
+        let state = AppState::new(config);
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 5));
+
+        for _ in 0..FREE_FAILURES + 2 {
+            state.record_failure(ip, false);
+        }
+        state
+            .state
+            .lock()
+            .unwrap()
+            .failures
+            .get_mut(&ip)
+            .unwrap()
+            .last_failure = unix_now() - FAILURE_MEMORY.as_secs();
+        assert!(state.check_rate_limit(ip).is_ok());
+
+        // The next failure starts counting from scratch
+        state.record_failure(ip, false);
+        assert_eq!(state.state.lock().unwrap().failures[&ip].count, 1);
+        assert!(state.check_rate_limit(ip).is_ok());
+    }
+
+    #[test]
+    fn test_success_clears_failures() {
+        let (_hash_file, state) = session_state();
+        let ip = client().ip;
+
+        assert!(matches!(
+            state.verify_pin(None, "wrong", None, client()),
+            Err(AuthError::InvalidPin)
+        ));
+        assert_eq!(state.state.lock().unwrap().failures[&ip].count, 1);
+        assert_eq!(state.state.lock().unwrap().failures_since_login, 1);
+
+        state.verify_pin(None, "1234", None, client()).unwrap();
+        assert!(!state.state.lock().unwrap().failures.contains_key(&ip));
+        assert_eq!(state.state.lock().unwrap().failures_since_login, 0);
+    }
+
+    #[test]
+    fn test_success_keeps_failures_of_others() {
+        let (_hash_file, state) = session_state();
+        let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        state.record_failure(other, true);
+        state.record_failure(other, true);
+        state.record_failure(client().ip, true);
+        assert_eq!(state.state.lock().unwrap().failures_since_login, 3);
+
+        // Logging in some other way only takes off the client's own
+        state.login_with_certificate("phone", client()).unwrap();
+        let auth = state.state.lock().unwrap();
+        assert_eq!(auth.failures_since_login, 2);
+        assert_eq!(auth.failures[&other].pin_count, 2);
+    }
+
+    #[test]
+    fn test_global_budget_suspends_pin_login() {
+        let (_hash_file, state) = session_state();
+
+        // Spread over many addresses, as an attacker rotating IPs would
+        for i in 0..MAX_GLOBAL_FAILURES {
+            state.record_failure(IpAddr::V4(Ipv4Addr::new(10, 0, 0, i as u8)), true);
+        }
+        let result = state.verify_pin(None, "1234", None, client());
+        assert!(
+            matches!(result, Err(AuthError::PinLoginSuspended { retry_after }) if retry_after > 0)
+        );
+        // The client itself has no failures and passkeys are not affected
+        assert!(state.check_rate_limit(client().ip).is_ok());
+
+        // Allowed again once the oldest failure leaves the window
+        state.state.lock().unwrap().pin_failures[0] -= GLOBAL_FAILURE_WINDOW.as_secs();
+        assert!(state.verify_pin(None, "1234", None, client()).is_ok());
+    }
+
+    #[test]
+    fn test_passkey_failures_skip_global_budget() {
+        let config = create_test_config(std::path::PathBuf::from("/tmp/nonexistent"));
+        let state = AppState::new(config);
+        state.record_failure(client().ip, false);
+
+        let auth = state.state.lock().unwrap();
+        assert_eq!(auth.failures[&client().ip].count, 1);
+        assert!(auth.pin_failures.is_empty());
+        assert_eq!(auth.failures_since_login, 0);
+    }
+
+    #[test]
+    fn test_lockout_after_failures() {
+        let hash_file = create_pin_hash("1234");
+        let dir = tempfile::TempDir::new().unwrap();
+        let lockout_path = dir.path().join("lockout");
+        let mut config = create_test_config(hash_file.path().to_path_buf());
+        config.login.lockout_after = Some(3);
+        config.login.lockout_path = lockout_path.clone();
+        let state = AppState::new(config);
+
+        for i in 0..3 {
+            let client = ClientInfo {
+                ip: IpAddr::V4(Ipv4Addr::new(10, 0, 0, i)),
+                user_agent: None,
+            };
+            assert!(matches!(
+                state.verify_pin(None, "wrong", None, client),
+                Err(AuthError::InvalidPin)
+            ));
+        }
+        assert!(lockout_path.exists());
+        assert!(
+            std::fs::read_to_string(&lockout_path)
+                .unwrap()
+                .contains("after 3 failed PIN logins")
+        );
+
+        // Even the right PIN is refused until the file is removed
+        assert!(matches!(
+            state.verify_pin(None, "1234", None, client()),
+            Err(AuthError::LockedOut)
+        ));
+        lockout::clear(&lockout_path).unwrap();
+        assert!(state.verify_pin(None, "1234", None, client()).is_ok());
+
+        // Failures from before the lockout are not taken off the new count
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0));
+        state.record_failure(client().ip, true);
+        state
+            .verify_pin(
+                None,
+                "1234",
+                None,
+                ClientInfo {
+                    ip,
+                    user_agent: None,
+                },
+            )
+            .unwrap();
+        assert_eq!(state.state.lock().unwrap().failures_since_login, 1);
+    }
+
     #[test]
     fn test_verify_pin_correct() {
         let hash_file = create_pin_hash("1234");
         let config = create_test_config(hash_file.path().to_path_buf());
         let state = AppState::new(config);
 
-        let result = state.verify_pin("1234");
+        let result = state.verify_pin(None, "1234", None, client());
         assert!(result.is_ok());
         assert_eq!(result.unwrap().len(), 64); // Token should be 64 hex chars
     }
@@ -249,7 +1292,7 @@ mod tests {
         let config = create_test_config(hash_file.path().to_path_buf());
         let state = AppState::new(config);
 
-        let result = state.verify_pin("wrong");
+        let result = state.verify_pin(None, "wrong", None, client());
         assert!(matches!(result, Err(AuthError::InvalidPin)));
     }
 
@@ -258,18 +1301,109 @@ mod tests {
         let config = create_test_config(std::path::PathBuf::from("/nonexistent/path"));
         let state = AppState::new(config);
 
-        let result = state.verify_pin("1234");
+        let result = state.verify_pin(None, "1234", None, client());
         assert!(matches!(result, Err(AuthError::PinHashReadFailed(_))));
     }
 
+    #[test]
+    fn test_reserve_hash() {
+        let hash_file = create_pin_hash("1234");
+        let mut config = create_test_config(hash_file.path().to_path_buf());
+        config.login.max_concurrent_hashes = 2;
+        let state = AppState::new(config);
+
+        let first = state.reserve_hash().unwrap();
+        let _second = state.reserve_hash().unwrap();
+        assert!(matches!(
+            state.reserve_hash(),
+            Err(AuthError::Busy { retry_after: 1 })
+        ));
+        drop(first);
+        assert!(state.reserve_hash().is_ok());
+    }
+
+    #[test]
+    fn test_open_checks_pin_hash() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let hash_path = dir.path().join("pin.hash");
+        let mut config = create_test_config(hash_path.clone());
+        config.users_path = dir.path().join("users.json");
+
+        // Missing, with nobody else to log in
+        assert!(matches!(
+            AppState::open(config.clone()),
+            Err(AuthError::PinHashReadFailed(_))
+        ));
+
+        for bad in [
+            "not a hash",
+            "$pbkdf2-sha256$i=1000$c2FsdA$aGFzaA",
+            "$argon2id$v=19$m=19456,t=2,p=1$c2FsdHNhbHQ",
+        ] {
+            fs::write(&hash_path, bad).unwrap();
+            let err = AppState::open(config.clone()).err().unwrap();
+            assert!(matches!(err, AuthError::InvalidPinHash(_)), "{}", bad);
+            assert!(err.to_string().contains("pin.hash"));
+        }
+
+        pin::write_hash(&hash_path, &pin::hash("1234", &Default::default()).unwrap()).unwrap();
+        assert!(AppState::open(config.clone()).is_ok());
+
+        // Missing is fine once user accounts exist
+        fs::remove_file(&hash_path).unwrap();
+        Users::new(config.users_path.clone(), Default::default())
+            .add("alice", Role::Admin, "1234")
+            .unwrap();
+        assert!(AppState::open(config).is_ok());
+    }
+
+    #[test]
+    fn test_reload_pin_hash() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let hash_path = dir.path().join("pin.hash");
+        pin::write_hash(&hash_path, &pin::hash("1234", &Default::default()).unwrap()).unwrap();
+        let state = AppState::open(create_test_config(hash_path.clone())).unwrap();
+
+        // The file is not read per login
+        pin::write_hash(
+            &hash_path,
+            &pin::hash("270418", &Default::default()).unwrap(),
+        )
+        .unwrap();
+        assert!(state.verify_pin(None, "1234", None, client()).is_ok());
+        state.reload_pin_hash().unwrap();
+        assert!(state.verify_pin(None, "270418", None, client()).is_ok());
+        assert!(matches!(
+            state.verify_pin(None, "1234", None, client()),
+            Err(AuthError::InvalidPin)
+        ));
+
+        // A broken file is refused and the old hash kept
+        fs::write(&hash_path, "garbage\n").unwrap();
+        assert!(matches!(
+            state.reload_pin_hash(),
+            Err(AuthError::InvalidPinHash(_))
+        ));
+        assert!(state.verify_pin(None, "270418", None, client()).is_ok());
+
+        fs::remove_file(&hash_path).unwrap();
+        state.reload_pin_hash().unwrap();
+        assert!(matches!(
+            state.verify_pin(None, "270418", None, client()),
+            Err(AuthError::PinHashReadFailed(_))
+        ));
+    }
+
     #[test]
     fn test_validate_token_valid() {
         let hash_file = create_pin_hash("1234");
         let config = create_test_config(hash_file.path().to_path_buf());
         let state = AppState::new(config);
 
-        let token = state.verify_pin("1234").unwrap();
-        assert!(state.validate_token(&token).is_ok());
+        let token = state.verify_pin(None, "1234", None, client()).unwrap();
+        let auth = state.validate_token(&token).unwrap();
+        assert_eq!(auth.session_id.len(), 16);
+        assert!(!token.contains(&auth.session_id));
     }
 
     #[test]
@@ -293,4 +1427,663 @@ mod tests {
             Err(AuthError::InvalidToken)
         ));
     }
+
+    fn session_state() -> (NamedTempFile, AppState) {
+        let hash_file = create_pin_hash("1234");
+        let state = AppState::new(create_test_config(hash_file.path().to_path_buf()));
+        (hash_file, state)
+    }
+
+    /// Runs `f` on the session behind `token`.
+    fn with_session(state: &AppState, token: &str, f: impl FnOnce(&mut Session)) {
+        let mut state = state.state.lock().unwrap();
+        f(state.sessions.get_mut(&hash_token(token)).unwrap());
+    }
+
+    #[test]
+    fn test_validate_token_slides_expiry() {
+        let (_hash_file, state) = session_state();
+        let token = state.verify_pin(None, "1234", None, client()).unwrap();
+
+        with_session(&state, &token, |s| s.expires_at = unix_now() + 1);
+        state.validate_token(&token).unwrap();
+        with_session(&state, &token, |s| {
+            assert!(s.expires_at >= unix_now() + TOKEN_EXPIRY.as_secs() - 5);
+        });
+
+        // Activity cannot extend a session past its maximum lifetime
+        with_session(&state, &token, |s| s.max_expires_at = unix_now() + 60);
+        state.validate_token(&token).unwrap();
+        with_session(&state, &token, |s| {
+            assert_eq!(s.expires_at, s.max_expires_at)
+        });
+    }
+
+    #[test]
+    fn test_expired_token_rejected() {
+        let (_hash_file, state) = session_state();
+        let token = state.verify_pin(None, "1234", None, client()).unwrap();
+        let session_id = state.validate_token(&token).unwrap().session_id;
+
+        with_session(&state, &token, |s| s.expires_at = unix_now());
+        assert!(matches!(
+            state.validate_token(&token),
+            Err(AuthError::InvalidToken)
+        ));
+        assert!(!state.session_active(&session_id));
+        assert!(state.sessions(&session_id).is_empty());
+    }
+
+    #[test]
+    fn test_list_and_revoke_sessions() {
+        let (_hash_file, state) = session_state();
+        let first = state.verify_pin(None, "1234", None, client()).unwrap();
+        let second = state.verify_pin(None, "1234", None, client()).unwrap();
+        let first_id = state.validate_token(&first).unwrap().session_id;
+        let second_id = state.validate_token(&second).unwrap().session_id;
+
+        let sessions = state.sessions(&first_id);
+        assert_eq!(sessions.len(), 2);
+        let current = sessions.iter().find(|s| s.current).unwrap();
+        assert_eq!(current.id, first_id);
+        assert_eq!(current.client_ip, client().ip);
+        assert_eq!(current.user_agent.as_deref(), Some("test"));
+
+        assert!(state.revoke_session(&second_id));
+        assert!(!state.revoke_session(&second_id));
+        assert!(state.validate_token(&second).is_err());
+        assert!(state.validate_token(&first).is_ok());
+        assert_eq!(state.sessions(&first_id).len(), 1);
+    }
+
+    #[test]
+    fn test_refresh_session_rotates_token() {
+        let (_hash_file, state) = session_state();
+        let token = state.verify_pin(None, "1234", None, client()).unwrap();
+        let session_id = state.validate_token(&token).unwrap().session_id;
+
+        let refreshed = state.refresh_session(&session_id).unwrap();
+        assert_ne!(refreshed, token);
+        assert!(state.validate_token(&token).is_err());
+        assert_eq!(
+            state.validate_token(&refreshed).unwrap().session_id,
+            session_id
+        );
+        assert!(state.session_active(&session_id));
+
+        assert!(state.revoke_session(&session_id));
+        assert!(state.refresh_session(&session_id).is_none());
+    }
+
+    #[test]
+    fn test_state_survives_restart() {
+        let hash_file = create_pin_hash("1234");
+        let dir = tempfile::TempDir::new().unwrap();
+        let state_path = dir.path().join("state.json");
+        let mut config = create_test_config(hash_file.path().to_path_buf());
+        config.state_path = Some(state_path.clone());
+
+        let state = AppState::new(config.clone());
+        let token = state.verify_pin(None, "1234", None, client()).unwrap();
+        let revoked = state.verify_pin(None, "1234", None, client()).unwrap();
+        let session_id = state.validate_token(&token).unwrap().session_id;
+        let revoked_id = state.validate_token(&revoked).unwrap().session_id;
+        assert!(state.revoke_session(&revoked_id));
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1));
+        for _ in 0..FREE_FAILURES {
+            state.record_failure(ip, true);
+        }
+        drop(state);
+
+        // Only token hashes reach the disk
+        let content = std::fs::read_to_string(&state_path).unwrap();
+        assert!(!content.contains(&token));
+        assert!(content.contains(&hash_token(&token)));
+
+        let state = AppState::new(config);
+        assert_eq!(state.validate_token(&token).unwrap().session_id, session_id);
+        assert!(state.validate_token(&revoked).is_err());
+        assert!(matches!(
+            state.check_rate_limit(ip),
+            Err(AuthError::RateLimited { .. })
+        ));
+        assert_eq!(state.state.lock().unwrap().pin_failures.len(), 5);
+    }
+
+    #[test]
+    fn test_expired_state_compacted_at_startup() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let state_path = dir.path().join("state.json");
+        let now = unix_now();
+        let mut saved = AuthState::default();
+        for (token, expires_at) in [("old", now - 1), ("new", now + 60)] {
+            saved.sessions.insert(
+                hash_token(token),
+                Session {
+                    id: token.to_string(),
+                    client: client(),
+                    username: None,
+                    role: Role::Admin,
+                    created_at: now - 600,
+                    last_used_at: now - 600,
+                    expires_at,
+                    max_expires_at: now + 3600,
+                },
+            );
+        }
+        saved.failures.insert(
+            client().ip,
+            FailureRecord {
+                count: 3,
+                last_failure: now - FAILURE_MEMORY.as_secs(),
+                pin_count: 3,
+            },
+        );
+        store::JsonFileStore::new(&state_path).save(&saved).unwrap();
+
+        let mut config = create_test_config(std::path::PathBuf::from("/tmp/nonexistent"));
+        config.state_path = Some(state_path.clone());
+        let _state = AppState::new(config);
+
+        let compacted = store::JsonFileStore::new(&state_path).load().unwrap();
+        assert_eq!(compacted.sessions.len(), 1);
+        assert!(compacted.sessions.contains_key(&hash_token("new")));
+        assert!(compacted.failures.is_empty());
+    }
+
+    #[test]
+    fn test_corrupt_state_file_starts_empty() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let state_path = dir.path().join("state.json");
+        std::fs::write(&state_path, "{not json").unwrap();
+
+        let mut config = create_test_config(std::path::PathBuf::from("/tmp/nonexistent"));
+        config.state_path = Some(state_path.clone());
+        let state = AppState::new(config);
+        assert!(state.sessions("").is_empty());
+
+        // The file is kept for inspection, not replaced with the empty state
+        state.create_session(client(), None, Role::Admin);
+        assert_eq!(std::fs::read_to_string(&state_path).unwrap(), "{not json");
+    }
+
+    #[test]
+    fn test_open_refuses_corrupt_state_file() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let hash_file = create_pin_hash("1234");
+        let state_path = dir.path().join("state.json");
+        let mut config = create_test_config(hash_file.path().to_path_buf());
+        config.state_path = Some(state_path.clone());
+
+        // A state file that does not exist yet is fine
+        assert!(AppState::open(config.clone()).is_ok());
+
+        std::fs::write(&state_path, "{not json").unwrap();
+        let err = AppState::open(config.clone()).err().unwrap();
+        assert!(matches!(err, AuthError::Store(_)), "{}", err);
+        assert!(err.to_string().contains("state.json"));
+        assert_eq!(std::fs::read_to_string(&state_path).unwrap(), "{not json");
+
+        std::fs::create_dir_all(dir.path().join("dir.json")).unwrap();
+        config.state_path = Some(dir.path().join("dir.json"));
+        assert!(matches!(AppState::open(config), Err(AuthError::Store(_))));
+    }
+
+    fn enroll_totp(state: &mut AppState) -> (tempfile::TempDir, Vec<u8>) {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("totp.secret");
+        let secret = totp::generate_secret();
+        totp::write_secret(&path, &secret, false).unwrap();
+        state.config.totp_secret_path = path;
+        (dir, secret)
+    }
+
+    #[test]
+    fn test_totp_required_once_enrolled() {
+        let hash_file = create_pin_hash("1234");
+        let mut state = AppState::new(create_test_config(hash_file.path().to_path_buf()));
+        assert!(!state.totp_enabled().unwrap());
+
+        let (_dir, secret) = enroll_totp(&mut state);
+        assert!(state.totp_enabled().unwrap());
+
+        // Missing codes are refused before the PIN is checked
+        assert!(matches!(
+            state.verify_pin(None, "wrong", None, client()),
+            Err(AuthError::TotpRequired)
+        ));
+        assert!(matches!(
+            state.verify_pin(None, "1234", Some(""), client()),
+            Err(AuthError::TotpRequired)
+        ));
+
+        let code = totp::code(&secret, totp::current_step());
+        assert!(matches!(
+            state.verify_pin(None, "wrong", Some(&code), client()),
+            Err(AuthError::InvalidPin)
+        ));
+        assert!(
+            state
+                .verify_pin(None, "1234", Some(&code), client())
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_totp_wrong_code() {
+        let hash_file = create_pin_hash("1234");
+        let mut state = AppState::new(create_test_config(hash_file.path().to_path_buf()));
+        let (_dir, secret) = enroll_totp(&mut state);
+
+        let step = totp::current_step();
+        let wrong = totp::code(&secret, step + 5);
+        assert!(matches!(
+            state.verify_pin(None, "1234", Some(&wrong), client()),
+            Err(AuthError::InvalidTotp)
+        ));
+        assert!(matches!(
+            state.verify_pin(None, "1234", Some("abcdef"), client()),
+            Err(AuthError::InvalidTotp)
+        ));
+    }
+
+    #[test]
+    fn test_users_share_totp_step() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let hash_file = create_pin_hash("1234");
+        let mut config = create_test_config(hash_file.path().to_path_buf());
+        config.users_path = dir.path().join("users.json");
+        let mut state = AppState::new(config);
+        state.users.add("alice", Role::Admin, "2468").unwrap();
+        state.users.add("guest", Role::Viewer, "5678").unwrap();
+        let (_dir, secret) = enroll_totp(&mut state);
+
+        // Everyone reads the same code off the same secret
+        let code = totp::code(&secret, totp::current_step());
+        for (username, pin) in [
+            (None, "1234"),
+            (Some("alice"), "2468"),
+            (Some("guest"), "5678"),
+        ] {
+            assert!(
+                state
+                    .verify_pin(username, pin, Some(&code), client())
+                    .is_ok()
+            );
+        }
+
+        // A replay is refused, but is no PIN failure
+        assert!(matches!(
+            state.verify_pin(Some("guest"), "5678", Some(&code), client()),
+            Err(AuthError::TotpReplayed)
+        ));
+        assert!(state.state.lock().unwrap().pin_failures.is_empty());
+    }
+
+    #[test]
+    fn test_certificate_replaces_totp() {
+        let hash_file = create_pin_hash("1234");
+        let mut state = AppState::new(create_test_config(hash_file.path().to_path_buf()));
+        let (_dir, _secret) = enroll_totp(&mut state);
+
+        assert!(matches!(
+            state.verify_pin_with_certificate(None, "wrong", client()),
+            Err(AuthError::InvalidPin)
+        ));
+        let token = state
+            .verify_pin_with_certificate(None, "1234", client())
+            .unwrap();
+        assert!(state.validate_token(&token).is_ok());
+    }
+
+    #[test]
+    fn test_login_with_certificate() {
+        let hash_file = create_pin_hash("1234");
+        let state = AppState::new(create_test_config(hash_file.path().to_path_buf()));
+        let ip = client().ip;
+        for _ in 0..FREE_FAILURES {
+            state.record_failure(ip, true);
+        }
+
+        let token = state.login_with_certificate("phone", client()).unwrap();
+        let auth = state.validate_token(&token).unwrap();
+        assert_eq!(auth.username, None);
+        assert!(auth.allows(Scope::Admin));
+        // Counts as a successful login
+        assert!(state.check_rate_limit(ip).is_ok());
+    }
+
+    #[test]
+    fn test_certificate_of_user() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut config = create_test_config(dir.path().join("pin.hash"));
+        config.users_path = dir.path().join("users.json");
+        let state = AppState::new(config);
+        state.users.add("guest", Role::Viewer, "5678").unwrap();
+
+        // Named after a user, it logs in as them
+        let token = state.login_with_certificate("guest", client()).unwrap();
+        let auth = state.validate_token(&token).unwrap();
+        assert_eq!(auth.username.as_deref(), Some("guest"));
+        assert_eq!(auth.scopes, Role::Viewer.scopes());
+
+        // Any other name would be the shared account, which has no PIN
+        assert!(matches!(
+            state.login_with_certificate("phone", client()),
+            Err(AuthError::UsernameRequired)
+        ));
+    }
+
+    #[test]
+    fn test_user_login() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let hash_file = create_pin_hash("1234");
+        let mut config = create_test_config(hash_file.path().to_path_buf());
+        config.users_path = dir.path().join("users.json");
+        let state = AppState::new(config);
+        state.users.add("guest", Role::Operator, "5678").unwrap();
+
+        // Another user's PIN, or the shared one, is not enough
+        assert!(matches!(
+            state.verify_pin(Some("guest"), "1234", None, client()),
+            Err(AuthError::InvalidPin)
+        ));
+        assert!(matches!(
+            state.verify_pin(Some("nobody"), "5678", None, client()),
+            Err(AuthError::InvalidPin)
+        ));
+
+        let token = state
+            .verify_pin(Some("guest"), "5678", None, client())
+            .unwrap();
+        let auth = state.validate_token(&token).unwrap();
+        assert_eq!(auth.username.as_deref(), Some("guest"));
+        assert!(auth.allows(Scope::Wol));
+        assert!(!auth.allows(Scope::Unlock));
+        let sessions = state.sessions(&auth.session_id);
+        assert_eq!(sessions[0].role, Role::Operator);
+
+        // The shared PIN still logs in with full access
+        let token = state.verify_pin(None, "1234", None, client()).unwrap();
+        let auth = state.validate_token(&token).unwrap();
+        assert_eq!(auth.username, None);
+        assert!(auth.allows(Scope::Admin));
+    }
+
+    #[test]
+    fn test_user_changes_apply_to_sessions() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut config = create_test_config(dir.path().join("pin.hash"));
+        config.users_path = dir.path().join("users.json");
+        let state = AppState::new(config);
+        state.users.add("guest", Role::Viewer, "5678").unwrap();
+        let token = state
+            .verify_pin(Some("guest"), "5678", None, client())
+            .unwrap();
+        assert!(!state.validate_token(&token).unwrap().allows(Scope::Wol));
+
+        state.users.set_role("guest", Role::Operator).unwrap();
+        let auth = state.validate_token(&token).unwrap();
+        assert!(auth.allows(Scope::Wol));
+        assert_eq!(state.sessions(&auth.session_id)[0].role, Role::Operator);
+
+        state.users.remove("guest").unwrap();
+        assert!(matches!(
+            state.validate_token(&token),
+            Err(AuthError::InvalidToken)
+        ));
+        assert!(state.sessions("").is_empty());
+    }
+
+    #[test]
+    fn test_username_required_without_shared_pin() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut config = create_test_config(dir.path().join("pin.hash"));
+        config.users_path = dir.path().join("users.json");
+        let state = AppState::new(config);
+        state.users.add("alice", Role::Admin, "1234").unwrap();
+
+        assert!(matches!(
+            state.verify_pin(None, "1234", None, client()),
+            Err(AuthError::UsernameRequired)
+        ));
+        // Not a guess, so not counted
+        assert!(state.check_rate_limit(client().ip).is_ok());
+        assert!(state.state.lock().unwrap().pin_failures.is_empty());
+    }
+
+    #[test]
+    fn test_change_shared_pin() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let hash_path = dir.path().join("pin.hash");
+        pin::write_hash(&hash_path, &pin::hash("1234", &Default::default()).unwrap()).unwrap();
+        let mut config = create_test_config(hash_path.clone());
+        config.users_path = dir.path().join("users.json");
+        let state = AppState::new(config);
+        state.users.add("guest", Role::Viewer, "5678").unwrap();
+
+        let current = state.verify_pin(None, "1234", None, client()).unwrap();
+        let other = state.verify_pin(None, "1234", None, client()).unwrap();
+        let guest = state
+            .verify_pin(Some("guest"), "5678", None, client())
+            .unwrap();
+        let auth = state.validate_token(&current).unwrap();
+        let ip = client().ip;
+
+        assert!(matches!(
+            state.change_pin(&auth, "1234", "111111", None, ip),
+            Err(AuthError::Pin(PinError::TooSimple))
+        ));
+        assert!(matches!(
+            state.change_pin(&auth, "wrong", "270418", None, ip),
+            Err(AuthError::InvalidPin)
+        ));
+        assert_eq!(state.state.lock().unwrap().pin_failures.len(), 1);
+
+        assert_eq!(
+            state.change_pin(&auth, "1234", "270418", None, ip).unwrap(),
+            1
+        );
+        assert!(state.validate_token(&current).is_ok());
+        assert!(state.validate_token(&other).is_err());
+        // Another account's sessions are left alone
+        assert!(state.validate_token(&guest).is_ok());
+
+        assert!(matches!(
+            state.verify_pin(None, "1234", None, client()),
+            Err(AuthError::InvalidPin)
+        ));
+        assert!(state.verify_pin(None, "270418", None, client()).is_ok());
+    }
+
+    #[test]
+    fn test_change_pin_needs_totp() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let hash_path = dir.path().join("pin.hash");
+        pin::write_hash(&hash_path, &pin::hash("1234", &Default::default()).unwrap()).unwrap();
+        let mut state = AppState::new(create_test_config(hash_path));
+        let (_totp_dir, secret) = enroll_totp(&mut state);
+        let code = totp::code(&secret, totp::current_step());
+        let token = state
+            .verify_pin(None, "1234", Some(&code), client())
+            .unwrap();
+        let auth = state.validate_token(&token).unwrap();
+        let ip = client().ip;
+
+        // A stolen token and PIN are not enough
+        assert!(matches!(
+            state.change_pin(&auth, "1234", "270418", None, ip),
+            Err(AuthError::TotpRequired)
+        ));
+        assert!(state.state.lock().unwrap().pin_failures.is_empty());
+        assert!(matches!(
+            state.change_pin(&auth, "1234", "270418", Some("000000"), ip),
+            Err(AuthError::InvalidTotp)
+        ));
+        assert_eq!(state.state.lock().unwrap().pin_failures.len(), 1);
+
+        // Next step's code, as the login used up this one
+        let code = totp::code(&secret, totp::current_step() + 1);
+        state
+            .change_pin(&auth, "1234", "270418", Some(&code), ip)
+            .unwrap();
+        let hash = state.pin_hash.read().unwrap().clone().unwrap();
+        assert!(pin::verify(&hash.password_hash(), "270418"));
+    }
+
+    #[test]
+    fn test_change_user_pin() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut config = create_test_config(dir.path().join("pin.hash"));
+        config.users_path = dir.path().join("users.json");
+        let state = AppState::new(config);
+        state.users.add("guest", Role::Viewer, "580236").unwrap();
+        state.users.add("alice", Role::Admin, "1234").unwrap();
+        let token = state
+            .verify_pin(Some("guest"), "580236", None, client())
+            .unwrap();
+        let other = state
+            .verify_pin(Some("guest"), "580236", None, client())
+            .unwrap();
+        let alice = state
+            .verify_pin(Some("alice"), "1234", None, client())
+            .unwrap();
+        let auth = state.validate_token(&token).unwrap();
+
+        assert!(matches!(
+            state.change_pin(&auth, "580236", "580236", None, client().ip),
+            Err(AuthError::Pin(PinError::Unchanged))
+        ));
+        let ended = state
+            .change_pin(&auth, "580236", "270418", None, client().ip)
+            .unwrap();
+        assert!(state.users.verify("guest", "270418").unwrap().is_some());
+        // Only guest's other sessions end; alice's PIN did not change
+        assert_eq!(ended, 1);
+        assert!(state.validate_token(&token).is_ok());
+        assert!(state.validate_token(&other).is_err());
+        assert!(state.validate_token(&alice).is_ok());
+        // No shared PIN file was created
+        assert!(!dir.path().join("pin.hash").exists());
+    }
+
+    #[test]
+    fn test_login_rehashes_weaker_pin() {
+        use crate::config::Argon2Config;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let hash_path = dir.path().join("pin.hash");
+        let weak = Argon2Config {
+            m_cost: 64,
+            t_cost: 1,
+            ..Argon2Config::default()
+        };
+        pin::write_hash(&hash_path, &pin::hash("1234", &weak).unwrap()).unwrap();
+        let mut config = create_test_config(hash_path.clone());
+        config.users_path = dir.path().join("users.json");
+        config.pin.argon2 = Argon2Config {
+            m_cost: 128,
+            ..weak.clone()
+        };
+        let state = AppState::new(config);
+        Users::new(dir.path().join("users.json"), weak)
+            .add("guest", Role::Viewer, "5678")
+            .unwrap();
+
+        // A wrong PIN leaves the hash alone
+        assert!(state.verify_pin(None, "4321", None, client()).is_err());
+        assert!(fs::read_to_string(&hash_path).unwrap().contains("m=64,"));
+
+        state.verify_pin(None, "1234", None, client()).unwrap();
+        assert!(fs::read_to_string(&hash_path).unwrap().contains("m=128,"));
+        assert!(state.verify_pin(None, "1234", None, client()).is_ok());
+
+        state
+            .verify_pin(Some("guest"), "5678", None, client())
+            .unwrap();
+        let users = fs::read_to_string(dir.path().join("users.json")).unwrap();
+        assert!(users.contains("m=128,"));
+    }
+
+    #[test]
+    fn test_rehash_does_not_undo_pin_change() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let hash_path = dir.path().join("pin.hash");
+        pin::write_hash(&hash_path, &pin::hash("1234", &Default::default()).unwrap()).unwrap();
+        let state = AppState::new(create_test_config(hash_path));
+        let verified = state.pin_hash.read().unwrap().clone().unwrap();
+
+        // The PIN changes while a login with the old one is being rehashed
+        let token = state.verify_pin(None, "1234", None, client()).unwrap();
+        let auth = state.validate_token(&token).unwrap();
+        state
+            .change_pin(&auth, "1234", "270418", None, client().ip)
+            .unwrap();
+        let rehashed = pin::hash("1234", &Default::default()).unwrap();
+        assert!(
+            !state
+                .replace_pin_hash(&rehashed, Some(verified.as_str()))
+                .unwrap()
+        );
+
+        assert!(matches!(
+            state.verify_pin(None, "1234", None, client()),
+            Err(AuthError::InvalidPin)
+        ));
+        assert!(state.verify_pin(None, "270418", None, client()).is_ok());
+    }
+
+    #[test]
+    fn test_totp_replay_rejected() {
+        let config = create_test_config(std::path::PathBuf::from("/tmp/nonexistent"));
+        let state = AppState::new(config);
+        let secret = totp::generate_secret();
+        let step = 1_000_000;
+
+        let code = totp::code(&secret, step);
+        assert!(state.check_totp(&secret, &code, step, None).is_ok());
+        // Same code again, even later within the drift window
+        assert!(matches!(
+            state.check_totp(&secret, &code, step, None),
+            Err(AuthError::TotpReplayed)
+        ));
+        assert!(state.check_totp(&secret, &code, step + 1, None).is_err());
+
+        // An older code is refused too, a newer one accepted
+        let older = totp::code(&secret, step - 1);
+        assert!(state.check_totp(&secret, &older, step, None).is_err());
+        let newer = totp::code(&secret, step + 1);
+        assert!(state.check_totp(&secret, &newer, step + 1, None).is_ok());
+
+        // Other accounts have their own
+        assert!(
+            state
+                .check_totp(&secret, &code, step, Some("alice"))
+                .is_ok()
+        );
+        assert!(matches!(
+            state.check_totp(&secret, &code, step, Some("alice")),
+            Err(AuthError::TotpReplayed)
+        ));
+        assert!(matches!(
+            state.check_totp(&secret, "000000x", step, Some("bob")),
+            Err(AuthError::InvalidTotp)
+        ));
+    }
+
+    #[test]
+    fn test_totp_unreadable_secret() {
+        let hash_file = create_pin_hash("1234");
+        let mut state = AppState::new(create_test_config(hash_file.path().to_path_buf()));
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("totp.secret");
+        std::fs::write(&path, "not base32!").unwrap();
+        state.config.totp_secret_path = path;
+
+        // A broken secret must not turn the second factor off
+        assert!(matches!(
+            state.verify_pin(None, "1234", None, client()),
+            Err(AuthError::TotpSecretReadFailed(_))
+        ));
+    }
 }
@@ -1,23 +1,40 @@
-use argon2::{
-    Argon2,
-    password_hash::{PasswordHasher, SaltString, rand_core::OsRng},
-};
+use articwake::config::Config;
+use articwake::pin;
 use std::env;
+use std::path::PathBuf;
+
+const USAGE: &str = "Usage: hash_pin [--config <path>] <PIN>";
+
+fn fail(message: impl std::fmt::Display) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: hash_pin <PIN>");
-        std::process::exit(1);
+    // Same `--config` forms as `articwake hash-pin`
+    let mut config_path = None;
+    let mut pins = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            config_path = Some(PathBuf::from(args.next().unwrap_or_else(|| fail(USAGE))));
+        } else if let Some(path) = arg.strip_prefix("--config=") {
+            config_path = Some(PathBuf::from(path));
+        } else {
+            pins.push(arg);
+        }
     }
+    let [pin] = pins.as_slice() else {
+        fail(USAGE);
+    };
 
-    let pin = &args[1];
-    let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
+    let pin_config = Config::load_pin(config_path.as_deref()).unwrap_or_else(|e| fail(e));
+    if let Err(e) = pin::check_policy(pin, &pin_config) {
+        fail(e);
+    }
 
-    let hash = argon2
-        .hash_password(pin.as_bytes(), &salt)
-        .expect("Failed to hash PIN");
+    // Bad `[pin.argon2]` settings only show up here
+    let hash = pin::hash(pin, &pin_config.argon2).unwrap_or_else(|e| fail(e));
 
     println!("{}", hash);
 }
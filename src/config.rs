@@ -1,6 +1,100 @@
+use ipnet::IpNet;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::env;
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use thiserror::Error;
+use toml_edit::{ArrayOfTables, ImDocument, InlineTable, Item, Table, Value};
+
+/// Host ID used when a single host is configured through the legacy
+/// `ARTICWAKE_HOMELAB_*` variables.
+pub const DEFAULT_HOST_ID: &str = "homelab";
+
+/// Config file read when neither `--config` nor `ARTICWAKE_CONFIG` is given.
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/articwake/config.toml";
+
+/// Where `enroll-totp` writes the secret unless told otherwise.
+pub const DEFAULT_TOTP_SECRET_PATH: &str = "/var/lib/articwake/totp.secret";
+
+/// Top-level config file keys and the environment variable each one maps to.
+/// A key inside a table is listed as `<table>.<key>`.
+const FILE_KEYS: &[(&str, &str)] = &[
+    ("bind_host", "ARTICWAKE_BIND_HOST"),
+    ("port", "ARTICWAKE_PORT"),
+    ("pin_hash_path", "ARTICWAKE_PIN_HASH_PATH"),
+    ("totp_secret_path", "ARTICWAKE_TOTP_SECRET_PATH"),
+    ("status_interval", "ARTICWAKE_STATUS_INTERVAL"),
+    ("audit_log_path", "ARTICWAKE_AUDIT_LOG_PATH"),
+    ("state_path", "ARTICWAKE_STATE_PATH"),
+    ("api_keys_path", "ARTICWAKE_API_KEYS_PATH"),
+    ("users_path", "ARTICWAKE_USERS_PATH"),
+    ("tls.enabled", "ARTICWAKE_TLS_ENABLED"),
+    ("tls.cert_path", "ARTICWAKE_TLS_CERT_PATH"),
+    ("tls.key_path", "ARTICWAKE_TLS_KEY_PATH"),
+    ("tls.redirect_port", "ARTICWAKE_TLS_REDIRECT_PORT"),
+    ("tls.client_auth", "ARTICWAKE_TLS_CLIENT_AUTH"),
+    ("tls.client_ca_path", "ARTICWAKE_TLS_CLIENT_CA_PATH"),
+    ("tls.client_ca_key_path", "ARTICWAKE_TLS_CLIENT_CA_KEY_PATH"),
+    ("tls.client_cert_routes", "ARTICWAKE_TLS_CLIENT_CERT_ROUTES"),
+    (
+        "tls.revoked_client_certs",
+        "ARTICWAKE_TLS_REVOKED_CLIENT_CERTS",
+    ),
+    ("trusted_proxies", "ARTICWAKE_TRUSTED_PROXIES"),
+    ("access.allow", "ARTICWAKE_ACCESS_ALLOW"),
+    ("access.deny", "ARTICWAKE_ACCESS_DENY"),
+    ("access.ui.allow", "ARTICWAKE_ACCESS_UI_ALLOW"),
+    ("access.ui.deny", "ARTICWAKE_ACCESS_UI_DENY"),
+    ("access.api.allow", "ARTICWAKE_ACCESS_API_ALLOW"),
+    ("access.api.deny", "ARTICWAKE_ACCESS_API_DENY"),
+    ("webauthn.origin", "ARTICWAKE_WEBAUTHN_ORIGIN"),
+    (
+        "webauthn.credentials_path",
+        "ARTICWAKE_WEBAUTHN_CREDENTIALS_PATH",
+    ),
+    ("broadcast", "ARTICWAKE_HOMELAB_BROADCAST"),
+    ("ssh_port", "ARTICWAKE_SSH_PORT"),
+    ("ssh_key_path", "ARTICWAKE_SSH_KEY_PATH"),
+    ("known_hosts_path", "ARTICWAKE_KNOWN_HOSTS_PATH"),
+    ("host_key_tofu", "ARTICWAKE_HOST_KEY_TOFU"),
+    ("unlock.backend", "ARTICWAKE_UNLOCK_BACKEND"),
+    ("login.lockout_after", "ARTICWAKE_LOGIN_LOCKOUT_AFTER"),
+    ("login.lockout_path", "ARTICWAKE_LOGIN_LOCKOUT_PATH"),
+    (
+        "login.max_concurrent_hashes",
+        "ARTICWAKE_LOGIN_MAX_CONCURRENT_HASHES",
+    ),
+    ("pin.min_length", "ARTICWAKE_PIN_MIN_LENGTH"),
+    ("pin.allow_simple", "ARTICWAKE_PIN_ALLOW_SIMPLE"),
+    ("pin.argon2.variant", "ARTICWAKE_PIN_ARGON2_VARIANT"),
+    ("pin.argon2.m_cost", "ARTICWAKE_PIN_ARGON2_M_COST"),
+    ("pin.argon2.t_cost", "ARTICWAKE_PIN_ARGON2_T_COST"),
+    ("pin.argon2.parallelism", "ARTICWAKE_PIN_ARGON2_PARALLELISM"),
+    ("boot.wake_timeout", "ARTICWAKE_BOOT_WAKE_TIMEOUT"),
+    ("boot.wake_attempts", "ARTICWAKE_BOOT_WAKE_ATTEMPTS"),
+    ("boot.unlock_attempts", "ARTICWAKE_BOOT_UNLOCK_ATTEMPTS"),
+    ("boot.boot_timeout", "ARTICWAKE_BOOT_BOOT_TIMEOUT"),
+];
+
+/// Keys of a `[[hosts]]` table, mapped to `ARTICWAKE_HOST_<ID>_<SUFFIX>`.
+const HOST_FILE_KEYS: &[(&str, &str)] = &[
+    ("mac", "MAC"),
+    ("ip", "IP"),
+    ("broadcast", "BROADCAST"),
+    ("ssh_port", "SSH_PORT"),
+    ("ssh_key_path", "SSH_KEY_PATH"),
+    ("host_key_fingerprints", "HOST_KEY_FINGERPRINTS"),
+    ("host_key_tofu", "HOST_KEY_TOFU"),
+    ("known_hosts_path", "KNOWN_HOSTS_PATH"),
+    ("unlock.backend", "UNLOCK_BACKEND"),
+    ("unlock.command", "UNLOCK_COMMAND"),
+    ("unlock.dataset", "UNLOCK_DATASET"),
+    ("unlock.continue_command", "UNLOCK_CONTINUE_COMMAND"),
+    ("probes", "PROBES"),
+];
 
 #[derive(Debug, Error)]
 pub enum ConfigError {
@@ -10,6 +104,54 @@ pub enum ConfigError {
     InvalidMac(String),
     #[error("Invalid port number: {0}")]
     InvalidPort(String),
+    #[error("Invalid host ID: {0}")]
+    InvalidHostId(String),
+    #[error("Duplicate host ID: {0}")]
+    DuplicateHost(String),
+    #[error("Host IDs {0} and {1} share the same ARTICWAKE_HOST_* variables")]
+    HostIdClash(String, String),
+    #[error("{key}: {reason}")]
+    Invalid { key: String, reason: String },
+    #[error("Failed to read config file {}: {reason}", path.display())]
+    ReadFailed { path: PathBuf, reason: String },
+    #[error("{}:{line}: {message}", path.display())]
+    Parse {
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
+    #[error("{}:{line}: {key}: {message}", path.display())]
+    File {
+        path: PathBuf,
+        line: usize,
+        key: String,
+        message: String,
+    },
+}
+
+impl ConfigError {
+    /// The environment variable this error is about, if any.
+    fn env_key(&self) -> Option<&str> {
+        match self {
+            ConfigError::MissingEnvVar(key)
+            | ConfigError::InvalidPort(key)
+            | ConfigError::Invalid { key, .. } => Some(key),
+            ConfigError::InvalidHostId(_)
+            | ConfigError::DuplicateHost(_)
+            | ConfigError::HostIdClash(..) => Some("ARTICWAKE_HOSTS"),
+            _ => None,
+        }
+    }
+
+    /// Description used when the offending value came from the config file.
+    fn file_message(&self) -> String {
+        match self {
+            ConfigError::MissingEnvVar(_) => "missing required key".to_string(),
+            ConfigError::InvalidPort(_) => "invalid port number".to_string(),
+            ConfigError::Invalid { reason, .. } => reason.clone(),
+            other => other.to_string(),
+        }
+    }
 }
 
 fn validate_mac(mac_str: &str) -> Result<(), ConfigError> {
@@ -20,55 +162,1492 @@ fn validate_mac(mac_str: &str) -> Result<(), ConfigError> {
     Ok(())
 }
 
+/// Accepts OpenSSH-style SHA256 fingerprints, e.g. `SHA256:uNiVztksCsDhcc0u9e8BujQXVUpKZIDTMczCvj3tD2s`.
+fn validate_fingerprint(fingerprint: &str) -> Result<(), String> {
+    let valid = fingerprint.strip_prefix("SHA256:").is_some_and(|b64| {
+        b64.len() == 43
+            && b64
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '/')
+    });
+    if !valid {
+        return Err(format!("invalid SHA256 fingerprint: {}", fingerprint));
+    }
+    Ok(())
+}
+
+fn parse_bool(key: &str, value: &str) -> Result<bool, ConfigError> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" => Ok(false),
+        _ => Err(ConfigError::Invalid {
+            key: key.to_string(),
+            reason: format!("expected true or false, got {}", value),
+        }),
+    }
+}
+
+/// Parses a count or number of seconds that must be at least 1.
+fn parse_positive(key: &str, value: &str) -> Result<u32, ConfigError> {
+    match value.trim().parse() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(ConfigError::Invalid {
+            key: key.to_string(),
+            reason: format!("expected a positive integer, got {}", value),
+        }),
+    }
+}
+
+/// Runs `parse` on the environment over the file, pointing errors in
+/// values that came from the file at their line.
+fn layered<T>(
+    file: Option<&ConfigFile>,
+    env: impl Fn(&str) -> Option<String>,
+    parse: impl FnOnce(&dyn Fn(&str) -> Option<String>) -> Result<T, ConfigError>,
+) -> Result<T, ConfigError> {
+    let Some(file) = file else {
+        return parse(&env);
+    };
+
+    parse(&|key| env(key).or_else(|| file.get(key))).map_err(|e| match e.env_key() {
+        Some(key) if env(key).is_none() => file.locate(key, &e).unwrap_or(e),
+        _ => e,
+    })
+}
+
+/// Parses a comma-separated list of networks such as `127.0.0.1, 10.0.0.0/8`.
+/// A bare address stands for itself.
+fn parse_networks(key: &str, value: &str) -> Result<Vec<IpNet>, ConfigError> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|net| !net.is_empty())
+        .map(|net| {
+            net.parse::<IpNet>()
+                .or_else(|_| net.parse::<std::net::IpAddr>().map(IpNet::from))
+                .map_err(|_| ConfigError::Invalid {
+                    key: key.to_string(),
+                    reason: format!("expected an IP address or CIDR network, got {}", net),
+                })
+        })
+        .collect()
+}
+
+/// Parses `/api/status=bypass, /api/auth=factor`.
+fn parse_cert_routes(key: &str, value: &str) -> Result<Vec<CertRoute>, ConfigError> {
+    let invalid = |reason: String| ConfigError::Invalid {
+        key: key.to_string(),
+        reason,
+    };
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|route| !route.is_empty())
+        .map(|route| {
+            let (prefix, policy) = route
+                .split_once('=')
+                .ok_or_else(|| invalid(format!("expected <path>=<policy>, got {}", route)))?;
+            let prefix = prefix.trim();
+            if !prefix.starts_with('/') {
+                return Err(invalid(format!("route must start with /, got {}", prefix)));
+            }
+            let policy = match policy.trim() {
+                "bypass" => CertPolicy::Bypass,
+                "factor" => CertPolicy::Factor,
+                other => {
+                    return Err(invalid(format!(
+                        "unknown policy {} (expected bypass or factor)",
+                        other
+                    )));
+                }
+            };
+            Ok(CertRoute {
+                prefix: prefix.to_string(),
+                policy,
+            })
+        })
+        .collect()
+}
+
+/// Parses SHA-256 fingerprints as hex, with or without colons, into lowercase
+/// hex without them.
+fn parse_fingerprints(key: &str, value: &str) -> Result<Vec<String>, ConfigError> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|fp| !fp.is_empty())
+        .map(|fp| {
+            let hex: String = fp.chars().filter(|&c| c != ':').collect();
+            if hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                Ok(hex.to_ascii_lowercase())
+            } else {
+                Err(ConfigError::Invalid {
+                    key: key.to_string(),
+                    reason: format!("expected a SHA-256 fingerprint in hex, got {}", fp),
+                })
+            }
+        })
+        .collect()
+}
+
+fn validate_host_id(id: &str) -> Result<(), ConfigError> {
+    let valid = !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+    if !valid {
+        return Err(ConfigError::InvalidHostId(id.to_string()));
+    }
+    Ok(())
+}
+
+/// Rejects an ID already in `existing`, or one whose environment variables
+/// would be those of another host (`gpu-box` and `gpu_box`).
+fn check_host_id_unique<'a>(
+    existing: impl IntoIterator<Item = &'a str>,
+    id: &str,
+) -> Result<(), ConfigError> {
+    let prefix = host_env_prefix(id);
+    for other in existing {
+        if other == id {
+            return Err(ConfigError::DuplicateHost(id.to_string()));
+        }
+        if host_env_prefix(other) == prefix {
+            return Err(ConfigError::HostIdClash(other.to_string(), id.to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// Validates a WebAuthn origin such as `https://pi.tailnet.ts.net` and returns
+/// it normalized. Browsers only allow passkeys on HTTPS (or localhost) origins
+/// whose host is a domain name.
+fn parse_origin(key: &str, value: &str) -> Result<String, ConfigError> {
+    let invalid = |reason: &str| ConfigError::Invalid {
+        key: key.to_string(),
+        reason: format!("{}, got {}", reason, value),
+    };
+    let origin = value.trim().trim_end_matches('/').to_ascii_lowercase();
+    let (scheme, authority) = origin
+        .split_once("://")
+        .ok_or_else(|| invalid("expected an origin like https://host"))?;
+    if authority.is_empty() || authority.contains(['/', '?', '#', '@']) {
+        return Err(invalid("expected an origin without a path"));
+    }
+
+    let host = origin_host(&origin);
+    if host.is_empty() || host.parse::<std::net::IpAddr>().is_ok() || host.starts_with('[') {
+        return Err(invalid("passkeys need a domain name, not an IP address"));
+    }
+    match scheme {
+        "https" => Ok(origin),
+        "http" if host == "localhost" => Ok(origin),
+        _ => Err(invalid("passkeys need an https:// origin")),
+    }
+}
+
+/// Host part of a validated origin, which is also the WebAuthn RP ID.
+fn origin_host(origin: &str) -> &str {
+    let authority = origin.split_once("://").map_or(origin, |(_, a)| a);
+    authority
+        .rsplit_once(':')
+        .filter(|(_, port)| port.chars().all(|c| c.is_ascii_digit()))
+        .map_or(authority, |(host, _)| host)
+}
+
+/// Passkey login (`/api/auth/passkeys`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebauthnConfig {
+    /// Origin the web UI is served from; `None` disables passkeys
+    pub origin: Option<String>,
+    /// JSON state file holding the registered passkeys
+    pub credentials_path: PathBuf,
+}
+
+impl WebauthnConfig {
+    /// Relying party ID: the origin's host name.
+    pub fn rp_id(&self) -> Option<&str> {
+        self.origin.as_deref().map(origin_host)
+    }
+}
+
+impl Default for WebauthnConfig {
+    fn default() -> Self {
+        WebauthnConfig {
+            origin: None,
+            credentials_path: PathBuf::from("/var/lib/articwake/webauthn.json"),
+        }
+    }
+}
+
+/// How the passphrase is delivered to the host's initrd.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum UnlockMethod {
+    /// Type into `cryptsetup-askpass` on an interactive shell (dropbear-initramfs)
+    #[default]
+    Askpass,
+    /// Answer `systemd-tty-ask-password-agent` in a systemd initrd
+    Systemd,
+    /// Pipe the passphrase into a command over an exec channel, e.g. `cryptroot-unlock`
+    Command(String),
+    /// `zfs load-key <dataset>`, then run `continue_command` to resume booting
+    Zfs {
+        dataset: String,
+        continue_command: String,
+    },
+}
+
+/// Coarse boot stage of a host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HostPhase {
+    Off,
+    /// Waiting in the initrd for its disk passphrase
+    Initrd,
+    Booted,
+}
+
+#[derive(Debug, Clone)]
+pub enum ProbeKind {
+    /// ICMP echo to the host
+    Icmp,
+    /// TCP connect
+    Tcp { port: u16 },
+    /// `GET` request that must answer with `expect_status`
+    Http {
+        host: String,
+        port: u16,
+        path: String,
+        expect_status: u16,
+    },
+    /// SSH identification line, optionally matched against `banner`
+    Ssh { port: u16, banner: Option<Regex> },
+}
+
+impl ProbeKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProbeKind::Icmp => "icmp",
+            ProbeKind::Tcp { .. } => "tcp",
+            ProbeKind::Http { .. } => "http",
+            ProbeKind::Ssh { .. } => "ssh",
+        }
+    }
+}
+
+/// A named check run against a host. A phase is reached when every probe
+/// tagged with it succeeds.
+#[derive(Debug, Clone)]
+pub struct ProbeConfig {
+    pub name: String,
+    pub kind: ProbeKind,
+    pub phase: Option<HostPhase>,
+}
+
+/// A probe as written in the config, before validation.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProbeSpec {
+    name: String,
+    kind: String,
+    port: Option<u16>,
+    url: Option<String>,
+    expect_status: Option<u16>,
+    banner: Option<String>,
+    phase: Option<String>,
+}
+
+/// A single machine that can be woken and unlocked.
+#[derive(Debug, Clone)]
+pub struct HostConfig {
+    pub id: String,
+    pub mac: String,
+    pub ip: String,
+    pub broadcast: String,
+    pub ssh_port: u16,
+    pub ssh_key_path: PathBuf,
+    /// Expected initrd SSH host key fingerprints (`SHA256:...`). Any match is accepted.
+    pub host_key_fingerprints: Vec<String>,
+    /// Record the host key on first connect and require it afterwards.
+    pub host_key_tofu: bool,
+    pub known_hosts_path: PathBuf,
+    pub unlock: UnlockMethod,
+    /// Status probes; empty means [`HostConfig::default_probes`]
+    pub probes: Vec<ProbeConfig>,
+}
+
+impl HostConfig {
+    /// The configured probes, or ICMP plus the initrd and system SSH ports.
+    pub fn probes(&self) -> Vec<ProbeConfig> {
+        if self.probes.is_empty() {
+            self.default_probes()
+        } else {
+            self.probes.clone()
+        }
+    }
+
+    fn default_probes(&self) -> Vec<ProbeConfig> {
+        vec![
+            ProbeConfig {
+                name: "ping".to_string(),
+                kind: ProbeKind::Icmp,
+                phase: None,
+            },
+            ProbeConfig {
+                name: "initrd_ssh".to_string(),
+                kind: ProbeKind::Tcp {
+                    port: self.ssh_port,
+                },
+                phase: Some(HostPhase::Initrd),
+            },
+            ProbeConfig {
+                name: "system_ssh".to_string(),
+                kind: ProbeKind::Tcp { port: 22 },
+                phase: Some(HostPhase::Booted),
+            },
+        ]
+    }
+}
+
+impl Default for HostConfig {
+    fn default() -> Self {
+        HostConfig {
+            id: DEFAULT_HOST_ID.to_string(),
+            mac: String::new(),
+            ip: String::new(),
+            broadcast: "255.255.255.255".to_string(),
+            ssh_port: 2222,
+            ssh_key_path: PathBuf::from("/etc/secrets/articwake-key"),
+            host_key_fingerprints: Vec::new(),
+            host_key_tofu: false,
+            known_hosts_path: PathBuf::from("/var/lib/articwake/known_hosts"),
+            unlock: UnlockMethod::default(),
+            probes: Vec::new(),
+        }
+    }
+}
+
+/// Timeouts and retries for the wake-and-unlock workflow (`POST /api/boot`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootConfig {
+    /// How long to wait for the initrd SSH port after each magic packet
+    pub wake_timeout: Duration,
+    /// Magic packets sent before giving up
+    pub wake_attempts: u32,
+    /// Unlock attempts after a timeout or SSH error; a rejected passphrase is never retried
+    pub unlock_attempts: u32,
+    /// How long to wait for system SSH once the disk is unlocked
+    pub boot_timeout: Duration,
+}
+
+impl Default for BootConfig {
+    fn default() -> Self {
+        BootConfig {
+            wake_timeout: Duration::from_secs(90),
+            wake_attempts: 3,
+            unlock_attempts: 2,
+            boot_timeout: Duration::from_secs(180),
+        }
+    }
+}
+
+/// Permanent lockout of PIN login after repeated failures, and how many PINs
+/// are checked at once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoginConfig {
+    /// Failed PIN logins in a row, from any client, that lock PIN login until
+    /// `articwake clear-lockout` is run; `None` never locks
+    pub lockout_after: Option<u32>,
+    /// Exists while PIN login is locked
+    pub lockout_path: PathBuf,
+    /// Argon2 hashes run at once; further PIN checks are refused with 503
+    pub max_concurrent_hashes: u32,
+}
+
+impl Default for LoginConfig {
+    fn default() -> Self {
+        LoginConfig {
+            lockout_after: None,
+            lockout_path: PathBuf::from("/var/lib/articwake/lockout"),
+            max_concurrent_hashes: 2,
+        }
+    }
+}
+
+/// Rules for new PINs, checked by `POST /api/pin`, `hash-pin` and
+/// `articwake user`. PINs that are already set are not checked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PinConfig {
+    pub min_length: u32,
+    /// Accept PINs that are one repeated character or a run such as `123456`
+    pub allow_simple: bool,
+    pub argon2: Argon2Config,
+}
+
+impl Default for PinConfig {
+    fn default() -> Self {
+        PinConfig {
+            min_length: 6,
+            allow_simple: false,
+            argon2: Argon2Config::default(),
+        }
+    }
+}
+
+impl PinConfig {
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let defaults = PinConfig::default();
+        Ok(PinConfig {
+            min_length: match var("ARTICWAKE_PIN_MIN_LENGTH") {
+                Some(value) => parse_positive("ARTICWAKE_PIN_MIN_LENGTH", &value)?,
+                None => defaults.min_length,
+            },
+            allow_simple: match var("ARTICWAKE_PIN_ALLOW_SIMPLE") {
+                Some(value) => parse_bool("ARTICWAKE_PIN_ALLOW_SIMPLE", &value)?,
+                None => defaults.allow_simple,
+            },
+            argon2: Argon2Config::from_vars(&var)?,
+        })
+    }
+}
+
+/// Argon2 settings for new PIN hashes (`[pin.argon2]`). A stored hash keeps
+/// the settings it was made with; logging in with a PIN whose hash is weaker
+/// stores it again with these.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Argon2Config {
+    pub variant: argon2::Algorithm,
+    /// Memory in KiB
+    pub m_cost: u32,
+    /// Passes over the memory
+    pub t_cost: u32,
+    /// Lanes, each with at least 8 KiB of the memory
+    pub parallelism: u32,
+}
+
+impl Default for Argon2Config {
+    fn default() -> Self {
+        Argon2Config {
+            variant: argon2::Algorithm::Argon2id,
+            m_cost: argon2::Params::DEFAULT_M_COST,
+            t_cost: argon2::Params::DEFAULT_T_COST,
+            parallelism: argon2::Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl Argon2Config {
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let defaults = Argon2Config::default();
+        let positive = |key: &str, default: u32| match var(key) {
+            Some(value) => parse_positive(key, &value),
+            None => Ok(default),
+        };
+        let config = Argon2Config {
+            variant: match var("ARTICWAKE_PIN_ARGON2_VARIANT") {
+                Some(value) => value.trim().parse().map_err(|_| ConfigError::Invalid {
+                    key: "ARTICWAKE_PIN_ARGON2_VARIANT".to_string(),
+                    reason: format!("expected argon2id, argon2i or argon2d, got {}", value),
+                })?,
+                None => defaults.variant,
+            },
+            m_cost: positive("ARTICWAKE_PIN_ARGON2_M_COST", defaults.m_cost)?,
+            t_cost: positive("ARTICWAKE_PIN_ARGON2_T_COST", defaults.t_cost)?,
+            parallelism: positive("ARTICWAKE_PIN_ARGON2_PARALLELISM", defaults.parallelism)?,
+        };
+
+        if config.parallelism > argon2::Params::MAX_P_COST {
+            return Err(ConfigError::Invalid {
+                key: "ARTICWAKE_PIN_ARGON2_PARALLELISM".to_string(),
+                reason: format!("expected at most {}", argon2::Params::MAX_P_COST),
+            });
+        }
+        if u64::from(config.m_cost) < 8 * u64::from(config.parallelism) {
+            return Err(ConfigError::Invalid {
+                key: "ARTICWAKE_PIN_ARGON2_M_COST".to_string(),
+                reason: format!(
+                    "expected at least {} KiB, 8 for each of the {} lanes",
+                    8 * config.parallelism,
+                    config.parallelism
+                ),
+            });
+        }
+        Ok(config)
+    }
+}
+
+/// Whether the HTTPS listener asks for client certificates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ClientAuth {
+    #[default]
+    Off,
+    /// Verify a certificate if the client sends one
+    Optional,
+    /// Refuse connections without a certificate signed by the client CA
+    Required,
+}
+
+/// What a valid client certificate does for requests to a route.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertPolicy {
+    /// The certificate alone authenticates; no PIN or token is needed
+    Bypass,
+    /// The certificate is one of two factors: it replaces the TOTP code at
+    /// login, and is needed alongside the session token elsewhere
+    Factor,
+}
+
+/// A [`CertPolicy`] for every path under `prefix`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertRoute {
+    pub prefix: String,
+    pub policy: CertPolicy,
+}
+
+/// HTTPS listener (`[tls]`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsConfig {
+    /// Serve HTTPS on `port` instead of plain HTTP
+    pub enabled: bool,
+    /// PEM certificate chain; a self-signed one is generated here if neither
+    /// file exists
+    pub cert_path: PathBuf,
+    /// PEM private key
+    pub key_path: PathBuf,
+    /// Plain HTTP port that redirects to HTTPS; `None` serves no plain HTTP
+    pub redirect_port: Option<u16>,
+    pub client_auth: ClientAuth,
+    /// CA that client certificates must chain to
+    pub client_ca_path: PathBuf,
+    /// Private key of the client CA, used by `articwake issue-client-cert`
+    pub client_ca_key_path: PathBuf,
+    /// Routes where a client certificate counts; the longest prefix wins
+    pub client_cert_routes: Vec<CertRoute>,
+    /// SHA-256 fingerprints (lowercase hex) of client certificates to ignore
+    pub revoked_client_certs: Vec<String>,
+}
+
+impl TlsConfig {
+    /// The policy of the longest route prefix covering `path`.
+    pub fn cert_policy(&self, path: &str) -> Option<CertPolicy> {
+        self.client_cert_routes
+            .iter()
+            .filter(|route| {
+                let prefix = route.prefix.trim_end_matches('/');
+                path.strip_prefix(prefix)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            })
+            .max_by_key(|route| route.prefix.len())
+            .map(|route| route.policy)
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            enabled: false,
+            cert_path: PathBuf::from("/var/lib/articwake/tls/cert.pem"),
+            key_path: PathBuf::from("/var/lib/articwake/tls/key.pem"),
+            redirect_port: None,
+            client_auth: ClientAuth::default(),
+            client_ca_path: PathBuf::from("/var/lib/articwake/tls/client-ca.pem"),
+            client_ca_key_path: PathBuf::from("/var/lib/articwake/tls/client-ca.key"),
+            client_cert_routes: Vec::new(),
+            revoked_client_certs: Vec::new(),
+        }
+    }
+}
+
+/// Which client addresses may reach one part of the server.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccessRules {
+    /// If not empty, only these networks are let in
+    pub allow: Vec<IpNet>,
+    /// Never let in, even if also allowed
+    pub deny: Vec<IpNet>,
+}
+
+impl AccessRules {
+    pub fn permits(&self, ip: std::net::IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// Address rules (`[access]`), separately for the web UI and `/api`.
+/// `[access] allow` and `deny` apply to both unless `[access.ui]` or
+/// `[access.api]` replaces them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccessConfig {
+    pub ui: AccessRules,
+    pub api: AccessRules,
+}
+
 #[derive(Debug, Clone)]
 pub struct Config {
     pub bind_host: String,
     pub port: u16,
-    pub homelab_mac: String,
-    pub homelab_ip: String,
-    pub homelab_broadcast: String,
-    pub ssh_port: u16,
-    pub ssh_key_path: PathBuf,
+    pub hosts: Vec<HostConfig>,
     pub pin_hash_path: PathBuf,
+    /// Base32 TOTP secret; logins need a code while this file exists
+    pub totp_secret_path: PathBuf,
+    /// How often the background monitor probes every host
+    pub status_interval: Duration,
+    pub boot: BootConfig,
+    pub login: LoginConfig,
+    pub pin: PinConfig,
+    /// JSON lines audit log; `None` disables auditing
+    pub audit_log_path: Option<PathBuf>,
+    /// Sessions and login rate-limit state, kept across restarts; `None`
+    /// keeps them in memory only
+    pub state_path: Option<PathBuf>,
+    /// Hashed API keys, managed with `articwake api-key`
+    pub api_keys_path: PathBuf,
+    /// User accounts with their own PIN and role, managed with
+    /// `articwake user`
+    pub users_path: PathBuf,
+    /// Reverse proxies whose `X-Forwarded-For` / `Forwarded` headers are
+    /// believed; requests from anywhere else use the peer address
+    pub trusted_proxies: Vec<IpNet>,
+    pub access: AccessConfig,
+    pub tls: TlsConfig,
+    pub webauthn: WebauthnConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind_host: "127.0.0.1".to_string(),
+            port: 80,
+            hosts: Vec::new(),
+            pin_hash_path: PathBuf::from("/var/lib/articwake/pin.hash"),
+            totp_secret_path: PathBuf::from(DEFAULT_TOTP_SECRET_PATH),
+            status_interval: Duration::from_secs(5),
+            boot: BootConfig::default(),
+            login: LoginConfig::default(),
+            pin: PinConfig::default(),
+            audit_log_path: Some(PathBuf::from("/var/lib/articwake/audit.log")),
+            state_path: None,
+            api_keys_path: PathBuf::from("/var/lib/articwake/api-keys.json"),
+            users_path: PathBuf::from("/var/lib/articwake/users.json"),
+            trusted_proxies: Vec::new(),
+            access: AccessConfig::default(),
+            tls: TlsConfig::default(),
+            webauthn: WebauthnConfig::default(),
+        }
+    }
 }
 
 impl Config {
     pub fn from_env() -> Result<Self, ConfigError> {
+        Self::from_vars(|key| env::var(key).ok())
+    }
+
+    /// Loads the config file (if any) and applies `ARTICWAKE_*` overrides on top.
+    ///
+    /// The file is taken from `path`, then `ARTICWAKE_CONFIG`, then
+    /// [`DEFAULT_CONFIG_PATH`] if it exists. Without a file this is equivalent
+    /// to [`Config::from_env`].
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let file = Self::read_file(path)?;
+        Self::from_layers(file.as_ref(), |key| env::var(key).ok())
+    }
+
+    /// Only the `[pin]` settings, for commands that can run before the rest
+    /// of the configuration exists, like `hash-pin` on first boot.
+    pub fn load_pin(path: Option<&Path>) -> Result<PinConfig, ConfigError> {
+        let file = Self::read_file(path)?;
+        layered(
+            file.as_ref(),
+            |key| env::var(key).ok(),
+            |var| PinConfig::from_vars(var),
+        )
+    }
+
+    fn read_file(path: Option<&Path>) -> Result<Option<ConfigFile>, ConfigError> {
+        let path = match path {
+            Some(path) => Some(path.to_path_buf()),
+            None => env::var_os("ARTICWAKE_CONFIG")
+                .map(PathBuf::from)
+                .or_else(|| {
+                    let default = PathBuf::from(DEFAULT_CONFIG_PATH);
+                    default.exists().then_some(default)
+                }),
+        };
+
+        match path {
+            Some(path) => {
+                let content = fs::read_to_string(&path).map_err(|e| ConfigError::ReadFailed {
+                    path: path.clone(),
+                    reason: e.to_string(),
+                })?;
+                Ok(Some(ConfigFile::parse(&path, &content)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn from_layers(
+        file: Option<&ConfigFile>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, ConfigError> {
+        layered(file, env, |var| Self::from_vars(var))
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let defaults = Config::default();
+
+        let hosts = match var("ARTICWAKE_HOSTS") {
+            Some(ids) => {
+                let mut hosts: Vec<HostConfig> = Vec::new();
+                for id in ids.split(',').map(str::trim).filter(|id| !id.is_empty()) {
+                    validate_host_id(id)?;
+                    check_host_id_unique(hosts.iter().map(|h| h.id.as_str()), id)?;
+                    hosts.push(host_from_vars(&var, id, &host_env_prefix(id))?);
+                }
+                if hosts.is_empty() {
+                    return Err(ConfigError::MissingEnvVar("ARTICWAKE_HOSTS".to_string()));
+                }
+                hosts
+            }
+            None => vec![host_from_vars(&var, DEFAULT_HOST_ID, "ARTICWAKE_HOMELAB")?],
+        };
+
+        let positive = |key: &str| {
+            var(key)
+                .map(|value| parse_positive(key, &value))
+                .transpose()
+        };
+        let boot = BootConfig {
+            wake_timeout: positive("ARTICWAKE_BOOT_WAKE_TIMEOUT")?
+                .map(|secs| Duration::from_secs(secs.into()))
+                .unwrap_or(defaults.boot.wake_timeout),
+            wake_attempts: positive("ARTICWAKE_BOOT_WAKE_ATTEMPTS")?
+                .unwrap_or(defaults.boot.wake_attempts),
+            unlock_attempts: positive("ARTICWAKE_BOOT_UNLOCK_ATTEMPTS")?
+                .unwrap_or(defaults.boot.unlock_attempts),
+            boot_timeout: positive("ARTICWAKE_BOOT_BOOT_TIMEOUT")?
+                .map(|secs| Duration::from_secs(secs.into()))
+                .unwrap_or(defaults.boot.boot_timeout),
+        };
+
+        let login = LoginConfig {
+            lockout_after: positive("ARTICWAKE_LOGIN_LOCKOUT_AFTER")?
+                .or(defaults.login.lockout_after),
+            lockout_path: var("ARTICWAKE_LOGIN_LOCKOUT_PATH")
+                .map(PathBuf::from)
+                .unwrap_or(defaults.login.lockout_path),
+            max_concurrent_hashes: positive("ARTICWAKE_LOGIN_MAX_CONCURRENT_HASHES")?
+                .unwrap_or(defaults.login.max_concurrent_hashes),
+        };
+
+        // A scope's own list replaces the shared one
+        let networks = |scope_key: &str, shared_key: &str, default: Vec<IpNet>| match var(scope_key)
+        {
+            Some(list) => parse_networks(scope_key, &list),
+            None => match var(shared_key) {
+                Some(list) => parse_networks(shared_key, &list),
+                None => Ok(default),
+            },
+        };
+        let access = AccessConfig {
+            ui: AccessRules {
+                allow: networks(
+                    "ARTICWAKE_ACCESS_UI_ALLOW",
+                    "ARTICWAKE_ACCESS_ALLOW",
+                    defaults.access.ui.allow,
+                )?,
+                deny: networks(
+                    "ARTICWAKE_ACCESS_UI_DENY",
+                    "ARTICWAKE_ACCESS_DENY",
+                    defaults.access.ui.deny,
+                )?,
+            },
+            api: AccessRules {
+                allow: networks(
+                    "ARTICWAKE_ACCESS_API_ALLOW",
+                    "ARTICWAKE_ACCESS_ALLOW",
+                    defaults.access.api.allow,
+                )?,
+                deny: networks(
+                    "ARTICWAKE_ACCESS_API_DENY",
+                    "ARTICWAKE_ACCESS_DENY",
+                    defaults.access.api.deny,
+                )?,
+            },
+        };
+
+        let tls = TlsConfig {
+            enabled: match var("ARTICWAKE_TLS_ENABLED") {
+                Some(value) => parse_bool("ARTICWAKE_TLS_ENABLED", &value)?,
+                None => defaults.tls.enabled,
+            },
+            cert_path: var("ARTICWAKE_TLS_CERT_PATH")
+                .map(PathBuf::from)
+                .unwrap_or(defaults.tls.cert_path),
+            key_path: var("ARTICWAKE_TLS_KEY_PATH")
+                .map(PathBuf::from)
+                .unwrap_or(defaults.tls.key_path),
+            redirect_port: match var("ARTICWAKE_TLS_REDIRECT_PORT") {
+                Some(port) if port.is_empty() => None,
+                Some(port) => Some(port.parse().map_err(|_| {
+                    ConfigError::InvalidPort("ARTICWAKE_TLS_REDIRECT_PORT".to_string())
+                })?),
+                None => defaults.tls.redirect_port,
+            },
+            client_auth: match var("ARTICWAKE_TLS_CLIENT_AUTH").as_deref() {
+                None => defaults.tls.client_auth,
+                Some("off") => ClientAuth::Off,
+                Some("optional") => ClientAuth::Optional,
+                Some("required") => ClientAuth::Required,
+                Some(other) => {
+                    return Err(ConfigError::Invalid {
+                        key: "ARTICWAKE_TLS_CLIENT_AUTH".to_string(),
+                        reason: format!("expected off, optional or required, got {}", other),
+                    });
+                }
+            },
+            client_ca_path: var("ARTICWAKE_TLS_CLIENT_CA_PATH")
+                .map(PathBuf::from)
+                .unwrap_or(defaults.tls.client_ca_path),
+            client_ca_key_path: var("ARTICWAKE_TLS_CLIENT_CA_KEY_PATH")
+                .map(PathBuf::from)
+                .unwrap_or(defaults.tls.client_ca_key_path),
+            client_cert_routes: match var("ARTICWAKE_TLS_CLIENT_CERT_ROUTES") {
+                Some(list) => parse_cert_routes("ARTICWAKE_TLS_CLIENT_CERT_ROUTES", &list)?,
+                None => defaults.tls.client_cert_routes,
+            },
+            revoked_client_certs: match var("ARTICWAKE_TLS_REVOKED_CLIENT_CERTS") {
+                Some(list) => parse_fingerprints("ARTICWAKE_TLS_REVOKED_CLIENT_CERTS", &list)?,
+                None => defaults.tls.revoked_client_certs,
+            },
+        };
+        if tls.client_auth != ClientAuth::Off && !tls.enabled {
+            return Err(ConfigError::Invalid {
+                key: "ARTICWAKE_TLS_CLIENT_AUTH".to_string(),
+                reason: "client certificates need tls.enabled".to_string(),
+            });
+        }
+
         Ok(Config {
-            bind_host: env::var("ARTICWAKE_BIND_HOST").unwrap_or_else(|_| "127.0.0.1".to_string()),
-            port: env::var("ARTICWAKE_PORT")
-                .unwrap_or_else(|_| "80".to_string())
-                .parse()
-                .map_err(|_| ConfigError::InvalidPort("ARTICWAKE_PORT".to_string()))?,
-            homelab_mac: {
-                let mac = env::var("ARTICWAKE_HOMELAB_MAC")
-                    .map_err(|_| ConfigError::MissingEnvVar("ARTICWAKE_HOMELAB_MAC".to_string()))?;
-                validate_mac(&mac)?;
-                mac
-            },
-            homelab_ip: env::var("ARTICWAKE_HOMELAB_IP")
-                .map_err(|_| ConfigError::MissingEnvVar("ARTICWAKE_HOMELAB_IP".to_string()))?,
-            homelab_broadcast: env::var("ARTICWAKE_HOMELAB_BROADCAST")
-                .unwrap_or_else(|_| "255.255.255.255".to_string()),
-            ssh_port: env::var("ARTICWAKE_SSH_PORT")
-                .unwrap_or_else(|_| "2222".to_string())
-                .parse()
-                .map_err(|_| ConfigError::InvalidPort("ARTICWAKE_SSH_PORT".to_string()))?,
-            ssh_key_path: PathBuf::from(
-                env::var("ARTICWAKE_SSH_KEY_PATH")
-                    .unwrap_or_else(|_| "/etc/secrets/articwake-key".to_string()),
-            ),
-            pin_hash_path: PathBuf::from(
-                env::var("ARTICWAKE_PIN_HASH_PATH")
-                    .unwrap_or_else(|_| "/var/lib/articwake/pin.hash".to_string()),
-            ),
+            bind_host: var("ARTICWAKE_BIND_HOST").unwrap_or(defaults.bind_host),
+            port: match var("ARTICWAKE_PORT") {
+                Some(port) => port
+                    .parse()
+                    .map_err(|_| ConfigError::InvalidPort("ARTICWAKE_PORT".to_string()))?,
+                // HTTPS defaults to its own well-known port
+                None if tls.enabled => 443,
+                None => defaults.port,
+            },
+            hosts,
+            pin_hash_path: var("ARTICWAKE_PIN_HASH_PATH")
+                .map(PathBuf::from)
+                .unwrap_or(defaults.pin_hash_path),
+            totp_secret_path: var("ARTICWAKE_TOTP_SECRET_PATH")
+                .map(PathBuf::from)
+                .unwrap_or(defaults.totp_secret_path),
+            status_interval: positive("ARTICWAKE_STATUS_INTERVAL")?
+                .map(|secs| Duration::from_secs(secs.into()))
+                .unwrap_or(defaults.status_interval),
+            boot,
+            login,
+            pin: PinConfig::from_vars(&var)?,
+            // An empty path turns the audit log off
+            audit_log_path: match var("ARTICWAKE_AUDIT_LOG_PATH") {
+                Some(path) if path.is_empty() => None,
+                Some(path) => Some(PathBuf::from(path)),
+                None => defaults.audit_log_path,
+            },
+            state_path: var("ARTICWAKE_STATE_PATH")
+                .filter(|path| !path.is_empty())
+                .map(PathBuf::from)
+                .or(defaults.state_path),
+            api_keys_path: var("ARTICWAKE_API_KEYS_PATH")
+                .map(PathBuf::from)
+                .unwrap_or(defaults.api_keys_path),
+            users_path: var("ARTICWAKE_USERS_PATH")
+                .map(PathBuf::from)
+                .unwrap_or(defaults.users_path),
+            trusted_proxies: match var("ARTICWAKE_TRUSTED_PROXIES") {
+                Some(list) => parse_networks("ARTICWAKE_TRUSTED_PROXIES", &list)?,
+                None => defaults.trusted_proxies,
+            },
+            access,
+            tls,
+            webauthn: WebauthnConfig {
+                origin: var("ARTICWAKE_WEBAUTHN_ORIGIN")
+                    .filter(|origin| !origin.is_empty())
+                    .map(|origin| parse_origin("ARTICWAKE_WEBAUTHN_ORIGIN", &origin))
+                    .transpose()?,
+                credentials_path: var("ARTICWAKE_WEBAUTHN_CREDENTIALS_PATH")
+                    .map(PathBuf::from)
+                    .unwrap_or(defaults.webauthn.credentials_path),
+            },
+        })
+    }
+
+    pub fn host(&self, id: &str) -> Option<&HostConfig> {
+        self.hosts.iter().find(|h| h.id == id)
+    }
+
+    /// The host served by the legacy single-host routes (`/api/status`, ...).
+    pub fn default_host(&self) -> &HostConfig {
+        &self.hosts[0]
+    }
+}
+
+/// Environment variable prefix for a host ID, e.g. `gpu-box` -> `ARTICWAKE_HOST_GPU_BOX`.
+fn host_env_prefix(id: &str) -> String {
+    format!(
+        "ARTICWAKE_HOST_{}",
+        id.to_ascii_uppercase().replace('-', "_")
+    )
+}
+
+/// Reads `<prefix>_MAC`, `<prefix>_IP` and `<prefix>_BROADCAST`. The SSH port and
+/// key fall back to the shared `ARTICWAKE_SSH_PORT` / `ARTICWAKE_SSH_KEY_PATH`.
+fn host_from_vars(
+    var: &impl Fn(&str) -> Option<String>,
+    id: &str,
+    prefix: &str,
+) -> Result<HostConfig, ConfigError> {
+    let defaults = HostConfig::default();
+    let required = |suffix: &str| {
+        let key = format!("{}_{}", prefix, suffix);
+        var(&key).ok_or(ConfigError::MissingEnvVar(key))
+    };
+    let host_or_shared = |suffix: &str, shared: &str| {
+        let key = format!("{}_{}", prefix, suffix);
+        match var(&key) {
+            Some(value) => Some((key, value)),
+            None => var(shared).map(|value| (shared.to_string(), value)),
+        }
+    };
+
+    let mac = required("MAC")?;
+    validate_mac(&mac).map_err(|e| ConfigError::Invalid {
+        key: format!("{}_MAC", prefix),
+        reason: e.to_string(),
+    })?;
+
+    let ssh_port = match host_or_shared("SSH_PORT", "ARTICWAKE_SSH_PORT") {
+        Some((key, value)) => value.parse().map_err(|_| ConfigError::InvalidPort(key))?,
+        None => defaults.ssh_port,
+    };
+
+    let fingerprints_key = format!("{}_HOST_KEY_FINGERPRINTS", prefix);
+    let host_key_fingerprints: Vec<String> = var(&fingerprints_key)
+        .map(|list| {
+            list.split(',')
+                .map(str::trim)
+                .filter(|fp| !fp.is_empty())
+                .map(String::from)
+                .collect()
         })
+        .unwrap_or_default();
+    for fingerprint in &host_key_fingerprints {
+        validate_fingerprint(fingerprint).map_err(|reason| ConfigError::Invalid {
+            key: fingerprints_key.clone(),
+            reason,
+        })?;
+    }
+
+    let host_key_tofu = match host_or_shared("HOST_KEY_TOFU", "ARTICWAKE_HOST_KEY_TOFU") {
+        Some((key, value)) => parse_bool(&key, &value)?,
+        None => defaults.host_key_tofu,
+    };
+
+    let unlock = match host_or_shared("UNLOCK_BACKEND", "ARTICWAKE_UNLOCK_BACKEND") {
+        None => defaults.unlock,
+        Some((key, backend)) => match backend.as_str() {
+            "askpass" => UnlockMethod::Askpass,
+            "systemd" => UnlockMethod::Systemd,
+            "command" => UnlockMethod::Command(
+                var(&format!("{}_UNLOCK_COMMAND", prefix))
+                    .unwrap_or_else(|| "cryptroot-unlock".to_string()),
+            ),
+            "zfs" => UnlockMethod::Zfs {
+                dataset: required("UNLOCK_DATASET")?,
+                continue_command: var(&format!("{}_UNLOCK_CONTINUE_COMMAND", prefix))
+                    .unwrap_or_else(|| "killall zfs".to_string()),
+            },
+            other => {
+                return Err(ConfigError::Invalid {
+                    key,
+                    reason: format!(
+                        "unknown unlock backend {} (expected askpass, systemd, command or zfs)",
+                        other
+                    ),
+                });
+            }
+        },
+    };
+
+    let ip = required("IP")?;
+
+    let probes_key = format!("{}_PROBES", prefix);
+    let probes = match var(&probes_key) {
+        Some(json) => parse_probes(&json, &ip).map_err(|reason| ConfigError::Invalid {
+            key: probes_key,
+            reason,
+        })?,
+        None => Vec::new(),
+    };
+
+    Ok(HostConfig {
+        id: id.to_string(),
+        mac,
+        ip,
+        broadcast: host_or_shared("BROADCAST", "ARTICWAKE_HOMELAB_BROADCAST")
+            .map(|(_, value)| value)
+            .unwrap_or(defaults.broadcast),
+        ssh_port,
+        ssh_key_path: host_or_shared("SSH_KEY_PATH", "ARTICWAKE_SSH_KEY_PATH")
+            .map(|(_, value)| PathBuf::from(value))
+            .unwrap_or(defaults.ssh_key_path),
+        host_key_fingerprints,
+        host_key_tofu,
+        known_hosts_path: host_or_shared("KNOWN_HOSTS_PATH", "ARTICWAKE_KNOWN_HOSTS_PATH")
+            .map(|(_, value)| PathBuf::from(value))
+            .unwrap_or(defaults.known_hosts_path),
+        unlock,
+        probes,
+    })
+}
+
+/// Parses a JSON list of probes. HTTP probes given only a path target `ip`.
+fn parse_probes(json: &str, ip: &str) -> Result<Vec<ProbeConfig>, String> {
+    let specs: Vec<ProbeSpec> =
+        serde_json::from_str(json).map_err(|e| format!("invalid probe list: {}", e))?;
+
+    let mut probes: Vec<ProbeConfig> = Vec::new();
+    for (index, spec) in specs.into_iter().enumerate() {
+        let probe = parse_probe(spec, ip).map_err(|e| format!("probes[{}]: {}", index, e))?;
+        if probes.iter().any(|p| p.name == probe.name) {
+            return Err(format!(
+                "probes[{}]: duplicate probe name: {}",
+                index, probe.name
+            ));
+        }
+        probes.push(probe);
+    }
+    Ok(probes)
+}
+
+fn parse_probe(spec: ProbeSpec, ip: &str) -> Result<ProbeConfig, String> {
+    if spec.name.is_empty() {
+        return Err("name must not be empty".to_string());
+    }
+    let port = |kind: &str| spec.port.ok_or(format!("{} probes need a port", kind));
+
+    let kind = match spec.kind.as_str() {
+        "icmp" => ProbeKind::Icmp,
+        "tcp" => ProbeKind::Tcp { port: port("tcp")? },
+        "ssh" => ProbeKind::Ssh {
+            port: port("ssh")?,
+            banner: spec
+                .banner
+                .as_deref()
+                .map(Regex::new)
+                .transpose()
+                .map_err(|e| format!("invalid banner pattern: {}", e))?,
+        },
+        "http" => {
+            let url = spec.url.as_deref().ok_or("http probes need a url")?;
+            let (host, url_port, path) = parse_http_url(url, ip)?;
+            ProbeKind::Http {
+                host,
+                port: url_port.or(spec.port).unwrap_or(80),
+                path,
+                expect_status: spec.expect_status.unwrap_or(200),
+            }
+        }
+        other => {
+            return Err(format!(
+                "unknown probe kind {} (expected icmp, tcp, http or ssh)",
+                other
+            ));
+        }
+    };
+
+    let phase = match spec.phase.as_deref() {
+        None => None,
+        Some("initrd") => Some(HostPhase::Initrd),
+        Some("booted") => Some(HostPhase::Booted),
+        Some(other) => {
+            return Err(format!(
+                "unknown phase {} (expected initrd or booted)",
+                other
+            ));
+        }
+    };
+
+    Ok(ProbeConfig {
+        name: spec.name,
+        kind,
+        phase,
+    })
+}
+
+/// Splits `http://host[:port]/path` into its parts. A bare `/path` targets `ip`.
+fn parse_http_url(url: &str, ip: &str) -> Result<(String, Option<u16>, String), String> {
+    if url.starts_with('/') {
+        return Ok((ip.to_string(), None, url.to_string()));
+    }
+    if url.starts_with("https://") {
+        return Err("https URLs are not supported".to_string());
+    }
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| format!("invalid URL: {}", url))?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+
+    // Bracketed IPv6 literals contain colons of their own
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => {
+            let port = port
+                .parse()
+                .map_err(|_| format!("invalid port in URL: {}", url))?;
+            (host, Some(port))
+        }
+        _ => (authority, None),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err(format!("invalid URL: {}", url));
+    }
+    Ok((host.to_string(), port, path.to_string()))
+}
+
+/// A value read from the config file, remembered with where it came from.
+struct FileEntry {
+    key: String,
+    line: usize,
+    value: String,
+}
+
+/// A parsed config file, flattened into the `ARTICWAKE_*` variables it sets.
+struct ConfigFile {
+    path: PathBuf,
+    entries: HashMap<String, FileEntry>,
+    /// `(env prefix, file key, line)` of every `[[hosts]]` table
+    hosts: Vec<(String, String, usize)>,
+}
+
+impl ConfigFile {
+    fn parse(path: &Path, content: &str) -> Result<Self, ConfigError> {
+        let doc = ImDocument::parse(content).map_err(|e| ConfigError::Parse {
+            path: path.to_path_buf(),
+            line: line_of(content, e.span().map(|s| s.start)),
+            message: e.message().to_string(),
+        })?;
+
+        let mut file = ConfigFile {
+            path: path.to_path_buf(),
+            entries: HashMap::new(),
+            hosts: Vec::new(),
+        };
+
+        let mut values = Vec::new();
+        flatten_table(doc.as_table(), "", content, &mut values);
+
+        for (key, line, item) in values {
+            if key == "hosts" {
+                file.add_hosts(item, line, content)?;
+                continue;
+            }
+            let env_key = FILE_KEYS
+                .iter()
+                .find(|(file_key, _)| *file_key == key)
+                .map(|(_, env_key)| env_key)
+                .ok_or_else(|| file.error(line, &key, "unknown key"))?;
+            let value = item
+                .scalar()
+                .ok_or_else(|| file.error(line, &key, "expected a value"))?;
+            file.entries
+                .insert(env_key.to_string(), FileEntry { key, line, value });
+        }
+
+        Ok(file)
+    }
+
+    fn add_hosts(&mut self, item: Leaf, line: usize, content: &str) -> Result<(), ConfigError> {
+        let tables = item
+            .as_array_of_tables()
+            .ok_or_else(|| self.error(line, "hosts", "expected [[hosts]] tables"))?;
+
+        let mut ids: Vec<String> = Vec::new();
+        for (index, table) in tables.iter().enumerate() {
+            let table_key = format!("hosts[{}]", index);
+            let table_line = line_of(content, table.span().map(|s| s.start));
+
+            let mut values = Vec::new();
+            flatten_table(table, "", content, &mut values);
+
+            let (id_line, id) = values
+                .iter()
+                .find(|(key, _, _)| key == "id")
+                .map(|(_, line, item)| (*line, item.as_str()))
+                .ok_or_else(|| self.error(table_line, &table_key, "missing required key `id`"))?;
+            let id_key = format!("{}.id", table_key);
+            let id = id.ok_or_else(|| self.error(id_line, &id_key, "expected a string"))?;
+            validate_host_id(id).map_err(|e| self.error(id_line, &id_key, &e.to_string()))?;
+            check_host_id_unique(ids.iter().map(String::as_str), id).map_err(|e| {
+                let message = match e {
+                    ConfigError::DuplicateHost(_) => format!("duplicate host ID: {}", id),
+                    other => other.to_string(),
+                };
+                self.error(id_line, &id_key, &message)
+            })?;
+
+            let prefix = host_env_prefix(id);
+            for (key, line, item) in values.iter().filter(|(key, _, _)| key != "id") {
+                let file_key = format!("{}.{}", table_key, key);
+                let suffix = HOST_FILE_KEYS
+                    .iter()
+                    .find(|(host_key, _)| host_key == key)
+                    .map(|(_, suffix)| suffix)
+                    .ok_or_else(|| self.error(*line, &file_key, "unknown key"))?;
+                let value = if *suffix == "PROBES" {
+                    item.json().map(|json| json.to_string())
+                } else {
+                    item.scalar()
+                }
+                .ok_or_else(|| self.error(*line, &file_key, "expected a value"))?;
+                self.entries.insert(
+                    format!("{}_{}", prefix, suffix),
+                    FileEntry {
+                        key: file_key,
+                        line: *line,
+                        value,
+                    },
+                );
+            }
+
+            self.hosts.push((prefix, table_key, table_line));
+            ids.push(id.to_string());
+        }
+
+        if !ids.is_empty() {
+            self.entries.insert(
+                "ARTICWAKE_HOSTS".to_string(),
+                FileEntry {
+                    key: "hosts".to_string(),
+                    line,
+                    value: ids.join(","),
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn get(&self, env_key: &str) -> Option<String> {
+        self.entries.get(env_key).map(|entry| entry.value.clone())
+    }
+
+    /// Points an error about `env_key` at the file line responsible for it.
+    fn locate(&self, env_key: &str, err: &ConfigError) -> Option<ConfigError> {
+        if let Some(entry) = self.entries.get(env_key) {
+            return Some(self.error(entry.line, &entry.key, &err.file_message()));
+        }
+
+        // A required per-host key missing from its [[hosts]] table
+        let (prefix, table_key, line) = self
+            .hosts
+            .iter()
+            .find(|(prefix, _, _)| env_key.starts_with(prefix.as_str()))?;
+        let suffix = env_key
+            .strip_prefix(prefix.as_str())?
+            .trim_start_matches('_');
+        let (host_key, _) = HOST_FILE_KEYS.iter().find(|(_, s)| *s == suffix)?;
+        Some(self.error(
+            *line,
+            &format!("{}.{}", table_key, host_key),
+            &err.file_message(),
+        ))
+    }
+
+    fn error(&self, line: usize, key: &str, message: &str) -> ConfigError {
+        ConfigError::File {
+            path: self.path.clone(),
+            line,
+            key: key.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+/// A leaf of the config document: a value, or an array of tables.
+#[derive(Clone, Copy)]
+enum Leaf<'a> {
+    Item(&'a Item),
+    Value(&'a Value),
+}
+
+impl<'a> Leaf<'a> {
+    fn as_str(&self) -> Option<&'a str> {
+        match self {
+            Leaf::Item(item) => item.as_str(),
+            Leaf::Value(value) => value.as_str(),
+        }
+    }
+
+    /// Renders any value, including tables and arrays of tables, as JSON.
+    fn json(&self) -> Option<serde_json::Value> {
+        match self {
+            Leaf::Item(item) => item_json(item),
+            Leaf::Value(value) => value_json(value),
+        }
+    }
+
+    fn as_array_of_tables(&self) -> Option<&'a ArrayOfTables> {
+        match self {
+            Leaf::Item(item) => item.as_array_of_tables(),
+            Leaf::Value(_) => None,
+        }
+    }
+
+    /// Renders a scalar (or array of scalars, comma-joined) as an env-style string.
+    fn scalar(&self) -> Option<String> {
+        fn render(value: &Value) -> Option<String> {
+            match value {
+                Value::String(s) => Some(s.value().clone()),
+                Value::Integer(i) => Some(i.value().to_string()),
+                Value::Float(f) => Some(f.value().to_string()),
+                Value::Boolean(b) => Some(b.value().to_string()),
+                Value::Array(array) => array
+                    .iter()
+                    .map(render)
+                    .collect::<Option<Vec<_>>>()
+                    .map(|values| values.join(",")),
+                _ => None,
+            }
+        }
+        match self {
+            Leaf::Item(item) => item.as_value().and_then(render),
+            Leaf::Value(value) => render(value),
+        }
+    }
+}
+
+fn join_key(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", prefix, key)
+    }
+}
+
+fn value_json(value: &Value) -> Option<serde_json::Value> {
+    match value {
+        Value::String(s) => Some(s.value().clone().into()),
+        Value::Integer(i) => Some((*i.value()).into()),
+        Value::Float(f) => Some((*f.value()).into()),
+        Value::Boolean(b) => Some((*b.value()).into()),
+        Value::Array(array) => array.iter().map(value_json).collect(),
+        Value::InlineTable(table) => table
+            .iter()
+            .map(|(k, v)| Some((k.to_string(), value_json(v)?)))
+            .collect(),
+        Value::Datetime(_) => None,
     }
 }
 
+fn table_json(table: &Table) -> Option<serde_json::Value> {
+    table
+        .iter()
+        .map(|(k, item)| Some((k.to_string(), item_json(item)?)))
+        .collect()
+}
+
+fn item_json(item: &Item) -> Option<serde_json::Value> {
+    match item {
+        Item::Value(value) => value_json(value),
+        Item::Table(table) => table_json(table),
+        Item::ArrayOfTables(tables) => tables.iter().map(table_json).collect(),
+        Item::None => None,
+    }
+}
+
+/// Collects `(dotted key, line, leaf)` for every leaf of `table`, descending
+/// into nested and inline tables. Arrays of tables are returned as leaves for
+/// the caller to interpret.
+fn flatten_table<'a>(
+    table: &'a Table,
+    prefix: &str,
+    content: &str,
+    out: &mut Vec<(String, usize, Leaf<'a>)>,
+) {
+    for (key, item) in table.iter() {
+        let full_key = join_key(prefix, key);
+        match item {
+            Item::Table(nested) => flatten_table(nested, &full_key, content, out),
+            Item::Value(Value::InlineTable(inline)) => {
+                flatten_inline(inline, &full_key, content, out)
+            }
+            _ => {
+                let span = table
+                    .key(key)
+                    .and_then(|k| k.span())
+                    .or_else(|| item.span());
+                out.push((
+                    full_key,
+                    line_of(content, span.map(|s| s.start)),
+                    Leaf::Item(item),
+                ));
+            }
+        }
+    }
+}
+
+fn flatten_inline<'a>(
+    table: &'a InlineTable,
+    prefix: &str,
+    content: &str,
+    out: &mut Vec<(String, usize, Leaf<'a>)>,
+) {
+    for (key, value) in table.iter() {
+        let full_key = join_key(prefix, key);
+        match value {
+            Value::InlineTable(nested) => flatten_inline(nested, &full_key, content, out),
+            _ => {
+                let span = table
+                    .key(key)
+                    .and_then(|k| k.span())
+                    .or_else(|| value.span());
+                out.push((
+                    full_key,
+                    line_of(content, span.map(|s| s.start)),
+                    Leaf::Value(value),
+                ));
+            }
+        }
+    }
+}
+
+fn line_of(content: &str, offset: Option<usize>) -> usize {
+    let offset = offset.unwrap_or(0).min(content.len());
+    content[..offset].matches('\n').count() + 1
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashMap;
+
+    fn config_from(vars: &[(&str, &str)]) -> Result<Config, ConfigError> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        Config::from_vars(|key| vars.get(key).cloned())
+    }
 
     #[test]
     fn test_validate_mac_colon_separated() {
@@ -111,4 +1690,1050 @@ mod tests {
     fn test_validate_mac_empty() {
         assert!(validate_mac("").is_err());
     }
+
+    #[test]
+    fn test_validate_host_id() {
+        assert!(validate_host_id("nas").is_ok());
+        assert!(validate_host_id("gpu-box_2").is_ok());
+        assert!(validate_host_id("").is_err());
+        assert!(validate_host_id("NAS").is_err());
+        assert!(validate_host_id("a/b").is_err());
+    }
+
+    #[test]
+    fn test_validate_fingerprint() {
+        assert!(validate_fingerprint("SHA256:uNiVztksCsDhcc0u9e8BujQXVUpKZIDTMczCvj3tD2s").is_ok());
+        assert!(validate_fingerprint("uNiVztksCsDhcc0u9e8BujQXVUpKZIDTMczCvj3tD2s").is_err());
+        assert!(validate_fingerprint("SHA256:tooshort").is_err());
+        assert!(
+            validate_fingerprint("MD5:16:27:ac:a5:76:28:2d:36:63:1b:56:4d:eb:df:a6:48").is_err()
+        );
+    }
+
+    #[test]
+    fn test_host_key_settings() {
+        let config = config_from(&[
+            ("ARTICWAKE_HOMELAB_MAC", "aa:bb:cc:dd:ee:ff"),
+            ("ARTICWAKE_HOMELAB_IP", "192.168.1.100"),
+            ("ARTICWAKE_HOST_KEY_TOFU", "true"),
+            (
+                "ARTICWAKE_HOMELAB_HOST_KEY_FINGERPRINTS",
+                "SHA256:uNiVztksCsDhcc0u9e8BujQXVUpKZIDTMczCvj3tD2s, SHA256:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU",
+            ),
+        ])
+        .unwrap();
+
+        let host = config.default_host();
+        assert!(host.host_key_tofu);
+        assert_eq!(host.host_key_fingerprints.len(), 2);
+        assert_eq!(
+            host.known_hosts_path,
+            PathBuf::from("/var/lib/articwake/known_hosts")
+        );
+    }
+
+    #[test]
+    fn test_host_key_invalid_fingerprint() {
+        let result = config_from(&[
+            ("ARTICWAKE_HOMELAB_MAC", "aa:bb:cc:dd:ee:ff"),
+            ("ARTICWAKE_HOMELAB_IP", "192.168.1.100"),
+            ("ARTICWAKE_HOMELAB_HOST_KEY_FINGERPRINTS", "md5:abc"),
+        ]);
+        assert!(
+            matches!(result, Err(ConfigError::Invalid { key, .. }) if key == "ARTICWAKE_HOMELAB_HOST_KEY_FINGERPRINTS")
+        );
+    }
+
+    #[test]
+    fn test_legacy_single_host() {
+        let config = config_from(&[
+            ("ARTICWAKE_HOMELAB_MAC", "aa:bb:cc:dd:ee:ff"),
+            ("ARTICWAKE_HOMELAB_IP", "192.168.1.100"),
+            ("ARTICWAKE_SSH_PORT", "4748"),
+        ])
+        .unwrap();
+
+        assert_eq!(config.hosts.len(), 1);
+        let host = config.default_host();
+        assert_eq!(host.id, DEFAULT_HOST_ID);
+        assert_eq!(host.ip, "192.168.1.100");
+        assert_eq!(host.broadcast, "255.255.255.255");
+        assert_eq!(host.ssh_port, 4748);
+    }
+
+    #[test]
+    fn test_legacy_missing_mac() {
+        let result = config_from(&[("ARTICWAKE_HOMELAB_IP", "192.168.1.100")]);
+        assert!(
+            matches!(result, Err(ConfigError::MissingEnvVar(k)) if k == "ARTICWAKE_HOMELAB_MAC")
+        );
+    }
+
+    #[test]
+    fn test_multiple_hosts() {
+        let config = config_from(&[
+            ("ARTICWAKE_HOSTS", "nas, gpu-box"),
+            ("ARTICWAKE_SSH_KEY_PATH", "/etc/secrets/shared-key"),
+            ("ARTICWAKE_HOST_NAS_MAC", "aa:bb:cc:dd:ee:01"),
+            ("ARTICWAKE_HOST_NAS_IP", "10.0.0.1"),
+            ("ARTICWAKE_HOST_GPU_BOX_MAC", "aa:bb:cc:dd:ee:02"),
+            ("ARTICWAKE_HOST_GPU_BOX_IP", "10.0.0.2"),
+            ("ARTICWAKE_HOST_GPU_BOX_SSH_PORT", "22"),
+            (
+                "ARTICWAKE_HOST_GPU_BOX_SSH_KEY_PATH",
+                "/etc/secrets/gpu-key",
+            ),
+        ])
+        .unwrap();
+
+        assert_eq!(config.hosts.len(), 2);
+        let nas = config.host("nas").unwrap();
+        assert_eq!(nas.ip, "10.0.0.1");
+        assert_eq!(nas.ssh_port, 2222);
+        assert_eq!(nas.ssh_key_path, PathBuf::from("/etc/secrets/shared-key"));
+
+        let gpu = config.host("gpu-box").unwrap();
+        assert_eq!(gpu.ssh_port, 22);
+        assert_eq!(gpu.ssh_key_path, PathBuf::from("/etc/secrets/gpu-key"));
+        assert!(config.host("backup").is_none());
+    }
+
+    #[test]
+    fn test_multiple_hosts_duplicate_id() {
+        let result = config_from(&[
+            ("ARTICWAKE_HOSTS", "nas,nas"),
+            ("ARTICWAKE_HOST_NAS_MAC", "aa:bb:cc:dd:ee:01"),
+            ("ARTICWAKE_HOST_NAS_IP", "10.0.0.1"),
+        ]);
+        assert!(matches!(result, Err(ConfigError::DuplicateHost(_))));
+    }
+
+    #[test]
+    fn test_multiple_hosts_env_prefix_clash() {
+        let result = config_from(&[
+            ("ARTICWAKE_HOSTS", "gpu-box,gpu_box"),
+            ("ARTICWAKE_HOST_GPU_BOX_MAC", "aa:bb:cc:dd:ee:01"),
+            ("ARTICWAKE_HOST_GPU_BOX_IP", "10.0.0.1"),
+        ]);
+        assert!(matches!(
+            result,
+            Err(ConfigError::HostIdClash(a, b)) if a == "gpu-box" && b == "gpu_box"
+        ));
+    }
+
+    #[test]
+    fn test_multiple_hosts_invalid_port() {
+        let result = config_from(&[
+            ("ARTICWAKE_HOSTS", "nas"),
+            ("ARTICWAKE_HOST_NAS_MAC", "aa:bb:cc:dd:ee:01"),
+            ("ARTICWAKE_HOST_NAS_IP", "10.0.0.1"),
+            ("ARTICWAKE_HOST_NAS_SSH_PORT", "not-a-port"),
+        ]);
+        assert!(
+            matches!(result, Err(ConfigError::InvalidPort(k)) if k == "ARTICWAKE_HOST_NAS_SSH_PORT")
+        );
+    }
+
+    #[test]
+    fn test_empty_host_list() {
+        let result = config_from(&[("ARTICWAKE_HOSTS", " , ")]);
+        assert!(matches!(result, Err(ConfigError::MissingEnvVar(_))));
+    }
+
+    fn file_config(content: &str, env: &[(&str, &str)]) -> Result<Config, ConfigError> {
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let file = ConfigFile::parse(Path::new("config.toml"), content)?;
+        Config::from_layers(Some(&file), |key| env.get(key).cloned())
+    }
+
+    const SAMPLE_FILE: &str = r#"
+bind_host = "0.0.0.0"
+port = 8080
+ssh_key_path = "/etc/articwake/shared-key"
+
+[[hosts]]
+id = "nas"
+mac = "aa:bb:cc:dd:ee:01"
+ip = "10.0.0.1"
+
+[[hosts]]
+id = "gpu"
+mac = "aa:bb:cc:dd:ee:02"
+ip = "10.0.0.2"
+ssh_port = 4748
+"#;
+
+    #[test]
+    fn test_file_config() {
+        let config = file_config(SAMPLE_FILE, &[]).unwrap();
+
+        assert_eq!(config.bind_host, "0.0.0.0");
+        assert_eq!(config.port, 8080);
+        assert_eq!(
+            config.pin_hash_path,
+            PathBuf::from("/var/lib/articwake/pin.hash")
+        );
+        assert_eq!(config.hosts.len(), 2);
+
+        let nas = config.host("nas").unwrap();
+        assert_eq!(nas.ssh_port, 2222);
+        assert_eq!(nas.ssh_key_path, PathBuf::from("/etc/articwake/shared-key"));
+        assert_eq!(config.host("gpu").unwrap().ssh_port, 4748);
+    }
+
+    #[test]
+    fn test_file_env_overrides() {
+        let config = file_config(
+            SAMPLE_FILE,
+            &[
+                ("ARTICWAKE_PORT", "9090"),
+                ("ARTICWAKE_HOST_GPU_IP", "10.0.0.22"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(config.port, 9090);
+        assert_eq!(config.host("gpu").unwrap().ip, "10.0.0.22");
+        assert_eq!(config.host("nas").unwrap().ip, "10.0.0.1");
+    }
+
+    #[test]
+    fn test_file_env_host_list_override() {
+        let config = file_config(SAMPLE_FILE, &[("ARTICWAKE_HOSTS", "gpu")]).unwrap();
+        assert_eq!(config.hosts.len(), 1);
+        assert_eq!(config.default_host().id, "gpu");
+    }
+
+    #[test]
+    fn test_file_without_hosts_uses_legacy_env() {
+        let config = file_config(
+            "port = 8080\n",
+            &[
+                ("ARTICWAKE_HOMELAB_MAC", "aa:bb:cc:dd:ee:ff"),
+                ("ARTICWAKE_HOMELAB_IP", "192.168.1.100"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.default_host().id, DEFAULT_HOST_ID);
+        assert_eq!(config.port, 8080);
+    }
+
+    #[test]
+    fn test_file_syntax_error_line() {
+        let result = file_config("port = 8080\nbind_host = \n", &[]);
+        assert!(matches!(result, Err(ConfigError::Parse { line: 2, .. })));
+    }
+
+    #[test]
+    fn test_file_invalid_mac_reports_key_and_line() {
+        let content = SAMPLE_FILE.replace("aa:bb:cc:dd:ee:02", "not-a-mac");
+        match file_config(&content, &[]) {
+            Err(ConfigError::File { line, key, .. }) => {
+                assert_eq!(key, "hosts[1].mac");
+                assert_eq!(line, 13);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_file_invalid_port_reports_key_and_line() {
+        match file_config(&SAMPLE_FILE.replace("8080", "99999"), &[]) {
+            Err(ConfigError::File {
+                line, key, message, ..
+            }) => {
+                assert_eq!(key, "port");
+                assert_eq!(line, 3);
+                assert_eq!(message, "invalid port number");
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_file_env_error_not_attributed_to_file() {
+        let result = file_config(SAMPLE_FILE, &[("ARTICWAKE_PORT", "abc")]);
+        assert!(matches!(result, Err(ConfigError::InvalidPort(k)) if k == "ARTICWAKE_PORT"));
+    }
+
+    #[test]
+    fn test_file_missing_host_key() {
+        let content = "[[hosts]]\nid = \"nas\"\nmac = \"aa:bb:cc:dd:ee:01\"\n";
+        match file_config(content, &[]) {
+            Err(ConfigError::File { line, key, .. }) => {
+                assert_eq!(key, "hosts[0].ip");
+                assert_eq!(line, 1);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_file_host_key_fingerprints_array() {
+        let content = SAMPLE_FILE.replace(
+            "ssh_port = 4748",
+            "ssh_port = 4748\nhost_key_fingerprints = [\"SHA256:uNiVztksCsDhcc0u9e8BujQXVUpKZIDTMczCvj3tD2s\"]",
+        );
+        let config = file_config(&content, &[]).unwrap();
+        assert_eq!(config.host("gpu").unwrap().host_key_fingerprints.len(), 1);
+        assert!(config.host("nas").unwrap().host_key_fingerprints.is_empty());
+    }
+
+    #[test]
+    fn test_file_unlock_backends() {
+        let content = format!(
+            "{}\n[hosts.unlock]\nbackend = \"zfs\"\ndataset = \"rpool\"\n",
+            SAMPLE_FILE.replace(
+                "ip = \"10.0.0.1\"",
+                "ip = \"10.0.0.1\"\nunlock = { backend = \"command\" }"
+            )
+        );
+        let config = file_config(&content, &[]).unwrap();
+
+        assert_eq!(
+            config.host("nas").unwrap().unlock,
+            UnlockMethod::Command("cryptroot-unlock".to_string())
+        );
+        assert_eq!(
+            config.host("gpu").unwrap().unlock,
+            UnlockMethod::Zfs {
+                dataset: "rpool".to_string(),
+                continue_command: "killall zfs".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_file_unlock_backend_errors() {
+        let content = format!("{}\n[hosts.unlock]\nbackend = \"zfs\"\n", SAMPLE_FILE);
+        match file_config(&content, &[]) {
+            Err(ConfigError::File { key, line, .. }) => {
+                assert_eq!(key, "hosts[1].unlock.dataset");
+                assert_eq!(line, 11);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let content = format!("{}\n[hosts.unlock]\nbackend = \"tpm\"\n", SAMPLE_FILE);
+        match file_config(&content, &[]) {
+            Err(ConfigError::File { key, line, .. }) => {
+                assert_eq!(key, "hosts[1].unlock.backend");
+                assert_eq!(line, 18);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_unlock_backend_env() {
+        let config = config_from(&[
+            ("ARTICWAKE_HOMELAB_MAC", "aa:bb:cc:dd:ee:ff"),
+            ("ARTICWAKE_HOMELAB_IP", "192.168.1.100"),
+            ("ARTICWAKE_UNLOCK_BACKEND", "systemd"),
+        ])
+        .unwrap();
+        assert_eq!(config.default_host().unlock, UnlockMethod::Systemd);
+
+        let config = config_from(&[
+            ("ARTICWAKE_HOMELAB_MAC", "aa:bb:cc:dd:ee:ff"),
+            ("ARTICWAKE_HOMELAB_IP", "192.168.1.100"),
+            ("ARTICWAKE_HOMELAB_UNLOCK_BACKEND", "command"),
+            ("ARTICWAKE_HOMELAB_UNLOCK_COMMAND", "/usr/bin/unlock-root"),
+        ])
+        .unwrap();
+        assert_eq!(
+            config.default_host().unlock,
+            UnlockMethod::Command("/usr/bin/unlock-root".to_string())
+        );
+    }
+
+    #[test]
+    fn test_default_probes() {
+        let host = HostConfig {
+            ssh_port: 4748,
+            ..Default::default()
+        };
+        let probes = host.probes();
+        let names: Vec<_> = probes.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["ping", "initrd_ssh", "system_ssh"]);
+        assert!(matches!(probes[1].kind, ProbeKind::Tcp { port: 4748 }));
+        assert_eq!(probes[2].phase, Some(HostPhase::Booted));
+    }
+
+    #[test]
+    fn test_file_probes() {
+        let content = format!(
+            "{}{}",
+            SAMPLE_FILE,
+            r#"
+[[hosts.probes]]
+name = "dropbear"
+kind = "ssh"
+port = 4748
+banner = "dropbear"
+phase = "initrd"
+
+[[hosts.probes]]
+name = "health"
+kind = "http"
+url = "/healthz"
+port = 8123
+expect_status = 204
+phase = "booted"
+"#
+        );
+        let config = file_config(&content, &[]).unwrap();
+
+        assert!(config.host("nas").unwrap().probes.is_empty());
+        let probes = &config.host("gpu").unwrap().probes;
+        assert_eq!(probes.len(), 2);
+        match &probes[0].kind {
+            ProbeKind::Ssh { port, banner } => {
+                assert_eq!(*port, 4748);
+                assert!(
+                    banner
+                        .as_ref()
+                        .unwrap()
+                        .is_match("SSH-2.0-dropbear_2022.83")
+                );
+            }
+            other => panic!("unexpected probe: {:?}", other),
+        }
+        assert_eq!(probes[0].phase, Some(HostPhase::Initrd));
+        match &probes[1].kind {
+            ProbeKind::Http {
+                host,
+                port,
+                path,
+                expect_status,
+            } => {
+                assert_eq!(host, "10.0.0.2");
+                assert_eq!(*port, 8123);
+                assert_eq!(path, "/healthz");
+                assert_eq!(*expect_status, 204);
+            }
+            other => panic!("unexpected probe: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_file_probe_errors() {
+        let content = format!(
+            "{}\n[[hosts.probes]]\nname = \"ssh\"\nkind = \"tcp\"\n",
+            SAMPLE_FILE
+        );
+        match file_config(&content, &[]) {
+            Err(ConfigError::File {
+                key, line, message, ..
+            }) => {
+                assert_eq!(key, "hosts[1].probes");
+                assert_eq!(line, 17);
+                assert!(message.contains("tcp probes need a port"), "{}", message);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_probes_env() {
+        let config = config_from(&[
+            ("ARTICWAKE_HOMELAB_MAC", "aa:bb:cc:dd:ee:ff"),
+            ("ARTICWAKE_HOMELAB_IP", "192.168.1.100"),
+            (
+                "ARTICWAKE_HOMELAB_PROBES",
+                r#"[{"name": "web", "kind": "http", "url": "http://nas.lan:8080/status", "phase": "booted"}, {"name": "ping", "kind": "icmp"}]"#,
+            ),
+        ])
+        .unwrap();
+
+        let probes = &config.default_host().probes;
+        assert_eq!(probes.len(), 2);
+        match &probes[0].kind {
+            ProbeKind::Http {
+                host,
+                port,
+                path,
+                expect_status,
+            } => {
+                assert_eq!(host, "nas.lan");
+                assert_eq!(*port, 8080);
+                assert_eq!(path, "/status");
+                assert_eq!(*expect_status, 200);
+            }
+            other => panic!("unexpected probe: {:?}", other),
+        }
+        assert!(matches!(probes[1].kind, ProbeKind::Icmp));
+        assert_eq!(probes[1].phase, None);
+    }
+
+    #[test]
+    fn test_invalid_probes() {
+        let cases = [
+            ("not json", "invalid probe list"),
+            (
+                r#"[{"name": "a", "kind": "udp"}]"#,
+                "unknown probe kind udp",
+            ),
+            (
+                r#"[{"name": "a", "kind": "ssh"}]"#,
+                "ssh probes need a port",
+            ),
+            (
+                r#"[{"name": "a", "kind": "http"}]"#,
+                "http probes need a url",
+            ),
+            (
+                r#"[{"name": "a", "kind": "http", "url": "https://nas/"}]"#,
+                "https URLs are not supported",
+            ),
+            (
+                r#"[{"name": "a", "kind": "ssh", "port": 22, "banner": "("}]"#,
+                "invalid banner pattern",
+            ),
+            (
+                r#"[{"name": "a", "kind": "icmp", "phase": "off"}]"#,
+                "unknown phase off",
+            ),
+            (
+                r#"[{"name": "a", "kind": "icmp"}, {"name": "a", "kind": "icmp"}]"#,
+                "probes[1]: duplicate probe name: a",
+            ),
+            (
+                r#"[{"name": "a", "kind": "icmp", "timeout": 1}]"#,
+                "unknown field",
+            ),
+        ];
+        for (probes, expected) in cases {
+            let result = config_from(&[
+                ("ARTICWAKE_HOMELAB_MAC", "aa:bb:cc:dd:ee:ff"),
+                ("ARTICWAKE_HOMELAB_IP", "192.168.1.100"),
+                ("ARTICWAKE_HOMELAB_PROBES", probes),
+            ]);
+            match result {
+                Err(ConfigError::Invalid { key, reason }) => {
+                    assert_eq!(key, "ARTICWAKE_HOMELAB_PROBES");
+                    assert!(reason.contains(expected), "{}: {}", probes, reason);
+                }
+                other => panic!("{}: unexpected result: {:?}", probes, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_http_url() {
+        let parse = |url| parse_http_url(url, "10.0.0.1");
+        assert_eq!(
+            parse("/health").unwrap(),
+            ("10.0.0.1".to_string(), None, "/health".to_string())
+        );
+        assert_eq!(
+            parse("http://nas.lan").unwrap(),
+            ("nas.lan".to_string(), None, "/".to_string())
+        );
+        assert_eq!(
+            parse("http://[fd00::1]:8080/ready").unwrap(),
+            ("fd00::1".to_string(), Some(8080), "/ready".to_string())
+        );
+        assert!(parse("http://nas:http/").is_err());
+        assert!(parse("ftp://nas/").is_err());
+    }
+
+    #[test]
+    fn test_audit_log_path() {
+        let base = [
+            ("ARTICWAKE_HOMELAB_MAC", "aa:bb:cc:dd:ee:ff"),
+            ("ARTICWAKE_HOMELAB_IP", "192.168.1.100"),
+        ];
+        let config = config_from(&base).unwrap();
+        assert_eq!(
+            config.audit_log_path,
+            Some(PathBuf::from("/var/lib/articwake/audit.log"))
+        );
+
+        let config =
+            config_from(&[base[0], base[1], ("ARTICWAKE_AUDIT_LOG_PATH", "/tmp/a.log")]).unwrap();
+        assert_eq!(config.audit_log_path, Some(PathBuf::from("/tmp/a.log")));
+
+        let config = config_from(&[base[0], base[1], ("ARTICWAKE_AUDIT_LOG_PATH", "")]).unwrap();
+        assert_eq!(config.audit_log_path, None);
+    }
+
+    #[test]
+    fn test_login_lockout() {
+        let base = [
+            ("ARTICWAKE_HOMELAB_MAC", "aa:bb:cc:dd:ee:ff"),
+            ("ARTICWAKE_HOMELAB_IP", "192.168.1.100"),
+        ];
+        assert_eq!(config_from(&base).unwrap().login, LoginConfig::default());
+
+        let config = config_from(&[
+            base[0],
+            base[1],
+            ("ARTICWAKE_LOGIN_LOCKOUT_AFTER", "20"),
+            ("ARTICWAKE_LOGIN_LOCKOUT_PATH", "/tmp/lockout"),
+            ("ARTICWAKE_LOGIN_MAX_CONCURRENT_HASHES", "4"),
+        ])
+        .unwrap();
+        assert_eq!(config.login.lockout_after, Some(20));
+        assert_eq!(config.login.lockout_path, PathBuf::from("/tmp/lockout"));
+        assert_eq!(config.login.max_concurrent_hashes, 4);
+
+        assert!(config_from(&[base[0], base[1], ("ARTICWAKE_LOGIN_LOCKOUT_AFTER", "0")]).is_err());
+        assert!(
+            config_from(&[
+                base[0],
+                base[1],
+                ("ARTICWAKE_LOGIN_MAX_CONCURRENT_HASHES", "0")
+            ])
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_pin_policy() {
+        let base = [
+            ("ARTICWAKE_HOMELAB_MAC", "aa:bb:cc:dd:ee:ff"),
+            ("ARTICWAKE_HOMELAB_IP", "192.168.1.100"),
+        ];
+        assert_eq!(config_from(&base).unwrap().pin, PinConfig::default());
+
+        let config = config_from(&[
+            base[0],
+            base[1],
+            ("ARTICWAKE_PIN_MIN_LENGTH", "4"),
+            ("ARTICWAKE_PIN_ALLOW_SIMPLE", "true"),
+        ])
+        .unwrap();
+        assert_eq!(config.pin.min_length, 4);
+        assert!(config.pin.allow_simple);
+
+        assert!(config_from(&[base[0], base[1], ("ARTICWAKE_PIN_MIN_LENGTH", "0")]).is_err());
+    }
+
+    #[test]
+    fn test_file_pin_table() {
+        let content = format!("{}\n[pin]\nmin_length = 8\n", SAMPLE_FILE);
+        let config = file_config(&content, &[]).unwrap();
+        assert_eq!(config.pin.min_length, 8);
+        assert!(!config.pin.allow_simple);
+
+        // Needs nothing but the [pin] table
+        let file = ConfigFile::parse(Path::new("config.toml"), "[pin]\nmin_length = 0\n").unwrap();
+        let err = layered(Some(&file), |_| None, |var| PinConfig::from_vars(var)).unwrap_err();
+        assert!(err.to_string().contains("config.toml"));
+        let file = ConfigFile::parse(Path::new("config.toml"), "[pin]\nmin_length = 8\n").unwrap();
+        let pin = layered(Some(&file), |_| None, |var| PinConfig::from_vars(var)).unwrap();
+        assert_eq!(pin.min_length, 8);
+    }
+
+    #[test]
+    fn test_argon2_settings() {
+        let base = [
+            ("ARTICWAKE_HOMELAB_MAC", "aa:bb:cc:dd:ee:ff"),
+            ("ARTICWAKE_HOMELAB_IP", "192.168.1.100"),
+        ];
+        let config = config_from(&[
+            base[0],
+            base[1],
+            ("ARTICWAKE_PIN_ARGON2_VARIANT", "argon2i"),
+            ("ARTICWAKE_PIN_ARGON2_M_COST", "65536"),
+            ("ARTICWAKE_PIN_ARGON2_T_COST", "3"),
+            ("ARTICWAKE_PIN_ARGON2_PARALLELISM", "4"),
+        ])
+        .unwrap();
+        assert_eq!(
+            config.pin.argon2,
+            Argon2Config {
+                variant: argon2::Algorithm::Argon2i,
+                m_cost: 65536,
+                t_cost: 3,
+                parallelism: 4,
+            }
+        );
+
+        for (key, value) in [
+            ("ARTICWAKE_PIN_ARGON2_VARIANT", "bcrypt"),
+            ("ARTICWAKE_PIN_ARGON2_T_COST", "0"),
+            ("ARTICWAKE_PIN_ARGON2_M_COST", "16"),
+        ] {
+            let err = config_from(&[
+                base[0],
+                base[1],
+                (key, value),
+                ("ARTICWAKE_PIN_ARGON2_PARALLELISM", "4"),
+            ])
+            .unwrap_err();
+            assert!(err.to_string().starts_with(key), "{}", err);
+        }
+
+        let content = format!(
+            "{}\n[pin.argon2]\nm_cost = 4\nparallelism = 1\n",
+            SAMPLE_FILE
+        );
+        let err = file_config(&content, &[]).unwrap_err();
+        assert!(err.to_string().contains("pin.argon2.m_cost"), "{}", err);
+    }
+
+    #[test]
+    fn test_state_path() {
+        let base = [
+            ("ARTICWAKE_HOMELAB_MAC", "aa:bb:cc:dd:ee:ff"),
+            ("ARTICWAKE_HOMELAB_IP", "192.168.1.100"),
+        ];
+        assert_eq!(config_from(&base).unwrap().state_path, None);
+
+        let config = config_from(&[
+            base[0],
+            base[1],
+            ("ARTICWAKE_STATE_PATH", "/var/lib/articwake/state.json"),
+        ])
+        .unwrap();
+        assert_eq!(
+            config.state_path,
+            Some(PathBuf::from("/var/lib/articwake/state.json"))
+        );
+
+        let config = config_from(&[base[0], base[1], ("ARTICWAKE_STATE_PATH", "")]).unwrap();
+        assert_eq!(config.state_path, None);
+    }
+
+    #[test]
+    fn test_trusted_proxies() {
+        let base = [
+            ("ARTICWAKE_HOMELAB_MAC", "aa:bb:cc:dd:ee:ff"),
+            ("ARTICWAKE_HOMELAB_IP", "192.168.1.100"),
+        ];
+        assert!(config_from(&base).unwrap().trusted_proxies.is_empty());
+
+        let config = config_from(&[
+            base[0],
+            base[1],
+            ("ARTICWAKE_TRUSTED_PROXIES", "127.0.0.1, 10.0.0.0/8,::1"),
+        ])
+        .unwrap();
+        let expected: Vec<IpNet> = ["127.0.0.1/32", "10.0.0.0/8", "::1/128"]
+            .iter()
+            .map(|net| net.parse().unwrap())
+            .collect();
+        assert_eq!(config.trusted_proxies, expected);
+
+        assert!(
+            config_from(&[
+                base[0],
+                base[1],
+                ("ARTICWAKE_TRUSTED_PROXIES", "10.0.0.0/33")
+            ])
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_tls() {
+        let base = [
+            ("ARTICWAKE_HOMELAB_MAC", "aa:bb:cc:dd:ee:ff"),
+            ("ARTICWAKE_HOMELAB_IP", "192.168.1.100"),
+        ];
+        let config = config_from(&base).unwrap();
+        assert_eq!(config.tls, TlsConfig::default());
+        assert_eq!(config.port, 80);
+
+        let config = config_from(&[
+            base[0],
+            base[1],
+            ("ARTICWAKE_TLS_ENABLED", "true"),
+            ("ARTICWAKE_TLS_CERT_PATH", "/etc/ssl/pi.crt"),
+            ("ARTICWAKE_TLS_KEY_PATH", "/etc/ssl/pi.key"),
+            ("ARTICWAKE_TLS_REDIRECT_PORT", "80"),
+        ])
+        .unwrap();
+        assert!(config.tls.enabled);
+        assert_eq!(config.tls.cert_path, PathBuf::from("/etc/ssl/pi.crt"));
+        assert_eq!(config.tls.key_path, PathBuf::from("/etc/ssl/pi.key"));
+        assert_eq!(config.tls.redirect_port, Some(80));
+        assert_eq!(config.port, 443);
+
+        let config = config_from(&[
+            base[0],
+            base[1],
+            ("ARTICWAKE_TLS_ENABLED", "true"),
+            ("ARTICWAKE_PORT", "8443"),
+        ])
+        .unwrap();
+        assert_eq!(config.port, 8443);
+
+        assert!(config_from(&[base[0], base[1], ("ARTICWAKE_TLS_REDIRECT_PORT", "http")]).is_err());
+    }
+
+    #[test]
+    fn test_client_cert_config() {
+        let base = [
+            ("ARTICWAKE_HOMELAB_MAC", "aa:bb:cc:dd:ee:ff"),
+            ("ARTICWAKE_HOMELAB_IP", "192.168.1.100"),
+            ("ARTICWAKE_TLS_ENABLED", "true"),
+        ];
+        let fingerprint = "AB:".repeat(31) + "AB";
+        let config = config_from(&[
+            base[0],
+            base[1],
+            base[2],
+            ("ARTICWAKE_TLS_CLIENT_AUTH", "optional"),
+            (
+                "ARTICWAKE_TLS_CLIENT_CERT_ROUTES",
+                "/api=factor, /api/status=bypass,/api/auth=bypass",
+            ),
+            ("ARTICWAKE_TLS_REVOKED_CLIENT_CERTS", &fingerprint),
+        ])
+        .unwrap();
+        let tls = &config.tls;
+        assert_eq!(tls.client_auth, ClientAuth::Optional);
+        assert_eq!(tls.client_cert_routes.len(), 3);
+        assert_eq!(tls.revoked_client_certs, vec!["ab".repeat(32)]);
+
+        assert_eq!(tls.cert_policy("/api/status"), Some(CertPolicy::Bypass));
+        assert_eq!(tls.cert_policy("/api/auth"), Some(CertPolicy::Bypass));
+        assert_eq!(
+            tls.cert_policy("/api/auth/passkeys"),
+            Some(CertPolicy::Bypass)
+        );
+        assert_eq!(tls.cert_policy("/api/wol"), Some(CertPolicy::Factor));
+        // Prefixes end at a path segment
+        assert_eq!(tls.cert_policy("/api/statusx"), Some(CertPolicy::Factor));
+        assert_eq!(tls.cert_policy("/index.html"), None);
+
+        for (key, value) in [
+            ("ARTICWAKE_TLS_CLIENT_AUTH", "sometimes"),
+            ("ARTICWAKE_TLS_CLIENT_CERT_ROUTES", "/api=always"),
+            ("ARTICWAKE_TLS_CLIENT_CERT_ROUTES", "api=bypass"),
+            ("ARTICWAKE_TLS_REVOKED_CLIENT_CERTS", "abcd"),
+        ] {
+            assert!(config_from(&[base[0], base[1], base[2], (key, value)]).is_err());
+        }
+        // Client certificates only exist with TLS
+        assert!(
+            config_from(&[base[0], base[1], ("ARTICWAKE_TLS_CLIENT_AUTH", "required")]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_file_tls_table() {
+        let content = format!(
+            "{}\n[tls]\nenabled = true\nredirect_port = 80\n",
+            SAMPLE_FILE
+        );
+        let config = file_config(&content, &[]).unwrap();
+        assert!(config.tls.enabled);
+        assert_eq!(config.tls.redirect_port, Some(80));
+        // The file sets port = 8080
+        assert_eq!(config.port, 8080);
+    }
+
+    #[test]
+    fn test_access_rules() {
+        let base = [
+            ("ARTICWAKE_HOMELAB_MAC", "aa:bb:cc:dd:ee:ff"),
+            ("ARTICWAKE_HOMELAB_IP", "192.168.1.100"),
+        ];
+        assert_eq!(config_from(&base).unwrap().access, AccessConfig::default());
+
+        let config = config_from(&[
+            base[0],
+            base[1],
+            ("ARTICWAKE_ACCESS_ALLOW", "100.64.0.0/10, 192.168.1.0/24"),
+            ("ARTICWAKE_ACCESS_DENY", "192.168.1.13"),
+            ("ARTICWAKE_ACCESS_API_ALLOW", "100.64.0.0/10"),
+        ])
+        .unwrap();
+        let ip = |s: &str| s.parse::<std::net::IpAddr>().unwrap();
+        let ui = &config.access.ui;
+        assert!(ui.permits(ip("100.100.1.2")));
+        assert!(ui.permits(ip("192.168.1.20")));
+        assert!(ui.permits(ip("::ffff:192.168.1.20")));
+        assert!(!ui.permits(ip("192.168.1.13")));
+        assert!(!ui.permits(ip("8.8.8.8")));
+        // The API's own allow list replaces the shared one; the deny list is shared
+        let api = &config.access.api;
+        assert!(api.permits(ip("100.100.1.2")));
+        assert!(!api.permits(ip("192.168.1.20")));
+        assert_eq!(api.deny, ui.deny);
+
+        // No allow list lets everyone in but the denied
+        let rules = AccessRules {
+            allow: Vec::new(),
+            deny: vec!["10.0.0.0/8".parse().unwrap()],
+        };
+        assert!(rules.permits(ip("192.0.2.1")));
+        assert!(!rules.permits(ip("10.1.2.3")));
+
+        assert!(config_from(&[base[0], base[1], ("ARTICWAKE_ACCESS_UI_DENY", "nope")]).is_err());
+    }
+
+    #[test]
+    fn test_file_access_tables() {
+        let content = format!(
+            "{}\n[access]\nallow = [\"192.168.1.0/24\"]\n\n[access.api]\ndeny = [\"192.168.1.13\"]\n",
+            SAMPLE_FILE
+        );
+        let config = file_config(&content, &[]).unwrap();
+        let net: IpNet = "192.168.1.0/24".parse().unwrap();
+        assert_eq!(config.access.ui.allow, vec![net]);
+        assert_eq!(config.access.api.allow, vec![net]);
+        assert!(config.access.ui.deny.is_empty());
+        assert_eq!(config.access.api.deny.len(), 1);
+    }
+
+    #[test]
+    fn test_file_trusted_proxies_array() {
+        let content = format!(
+            "trusted_proxies = [\"127.0.0.1\", \"100.64.0.0/10\"]\n{}",
+            SAMPLE_FILE
+        );
+        let config = file_config(&content, &[]).unwrap();
+        assert_eq!(config.trusted_proxies.len(), 2);
+    }
+
+    #[test]
+    fn test_webauthn_origin() {
+        let base = [
+            ("ARTICWAKE_HOMELAB_MAC", "aa:bb:cc:dd:ee:ff"),
+            ("ARTICWAKE_HOMELAB_IP", "192.168.1.100"),
+        ];
+        let config = config_from(&base).unwrap();
+        assert_eq!(config.webauthn.origin, None);
+        assert_eq!(config.webauthn.rp_id(), None);
+
+        let config = config_from(&[
+            base[0],
+            base[1],
+            (
+                "ARTICWAKE_WEBAUTHN_ORIGIN",
+                "https://Pi.Tailnet.ts.net:8443/",
+            ),
+        ])
+        .unwrap();
+        assert_eq!(
+            config.webauthn.origin.as_deref(),
+            Some("https://pi.tailnet.ts.net:8443")
+        );
+        assert_eq!(config.webauthn.rp_id(), Some("pi.tailnet.ts.net"));
+
+        let config = config_from(&[
+            base[0],
+            base[1],
+            ("ARTICWAKE_WEBAUTHN_ORIGIN", "http://localhost:8080"),
+        ])
+        .unwrap();
+        assert_eq!(config.webauthn.rp_id(), Some("localhost"));
+
+        for origin in [
+            "http://pi.lan",
+            "https://192.168.1.2",
+            "https://[fd00::1]",
+            "https://pi.lan/app",
+            "pi.lan",
+            "https://",
+        ] {
+            let result = config_from(&[base[0], base[1], ("ARTICWAKE_WEBAUTHN_ORIGIN", origin)]);
+            assert!(
+                matches!(result, Err(ConfigError::Invalid { ref key, .. }) if key == "ARTICWAKE_WEBAUTHN_ORIGIN"),
+                "{} accepted",
+                origin
+            );
+        }
+    }
+
+    #[test]
+    fn test_file_webauthn_section() {
+        let content = format!(
+            "{}\n[webauthn]\norigin = \"https://pi.lan\"\ncredentials_path = \"/tmp/passkeys.json\"\n",
+            SAMPLE_FILE
+        );
+        let config = file_config(&content, &[]).unwrap();
+        assert_eq!(config.webauthn.origin.as_deref(), Some("https://pi.lan"));
+        assert_eq!(
+            config.webauthn.credentials_path,
+            PathBuf::from("/tmp/passkeys.json")
+        );
+
+        let content = format!("{}\n[webauthn]\norigin = \"ftp://pi.lan\"\n", SAMPLE_FILE);
+        let result = file_config(&content, &[]);
+        assert!(matches!(result, Err(ConfigError::File { key, .. }) if key == "webauthn.origin"));
+    }
+
+    #[test]
+    fn test_boot_defaults() {
+        let config = config_from(&[
+            ("ARTICWAKE_HOMELAB_MAC", "aa:bb:cc:dd:ee:ff"),
+            ("ARTICWAKE_HOMELAB_IP", "192.168.1.100"),
+        ])
+        .unwrap();
+        assert_eq!(config.boot, BootConfig::default());
+        assert_eq!(config.status_interval, Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_file_boot_section() {
+        let content = format!(
+            "{}\n[boot]\nwake_timeout = 30\nwake_attempts = 5\n",
+            SAMPLE_FILE
+        );
+        let config = file_config(&content, &[("ARTICWAKE_BOOT_UNLOCK_ATTEMPTS", "1")]).unwrap();
+
+        assert_eq!(config.boot.wake_timeout, Duration::from_secs(30));
+        assert_eq!(config.boot.wake_attempts, 5);
+        assert_eq!(config.boot.unlock_attempts, 1);
+        assert_eq!(config.boot.boot_timeout, Duration::from_secs(180));
+    }
+
+    #[test]
+    fn test_invalid_boot_attempts() {
+        let result = config_from(&[
+            ("ARTICWAKE_HOMELAB_MAC", "aa:bb:cc:dd:ee:ff"),
+            ("ARTICWAKE_HOMELAB_IP", "192.168.1.100"),
+            ("ARTICWAKE_BOOT_WAKE_ATTEMPTS", "0"),
+        ]);
+        match result {
+            Err(ConfigError::Invalid { key, .. }) => {
+                assert_eq!(key, "ARTICWAKE_BOOT_WAKE_ATTEMPTS")
+            }
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_file_unknown_key() {
+        let result = file_config("prot = 8080\n", &[]);
+        assert!(matches!(result, Err(ConfigError::File { key, .. }) if key == "prot"));
+    }
+
+    #[test]
+    fn test_file_duplicate_host() {
+        let content = SAMPLE_FILE.replace("id = \"gpu\"", "id = \"nas\"");
+        match file_config(&content, &[]) {
+            Err(ConfigError::File { line, key, .. }) => {
+                assert_eq!(key, "hosts[1].id");
+                assert_eq!(line, 12);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_file_host_id_clash() {
+        let content = SAMPLE_FILE
+            .replace("id = \"nas\"", "id = \"gpu-box\"")
+            .replace("id = \"gpu\"", "id = \"gpu_box\"");
+        match file_config(&content, &[]) {
+            Err(ConfigError::File {
+                line, key, message, ..
+            }) => {
+                assert_eq!(key, "hosts[1].id");
+                assert_eq!(line, 12);
+                assert!(message.contains("gpu-box"), "{}", message);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
 }
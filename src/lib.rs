@@ -1,4 +1,15 @@
 pub mod api;
+pub mod apikeys;
+pub mod audit;
 pub mod auth;
 pub mod config;
+pub mod lockout;
+pub mod pin;
+pub mod proxy;
 pub mod services;
+pub mod store;
+pub mod tls;
+pub mod totp;
+pub mod users;
+pub mod util;
+pub mod webauthn;
@@ -0,0 +1,68 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Whether PIN login is locked. A file that cannot be checked counts as a
+/// lock, so a permissions problem never turns the lockout off.
+pub fn is_locked(path: &Path) -> bool {
+    match path.try_exists() {
+        Ok(exists) => exists,
+        Err(e) => {
+            tracing::error!("Failed to check lockout file {}: {}", path.display(), e);
+            true
+        }
+    }
+}
+
+/// Creates the lockout file, noting why and when. Keeps an existing one.
+pub fn lock(path: &Path, reason: &str) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    match options.open(path) {
+        Ok(mut file) => writeln!(file, "{}", reason),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Removes the lockout file. Returns `false` if there was none.
+pub fn clear(path: &Path) -> io::Result<bool> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock_and_clear() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("lockout");
+        assert!(!is_locked(&path));
+        assert!(!clear(&path).unwrap());
+
+        lock(&path, "first").unwrap();
+        assert!(is_locked(&path));
+        // The first reason is kept
+        lock(&path, "second").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "first\n");
+
+        assert!(clear(&path).unwrap());
+        assert!(!is_locked(&path));
+    }
+
+    #[test]
+    fn test_lock_in_missing_directory_fails() {
+        let dir = tempfile::TempDir::new().unwrap();
+        assert!(lock(&dir.path().join("missing/lockout"), "reason").is_err());
+    }
+}
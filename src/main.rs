@@ -1,6 +1,9 @@
-use actix_web::{App, HttpRequest, HttpResponse, HttpServer, web};
-use articwake::{api, auth, config};
+use actix_web::{App, HttpRequest, HttpResponse, HttpServer, http::header, middleware, web};
+use articwake::{api, apikeys, auth, config, lockout, pin, tls, totp, users};
 use rust_embed::Embed;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tracing_actix_web::TracingLogger;
 
 #[derive(Embed)]
@@ -22,11 +25,64 @@ async fn serve_static(req: HttpRequest) -> HttpResponse {
     }
 }
 
-fn hash_pin() -> anyhow::Result<()> {
-    use argon2::Argon2;
-    use argon2::password_hash::{PasswordHasher, SaltString, rand_core::OsRng};
+/// Reads a PIN from stdin and prints its hash, refusing PINs outside the
+/// `[pin]` policy. With `--benchmark`, prints `[pin.argon2]` settings that
+/// take about `--target-ms` to check on this machine instead.
+fn hash_pin(args: Vec<String>, config_path: Option<&Path>) -> anyhow::Result<()> {
     use std::io::{self, BufRead};
 
+    let mut benchmark = false;
+    let mut target_ms = 500;
+    let mut max_memory_mib = 64;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--benchmark" => benchmark = true,
+            "--target-ms" => {
+                target_ms = args
+                    .next()
+                    .and_then(|ms| ms.parse().ok())
+                    .filter(|&ms| ms > 0)
+                    .ok_or_else(|| anyhow::anyhow!("--target-ms requires a positive number"))?;
+            }
+            "--max-memory" => {
+                max_memory_mib = args
+                    .next()
+                    .and_then(|mib| mib.parse().ok())
+                    .filter(|&mib: &u32| (1..4 * 1024 * 1024).contains(&mib))
+                    .ok_or_else(|| anyhow::anyhow!("--max-memory requires a size in MiB"))?;
+            }
+            _ => anyhow::bail!(
+                "Usage: articwake hash-pin [--benchmark [--target-ms N] [--max-memory MIB]]"
+            ),
+        }
+    }
+    let pin_config = config::Config::load_pin(config_path)?;
+
+    if benchmark {
+        let target = std::time::Duration::from_millis(target_ms);
+        eprintln!(
+            "Looking for settings that take {} ms, using up to {} MiB...",
+            target_ms, max_memory_mib
+        );
+        let result = pin::benchmark(&pin_config.argon2, target, max_memory_mib * 1024)?;
+        if result.took > target {
+            eprintln!(
+                "Even the cheapest settings take {} ms on this machine",
+                result.took.as_millis()
+            );
+        } else {
+            eprintln!("Checking a PIN takes {} ms", result.took.as_millis());
+        }
+        let argon2 = result.config;
+        println!("[pin.argon2]");
+        println!("variant = \"{}\"", argon2.variant);
+        println!("m_cost = {}", argon2.m_cost);
+        println!("t_cost = {}", argon2.t_cost);
+        println!("parallelism = {}", argon2.parallelism);
+        return Ok(());
+    }
+
     let stdin = io::stdin();
     let pin = stdin
         .lock()
@@ -38,21 +94,461 @@ fn hash_pin() -> anyhow::Result<()> {
     if pin.is_empty() {
         anyhow::bail!("PIN cannot be empty");
     }
+    pin::check_policy(pin, &pin_config)?;
+
+    println!("{}", pin::hash(pin, &pin_config.argon2)?);
+    Ok(())
+}
+
+/// Generates a TOTP secret and prints the URI to enroll it in an
+/// authenticator app. Refuses to replace an existing secret without `--force`.
+fn enroll_totp(args: Vec<String>, config_path: Option<&Path>) -> anyhow::Result<()> {
+    let mut force = false;
+    let mut path = None;
+    for arg in args {
+        match arg.as_str() {
+            "--force" => force = true,
+            _ if arg.starts_with('-') => anyhow::bail!("Unknown option: {}", arg),
+            _ => path = Some(PathBuf::from(arg)),
+        }
+    }
+    let path = match path {
+        Some(path) => path,
+        None => config::Config::load(config_path)?.totp_secret_path,
+    };
+
+    let secret = totp::generate_secret();
+    totp::write_secret(&path, &secret, force).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::AlreadyExists {
+            anyhow::anyhow!(
+                "{} already exists; pass --force to replace it",
+                path.display()
+            )
+        } else {
+            anyhow::anyhow!("Failed to write {}: {}", path.display(), e)
+        }
+    })?;
+
+    let account = hostname().unwrap_or_else(|| "articwake".to_string());
+    eprintln!("Wrote TOTP secret to {}", path.display());
+    eprintln!("Add this URI to your authenticator app (e.g. as a QR code):");
+    println!("{}", totp::otpauth_uri(&secret, "articwake", &account));
+    Ok(())
+}
+
+/// Removes the lockout file so PIN login works again.
+fn clear_lockout(args: Vec<String>, config_path: Option<&Path>) -> anyhow::Result<()> {
+    let mut path = None;
+    for arg in args {
+        match arg.as_str() {
+            _ if arg.starts_with('-') => anyhow::bail!("Unknown option: {}", arg),
+            _ => path = Some(PathBuf::from(arg)),
+        }
+    }
+    let path = match path {
+        Some(path) => path,
+        None => config::Config::load(config_path)?.login.lockout_path,
+    };
+
+    let reason = std::fs::read_to_string(&path).unwrap_or_default();
+    if lockout::clear(&path)
+        .map_err(|e| anyhow::anyhow!("Failed to remove {}: {}", path.display(), e))?
+    {
+        eprintln!("{}", reason.trim());
+        eprintln!("PIN login unlocked");
+    } else {
+        eprintln!(
+            "PIN login is not locked ({} does not exist)",
+            path.display()
+        );
+    }
+    Ok(())
+}
 
-    let salt = SaltString::generate(&mut OsRng);
-    let hash = Argon2::default()
-        .hash_password(pin.as_bytes(), &salt)
-        .map_err(|e| anyhow::anyhow!("Failed to hash PIN: {}", e))?;
+/// Issues a client certificate from the local client CA, creating the CA on
+/// first use, and writes `<name>.crt` and `<name>.key` for enrolling a device.
+fn issue_client_cert(args: Vec<String>, config_path: Option<&Path>) -> anyhow::Result<()> {
+    let mut name = None;
+    let mut days = 365;
+    let mut out = PathBuf::from(".");
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--days" => {
+                days = args
+                    .next()
+                    .and_then(|d| d.parse().ok())
+                    .filter(|&d| d > 0)
+                    .ok_or_else(|| anyhow::anyhow!("--days requires a positive number"))?;
+            }
+            "--out" => {
+                out = args
+                    .next()
+                    .map(PathBuf::from)
+                    .ok_or_else(|| anyhow::anyhow!("--out requires a directory"))?;
+            }
+            _ if arg.starts_with('-') => anyhow::bail!("Unknown option: {}", arg),
+            _ => name = Some(arg),
+        }
+    }
+    let name = name.ok_or_else(|| {
+        anyhow::anyhow!("Usage: articwake issue-client-cert <name> [--days N] [--out DIR]")
+    })?;
+    let config = config::Config::load(config_path)?;
 
-    println!("{}", hash);
+    let cert_path = out.join(format!("{}.crt", name));
+    let key_path = out.join(format!("{}.key", name));
+    for path in [&cert_path, &key_path] {
+        if path.exists() {
+            anyhow::bail!("{} already exists", path.display());
+        }
+    }
+    let issued = tls::issue_client_cert(&config.tls, &name, days)?;
+    if issued.created_ca {
+        eprintln!("Created client CA {}", config.tls.client_ca_path.display());
+    }
+    write_private(&key_path, &issued.key_pem)?;
+    std::fs::write(&cert_path, &issued.cert_pem)
+        .map_err(|e| anyhow::anyhow!("Failed to write {}: {}", cert_path.display(), e))?;
+
+    eprintln!("Wrote {} and {}", cert_path.display(), key_path.display());
+    eprintln!("Valid for {} days. SHA-256 fingerprint:", days);
+    println!("{}", issued.fingerprint);
+    eprintln!("For phones, bundle them with:");
+    eprintln!(
+        "  openssl pkcs12 -export -in {} -inkey {} -out {}.p12",
+        cert_path.display(),
+        key_path.display(),
+        name
+    );
     Ok(())
 }
 
+/// `articwake api-key create|list|revoke`: manages keys for machine clients.
+fn api_key(args: Vec<String>, config_path: Option<&Path>) -> anyhow::Result<()> {
+    const USAGE: &str = "Usage: articwake api-key create <name> --scope <scopes> [--days N]\n       \
+                         articwake api-key list\n       \
+                         articwake api-key revoke <id>";
+    let mut args = args.into_iter();
+    let command = args.next();
+    let mut positional = Vec::new();
+    let mut scopes = Vec::new();
+    let mut days = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--scope" => {
+                let list = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--scope requires a scope"))?;
+                for scope in apikeys::parse_scopes(&list)? {
+                    if !scopes.contains(&scope) {
+                        scopes.push(scope);
+                    }
+                }
+            }
+            "--days" => {
+                days = Some(
+                    args.next()
+                        .and_then(|d| d.parse().ok())
+                        .filter(|&d: &u32| d > 0)
+                        .ok_or_else(|| anyhow::anyhow!("--days requires a positive number"))?,
+                );
+            }
+            _ if arg.starts_with('-') => anyhow::bail!("Unknown option: {}", arg),
+            _ => positional.push(arg),
+        }
+    }
+    let config = config::Config::load(config_path)?;
+    let keys = apikeys::ApiKeys::new(config.api_keys_path.clone());
+    let date = |unix: u64| {
+        time::OffsetDateTime::from_unix_timestamp(unix as i64)
+            .map(|t| t.date().to_string())
+            .unwrap_or_else(|_| unix.to_string())
+    };
+    let scope_list = |scopes: &[apikeys::Scope]| {
+        scopes
+            .iter()
+            .map(|s| s.as_str())
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+
+    match (command.as_deref(), positional.as_slice()) {
+        (Some("create"), [name]) => {
+            if scopes.is_empty() {
+                anyhow::bail!("--scope is required: status:read, wol, unlock or admin");
+            }
+            let (entry, key) = keys.create(name, scopes, days)?;
+            eprintln!(
+                "Created API key {} ({}) with scope {}",
+                entry.id,
+                entry.name,
+                scope_list(&entry.scopes)
+            );
+            match entry.expires_at {
+                Some(at) => eprintln!("Expires {}", date(at)),
+                None => eprintln!("Never expires"),
+            }
+            eprintln!("Copy the key now; it cannot be shown again:");
+            println!("{}", key);
+        }
+        (Some("list"), []) => {
+            let list = keys.list()?;
+            if list.is_empty() {
+                eprintln!("No API keys in {}", config.api_keys_path.display());
+            }
+            let now = time::OffsetDateTime::now_utc().unix_timestamp() as u64;
+            for key in list {
+                let expires = match key.expires_at {
+                    Some(at) if key.expired(now) => format!("expired {}", date(at)),
+                    Some(at) => format!("expires {}", date(at)),
+                    None => "never expires".to_string(),
+                };
+                println!(
+                    "{}  {}  {}  created {}  {}",
+                    key.id,
+                    key.name,
+                    scope_list(&key.scopes),
+                    date(key.created_at),
+                    expires
+                );
+            }
+        }
+        (Some("revoke"), [id]) => {
+            let key = keys.revoke(id)?;
+            eprintln!("Revoked API key {} ({})", key.id, key.name);
+        }
+        _ => anyhow::bail!(USAGE),
+    }
+    Ok(())
+}
+
+/// `articwake user add|list|remove|set-role|set-pin`: manages user accounts.
+/// PINs are read from stdin, like `hash-pin`.
+fn user(args: Vec<String>, config_path: Option<&Path>) -> anyhow::Result<()> {
+    const USAGE: &str = "Usage: articwake user add <username> --role <role>\n       \
+                         articwake user list\n       \
+                         articwake user remove <username>\n       \
+                         articwake user set-role <username> <role>\n       \
+                         articwake user set-pin <username>";
+    let mut args = args.into_iter();
+    let command = args.next();
+    let mut positional = Vec::new();
+    let mut role = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--role" => {
+                let name = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--role requires a role"))?;
+                role = Some(name.parse::<users::Role>()?);
+            }
+            _ if arg.starts_with('-') => anyhow::bail!("Unknown option: {}", arg),
+            _ => positional.push(arg),
+        }
+    }
+    let config = config::Config::load(config_path)?;
+    let store = users::Users::new(config.users_path.clone(), config.pin.argon2.clone());
+    let read_pin = || -> anyhow::Result<String> {
+        use std::io::BufRead;
+        let line = std::io::stdin()
+            .lock()
+            .lines()
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("No PIN provided on stdin"))??;
+        let pin = line.trim().to_string();
+        pin::check_policy(&pin, &config.pin)?;
+        Ok(pin)
+    };
+
+    match (command.as_deref(), positional.as_slice()) {
+        (Some("add"), [username]) => {
+            let role = role.ok_or_else(|| {
+                anyhow::anyhow!("--role is required: viewer, operator, unlocker or admin")
+            })?;
+            // Before waiting for a PIN that would be thrown away
+            if !users::valid_username(username) {
+                return Err(users::UserError::InvalidUsername(username.clone()).into());
+            }
+            let user = store.add(username, role, &read_pin()?)?;
+            eprintln!("Added user {} with role {}", user.username, user.role);
+        }
+        (Some("list"), []) => {
+            let list = store.list()?;
+            if list.is_empty() {
+                eprintln!("No users in {}", config.users_path.display());
+            }
+            for user in list {
+                let created = time::OffsetDateTime::from_unix_timestamp(user.created_at as i64)
+                    .map(|t| t.date().to_string())
+                    .unwrap_or_else(|_| user.created_at.to_string());
+                println!("{}  {}  created {}", user.username, user.role, created);
+            }
+        }
+        (Some("remove"), [username]) => {
+            store.remove(username)?;
+            eprintln!("Removed user {}; their sessions end with it", username);
+        }
+        (Some("set-role"), [username, role]) => {
+            let role = role.parse::<users::Role>()?;
+            store.set_role(username, role)?;
+            eprintln!("User {} now has role {}", username, role);
+        }
+        (Some("set-pin"), [username]) => {
+            store.set_pin(username, &read_pin()?)?;
+            eprintln!("Changed the PIN of {}", username);
+        }
+        _ => anyhow::bail!(USAGE),
+    }
+    Ok(())
+}
+
+/// Writes a file readable only by its owner.
+fn write_private(path: &std::path::Path, content: &str) -> anyhow::Result<()> {
+    use std::io::Write;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(content.as_bytes()))
+        .map_err(|e| anyhow::anyhow!("Failed to write {}: {}", path.display(), e))
+}
+
+/// Name of this machine from `/etc/hostname`.
+fn hostname() -> Option<String> {
+    std::fs::read_to_string("/etc/hostname")
+        .ok()
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+}
+
+/// Loads the HTTPS certificate, generating a self-signed one on first start,
+/// and reloads it whenever the process receives SIGHUP.
+fn start_tls(config: &config::Config) -> anyhow::Result<rustls::ServerConfig> {
+    let mut names = vec!["localhost".to_string()];
+    if let Some(name) = hostname() {
+        names.push(format!("{}.local", name));
+        names.push(name);
+    }
+    if config
+        .bind_host
+        .parse::<IpAddr>()
+        .is_ok_and(|ip| !ip.is_unspecified())
+    {
+        names.push(config.bind_host.clone());
+    }
+    if tls::ensure_certificate(&config.tls, &names)? {
+        tracing::info!(
+            "Generated self-signed certificate {} for {}",
+            config.tls.cert_path.display(),
+            names.join(", ")
+        );
+    }
+    let resolver = Arc::new(tls::CertResolver::load(&config.tls)?);
+
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        let mut hangup = signal(SignalKind::hangup())?;
+        let resolver = resolver.clone();
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                match resolver.reload() {
+                    Ok(()) => tracing::info!("Reloaded TLS certificate"),
+                    Err(e) => tracing::error!(
+                        "Failed to reload TLS certificate, keeping the old one: {}",
+                        e
+                    ),
+                }
+            }
+        });
+    }
+
+    Ok(tls::server_config(resolver, &config.tls)?)
+}
+
+/// Reads the shared PIN hash, users and API keys again whenever the process
+/// receives SIGHUP, so a PIN set with `hash-pin` takes effect without a
+/// restart and other changes take effect at once.
+#[cfg(unix)]
+fn reload_on_hangup(app_state: web::Data<auth::AppState>) -> anyhow::Result<()> {
+    use tokio::signal::unix::{SignalKind, signal};
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            app_state.users.reload();
+            app_state.api_keys.reload();
+            match app_state.reload_pin_hash() {
+                Ok(()) => tracing::info!("Reloaded PIN hash"),
+                Err(e) => tracing::error!("Failed to reload PIN hash, keeping the old one: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Sends plain HTTP requests to the same path on the HTTPS port.
+async fn redirect_to_https(req: HttpRequest, https_port: web::Data<u16>) -> HttpResponse {
+    let Some(host) = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+    else {
+        return HttpResponse::BadRequest().body("Missing Host header");
+    };
+    let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+    HttpResponse::PermanentRedirect()
+        .insert_header((
+            header::LOCATION,
+            tls::redirect_url(host, **https_port, path),
+        ))
+        .finish()
+}
+
+/// Takes `--config <path>` / `--config=<path>` out of the command line, so
+/// subcommands only see their own arguments. Returns those and the path.
+fn split_config_arg(
+    mut args: impl Iterator<Item = String>,
+) -> anyhow::Result<(Vec<String>, Option<PathBuf>)> {
+    let mut rest = Vec::new();
+    let mut config_path = None;
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            let path = args
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("--config requires a path"))?;
+            config_path = Some(PathBuf::from(path));
+        } else if let Some(path) = arg.strip_prefix("--config=") {
+            config_path = Some(PathBuf::from(path));
+        } else {
+            rest.push(arg);
+        }
+    }
+    Ok((rest, config_path))
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    // Handle hash-pin subcommand before any other initialization
-    if let Some("hash-pin") = std::env::args().nth(1).as_deref() {
-        return hash_pin();
+    let (args, config_path) = split_config_arg(std::env::args().skip(1))?;
+    let config_path = config_path.as_deref();
+
+    // Handle subcommands before any other initialization
+    if let Some((subcommand, rest)) = args.split_first() {
+        let rest = rest.to_vec();
+        match subcommand.as_str() {
+            "hash-pin" => return hash_pin(rest, config_path),
+            "enroll-totp" => return enroll_totp(rest, config_path),
+            "clear-lockout" => return clear_lockout(rest, config_path),
+            "issue-client-cert" => return issue_client_cert(rest, config_path),
+            "api-key" => return api_key(rest, config_path),
+            "user" => return user(rest, config_path),
+            _ => {}
+        }
     }
 
     tracing_subscriber::fmt()
@@ -63,27 +559,126 @@ async fn main() -> anyhow::Result<()> {
         )
         .init();
 
-    let config = config::Config::from_env()?;
+    let config = config::Config::load(config_path)?;
     let bind_addr = format!("{}:{}", config.bind_host, config.port);
+    let tls_config = match config.tls.enabled {
+        true => Some(start_tls(&config)?),
+        false => None,
+    };
+    let redirect = match (&tls_config, config.tls.redirect_port) {
+        (Some(_), Some(port)) => Some((format!("{}:{}", config.bind_host, port), config.port)),
+        _ => None,
+    };
 
-    tracing::info!("Starting articwake on {}", bind_addr);
+    let scheme = if tls_config.is_some() {
+        "https"
+    } else {
+        "http"
+    };
+    tracing::info!("Starting articwake on {}://{}", scheme, bind_addr);
+    for host in &config.hosts {
+        tracing::info!("Managing host {} ({})", host.id, host.ip);
+    }
 
-    let app_state = web::Data::new(auth::AppState::new(config));
+    let hosts = config.hosts.clone();
+    let app_state = web::Data::new(auth::AppState::open(config)?);
+    app_state.monitor.clone().spawn(hosts);
+    #[cfg(unix)]
+    reload_on_hangup(app_state.clone())?;
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
-            .wrap(TracingLogger::default())
+            .wrap(middleware::from_fn(api::access::enforce))
+            .wrap(TracingLogger::<api::RequestSpan>::new())
             .app_data(app_state.clone())
+            .route("/api/auth", web::get().to(api::auth::auth_methods))
             .route("/api/auth", web::post().to(api::auth::authenticate))
+            .route(
+                "/api/auth/passkeys",
+                web::get().to(api::auth::list_passkeys),
+            )
+            .route(
+                "/api/auth/passkeys/{id}",
+                web::delete().to(api::auth::delete_passkey),
+            )
+            .route(
+                "/api/auth/passkeys/register/start",
+                web::post().to(api::auth::start_passkey_registration),
+            )
+            .route(
+                "/api/auth/passkeys/register/finish",
+                web::post().to(api::auth::finish_passkey_registration),
+            )
+            .route(
+                "/api/auth/passkeys/login/start",
+                web::post().to(api::auth::start_passkey_login),
+            )
+            .route(
+                "/api/auth/passkeys/login/finish",
+                web::post().to(api::auth::finish_passkey_login),
+            )
+            .route("/api/pin", web::post().to(api::auth::change_pin))
+            .route("/api/logout", web::post().to(api::sessions::logout))
+            .route("/api/refresh", web::post().to(api::sessions::refresh))
+            .route("/api/sessions", web::get().to(api::sessions::list_sessions))
+            .route(
+                "/api/sessions/{id}",
+                web::delete().to(api::sessions::delete_session),
+            )
             .route("/api/status", web::get().to(api::status::get_status))
             .route("/api/wol", web::post().to(api::wol::send_wol))
             .route("/api/unlock", web::post().to(api::unlock::unlock))
+            .route("/api/boot", web::post().to(api::boot::start_boot))
+            .route("/api/jobs/{id}", web::get().to(api::boot::get_job))
+            .route("/api/events", web::get().to(api::events::status_events))
+            .route(
+                "/api/events/ticket",
+                web::post().to(api::events::events_ticket),
+            )
+            .route("/api/audit", web::get().to(api::audit::get_audit))
+            .route("/api/access", web::get().to(api::access::get_access))
+            .route("/api/hosts", web::get().to(api::hosts::list_hosts))
+            .route(
+                "/api/hosts/{id}/status",
+                web::get().to(api::status::get_host_status),
+            )
+            .route(
+                "/api/hosts/{id}/wol",
+                web::post().to(api::wol::send_host_wol),
+            )
+            .route(
+                "/api/hosts/{id}/unlock",
+                web::post().to(api::unlock::unlock_host_by_id),
+            )
             .route("/", web::get().to(serve_static))
             .route("/{filename:.*}", web::get().to(serve_static))
     })
-    .bind(&bind_addr)?
-    .run()
-    .await?;
+    .on_connect(|connection, data| {
+        if let Some(cert) = tls::peer_client_cert(connection) {
+            data.insert(cert);
+        }
+    });
+    let server = match tls_config {
+        Some(tls_config) => server.bind_rustls_0_23(&bind_addr, tls_config)?,
+        None => server.bind(&bind_addr)?,
+    }
+    .run();
+
+    match redirect {
+        Some((redirect_addr, https_port)) => {
+            tracing::info!("Redirecting http://{} to HTTPS", redirect_addr);
+            let redirect = HttpServer::new(move || {
+                App::new()
+                    .app_data(web::Data::new(https_port))
+                    .default_service(web::to(redirect_to_https))
+            })
+            .workers(1)
+            .bind(&redirect_addr)?
+            .run();
+            tokio::try_join!(server, redirect)?;
+        }
+        None => server.await?,
+    }
 
     Ok(())
 }
@@ -0,0 +1,303 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use argon2::password_hash::{PasswordHasher, SaltString, rand_core::OsRng};
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordVerifier, Version};
+use thiserror::Error;
+
+use crate::config::{Argon2Config, PinConfig};
+use crate::util;
+
+/// Longest PIN accepted, so a login cannot make Argon2 hash megabytes
+pub const MAX_PIN_LEN: usize = 128;
+
+#[derive(Debug, Error)]
+pub enum PinError {
+    #[error("PIN must be at least {0} characters")]
+    TooShort(u32),
+    #[error("PIN must be at most {MAX_PIN_LEN} characters")]
+    TooLong,
+    #[error("PIN is too simple: avoid repeating one character or runs like 123456")]
+    TooSimple,
+    #[error("The new PIN must differ from the current one")]
+    Unchanged,
+    #[error("Failed to hash PIN: {0}")]
+    HashFailed(String),
+    #[error("Failed to write {0}: {1}")]
+    WriteFailed(PathBuf, String),
+}
+
+/// Checks a new PIN against the `[pin]` policy.
+pub fn check_policy(pin: &str, policy: &PinConfig) -> Result<(), PinError> {
+    let chars: Vec<char> = pin.chars().collect();
+    if chars.len() < policy.min_length as usize {
+        return Err(PinError::TooShort(policy.min_length));
+    }
+    if chars.len() > MAX_PIN_LEN {
+        return Err(PinError::TooLong);
+    }
+    if !policy.allow_simple && is_simple(&chars) {
+        return Err(PinError::TooSimple);
+    }
+    Ok(())
+}
+
+/// One repeated character (`000000`) or a run up or down (`123456`,
+/// `fedcba`).
+fn is_simple(chars: &[char]) -> bool {
+    let steps: Vec<i64> = chars
+        .windows(2)
+        .map(|pair| pair[1] as i64 - pair[0] as i64)
+        .collect();
+    [0, 1, -1]
+        .iter()
+        .any(|&step| steps.iter().all(|&s| s == step))
+}
+
+/// Argon2 hash of `pin` in PHC string format, as stored in `pin_hash_path`.
+pub fn hash(pin: &str, config: &Argon2Config) -> Result<String, PinError> {
+    let params = Params::new(config.m_cost, config.t_cost, config.parallelism, None)
+        .map_err(|e| PinError::HashFailed(e.to_string()))?;
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::new(config.variant, Version::V0x13, params)
+        .hash_password(pin.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| PinError::HashFailed(e.to_string()))
+}
+
+/// Whether `pin` matches `hash`, using the settings stored in the hash.
+pub fn verify(hash: &PasswordHash, pin: &str) -> bool {
+    Argon2::default()
+        .verify_password(pin.as_bytes(), hash)
+        .is_ok()
+}
+
+/// Whether `hash` should be made again with `config`: it uses another
+/// variant, an older Argon2 version, or less memory, passes or lanes.
+pub fn needs_rehash(hash: &PasswordHash, config: &Argon2Config) -> bool {
+    let Ok(params) = Params::try_from(hash) else {
+        return true;
+    };
+    Algorithm::try_from(hash.algorithm).ok() != Some(config.variant)
+        || hash
+            .version
+            .is_none_or(|version| version < Version::V0x13 as u32)
+        || params.m_cost() < config.m_cost
+        || params.t_cost() < config.t_cost
+        || params.p_cost() < config.parallelism
+}
+
+/// Settings picked by [`benchmark`], and how long checking a PIN took with
+/// them.
+pub struct Benchmark {
+    pub config: Argon2Config,
+    pub took: Duration,
+}
+
+/// Finds the strongest settings that check a PIN within `target` on this
+/// machine, keeping the variant and lanes of `base`. Memory is doubled from
+/// 8 MiB up to `max_m_cost` KiB first, as it is what makes guessing on GPUs
+/// expensive, then passes are added. If even the cheapest settings are too
+/// slow, those are returned.
+pub fn benchmark(
+    base: &Argon2Config,
+    target: Duration,
+    max_m_cost: u32,
+) -> Result<Benchmark, PinError> {
+    search(base, target, max_m_cost, time_hash)
+}
+
+/// [`benchmark`] with the hashing time measured by `time`.
+fn search(
+    base: &Argon2Config,
+    target: Duration,
+    max_m_cost: u32,
+    mut time: impl FnMut(&Argon2Config) -> Result<Duration, PinError>,
+) -> Result<Benchmark, PinError> {
+    let floor = 8 * base.parallelism;
+    let mut candidate = Argon2Config {
+        m_cost: (8 * 1024).min(max_m_cost).max(floor),
+        t_cost: 1,
+        ..base.clone()
+    };
+    let mut best = Benchmark {
+        took: time(&candidate)?,
+        config: candidate.clone(),
+    };
+    while best.took <= target {
+        if candidate.m_cost < max_m_cost {
+            candidate.m_cost = candidate.m_cost.saturating_mul(2).min(max_m_cost);
+        } else {
+            candidate.t_cost += 1;
+        }
+        let took = time(&candidate)?;
+        if took > target {
+            break;
+        }
+        best = Benchmark {
+            config: candidate.clone(),
+            took,
+        };
+    }
+    Ok(best)
+}
+
+/// Checking a PIN costs one hash with the stored settings.
+fn time_hash(config: &Argon2Config) -> Result<Duration, PinError> {
+    let start = Instant::now();
+    hash("benchmark", config)?;
+    Ok(start.elapsed())
+}
+
+/// Replaces the PIN hash file atomically, readable only by its owner, so a
+/// login never sees a half-written hash.
+pub fn write_hash(path: &Path, hash: &str) -> Result<(), PinError> {
+    let write_failed = |e: io::Error| PinError::WriteFailed(path.to_path_buf(), e.to_string());
+    util::write_atomic(path, format!("{}\n", hash).as_bytes(), 0o600).map_err(write_failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Cheap settings, so the tests do not spend seconds hashing
+    fn weak() -> Argon2Config {
+        Argon2Config {
+            m_cost: 64,
+            t_cost: 1,
+            ..Argon2Config::default()
+        }
+    }
+
+    #[test]
+    fn test_policy() {
+        let policy = PinConfig::default();
+        assert!(check_policy("270418", &policy).is_ok());
+        assert!(check_policy("correct horse", &policy).is_ok());
+        assert!(matches!(
+            check_policy("2704", &policy),
+            Err(PinError::TooShort(6))
+        ));
+        assert!(matches!(
+            check_policy(&"7".repeat(MAX_PIN_LEN + 1), &policy),
+            Err(PinError::TooLong)
+        ));
+        for simple in ["000000", "123456", "987654", "abcdefg"] {
+            assert!(matches!(
+                check_policy(simple, &policy),
+                Err(PinError::TooSimple)
+            ));
+        }
+        // Not a run all the way through
+        assert!(check_policy("123457", &policy).is_ok());
+
+        let lenient = PinConfig {
+            min_length: 4,
+            allow_simple: true,
+            ..PinConfig::default()
+        };
+        assert!(check_policy("1234", &lenient).is_ok());
+        assert!(check_policy("123", &lenient).is_err());
+    }
+
+    #[test]
+    fn test_hash_and_write() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("pin.hash");
+        write_hash(&path, &hash("270418", &weak()).unwrap()).unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        let parsed = PasswordHash::new(content.trim()).unwrap();
+        assert!(verify(&parsed, "270418"));
+        assert!(!verify(&parsed, "270419"));
+        // Only the file itself, no temporary file
+        assert_eq!(fs::read_dir(path.parent().unwrap()).unwrap().count(), 1);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn test_needs_rehash() {
+        let stored = hash("270418", &weak()).unwrap();
+        let stored = PasswordHash::new(&stored).unwrap();
+        assert!(
+            stored
+                .to_string()
+                .starts_with("$argon2id$v=19$m=64,t=1,p=1$")
+        );
+        assert!(!needs_rehash(&stored, &weak()));
+
+        // Weaker settings than the stored ones are no reason to rehash
+        let weaker = Argon2Config {
+            m_cost: 32,
+            ..weak()
+        };
+        assert!(!needs_rehash(&stored, &weaker));
+
+        for stronger in [
+            Argon2Config {
+                m_cost: 128,
+                ..weak()
+            },
+            Argon2Config {
+                t_cost: 2,
+                ..weak()
+            },
+            Argon2Config {
+                parallelism: 2,
+                ..weak()
+            },
+            Argon2Config {
+                variant: Algorithm::Argon2i,
+                ..weak()
+            },
+        ] {
+            assert!(needs_rehash(&stored, &stronger), "{:?}", stronger);
+            let rehashed = hash("270418", &stronger).unwrap();
+            let rehashed = PasswordHash::new(&rehashed).unwrap();
+            assert!(verify(&rehashed, "270418"));
+            assert!(!needs_rehash(&rehashed, &stronger));
+        }
+    }
+
+    #[test]
+    fn test_benchmark() {
+        // A machine that takes 1 ms per MiB and pass
+        let time = |config: &Argon2Config| {
+            Ok(Duration::from_millis(
+                u64::from(config.m_cost / 1024) * u64::from(config.t_cost),
+            ))
+        };
+        let search = |target_ms, max_m_cost| {
+            let target = Duration::from_millis(target_ms);
+            search(&Argon2Config::default(), target, max_m_cost, time).unwrap()
+        };
+
+        // Nothing is fast enough, so the cheapest settings come back
+        let result = search(0, 64 * 1024);
+        assert_eq!(result.config.m_cost, 8 * 1024);
+        assert_eq!(result.config.t_cost, 1);
+        assert_eq!(result.config.variant, Algorithm::Argon2id);
+
+        // Memory is doubled while it fits
+        let result = search(40, 64 * 1024);
+        assert_eq!((result.config.m_cost, result.config.t_cost), (32 * 1024, 1));
+        assert_eq!(result.took, Duration::from_millis(32));
+
+        // Memory stops at the cap and passes are added instead
+        let result = search(50, 16 * 1024);
+        assert_eq!((result.config.m_cost, result.config.t_cost), (16 * 1024, 3));
+
+        // Real hashing runs with the chosen settings
+        let result = benchmark(&weak(), Duration::ZERO, 64).unwrap();
+        assert_eq!(result.config.m_cost, 64);
+        assert_eq!(result.config.t_cost, 1);
+    }
+}
@@ -0,0 +1,190 @@
+use std::net::{IpAddr, SocketAddr};
+
+use actix_web::http::header::HeaderMap;
+use ipnet::IpNet;
+
+/// The address of the client behind any trusted reverse proxies.
+///
+/// Requests from a peer outside `trusted` are taken at face value. From a
+/// trusted peer, the forwarding chain (`Forwarded`, or `X-Forwarded-For` if
+/// that is absent) is walked from the nearest hop outwards and the first
+/// address not in `trusted` is the client. Addresses further out were
+/// supplied by that client and are ignored. A hop that cannot be parsed
+/// stops the walk at the proxy that reported it.
+pub fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted: &[IpNet]) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|net| net.contains(ip));
+    let mut client = peer.to_canonical();
+    if !is_trusted(&client) {
+        return client;
+    }
+
+    let hops = if headers.contains_key("forwarded") {
+        forwarded_for(headers)
+    } else {
+        x_forwarded_for(headers)
+    };
+    for hop in hops.into_iter().rev() {
+        match hop {
+            Some(ip) => client = ip.to_canonical(),
+            None => break,
+        }
+        if !is_trusted(&client) {
+            break;
+        }
+    }
+    client
+}
+
+/// `X-Forwarded-For: client, proxy1, proxy2`, across all header lines.
+fn x_forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    header_values(headers, "x-forwarded-for")
+        .map(|hop| parse_node(hop.trim()))
+        .collect()
+}
+
+/// The `for=` parameter of each element of RFC 7239 `Forwarded` headers,
+/// e.g. `for=192.0.2.60;proto=https, for="[2001:db8::1]:4711"`.
+fn forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    header_values(headers, "forwarded")
+        .map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("for"))
+                .and_then(|(_, node)| parse_node(node.trim().trim_matches('"')))
+        })
+        .collect()
+}
+
+/// Comma-separated entries of every line of a header, in order.
+fn header_values<'a>(headers: &'a HeaderMap, name: &str) -> impl Iterator<Item = &'a str> {
+    headers
+        .get_all(name)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+}
+
+/// An address as proxies write it: `192.0.2.1`, `192.0.2.1:4711`, `2001:db8::1`
+/// or `[2001:db8::1]:4711`. Obfuscated or `unknown` nodes give `None`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    node.parse::<IpAddr>()
+        .or_else(|_| node.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .or_else(|_| {
+            node.strip_prefix('[')
+                .and_then(|rest| rest.strip_suffix(']'))
+                .unwrap_or(node)
+                .parse::<IpAddr>()
+        })
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.append(
+                HeaderName::from_static(name),
+                HeaderValue::from_static(value),
+            );
+        }
+        map
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn nets(list: &[&str]) -> Vec<IpNet> {
+        list.iter().map(|net| net.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_untrusted_peer_ignores_headers() {
+        let h = headers(&[("x-forwarded-for", "10.9.9.9")]);
+        assert_eq!(client_ip(ip("192.0.2.1"), &h, &[]), ip("192.0.2.1"));
+        assert_eq!(
+            client_ip(ip("192.0.2.1"), &h, &nets(&["127.0.0.1/32"])),
+            ip("192.0.2.1")
+        );
+    }
+
+    #[test]
+    fn test_x_forwarded_for() {
+        let trusted = nets(&["127.0.0.1/32", "10.0.0.0/8"]);
+        let h = headers(&[("x-forwarded-for", "203.0.113.9, 10.0.0.2")]);
+        assert_eq!(client_ip(ip("127.0.0.1"), &h, &trusted), ip("203.0.113.9"));
+
+        // Whatever the client put in front is not believed
+        let h = headers(&[("x-forwarded-for", "1.2.3.4, 203.0.113.9")]);
+        assert_eq!(client_ip(ip("127.0.0.1"), &h, &trusted), ip("203.0.113.9"));
+
+        // Several header lines form one list
+        let h = headers(&[
+            ("x-forwarded-for", "1.2.3.4"),
+            ("x-forwarded-for", "203.0.113.9"),
+        ]);
+        assert_eq!(client_ip(ip("127.0.0.1"), &h, &trusted), ip("203.0.113.9"));
+    }
+
+    #[test]
+    fn test_forwarded() {
+        let trusted = nets(&["127.0.0.1/32"]);
+        let h = headers(&[(
+            "forwarded",
+            "for=1.2.3.4, for=\"[2001:db8::1]:4711\";proto=https",
+        )]);
+        assert_eq!(client_ip(ip("127.0.0.1"), &h, &trusted), ip("2001:db8::1"));
+
+        let h = headers(&[("forwarded", "proto=https;For=203.0.113.9:8080")]);
+        assert_eq!(client_ip(ip("127.0.0.1"), &h, &trusted), ip("203.0.113.9"));
+
+        // Forwarded wins over X-Forwarded-For
+        let h = headers(&[
+            ("forwarded", "for=203.0.113.9"),
+            ("x-forwarded-for", "198.51.100.7"),
+        ]);
+        assert_eq!(client_ip(ip("127.0.0.1"), &h, &trusted), ip("203.0.113.9"));
+    }
+
+    #[test]
+    fn test_missing_or_bad_header() {
+        let trusted = nets(&["127.0.0.1/32", "10.0.0.0/8"]);
+        let peer = ip("127.0.0.1");
+        assert_eq!(client_ip(peer, &headers(&[]), &trusted), peer);
+
+        let h = headers(&[("x-forwarded-for", "not-an-ip")]);
+        assert_eq!(client_ip(peer, &h, &trusted), peer);
+
+        // Stops at the proxy that passed on the garbage
+        let h = headers(&[("x-forwarded-for", "203.0.113.9, unknown, 10.0.0.2")]);
+        assert_eq!(client_ip(peer, &h, &trusted), ip("10.0.0.2"));
+
+        let h = headers(&[("forwarded", "for=_hidden")]);
+        assert_eq!(client_ip(peer, &h, &trusted), peer);
+    }
+
+    #[test]
+    fn test_all_hops_trusted() {
+        let trusted = nets(&["10.0.0.0/8", "127.0.0.1/32"]);
+        let h = headers(&[("x-forwarded-for", "10.0.0.5, 10.0.0.2")]);
+        assert_eq!(client_ip(ip("127.0.0.1"), &h, &trusted), ip("10.0.0.5"));
+    }
+
+    #[test]
+    fn test_ipv4_mapped_peer() {
+        let trusted = nets(&["127.0.0.1/32"]);
+        let h = headers(&[("x-forwarded-for", "203.0.113.9")]);
+        assert_eq!(
+            client_ip(ip("::ffff:127.0.0.1"), &h, &trusted),
+            ip("203.0.113.9")
+        );
+        assert_eq!(
+            client_ip(ip("::ffff:192.0.2.1"), &h, &trusted),
+            ip("192.0.2.1")
+        );
+    }
+}
@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::audit::{AuditAction, AuditEntry, AuditLog, AuditOutcome};
+use crate::config::{BootConfig, HostConfig, HostPhase};
+use crate::services::network::{check_host_status, wait_for_status};
+use crate::services::ssh::UnlockOutcome;
+use crate::services::unlock::backend_for;
+use crate::services::wol::send_magic_packet;
+use crate::util::{random_bytes, unix_now};
+
+/// How long finished jobs stay queryable
+const JOB_RETENTION: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BootPhase {
+    /// Sending magic packets and waiting for the initrd phase
+    Waking,
+    /// Delivering the passphrase
+    Unlocking,
+    /// Waiting for the booted phase
+    Booting,
+    Completed,
+    Failed,
+}
+
+impl BootPhase {
+    pub fn is_finished(self) -> bool {
+        matches!(self, BootPhase::Completed | BootPhase::Failed)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BootJob {
+    pub id: String,
+    pub host: String,
+    pub phase: BootPhase,
+    /// Attempt number within the current phase, starting at 1
+    pub attempt: u32,
+    pub error: Option<String>,
+    /// Unix timestamps in seconds
+    pub started_at: u64,
+    pub updated_at: u64,
+    #[serde(skip)]
+    finished_at: Option<Instant>,
+}
+
+/// In-memory record of boot jobs, shared between handlers and running jobs.
+#[derive(Default)]
+pub struct JobRegistry {
+    jobs: Mutex<HashMap<String, BootJob>>,
+}
+
+impl JobRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a new job for `host`, or returns the unfinished job that is
+    /// already running for it as the error.
+    pub fn create(&self, host: &str) -> Result<BootJob, BootJob> {
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.retain(|_, job| {
+            job.finished_at
+                .is_none_or(|finished| finished.elapsed() < JOB_RETENTION)
+        });
+
+        if let Some(running) = jobs
+            .values()
+            .find(|job| job.host == host && !job.phase.is_finished())
+        {
+            return Err(running.clone());
+        }
+
+        let now = unix_now();
+        let job = BootJob {
+            id: generate_job_id(),
+            host: host.to_string(),
+            phase: BootPhase::Waking,
+            attempt: 1,
+            error: None,
+            started_at: now,
+            updated_at: now,
+            finished_at: None,
+        };
+        jobs.insert(job.id.clone(), job.clone());
+        Ok(job)
+    }
+
+    pub fn get(&self, id: &str) -> Option<BootJob> {
+        self.jobs.lock().unwrap().get(id).cloned()
+    }
+
+    fn set_phase(&self, id: &str, phase: BootPhase, attempt: u32) {
+        self.update(id, |job| {
+            job.phase = phase;
+            job.attempt = attempt;
+        });
+    }
+
+    fn finish(&self, id: &str, result: Result<(), String>) {
+        self.update(id, |job| {
+            match result {
+                Ok(()) => job.phase = BootPhase::Completed,
+                Err(error) => {
+                    job.phase = BootPhase::Failed;
+                    job.error = Some(error);
+                }
+            }
+            job.finished_at = Some(Instant::now());
+        });
+    }
+
+    fn update(&self, id: &str, f: impl FnOnce(&mut BootJob)) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(id) {
+            f(job);
+            job.updated_at = unix_now();
+        }
+    }
+}
+
+/// Records who started a job and where its audit entries go.
+pub struct JobAudit {
+    pub log: Arc<AuditLog>,
+    /// Client and session of the request that started the job
+    pub origin: AuditEntry,
+}
+
+impl JobAudit {
+    fn record(&self, entry: AuditEntry) {
+        self.log.record(AuditEntry {
+            action: entry.action,
+            outcome: entry.outcome,
+            detail: entry.detail,
+            ..self.origin.clone()
+        });
+    }
+}
+
+/// Starts the wake-and-unlock workflow for `host` in the background.
+pub fn start(
+    jobs: Arc<JobRegistry>,
+    host: HostConfig,
+    boot: BootConfig,
+    passphrase: String,
+    audit: JobAudit,
+) -> Result<BootJob, BootJob> {
+    let job = jobs.create(&host.id)?;
+    let id = job.id.clone();
+    audit.record(
+        AuditEntry::new(AuditAction::Boot, AuditOutcome::Success)
+            .detail(format!("Job {} started", id)),
+    );
+
+    tokio::spawn(async move {
+        let result = boot_host(&jobs, &id, &host, &boot, &passphrase, &audit).await;
+        let entry = match &result {
+            Ok(()) => {
+                tracing::info!("Boot job {} for {} completed", id, host.id);
+                AuditEntry::new(AuditAction::Boot, AuditOutcome::Success)
+                    .detail(format!("Job {} completed", id))
+            }
+            Err(e) => {
+                tracing::warn!("Boot job {} for {} failed: {}", id, host.id, e);
+                AuditEntry::new(AuditAction::Boot, AuditOutcome::Failure)
+                    .detail(format!("Job {} failed: {}", id, e))
+            }
+        };
+        audit.record(entry);
+        jobs.finish(&id, result);
+    });
+
+    Ok(job)
+}
+
+async fn boot_host(
+    jobs: &JobRegistry,
+    id: &str,
+    host: &HostConfig,
+    boot: &BootConfig,
+    passphrase: &str,
+    audit: &JobAudit,
+) -> Result<(), String> {
+    let status = check_host_status(host).await;
+    if status.phase == HostPhase::Booted {
+        return Ok(());
+    }
+
+    if status.phase != HostPhase::Initrd {
+        let mut woke = false;
+        for attempt in 1..=boot.wake_attempts {
+            jobs.set_phase(id, BootPhase::Waking, attempt);
+            let sent = send_magic_packet(&host.mac, &host.broadcast);
+            audit.record(match &sent {
+                Ok(()) => AuditEntry::new(AuditAction::Wol, AuditOutcome::Success)
+                    .detail(format!("Job {}, attempt {}", id, attempt)),
+                Err(e) => AuditEntry::new(AuditAction::Wol, AuditOutcome::Failure)
+                    .detail(format!("Job {}, attempt {}: {}", id, attempt, e)),
+            });
+            sent.map_err(|e| format!("Failed to send WOL packet: {}", e))?;
+            if wait_for_status(host, boot.wake_timeout, |s| s.phase == HostPhase::Initrd)
+                .await
+                .is_some()
+            {
+                woke = true;
+                break;
+            }
+        }
+        if !woke {
+            return Err(format!(
+                "Host did not reach the initrd after {} wake attempt(s)",
+                boot.wake_attempts
+            ));
+        }
+    }
+
+    let backend = backend_for(&host.unlock);
+    let mut attempt = 1;
+    loop {
+        jobs.set_phase(id, BootPhase::Unlocking, attempt);
+        let result = backend.unlock(host, passphrase).await;
+        let entry =
+            AuditEntry::new(AuditAction::Unlock, AuditOutcome::Success).unlock_result(&result);
+        audit.record(AuditEntry {
+            detail: Some(match entry.detail {
+                Some(reason) => format!("Job {}, attempt {}: {}", id, attempt, reason),
+                None => format!("Job {}, attempt {}", id, attempt),
+            }),
+            ..entry
+        });
+        let error = match result {
+            Ok(UnlockOutcome::Unlocked) => break,
+            Ok(UnlockOutcome::Rejected) => return Err("Passphrase rejected".to_string()),
+            Ok(UnlockOutcome::Timeout) => "Timed out waiting for the server".to_string(),
+            Err(e) => format!("Failed to unlock: {}", e),
+        };
+        if attempt >= boot.unlock_attempts {
+            return Err(error);
+        }
+        tracing::warn!(
+            "Unlock attempt {} for {} failed: {}",
+            attempt,
+            host.id,
+            error
+        );
+        attempt += 1;
+    }
+
+    jobs.set_phase(id, BootPhase::Booting, 1);
+    wait_for_status(host, boot.boot_timeout, |s| s.phase == HostPhase::Booted)
+        .await
+        .map(|_| ())
+        .ok_or_else(|| "Host did not finish booting after unlocking".to_string())
+}
+
+fn generate_job_id() -> String {
+    hex::encode(random_bytes::<16>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_and_get() {
+        let jobs = JobRegistry::new();
+        let job = jobs.create("nas").unwrap();
+
+        assert_eq!(job.id.len(), 32);
+        assert_eq!(job.phase, BootPhase::Waking);
+        assert_eq!(jobs.get(&job.id).unwrap().host, "nas");
+        assert!(jobs.get("missing").is_none());
+    }
+
+    #[test]
+    fn test_one_running_job_per_host() {
+        let jobs = JobRegistry::new();
+        let job = jobs.create("nas").unwrap();
+
+        assert_eq!(jobs.create("nas").unwrap_err().id, job.id);
+        assert!(jobs.create("gpu").is_ok());
+
+        jobs.finish(&job.id, Ok(()));
+        assert!(jobs.create("nas").is_ok());
+    }
+
+    #[test]
+    fn test_phase_updates() {
+        let jobs = JobRegistry::new();
+        let job = jobs.create("nas").unwrap();
+
+        jobs.set_phase(&job.id, BootPhase::Unlocking, 2);
+        let current = jobs.get(&job.id).unwrap();
+        assert_eq!(current.phase, BootPhase::Unlocking);
+        assert_eq!(current.attempt, 2);
+
+        jobs.finish(&job.id, Err("Passphrase rejected".to_string()));
+        let current = jobs.get(&job.id).unwrap();
+        assert_eq!(current.phase, BootPhase::Failed);
+        assert_eq!(current.error.as_deref(), Some("Passphrase rejected"));
+    }
+
+    #[test]
+    fn test_job_serialization() {
+        let jobs = JobRegistry::new();
+        let job = jobs.create("nas").unwrap();
+        let json = serde_json::to_value(&job).unwrap();
+
+        assert_eq!(json["phase"], "waking");
+        assert_eq!(json["host"], "nas");
+        assert!(json["error"].is_null());
+        assert!(json.get("finished_at").is_none());
+    }
+}
@@ -1,3 +1,6 @@
+pub mod boot;
+pub mod monitor;
 pub mod network;
 pub mod ssh;
+pub mod unlock;
 pub mod wol;
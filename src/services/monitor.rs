@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::config::{HostConfig, HostPhase};
+use crate::services::network::{HostStatus, check_host_status};
+use crate::util::unix_now;
+
+/// Buffered events per subscriber before it starts lagging
+const EVENT_CAPACITY: usize = 64;
+
+/// Last known status of a host.
+#[derive(Debug, Clone, Serialize)]
+pub struct HostSnapshot {
+    pub host: String,
+    pub phase: HostPhase,
+    pub status: HostStatus,
+    /// Unix timestamp in seconds
+    pub updated_at: u64,
+    #[serde(skip)]
+    probed_at: Instant,
+}
+
+/// Pushed to subscribers whenever a host's status changes.
+#[derive(Debug, Clone, Serialize)]
+pub struct StatusEvent {
+    pub host: String,
+    pub phase: HostPhase,
+    /// `None` for the first probe of a host
+    pub previous: Option<HostPhase>,
+    pub status: HostStatus,
+}
+
+/// Keeps the status of every host current and fans changes out to
+/// subscribers, so viewers share one set of probes.
+pub struct StatusMonitor {
+    interval: Duration,
+    snapshots: RwLock<HashMap<String, HostSnapshot>>,
+    events: broadcast::Sender<StatusEvent>,
+}
+
+impl StatusMonitor {
+    pub fn new(interval: Duration) -> Self {
+        StatusMonitor {
+            interval,
+            snapshots: RwLock::new(HashMap::new()),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<StatusEvent> {
+        self.events.subscribe()
+    }
+
+    /// Snapshots of every probed host, in no particular order.
+    pub fn snapshots(&self) -> Vec<HostSnapshot> {
+        self.snapshots.read().unwrap().values().cloned().collect()
+    }
+
+    /// Stores a fresh probe result and notifies subscribers if it changed.
+    pub fn record(&self, host: &str, status: HostStatus) -> HostSnapshot {
+        let snapshot = HostSnapshot {
+            host: host.to_string(),
+            phase: status.phase,
+            status,
+            updated_at: unix_now(),
+            probed_at: Instant::now(),
+        };
+
+        let previous = self
+            .snapshots
+            .write()
+            .unwrap()
+            .insert(host.to_string(), snapshot.clone());
+
+        if !previous
+            .as_ref()
+            .is_some_and(|p| p.status.same_state(&snapshot.status))
+        {
+            let previous = previous.map(|p| p.phase);
+            if previous != Some(snapshot.phase) {
+                tracing::info!(
+                    "Host {} is now {:?} (was {:?})",
+                    host,
+                    snapshot.phase,
+                    previous
+                );
+            }
+            // No receivers is fine
+            let _ = self.events.send(StatusEvent {
+                host: host.to_string(),
+                phase: snapshot.phase,
+                previous,
+                status: snapshot.status.clone(),
+            });
+        }
+
+        snapshot
+    }
+
+    /// Cached status if the monitor probed the host recently, otherwise a
+    /// live probe.
+    pub async fn status(&self, host: &HostConfig) -> HostSnapshot {
+        let cached = self.snapshots.read().unwrap().get(&host.id).cloned();
+        match cached {
+            Some(snapshot) if snapshot.probed_at.elapsed() < self.interval * 2 => snapshot,
+            _ => {
+                let status = check_host_status(host).await;
+                self.record(&host.id, status)
+            }
+        }
+    }
+
+    /// Probes all hosts every interval until the runtime shuts down.
+    pub fn spawn(self: Arc<Self>, hosts: Vec<HostConfig>) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(self.interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                ticker.tick().await;
+                let probes = hosts.iter().map(|host| async {
+                    let status = check_host_status(host).await;
+                    self.record(&host.id, status);
+                });
+                futures_util::future::join_all(probes).await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::network::ProbeResult;
+
+    fn status(initrd_ssh_open: bool, system_ssh_open: bool) -> HostStatus {
+        let probe = |name: &str, phase, ok: bool| ProbeResult {
+            name: name.to_string(),
+            kind: "tcp",
+            phase: Some(phase),
+            ok,
+            latency_ms: ok.then_some(3),
+            error: None,
+            responded: ok,
+        };
+        HostStatus::from_probes(vec![
+            probe("initrd_ssh", HostPhase::Initrd, initrd_ssh_open),
+            probe("system_ssh", HostPhase::Booted, system_ssh_open),
+        ])
+    }
+
+    #[test]
+    fn test_record_publishes_changes_only() {
+        let monitor = StatusMonitor::new(Duration::from_secs(5));
+        let mut events = monitor.subscribe();
+
+        monitor.record("nas", status(false, false));
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.host, "nas");
+        assert_eq!(event.phase, HostPhase::Off);
+        assert_eq!(event.previous, None);
+
+        monitor.record("nas", status(false, false));
+        assert!(events.try_recv().is_err());
+
+        // Latency alone is not a change
+        let mut slower = status(false, false);
+        slower.probes[0].latency_ms = Some(900);
+        monitor.record("nas", slower);
+        assert!(events.try_recv().is_err());
+
+        monitor.record("nas", status(true, false));
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.phase, HostPhase::Initrd);
+        assert_eq!(event.previous, Some(HostPhase::Off));
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let monitor = StatusMonitor::new(Duration::from_secs(5));
+        monitor.record("nas", status(false, true));
+        monitor.record("gpu", status(true, false));
+
+        let mut snapshots = monitor.snapshots();
+        snapshots.sort_by(|a, b| a.host.cmp(&b.host));
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(snapshots[0].phase, HostPhase::Initrd);
+        assert_eq!(snapshots[1].phase, HostPhase::Booted);
+    }
+
+    #[tokio::test]
+    async fn test_status_uses_fresh_cache() {
+        let monitor = StatusMonitor::new(Duration::from_secs(60));
+        let host = HostConfig {
+            id: "nas".to_string(),
+            ip: "192.0.2.1".to_string(),
+            ..Default::default()
+        };
+        monitor.record("nas", status(false, true));
+
+        let snapshot = monitor.status(&host).await;
+        assert_eq!(snapshot.phase, HostPhase::Booted);
+    }
+}
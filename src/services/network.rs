@@ -1,85 +1,686 @@
-use std::net::{TcpStream, ToSocketAddrs};
-use std::process::Command;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
 use std::time::Duration;
 
-#[derive(Debug, Clone, serde::Serialize)]
+use crate::config::{HostConfig, HostPhase, ProbeConfig, ProbeKind};
+
+use regex::Regex;
+use serde::Serialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::{Instant, timeout_at};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Deadline shared by all probes of one status check
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Longest banner or status line read from a probed service
+const MAX_LINE: usize = 1024;
+
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+
+/// Result of one probe in a status check.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ProbeResult {
+    pub name: String,
+    /// Probe kind: `icmp`, `tcp`, `http` or `ssh`
+    pub kind: &'static str,
+    /// Phase this probe counts towards, if any
+    pub phase: Option<HostPhase>,
+    pub ok: bool,
+    /// Round-trip time of a successful probe
+    pub latency_ms: Option<u64>,
+    pub error: Option<String>,
+    /// The host answered at all, e.g. with a TCP reset or an unexpected status
+    #[serde(skip)]
+    pub responded: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub struct HostStatus {
     pub reachable: bool,
+    pub phase: HostPhase,
+    /// Same as `phase == initrd`, kept for existing clients
     pub initrd_ssh_open: bool,
+    /// Same as `phase == booted`, kept for existing clients
     pub system_ssh_open: bool,
+    pub probes: Vec<ProbeResult>,
 }
 
-pub fn check_host_status(ip: &str, initrd_ssh_port: u16) -> HostStatus {
-    let reachable = ping_host(ip);
-    let initrd_ssh_open = check_tcp_port(ip, initrd_ssh_port);
-    let system_ssh_open = check_tcp_port(ip, 22);
+impl HostStatus {
+    /// Derives the phase from the probe results: a phase is reached when it
+    /// has at least one probe and all of its probes succeeded. Booted wins
+    /// over initrd.
+    pub fn from_probes(probes: Vec<ProbeResult>) -> Self {
+        let reached = |phase| {
+            let mut tagged = probes.iter().filter(|p| p.phase == Some(phase)).peekable();
+            tagged.peek().is_some() && tagged.all(|p| p.ok)
+        };
+        let phase = if reached(HostPhase::Booted) {
+            HostPhase::Booted
+        } else if reached(HostPhase::Initrd) {
+            HostPhase::Initrd
+        } else {
+            HostPhase::Off
+        };
+
+        HostStatus {
+            reachable: probes.iter().any(|p| p.ok || p.responded),
+            phase,
+            initrd_ssh_open: phase == HostPhase::Initrd,
+            system_ssh_open: phase == HostPhase::Booted,
+            probes,
+        }
+    }
 
-    HostStatus {
-        reachable,
-        initrd_ssh_open,
-        system_ssh_open,
+    /// Compares everything but latencies, which change on every probe.
+    pub fn same_state(&self, other: &HostStatus) -> bool {
+        self.reachable == other.reachable
+            && self.phase == other.phase
+            && self.probes.len() == other.probes.len()
+            && self
+                .probes
+                .iter()
+                .zip(&other.probes)
+                .all(|(a, b)| a.name == b.name && a.ok == b.ok && a.error == b.error)
     }
 }
 
-fn ping_host(ip: &str) -> bool {
-    Command::new("ping")
-        .args(["-c", "1", "-W", "2", ip])
-        .output()
-        .map(|o| o.status.success())
-        .unwrap_or(false)
+/// Outcome of a TCP connection attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PortState {
+    Open,
+    /// The host answered with a reset, so it is up but nothing listens
+    Refused,
+    /// No answer before the deadline, or no route
+    Unreachable,
 }
 
-fn check_tcp_port(ip: &str, port: u16) -> bool {
-    let addr = format!("{}:{}", ip, port);
-    if let Ok(mut addrs) = addr.to_socket_addrs()
-        && let Some(addr) = addrs.next()
-    {
-        return TcpStream::connect_timeout(&addr, Duration::from_secs(3)).is_ok();
+/// Runs all of the host's probes concurrently, within [`PROBE_TIMEOUT`].
+pub async fn check_host_status(host: &HostConfig) -> HostStatus {
+    let deadline = Instant::now() + PROBE_TIMEOUT;
+    let addr = resolve(&host.ip, deadline).await;
+
+    let probes = host.probes();
+    let results = futures_util::future::join_all(
+        probes
+            .iter()
+            .map(|probe| run_probe(probe, &host.ip, addr, deadline)),
+    )
+    .await;
+
+    HostStatus::from_probes(results)
+}
+
+/// Polls the host until `done` accepts its status or `timeout` elapses.
+pub async fn wait_for_status(
+    host: &HostConfig,
+    timeout: Duration,
+    done: impl Fn(&HostStatus) -> bool,
+) -> Option<HostStatus> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let status = check_host_status(host).await;
+        if done(&status) {
+            return Some(status);
+        }
+        if Instant::now() + POLL_INTERVAL >= deadline {
+            return None;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Why a probe failed, and whether the host answered anyway.
+struct ProbeError {
+    message: String,
+    responded: bool,
+}
+
+impl ProbeError {
+    fn silent(message: impl Into<String>) -> Self {
+        ProbeError {
+            message: message.into(),
+            responded: false,
+        }
+    }
+
+    fn answered(message: impl Into<String>) -> Self {
+        ProbeError {
+            message: message.into(),
+            responded: true,
+        }
+    }
+}
+
+async fn run_probe(
+    probe: &ProbeConfig,
+    ip: &str,
+    addr: Option<IpAddr>,
+    deadline: Instant,
+) -> ProbeResult {
+    let started = Instant::now();
+    let outcome = match addr {
+        None => Err(ProbeError::silent(format!("Could not resolve {}", ip))),
+        Some(addr) => match &probe.kind {
+            ProbeKind::Icmp => match ping_host(addr, deadline).await {
+                Some(true) => Ok(()),
+                Some(false) => Err(ProbeError::silent("No echo reply")),
+                None => Err(ProbeError::silent("ICMP sockets are not permitted")),
+            },
+            ProbeKind::Tcp { port } => {
+                match check_tcp_port(SocketAddr::new(addr, *port), deadline).await {
+                    PortState::Open => Ok(()),
+                    PortState::Refused => Err(ProbeError::answered("Connection refused")),
+                    PortState::Unreachable => Err(ProbeError::silent("No answer")),
+                }
+            }
+            ProbeKind::Http {
+                host,
+                port,
+                path,
+                expect_status,
+            } => {
+                let target = if host == ip {
+                    Some(addr)
+                } else {
+                    resolve(host, deadline).await
+                };
+                match target {
+                    Some(target) => {
+                        check_http(
+                            SocketAddr::new(target, *port),
+                            host,
+                            path,
+                            *expect_status,
+                            deadline,
+                        )
+                        .await
+                    }
+                    None => Err(ProbeError::silent(format!("Could not resolve {}", host))),
+                }
+            }
+            ProbeKind::Ssh { port, banner } => {
+                check_ssh_banner(SocketAddr::new(addr, *port), banner.as_ref(), deadline).await
+            }
+        },
+    };
+
+    let (ok, latency_ms, error, responded) = match outcome {
+        Ok(()) => (true, Some(started.elapsed().as_millis() as u64), None, true),
+        Err(e) => (false, None, Some(e.message), e.responded),
+    };
+    ProbeResult {
+        name: probe.name.clone(),
+        kind: probe.kind.name(),
+        phase: probe.phase,
+        ok,
+        latency_ms,
+        error,
+        responded,
+    }
+}
+
+/// Accepts an IP address or a hostname such as a Tailscale MagicDNS name.
+async fn resolve(host: &str, deadline: Instant) -> Option<IpAddr> {
+    if let Ok(addr) = host.parse() {
+        return Some(addr);
+    }
+    let mut addrs = timeout_at(deadline, tokio::net::lookup_host((host, 0)))
+        .await
+        .ok()?
+        .ok()?;
+    addrs.next().map(|addr| addr.ip())
+}
+
+/// Sends one ICMP echo request. Returns `None` when the kernel does not allow
+/// unprivileged ICMP sockets (see `net.ipv4.ping_group_range`).
+async fn ping_host(addr: IpAddr, deadline: Instant) -> Option<bool> {
+    let socket = match icmp_socket(addr) {
+        Ok(socket) => socket,
+        Err(e) => {
+            tracing::debug!("ICMP socket unavailable, using TCP only: {}", e);
+            return None;
+        }
+    };
+
+    let sequence: u16 = rand::random();
+    let request = echo_request(addr, sequence);
+    let exchange = async {
+        socket.send(&request).await?;
+        let mut reply = [0u8; 1500];
+        loop {
+            let len = socket.recv(&mut reply).await?;
+            if is_echo_reply(addr, &reply[..len], sequence) {
+                return Ok::<_, io::Error>(());
+            }
+        }
+    };
+
+    Some(matches!(timeout_at(deadline, exchange).await, Ok(Ok(()))))
+}
+
+fn icmp_socket(addr: IpAddr) -> io::Result<UdpSocket> {
+    let (domain, protocol) = match addr {
+        IpAddr::V4(_) => (Domain::IPV4, Protocol::ICMPV4),
+        IpAddr::V6(_) => (Domain::IPV6, Protocol::ICMPV6),
+    };
+    let socket = Socket::new(domain, Type::DGRAM, Some(protocol))?;
+    socket.set_nonblocking(true)?;
+    socket.connect(&SocketAddr::new(addr, 0).into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Echo request with an 8-byte header. The kernel fills in the identifier and
+/// checksum for datagram ICMP sockets.
+fn echo_request(addr: IpAddr, sequence: u16) -> Vec<u8> {
+    let kind = match addr {
+        IpAddr::V4(_) => ICMP_ECHO_REQUEST,
+        IpAddr::V6(_) => ICMPV6_ECHO_REQUEST,
+    };
+    let mut packet = vec![kind, 0, 0, 0, 0, 0];
+    packet.extend_from_slice(&sequence.to_be_bytes());
+    packet.extend_from_slice(b"articwake");
+    packet
+}
+
+fn is_echo_reply(addr: IpAddr, packet: &[u8], sequence: u16) -> bool {
+    let kind = match addr {
+        IpAddr::V4(_) => ICMP_ECHO_REPLY,
+        IpAddr::V6(_) => ICMPV6_ECHO_REPLY,
+    };
+    packet.len() >= 8 && packet[0] == kind && packet[6..8] == sequence.to_be_bytes()
+}
+
+/// Sends a `GET` request and compares the status code of the response.
+async fn check_http(
+    addr: SocketAddr,
+    host: &str,
+    path: &str,
+    expect_status: u16,
+    deadline: Instant,
+) -> Result<(), ProbeError> {
+    let mut stream = connect(addr, deadline).await?;
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: articwake\r\nConnection: close\r\n\r\n",
+        path,
+        host_header(host, addr.port())
+    );
+    let line = timeout_at(deadline, async {
+        stream.write_all(request.as_bytes()).await?;
+        read_line(&mut stream).await
+    })
+    .await
+    .map_err(|_| ProbeError::silent("Timed out waiting for a response"))?
+    .map_err(|e| ProbeError::silent(format!("Request failed: {}", e)))?;
+
+    let status =
+        parse_status_line(&line).ok_or_else(|| ProbeError::answered("Invalid HTTP response"))?;
+    if status == expect_status {
+        Ok(())
+    } else {
+        Err(ProbeError::answered(format!(
+            "Expected status {}, got {}",
+            expect_status, status
+        )))
+    }
+}
+
+fn host_header(host: &str, port: u16) -> String {
+    let host = if host.contains(':') {
+        format!("[{}]", host)
+    } else {
+        host.to_string()
+    };
+    if port == 80 {
+        host
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Status code of an `HTTP/1.x NNN Reason` line.
+fn parse_status_line(line: &str) -> Option<u16> {
+    let mut parts = line.split_whitespace();
+    if !parts.next()?.starts_with("HTTP/") {
+        return None;
+    }
+    parts.next()?.parse().ok()
+}
+
+/// Reads the server's identification line and matches it against `banner`.
+async fn check_ssh_banner(
+    addr: SocketAddr,
+    banner: Option<&Regex>,
+    deadline: Instant,
+) -> Result<(), ProbeError> {
+    let mut stream = connect(addr, deadline).await?;
+    let line = timeout_at(deadline, read_line(&mut stream))
+        .await
+        .map_err(|_| ProbeError::answered("Timed out waiting for the SSH banner"))?
+        .map_err(|e| ProbeError::answered(format!("Failed to read the SSH banner: {}", e)))?;
+
+    if !line.starts_with("SSH-") {
+        return Err(ProbeError::answered("Not an SSH server"));
+    }
+    match banner {
+        Some(pattern) if !pattern.is_match(&line) => Err(ProbeError::answered(format!(
+            "Banner {} does not match",
+            line
+        ))),
+        _ => Ok(()),
+    }
+}
+
+async fn connect(addr: SocketAddr, deadline: Instant) -> Result<TcpStream, ProbeError> {
+    match timeout_at(deadline, TcpStream::connect(addr)).await {
+        Ok(Ok(stream)) => Ok(stream),
+        Ok(Err(e)) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            Err(ProbeError::answered("Connection refused"))
+        }
+        Ok(Err(e)) => Err(ProbeError::silent(format!("Connection failed: {}", e))),
+        Err(_) => Err(ProbeError::silent("No answer")),
+    }
+}
+
+/// First line sent by the peer, without the line ending. Stops after
+/// [`MAX_LINE`] bytes so a misbehaving service cannot stall the probe.
+async fn read_line(stream: &mut TcpStream) -> io::Result<String> {
+    let mut line = Vec::new();
+    let mut buf = [0u8; 256];
+    while !line.contains(&b'\n') && line.len() < MAX_LINE {
+        let len = stream.read(&mut buf).await?;
+        if len == 0 {
+            break;
+        }
+        line.extend_from_slice(&buf[..len]);
+    }
+    let end = line.iter().position(|&b| b == b'\n').unwrap_or(line.len());
+    Ok(String::from_utf8_lossy(&line[..end]).trim_end().to_string())
+}
+
+async fn check_tcp_port(addr: SocketAddr, deadline: Instant) -> PortState {
+    match timeout_at(deadline, TcpStream::connect(addr)).await {
+        Ok(Ok(_)) => PortState::Open,
+        Ok(Err(e)) if e.kind() == io::ErrorKind::ConnectionRefused => PortState::Refused,
+        _ => PortState::Unreachable,
     }
-    false
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    #[test]
-    fn test_check_host_status_localhost() {
+    fn host(ip: &str, ssh_port: u16) -> HostConfig {
+        HostConfig {
+            ip: ip.to_string(),
+            ssh_port,
+            ..Default::default()
+        }
+    }
+
+    fn result(name: &str, phase: Option<HostPhase>, ok: bool) -> ProbeResult {
+        ProbeResult {
+            name: name.to_string(),
+            kind: "tcp",
+            phase,
+            ok,
+            latency_ms: ok.then_some(1),
+            error: (!ok).then(|| "No answer".to_string()),
+            responded: ok,
+        }
+    }
+
+    /// Listener that answers each connection with `reply`.
+    async fn serve(reply: &'static str) -> u16 {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let _ = stream.write_all(reply.as_bytes()).await;
+            }
+        });
+        port
+    }
+
+    fn probe(name: &str, kind: ProbeKind) -> ProbeConfig {
+        ProbeConfig {
+            name: name.to_string(),
+            kind,
+            phase: Some(HostPhase::Booted),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_host_status_localhost() {
         // Localhost should be reachable
-        let status = check_host_status("127.0.0.1", 2222);
+        let status = check_host_status(&host("127.0.0.1", 2222)).await;
         assert!(status.reachable);
     }
 
-    #[test]
-    fn test_check_host_status_unreachable() {
+    #[tokio::test]
+    async fn test_check_host_status_unreachable() {
         // Non-routable IP should not be reachable (or timeout quickly)
-        let status = check_host_status("192.0.2.1", 2222); // TEST-NET-1, should not route
+        let started = Instant::now();
+        let status = check_host_status(&host("192.0.2.1", 2222)).await; // TEST-NET-1, should not route
         assert!(!status.initrd_ssh_open);
         assert!(!status.system_ssh_open);
+        assert_eq!(status.phase, HostPhase::Off);
+        assert!(started.elapsed() < PROBE_TIMEOUT + Duration::from_secs(1));
     }
 
-    #[test]
-    fn test_check_tcp_port_closed() {
+    #[tokio::test]
+    async fn test_check_host_status_unknown_host() {
+        let status = check_host_status(&host("not-an-ip.invalid", 2222)).await;
+        assert!(!status.reachable);
+        assert!(status.probes.iter().all(|p| !p.ok));
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let deadline = Instant::now() + PROBE_TIMEOUT;
+        assert_eq!(
+            resolve("192.0.2.1", deadline).await,
+            Some(IpAddr::from([192, 0, 2, 1]))
+        );
+        assert!(resolve("localhost", deadline).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_check_tcp_port_closed() {
         // Port 59999 should not be open on localhost
-        assert!(!check_tcp_port("127.0.0.1", 59999));
+        let deadline = Instant::now() + PROBE_TIMEOUT;
+        let addr = SocketAddr::from(([127, 0, 0, 1], 59999));
+        assert_eq!(check_tcp_port(addr, deadline).await, PortState::Refused);
+    }
+
+    #[tokio::test]
+    async fn test_check_tcp_port_open() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let deadline = Instant::now() + PROBE_TIMEOUT;
+        let addr = listener.local_addr().unwrap();
+        assert_eq!(check_tcp_port(addr, deadline).await, PortState::Open);
+    }
+
+    #[tokio::test]
+    async fn test_check_host_status_open_port() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let status = check_host_status(&host("127.0.0.1", port)).await;
+        assert!(status.reachable);
+
+        let initrd = status
+            .probes
+            .iter()
+            .find(|p| p.name == "initrd_ssh")
+            .unwrap();
+        assert!(initrd.ok);
+        assert!(initrd.latency_ms.is_some());
+        assert_eq!(initrd.phase, Some(HostPhase::Initrd));
+    }
+
+    #[tokio::test]
+    async fn test_http_probe() {
+        let port = serve("HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n").await;
+        let mut host = host("127.0.0.1", 2222);
+        host.probes = vec![
+            probe(
+                "health",
+                ProbeKind::Http {
+                    host: "127.0.0.1".to_string(),
+                    port,
+                    path: "/health".to_string(),
+                    expect_status: 204,
+                },
+            ),
+            probe(
+                "ready",
+                ProbeKind::Http {
+                    host: "127.0.0.1".to_string(),
+                    port,
+                    path: "/ready".to_string(),
+                    expect_status: 200,
+                },
+            ),
+        ];
+
+        let status = check_host_status(&host).await;
+        assert!(status.probes[0].ok);
+        assert!(!status.probes[1].ok);
+        assert_eq!(
+            status.probes[1].error.as_deref(),
+            Some("Expected status 200, got 204")
+        );
+        assert!(status.reachable);
+        assert_eq!(status.phase, HostPhase::Off);
+    }
+
+    #[tokio::test]
+    async fn test_ssh_banner_probe() {
+        let port = serve("SSH-2.0-dropbear_2022.83\r\n").await;
+        let mut host = host("127.0.0.1", 2222);
+        host.probes = vec![
+            probe(
+                "dropbear",
+                ProbeKind::Ssh {
+                    port,
+                    banner: Some(Regex::new("dropbear").unwrap()),
+                },
+            ),
+            probe(
+                "openssh",
+                ProbeKind::Ssh {
+                    port,
+                    banner: Some(Regex::new("OpenSSH").unwrap()),
+                },
+            ),
+            probe("any", ProbeKind::Ssh { port, banner: None }),
+        ];
+
+        let status = check_host_status(&host).await;
+        assert!(status.probes[0].ok);
+        assert!(!status.probes[1].ok);
+        assert!(status.probes[2].ok);
+    }
+
+    #[tokio::test]
+    async fn test_ssh_probe_rejects_other_services() {
+        let port = serve("HTTP/1.1 400 Bad Request\r\n\r\n").await;
+        let mut host = host("127.0.0.1", 2222);
+        host.probes = vec![probe("ssh", ProbeKind::Ssh { port, banner: None })];
+
+        let status = check_host_status(&host).await;
+        assert_eq!(status.probes[0].error.as_deref(), Some("Not an SSH server"));
+    }
+
+    #[test]
+    fn test_parse_status_line() {
+        assert_eq!(parse_status_line("HTTP/1.1 200 OK"), Some(200));
+        assert_eq!(parse_status_line("HTTP/1.0 503"), Some(503));
+        assert_eq!(parse_status_line("SSH-2.0-OpenSSH"), None);
+        assert_eq!(parse_status_line(""), None);
+    }
+
+    #[test]
+    fn test_host_header() {
+        assert_eq!(host_header("nas.local", 80), "nas.local");
+        assert_eq!(host_header("10.0.0.5", 8080), "10.0.0.5:8080");
+        assert_eq!(host_header("fd00::5", 80), "[fd00::5]");
     }
 
     #[test]
-    fn test_check_tcp_port_invalid_ip() {
-        assert!(!check_tcp_port("not-an-ip", 80));
+    fn test_echo_packets() {
+        let v4: IpAddr = "192.0.2.1".parse().unwrap();
+        let request = echo_request(v4, 0x1234);
+        assert_eq!(&request[..2], &[ICMP_ECHO_REQUEST, 0]);
+        assert_eq!(&request[6..8], &[0x12, 0x34]);
+
+        let mut reply = request.clone();
+        reply[0] = ICMP_ECHO_REPLY;
+        assert!(is_echo_reply(v4, &reply, 0x1234));
+        assert!(!is_echo_reply(v4, &reply, 0x1235));
+        assert!(!is_echo_reply(v4, &request, 0x1234));
+        assert!(!is_echo_reply(v4, &reply[..4], 0x1234));
+
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(echo_request(v6, 1)[0], ICMPV6_ECHO_REQUEST);
     }
 
     #[test]
     fn test_host_status_struct_serialization() {
-        let status = HostStatus {
-            reachable: true,
-            initrd_ssh_open: false,
-            system_ssh_open: true,
+        let status = HostStatus::from_probes(vec![
+            result("initrd_ssh", Some(HostPhase::Initrd), false),
+            result("system_ssh", Some(HostPhase::Booted), true),
+        ]);
+
+        let json = serde_json::to_value(&status).unwrap();
+        assert_eq!(json["reachable"], true);
+        assert_eq!(json["phase"], "booted");
+        assert_eq!(json["initrd_ssh_open"], false);
+        assert_eq!(json["system_ssh_open"], true);
+        assert_eq!(json["probes"][1]["name"], "system_ssh");
+        assert_eq!(json["probes"][1]["latency_ms"], 1);
+        assert!(json["probes"][0]["latency_ms"].is_null());
+        assert!(json["probes"][0].get("responded").is_none());
+    }
+
+    #[test]
+    fn test_host_phase() {
+        let status = |initrd_ok, booted_ok| {
+            HostStatus::from_probes(vec![
+                result("ping", None, true),
+                result("initrd_ssh", Some(HostPhase::Initrd), initrd_ok),
+                result("system_ssh", Some(HostPhase::Booted), booted_ok),
+                result("health", Some(HostPhase::Booted), true),
+            ])
+            .phase
         };
 
-        let json = serde_json::to_string(&status).unwrap();
-        assert!(json.contains("\"reachable\":true"));
-        assert!(json.contains("\"initrd_ssh_open\":false"));
-        assert!(json.contains("\"system_ssh_open\":true"));
+        assert_eq!(status(false, false), HostPhase::Off);
+        assert_eq!(status(true, false), HostPhase::Initrd);
+        assert_eq!(status(false, true), HostPhase::Booted);
+        assert_eq!(status(true, true), HostPhase::Booted);
+
+        // A phase without probes is never reached
+        let untagged = HostStatus::from_probes(vec![result("ping", None, true)]);
+        assert_eq!(untagged.phase, HostPhase::Off);
+        assert!(untagged.reachable);
+    }
+
+    #[test]
+    fn test_same_state_ignores_latency() {
+        let status = HostStatus::from_probes(vec![result("a", Some(HostPhase::Booted), true)]);
+
+        let mut slower = status.clone();
+        slower.probes[0].latency_ms = Some(250);
+        assert!(status.same_state(&slower));
+
+        let down = HostStatus::from_probes(vec![result("a", Some(HostPhase::Booted), false)]);
+        assert!(!status.same_state(&down));
     }
 }
@@ -1,10 +1,16 @@
-use std::path::Path;
-use std::sync::Arc;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use thiserror::Error;
 
 use russh::client;
 use russh::keys::ssh_key;
-use russh::keys::{PrivateKeyWithHashAlg, load_secret_key};
+use russh::keys::{HashAlg, PrivateKeyWithHashAlg, load_secret_key};
+use russh::{Channel, ChannelMsg};
+
+use crate::config::HostConfig;
 
 #[derive(Debug, Error)]
 pub enum SshError {
@@ -12,6 +18,8 @@ pub enum SshError {
     KeyLoadFailed(String),
     #[error("SSH connection failed: {0}")]
     ConnectionFailed(String),
+    #[error("SSH host key mismatch: server presented {0}")]
+    HostKeyMismatch(String),
     #[error("SSH authentication failed")]
     AuthFailed,
     #[error("Failed to open channel: {0}")]
@@ -20,7 +28,71 @@ pub enum SshError {
     SendFailed(String),
 }
 
-struct ClientHandler;
+/// Decides whether a server's host key is trusted.
+#[derive(Debug, Clone)]
+pub struct HostKeyVerifier {
+    /// `host:port` entry name used in the known-hosts file
+    host: String,
+    pinned: Vec<String>,
+    known_hosts: Option<PathBuf>,
+}
+
+impl HostKeyVerifier {
+    pub fn for_host(host: &HostConfig) -> Self {
+        HostKeyVerifier {
+            host: format!("{}:{}", host.ip, host.ssh_port),
+            pinned: host.host_key_fingerprints.clone(),
+            known_hosts: host.host_key_tofu.then(|| host.known_hosts_path.clone()),
+        }
+    }
+
+    /// Checks a `SHA256:...` fingerprint. Pinned fingerprints take precedence;
+    /// otherwise trust-on-first-use records the first key seen for this host.
+    pub fn verify(&self, fingerprint: &str) -> std::io::Result<bool> {
+        if !self.pinned.is_empty() {
+            return Ok(self.pinned.iter().any(|fp| fp == fingerprint));
+        }
+
+        let Some(path) = &self.known_hosts else {
+            tracing::warn!(
+                "No host key pinned for {}, accepting {}",
+                self.host,
+                fingerprint
+            );
+            return Ok(true);
+        };
+
+        if let Some(known) = self.lookup(path)? {
+            return Ok(known == fingerprint);
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{} {}", self.host, fingerprint)?;
+        tracing::info!("Trusting new host key {} for {}", fingerprint, self.host);
+        Ok(true)
+    }
+
+    fn lookup(&self, path: &Path) -> std::io::Result<Option<String>> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        Ok(content.lines().find_map(|line| {
+            let (host, fingerprint) = line.trim().split_once(' ')?;
+            (host == self.host).then(|| fingerprint.trim().to_string())
+        }))
+    }
+}
+
+struct ClientHandler {
+    verifier: HostKeyVerifier,
+    /// Fingerprint of a rejected server key, reported back to the caller
+    rejected: Arc<Mutex<Option<String>>>,
+}
 
 impl client::Handler for ClientHandler {
     type Error = russh::Error;
@@ -28,31 +100,188 @@ impl client::Handler for ClientHandler {
     #[allow(clippy::manual_async_fn)]
     fn check_server_key(
         &mut self,
-        _server_public_key: &ssh_key::PublicKey,
+        server_public_key: &ssh_key::PublicKey,
     ) -> impl std::future::Future<Output = Result<bool, Self::Error>> + Send {
-        // Accept any host key (initrd dropbear regenerates keys)
-        async { Ok(true) }
+        let fingerprint = server_public_key.fingerprint(HashAlg::Sha256).to_string();
+        let trusted = match self.verifier.verify(&fingerprint) {
+            Ok(trusted) => trusted,
+            Err(e) => {
+                tracing::error!("Failed to check known hosts: {}", e);
+                false
+            }
+        };
+        if !trusted {
+            *self.rejected.lock().unwrap() = Some(fingerprint);
+        }
+        async move { Ok(trusted) }
     }
 }
 
-pub async fn send_passphrase(
-    host: &str,
-    port: u16,
-    key_path: &Path,
-    passphrase: &str,
-) -> Result<(), SshError> {
-    let key =
-        load_secret_key(key_path, None).map_err(|e| SshError::KeyLoadFailed(e.to_string()))?;
+/// What happened after the passphrase was typed into the initrd prompt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnlockOutcome {
+    /// The disk was unlocked (or the initrd closed the session to continue booting)
+    Unlocked,
+    /// The passphrase was rejected and the prompt asked again
+    Rejected,
+    /// No prompt or no verdict before the deadline
+    Timeout,
+}
+
+/// How long to wait for the askpass prompt after opening the shell
+const PROMPT_TIMEOUT: Duration = Duration::from_secs(15);
+/// How long cryptsetup may take to check the passphrase (Argon2 key slots are slow)
+pub const VERDICT_TIMEOUT: Duration = Duration::from_secs(60);
+
+const PROMPT_MARKERS: &[&str] = &["passphrase", "password", "unlock disk"];
+/// Messages printed when the passphrase does not open the volume, from
+/// cryptsetup, Debian's cryptroot scripts, systemd-cryptsetup and ZFS.
+const REJECTED_MESSAGES: &[&str] = &[
+    "no key available with this passphrase",
+    "bad password",
+    "failed to activate with specified passphrase",
+    "key load error: incorrect key provided",
+];
+const UNLOCKED_MARKERS: &[&str] = &["set up successfully", "unlocked successfully"];
+/// Printed after the prompt by `systemd-tty-ask-password-agent`
+const NO_ECHO_HINT: &str = "(press tab for no echo)";
+
+/// Removes ANSI escape sequences (colours, cursor movement) from terminal output.
+fn strip_escapes(output: &str) -> String {
+    let mut stripped = String::with_capacity(output.len());
+    let mut chars = output.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            stripped.push(c);
+            continue;
+        }
+        match chars.next() {
+            // CSI: parameters and intermediates up to a final byte in @..~
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            // OSC: up to BEL or ESC \
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' {
+                        break;
+                    }
+                    if c == '\x1b' && chars.peek() == Some(&'\\') {
+                        chars.next();
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    stripped
+}
+
+/// The passphrase prompt the terminal output ends with, if any.
+pub fn prompt_line(output: &str) -> Option<String> {
+    let output = strip_escapes(output);
+    let line = output.trim_end().rsplit(['\n', '\r']).next().unwrap_or("");
+    let mut lower = line.to_ascii_lowercase();
+    if lower.ends_with(NO_ECHO_HINT) {
+        lower.truncate(lower.len() - NO_ECHO_HINT.len());
+    }
+    let lower = lower.trim_end();
+    (lower.ends_with(':') && PROMPT_MARKERS.iter().any(|m| lower.contains(m)))
+        .then(|| line[..lower.len()].trim().to_string())
+}
+
+/// Whether the terminal output ends with a passphrase prompt.
+pub fn detect_prompt(output: &str) -> bool {
+    prompt_line(output).is_some()
+}
+
+/// Interprets output printed after the passphrase was sent. `prompt` is the
+/// prompt that was answered: asking it again means the passphrase was
+/// rejected, while a prompt for another volume means this one was opened.
+pub fn classify_response(prompt: Option<&str>, output: &str) -> Option<UnlockOutcome> {
+    let lower = output.to_ascii_lowercase();
+    if UNLOCKED_MARKERS.iter().any(|m| lower.contains(m)) {
+        return Some(UnlockOutcome::Unlocked);
+    }
+    if lower
+        .lines()
+        .any(|line| REJECTED_MESSAGES.iter().any(|m| line.contains(m)))
+    {
+        return Some(UnlockOutcome::Rejected);
+    }
+    let answered = prompt?;
+    match prompt_line(output) {
+        Some(again) if again == answered => Some(UnlockOutcome::Rejected),
+        Some(_) => Some(UnlockOutcome::Unlocked),
+        None => None,
+    }
+}
+
+enum ReadResult<T> {
+    Matched(T),
+    /// Channel or connection closed, with the exit status if one was sent
+    Closed(Option<u32>),
+    Timeout,
+}
+
+/// Appends channel output to `output` until `check` returns a value, the
+/// channel closes, or `timeout` elapses.
+async fn read_until<T>(
+    channel: &mut Channel<client::Msg>,
+    timeout: Duration,
+    output: &mut String,
+    check: impl Fn(&str) -> Option<T>,
+) -> ReadResult<T> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut exit_status = None;
+    loop {
+        let msg = match tokio::time::timeout_at(deadline, channel.wait()).await {
+            Ok(msg) => msg,
+            Err(_) => return ReadResult::Timeout,
+        };
+        match msg {
+            Some(ChannelMsg::Data { data }) | Some(ChannelMsg::ExtendedData { data, .. }) => {
+                output.push_str(&String::from_utf8_lossy(&data));
+                if let Some(value) = check(output) {
+                    return ReadResult::Matched(value);
+                }
+            }
+            Some(ChannelMsg::ExitStatus { exit_status: code }) => exit_status = Some(code),
+            // The exit status may still follow EOF
+            Some(ChannelMsg::Close) | None => {
+                return ReadResult::Closed(exit_status);
+            }
+            Some(_) => {}
+        }
+    }
+}
+
+async fn connect(host: &HostConfig) -> Result<client::Handle<ClientHandler>, SshError> {
+    let key = load_secret_key(&host.ssh_key_path, None)
+        .map_err(|e| SshError::KeyLoadFailed(e.to_string()))?;
 
     let key_with_hash = PrivateKeyWithHashAlg::new(Arc::new(key), None);
 
     let config = Arc::new(client::Config::default());
-    let handler = ClientHandler;
+    let rejected = Arc::new(Mutex::new(None));
+    let handler = ClientHandler {
+        verifier: HostKeyVerifier::for_host(host),
+        rejected: rejected.clone(),
+    };
 
-    let addr = format!("{}:{}", host, port);
+    let addr = format!("{}:{}", host.ip, host.ssh_port);
     let mut session = client::connect(config, &addr, handler)
         .await
-        .map_err(|e| SshError::ConnectionFailed(e.to_string()))?;
+        .map_err(|e| match rejected.lock().unwrap().take() {
+            Some(fingerprint) => SshError::HostKeyMismatch(fingerprint),
+            None => SshError::ConnectionFailed(e.to_string()),
+        })?;
 
     let username = "root";
     let auth_result = session
@@ -64,25 +293,50 @@ pub async fn send_passphrase(
         return Err(SshError::AuthFailed);
     }
 
-    let channel = session
+    Ok(session)
+}
+
+/// Answers an interactive passphrase prompt on a PTY. With no `command` the
+/// login shell is used, which starts `cryptsetup-askpass` on dropbear initrds.
+pub async fn answer_prompt(
+    host: &HostConfig,
+    command: Option<&str>,
+    passphrase: &str,
+) -> Result<UnlockOutcome, SshError> {
+    let session = connect(host).await?;
+
+    let mut channel = session
         .channel_open_session()
         .await
         .map_err(|e| SshError::ChannelFailed(e.to_string()))?;
 
-    // Request a PTY - cryptsetup-askpass needs a terminal
+    // Request a PTY - password agents need a terminal
     channel
         .request_pty(false, "xterm", 80, 24, 0, 0, &[])
         .await
         .map_err(|e| SshError::ChannelFailed(e.to_string()))?;
 
-    // Request a shell - this triggers cryptsetup-askpass on the initrd
-    channel
-        .request_shell(false)
-        .await
-        .map_err(|e| SshError::ChannelFailed(e.to_string()))?;
+    match command {
+        Some(command) => channel.exec(false, command).await,
+        None => channel.request_shell(false).await,
+    }
+    .map_err(|e| SshError::ChannelFailed(e.to_string()))?;
 
     // Wait for the prompt to appear
-    tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await;
+    let mut output = String::new();
+    let prompt = read_until(&mut channel, PROMPT_TIMEOUT, &mut output, prompt_line).await;
+    let prompt = match prompt {
+        ReadResult::Matched(prompt) => prompt,
+        ReadResult::Timeout => {
+            tracing::warn!("No passphrase prompt from {}: {:?}", host.id, output);
+            return Ok(UnlockOutcome::Timeout);
+        }
+        ReadResult::Closed(_) => {
+            return Err(SshError::ChannelFailed(
+                "Session closed before the passphrase prompt".to_string(),
+            ));
+        }
+    };
 
     // Send passphrase followed by newline to stdin
     channel
@@ -90,11 +344,326 @@ pub async fn send_passphrase(
         .await
         .map_err(|e| SshError::SendFailed(e.to_string()))?;
 
-    // Wait a bit for processing
-    tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+    // Only output after the passphrase counts. A clean disconnect means the
+    // initrd moved on to booting the system
+    output.clear();
+    let outcome = match read_until(&mut channel, VERDICT_TIMEOUT, &mut output, |out| {
+        classify_response(Some(&prompt), out)
+    })
+    .await
+    {
+        ReadResult::Matched(outcome) => outcome,
+        ReadResult::Closed(Some(code)) if code != 0 => UnlockOutcome::Rejected,
+        ReadResult::Closed(_) => UnlockOutcome::Unlocked,
+        ReadResult::Timeout => UnlockOutcome::Timeout,
+    };
 
     channel.eof().await.ok();
 
-    tracing::info!("Successfully sent passphrase via SSH to {}:{}", host, port);
-    Ok(())
+    Ok(outcome)
+}
+
+/// Result of a non-interactive command run by [`exec_with_input`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExecResult {
+    /// The command exited, with its status if the server reported one
+    Exited {
+        exit_status: Option<u32>,
+        output: String,
+    },
+    TimedOut,
+}
+
+/// Runs `command` without a PTY, writes `input` to its stdin and closes it,
+/// then collects output until the command exits or `timeout` elapses.
+pub async fn exec_with_input(
+    host: &HostConfig,
+    command: &str,
+    input: &str,
+    timeout: Duration,
+) -> Result<ExecResult, SshError> {
+    let session = connect(host).await?;
+
+    let mut channel = session
+        .channel_open_session()
+        .await
+        .map_err(|e| SshError::ChannelFailed(e.to_string()))?;
+
+    channel
+        .exec(true, command)
+        .await
+        .map_err(|e| SshError::ChannelFailed(e.to_string()))?;
+
+    channel
+        .data(input.as_bytes())
+        .await
+        .map_err(|e| SshError::SendFailed(e.to_string()))?;
+    channel
+        .eof()
+        .await
+        .map_err(|e| SshError::SendFailed(e.to_string()))?;
+
+    let mut output = String::new();
+    let result = match read_until(&mut channel, timeout, &mut output, |_| None::<()>).await {
+        ReadResult::Closed(exit_status) => ExecResult::Exited {
+            exit_status,
+            output,
+        },
+        ReadResult::Timeout | ReadResult::Matched(()) => ExecResult::TimedOut,
+    };
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const FP_A: &str = "SHA256:uNiVztksCsDhcc0u9e8BujQXVUpKZIDTMczCvj3tD2s";
+    const FP_B: &str = "SHA256:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU";
+
+    fn test_host(known_hosts_path: PathBuf) -> HostConfig {
+        HostConfig {
+            ip: "192.168.1.100".to_string(),
+            known_hosts_path,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_verifier_accepts_any_without_pins() {
+        let dir = TempDir::new().unwrap();
+        let verifier = HostKeyVerifier::for_host(&test_host(dir.path().join("known_hosts")));
+
+        assert!(verifier.verify(FP_A).unwrap());
+        assert!(verifier.verify(FP_B).unwrap());
+        assert!(!dir.path().join("known_hosts").exists());
+    }
+
+    #[test]
+    fn test_verifier_pinned() {
+        let dir = TempDir::new().unwrap();
+        let mut host = test_host(dir.path().join("known_hosts"));
+        host.host_key_fingerprints = vec![FP_A.to_string()];
+        let verifier = HostKeyVerifier::for_host(&host);
+
+        assert!(verifier.verify(FP_A).unwrap());
+        assert!(!verifier.verify(FP_B).unwrap());
+    }
+
+    #[test]
+    fn test_verifier_trust_on_first_use() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("state").join("known_hosts");
+        let mut host = test_host(path.clone());
+        host.host_key_tofu = true;
+        let verifier = HostKeyVerifier::for_host(&host);
+
+        assert!(verifier.verify(FP_A).unwrap());
+        assert_eq!(
+            fs::read_to_string(&path).unwrap().trim(),
+            format!("192.168.1.100:2222 {}", FP_A)
+        );
+
+        assert!(verifier.verify(FP_A).unwrap());
+        assert!(!verifier.verify(FP_B).unwrap());
+    }
+
+    #[test]
+    fn test_verifier_trust_on_first_use_per_host() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("known_hosts");
+        let mut host = test_host(path.clone());
+        host.host_key_tofu = true;
+        let mut other = host.clone();
+        other.ssh_port = 2223;
+
+        assert!(HostKeyVerifier::for_host(&host).verify(FP_A).unwrap());
+        assert!(HostKeyVerifier::for_host(&other).verify(FP_B).unwrap());
+        assert!(!HostKeyVerifier::for_host(&host).verify(FP_B).unwrap());
+    }
+
+    #[test]
+    fn test_verifier_pins_override_trust_on_first_use() {
+        let dir = TempDir::new().unwrap();
+        let mut host = test_host(dir.path().join("known_hosts"));
+        host.host_key_tofu = true;
+        host.host_key_fingerprints = vec![FP_B.to_string()];
+        let verifier = HostKeyVerifier::for_host(&host);
+
+        assert!(!verifier.verify(FP_A).unwrap());
+        assert!(!dir.path().join("known_hosts").exists());
+    }
+
+    #[test]
+    fn test_detect_prompt() {
+        assert!(detect_prompt("Please unlock disk cryptroot: "));
+        assert!(detect_prompt("Enter passphrase for /dev/sda2: "));
+        assert!(detect_prompt(
+            "BusyBox v1.36\r\nPlease unlock disk luks-root: "
+        ));
+        assert!(detect_prompt(
+            "Please enter passphrase for disk root (cryptroot):"
+        ));
+        assert!(!detect_prompt("BusyBox v1.36 built-in shell (ash)\r\n# "));
+        assert!(!detect_prompt(
+            "Please unlock disk cryptroot: \r\nunlocking\r\n"
+        ));
+        assert!(!detect_prompt(""));
+    }
+
+    const ASKPASS_PROMPT: &str = "Please unlock disk cryptroot:";
+
+    fn classify(output: &str) -> Option<UnlockOutcome> {
+        classify_response(Some(ASKPASS_PROMPT), output)
+    }
+
+    #[test]
+    fn test_prompt_line() {
+        assert_eq!(
+            prompt_line("BusyBox v1.36\r\nPlease unlock disk cryptroot: ").as_deref(),
+            Some(ASKPASS_PROMPT)
+        );
+        assert_eq!(prompt_line("Starting cryptroot\r\n"), None);
+    }
+
+    #[test]
+    fn test_prompt_line_systemd_agent() {
+        // systemd-tty-ask-password-agent --query on a colour terminal
+        let output = "\x1b[0;1;39m\u{1f510} Please enter passphrase for disk \
+                      Samsung SSD (cryptroot): \x1b[0m\x1b[0;38;5;245m(press TAB for no echo)\
+                      \x1b[0m\x1b[23D";
+        assert!(detect_prompt(output));
+        assert_eq!(
+            prompt_line(output).as_deref(),
+            Some("\u{1f510} Please enter passphrase for disk Samsung SSD (cryptroot):")
+        );
+        assert!(detect_prompt(
+            "Please enter passphrase for disk cryptroot: (press TAB for no echo)"
+        ));
+        assert!(detect_prompt(
+            "\x1b]0;initrd\x07Please unlock disk cryptroot: \x1b[K"
+        ));
+        assert!(!detect_prompt(
+            "\x1b[0;32m[  OK  ]\x1b[0m Reached target Paths."
+        ));
+
+        // Asked again after a wrong passphrase
+        let prompt = prompt_line(output).unwrap();
+        assert_eq!(
+            classify_response(Some(&prompt), &format!("\r\n{}", output)),
+            Some(UnlockOutcome::Rejected)
+        );
+    }
+
+    #[test]
+    fn test_classify_response_rejected() {
+        assert_eq!(
+            classify(
+                "\r\ncryptsetup: ERROR: cryptroot: cryptsetup failed, bad password or options?\r\n"
+            ),
+            Some(UnlockOutcome::Rejected)
+        );
+        assert_eq!(
+            classify("No key available with this passphrase.\r\n"),
+            Some(UnlockOutcome::Rejected)
+        );
+        assert_eq!(
+            classify("\r\nPlease unlock disk cryptroot: "),
+            Some(UnlockOutcome::Rejected)
+        );
+    }
+
+    #[test]
+    fn test_classify_response_unlocked() {
+        assert_eq!(
+            classify("\r\ncryptsetup: cryptroot: set up successfully\r\n"),
+            Some(UnlockOutcome::Unlocked)
+        );
+    }
+
+    #[test]
+    fn test_classify_response_pending() {
+        assert_eq!(classify(""), None);
+        assert_eq!(classify("\r\n"), None);
+    }
+
+    #[test]
+    fn test_classify_response_askpass_transcripts() {
+        // Debian cryptroot-unlock, wrong passphrase: error, then the same prompt
+        assert_eq!(
+            classify(
+                "\r\ncryptsetup: ERROR: cryptroot: cryptsetup failed, bad password or options?\r\n\
+                 Please unlock disk cryptroot: "
+            ),
+            Some(UnlockOutcome::Rejected)
+        );
+        // Boot noise around a successful unlock
+        assert_eq!(
+            classify(
+                "\r\nBegin: Running /scripts/local-premount ... done.\r\n\
+                 Failed to start Load Kernel Modules.\r\n\
+                 WARNING: Failed to connect to lvmetad. Falling back to device scanning.\r\n\
+                 cryptsetup: cryptroot: set up successfully\r\n"
+            ),
+            Some(UnlockOutcome::Unlocked)
+        );
+        // Noise alone is no verdict yet
+        assert_eq!(
+            classify("\r\nFailed to start Load Kernel Modules.\r\nTry again later\r\n"),
+            None
+        );
+        // The next volume asks for its own passphrase
+        assert_eq!(
+            classify("\r\nPlease unlock disk cryptswap: "),
+            Some(UnlockOutcome::Unlocked)
+        );
+        assert_eq!(
+            classify(
+                "\r\ncryptsetup: cryptroot: set up successfully\r\nPlease unlock disk cryptswap: "
+            ),
+            Some(UnlockOutcome::Unlocked)
+        );
+    }
+
+    #[test]
+    fn test_classify_response_cryptsetup_transcripts() {
+        let prompt = "Enter passphrase for /dev/sda2:";
+        assert_eq!(
+            classify_response(
+                Some(prompt),
+                "\r\nNo key available with this passphrase.\r\nEnter passphrase for /dev/sda2: "
+            ),
+            Some(UnlockOutcome::Rejected)
+        );
+        assert_eq!(
+            classify_response(
+                None,
+                "Failed to activate with specified passphrase. (Passphrase incorrect?)\n"
+            ),
+            Some(UnlockOutcome::Rejected)
+        );
+        // Without a prompt to compare against, a prompt is no verdict
+        assert_eq!(
+            classify_response(None, "Enter passphrase for /dev/sda2: "),
+            None
+        );
+    }
+
+    #[test]
+    fn test_unlock_outcome_serialization() {
+        assert_eq!(
+            serde_json::to_string(&UnlockOutcome::Unlocked).unwrap(),
+            "\"unlocked\""
+        );
+        assert_eq!(
+            serde_json::to_string(&UnlockOutcome::Rejected).unwrap(),
+            "\"rejected\""
+        );
+        assert_eq!(
+            serde_json::to_string(&UnlockOutcome::Timeout).unwrap(),
+            "\"timeout\""
+        );
+    }
 }
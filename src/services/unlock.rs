@@ -0,0 +1,161 @@
+use async_trait::async_trait;
+
+use crate::config::{HostConfig, UnlockMethod};
+use crate::services::ssh::{
+    self, ExecResult, SshError, UnlockOutcome, VERDICT_TIMEOUT, classify_response,
+};
+
+/// Delivers a passphrase to a host's initrd.
+#[async_trait]
+pub trait UnlockBackend: Send + Sync {
+    async fn unlock(&self, host: &HostConfig, passphrase: &str) -> Result<UnlockOutcome, SshError>;
+}
+
+/// Returns the backend configured for a host.
+pub fn backend_for(method: &UnlockMethod) -> Box<dyn UnlockBackend> {
+    match method {
+        UnlockMethod::Askpass => Box::new(AskpassBackend),
+        UnlockMethod::Systemd => Box::new(SystemdBackend),
+        UnlockMethod::Command(command) => Box::new(CommandBackend {
+            command: command.clone(),
+        }),
+        UnlockMethod::Zfs {
+            dataset,
+            continue_command,
+        } => Box::new(ZfsBackend {
+            dataset: dataset.clone(),
+            continue_command: continue_command.clone(),
+        }),
+    }
+}
+
+/// dropbear-initramfs: the login shell runs `cryptsetup-askpass`.
+pub struct AskpassBackend;
+
+#[async_trait]
+impl UnlockBackend for AskpassBackend {
+    async fn unlock(&self, host: &HostConfig, passphrase: &str) -> Result<UnlockOutcome, SshError> {
+        ssh::answer_prompt(host, None, passphrase).await
+    }
+}
+
+/// systemd initrd: answer pending password requests through the tty agent.
+pub struct SystemdBackend;
+
+const SYSTEMD_AGENT: &str = "systemd-tty-ask-password-agent --query";
+
+#[async_trait]
+impl UnlockBackend for SystemdBackend {
+    async fn unlock(&self, host: &HostConfig, passphrase: &str) -> Result<UnlockOutcome, SshError> {
+        ssh::answer_prompt(host, Some(SYSTEMD_AGENT), passphrase).await
+    }
+}
+
+/// Pipes the passphrase into an unlock command such as `cryptroot-unlock`.
+pub struct CommandBackend {
+    pub command: String,
+}
+
+#[async_trait]
+impl UnlockBackend for CommandBackend {
+    async fn unlock(&self, host: &HostConfig, passphrase: &str) -> Result<UnlockOutcome, SshError> {
+        let result = ssh::exec_with_input(
+            host,
+            &self.command,
+            &format!("{}\n", passphrase),
+            VERDICT_TIMEOUT,
+        )
+        .await?;
+        Ok(exec_outcome(&result))
+    }
+}
+
+/// ZFS native encryption: `zfs load-key`, then let the initrd continue booting.
+pub struct ZfsBackend {
+    pub dataset: String,
+    pub continue_command: String,
+}
+
+#[async_trait]
+impl UnlockBackend for ZfsBackend {
+    async fn unlock(&self, host: &HostConfig, passphrase: &str) -> Result<UnlockOutcome, SshError> {
+        let load_key = format!("zfs load-key {}", shell_quote(&self.dataset));
+        let result = ssh::exec_with_input(host, &load_key, passphrase, VERDICT_TIMEOUT).await?;
+        let outcome = exec_outcome(&result);
+        if outcome != UnlockOutcome::Unlocked {
+            return Ok(outcome);
+        }
+
+        // The key is loaded even if this fails, e.g. `killall` finding nothing to kill
+        match ssh::exec_with_input(host, &self.continue_command, "", VERDICT_TIMEOUT).await {
+            Ok(ExecResult::Exited {
+                exit_status: Some(0) | None,
+                ..
+            }) => {}
+            Ok(other) => tracing::warn!(
+                "Continue command for {} did not succeed: {:?}",
+                host.id,
+                other
+            ),
+            Err(e) => tracing::warn!("Continue command for {} failed: {}", host.id, e),
+        }
+        Ok(UnlockOutcome::Unlocked)
+    }
+}
+
+/// Maps a finished command to an outcome. The exit status wins; without one
+/// (the initrd dropped the connection) the output decides.
+fn exec_outcome(result: &ExecResult) -> UnlockOutcome {
+    match result {
+        ExecResult::Exited {
+            exit_status: Some(0),
+            ..
+        } => UnlockOutcome::Unlocked,
+        ExecResult::Exited {
+            exit_status: Some(_),
+            ..
+        } => UnlockOutcome::Rejected,
+        ExecResult::Exited {
+            exit_status: None,
+            output,
+        } => classify_response(None, output).unwrap_or(UnlockOutcome::Unlocked),
+        ExecResult::TimedOut => UnlockOutcome::Timeout,
+    }
+}
+
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exited(exit_status: Option<u32>, output: &str) -> ExecResult {
+        ExecResult::Exited {
+            exit_status,
+            output: output.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_exec_outcome() {
+        assert_eq!(exec_outcome(&exited(Some(0), "")), UnlockOutcome::Unlocked);
+        assert_eq!(
+            exec_outcome(&exited(Some(1), "Key load error: Incorrect key provided")),
+            UnlockOutcome::Rejected
+        );
+        assert_eq!(
+            exec_outcome(&exited(None, "cryptsetup: ERROR: bad password")),
+            UnlockOutcome::Rejected
+        );
+        assert_eq!(exec_outcome(&exited(None, "")), UnlockOutcome::Unlocked);
+        assert_eq!(exec_outcome(&ExecResult::TimedOut), UnlockOutcome::Timeout);
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("rpool/root"), "'rpool/root'");
+        assert_eq!(shell_quote("a'b"), "'a'\\''b'");
+    }
+}
@@ -1,7 +1,23 @@
 (function() {
     let token = null;
+    let hostId = null;
     let pollInterval = null;
     let pendingUnlockPrompt = false;
+    let jobInterval = null;
+    let events = null;
+    // Whether the passphrase modal starts a full boot job instead of an unlock
+    let bootMode = false;
+
+    const JOB_KEY = 'articwake-boot-job';
+    const AUDIT_PAGE_SIZE = 20;
+    let auditOffset = 0;
+
+    const JOB_STATES = {
+        waking: 'waking',
+        unlocking: 'unlocking',
+        booting: 'booting',
+        completed: 'ready'
+    };
 
     // Server states
     const STATE = {
@@ -33,10 +49,12 @@
         if (body) opts.body = JSON.stringify(body);
 
         const res = await fetch('/api/' + endpoint, opts);
-        const data = await res.json();
+        const data = res.status === 204 ? {} : await res.json();
 
         if (!res.ok) {
-            throw new Error(data.error || 'Request failed');
+            const err = new Error(data.error || 'Request failed');
+            err.data = data;
+            throw err;
         }
         return data;
     }
@@ -94,6 +112,7 @@
         wakeAction.classList.remove('hidden');
         unlockAction.classList.add('hidden');
         wolBtn.disabled = false;
+        $('boot-btn').disabled = jobInterval !== null;
 
         switch (state) {
             case STATE.OFFLINE:
@@ -127,6 +146,20 @@
                 break;
 
             case STATE.UNLOCKING:
+                wakeCircle.classList.add('completed');
+                wakeCircle.innerHTML = '&#10003;';
+                wakeLine.classList.add('completed');
+                wakeDesc.textContent = 'Server is awake';
+                wakeAction.classList.add('hidden');
+
+                unlockCircle.classList.add('active');
+                unlockCircle.innerHTML = '<div class="spinner-small"></div>';
+                unlockDesc.textContent = 'Sending passphrase...';
+                unlockAction.classList.add('hidden');
+
+                readyDesc.textContent = 'Waiting for unlock';
+                break;
+
             case STATE.BOOTING:
                 wakeCircle.classList.add('completed');
                 wakeCircle.innerHTML = '&#10003;';
@@ -166,28 +199,39 @@
         }
     }
 
-    async function refreshStatus() {
-        try {
-            const status = await api('status');
-            const newState = determineState(status, currentState);
-
-            // Auto-show unlock modal when initrd becomes available
-            if (newState === STATE.INITRD && currentState !== STATE.INITRD && !pendingUnlockPrompt) {
-                pendingUnlockPrompt = true;
-                setTimeout(() => {
-                    showUnlockModal();
-                    pendingUnlockPrompt = false;
-                }, 500);
-            }
+    function hostEndpoint(action) {
+        return 'hosts/' + encodeURIComponent(hostId) + '/' + action;
+    }
 
-            // Notify when system becomes ready
-            if (newState === STATE.READY && currentState !== STATE.READY) {
-                showMessage('System is fully booted and ready!');
-                stopPolling();
-            }
+    async function loadHosts() {
+        const data = await api('hosts');
+        const select = $('host-select');
+        select.innerHTML = '';
+        data.hosts.forEach(host => {
+            const option = document.createElement('option');
+            option.value = host.id;
+            option.textContent = host.id + ' (' + host.ip + ')';
+            select.appendChild(option);
+        });
+        if (!data.hosts.some(host => host.id === hostId)) {
+            hostId = data.hosts.length ? data.hosts[0].id : null;
+        }
+        select.value = hostId;
+        $('host-picker').classList.toggle('hidden', data.hosts.length < 2);
+    }
 
-            currentState = newState;
-            updateUI(currentState, status);
+    async function selectHost() {
+        hostId = $('host-select').value;
+        currentState = STATE.OFFLINE;
+        stopPolling();
+        await refreshStatus();
+        startPolling(10000);
+    }
+
+    async function refreshStatus() {
+        try {
+            const status = await api(hostEndpoint('status'));
+            applyStatus(status);
             return status;
         } catch (e) {
             if (e.message.includes('token') || e.message.includes('Unauthorized')) {
@@ -197,8 +241,73 @@
         }
     }
 
+    function applyStatus(status) {
+        const newState = determineState(status, currentState);
+
+        // A running boot job drives the UI instead
+        if (jobInterval) {
+            return;
+        }
+
+        // Auto-show unlock modal when initrd becomes available
+        if (newState === STATE.INITRD && currentState !== STATE.INITRD && !pendingUnlockPrompt) {
+            pendingUnlockPrompt = true;
+            setTimeout(() => {
+                showUnlockModal();
+                pendingUnlockPrompt = false;
+            }, 500);
+        }
+
+        // Notify when system becomes ready
+        if (newState === STATE.READY && currentState !== STATE.READY) {
+            showMessage('System is fully booted and ready!');
+            stopPolling();
+        }
+
+        currentState = newState;
+        updateUI(currentState, status);
+    }
+
+    // Live status pushed by the server; polling is only a fallback
+    async function subscribeEvents() {
+        closeEvents();
+        if (!window.EventSource) return;
+
+        // A single-use ticket keeps the session token out of the URL
+        let ticket;
+        try {
+            ticket = (await api('events/ticket', 'POST')).ticket;
+        } catch (e) {
+            return; // Keep polling
+        }
+        closeEvents();
+        events = new EventSource('/api/events?ticket=' + encodeURIComponent(ticket));
+        const onStatus = e => {
+            const data = JSON.parse(e.data);
+            if (data.host === hostId) {
+                applyStatus(data.status);
+            }
+        };
+        events.addEventListener('snapshot', onStatus);
+        events.addEventListener('status', onStatus);
+        events.onopen = () => stopPolling();
+        events.onerror = () => {
+            // Session expired or server gone: fall back to polling, which logs out on 401
+            closeEvents();
+            startPolling(10000);
+        };
+    }
+
+    function closeEvents() {
+        if (events) {
+            events.close();
+            events = null;
+        }
+    }
+
     function startPolling(interval = 2000) {
         stopPolling();
+        if (events) return; // Updates are pushed
         pollInterval = setInterval(async () => {
             try {
                 await refreshStatus();
@@ -215,6 +324,68 @@
         }
     }
 
+    function watchJob(jobId) {
+        localStorage.setItem(JOB_KEY, jobId);
+        stopPolling();
+        stopJobPolling();
+        closeEvents();
+        jobInterval = setInterval(() => refreshJob(jobId), 2000);
+        refreshJob(jobId);
+    }
+
+    function stopJobPolling() {
+        if (jobInterval) {
+            clearInterval(jobInterval);
+            jobInterval = null;
+        }
+    }
+
+    async function refreshJob(jobId) {
+        let job;
+        try {
+            job = await api('jobs/' + encodeURIComponent(jobId));
+        } catch (e) {
+            if (e.message.includes('token') || e.message.includes('Unauthorized')) {
+                logout();
+                return;
+            }
+            // Unknown job, e.g. after a restart
+            finishJob();
+            return;
+        }
+
+        if (job.host !== hostId) {
+            hostId = job.host;
+            $('host-select').value = hostId;
+        }
+
+        if (job.phase === 'failed') {
+            showMessage(job.error || 'Boot failed', true);
+            finishJob();
+            return;
+        }
+
+        currentState = JOB_STATES[job.phase];
+        updateUI(currentState, null);
+        if (job.phase === 'completed') {
+            showMessage('System is fully booted and ready!');
+            finishJob();
+        }
+    }
+
+    function finishJob() {
+        localStorage.removeItem(JOB_KEY);
+        stopJobPolling();
+        refreshStatus().catch(e => console.error('Status refresh failed:', e));
+        startPolling(10000);
+    }
+
+    async function startBoot(passphrase) {
+        const job = await api('boot', 'POST', { passphrase, host: hostId });
+        hideUnlockModal();
+        watchJob(job.id);
+    }
+
     async function checkBackendReachable() {
         try {
             const res = await fetch('/api/status', { method: 'GET' });
@@ -236,13 +407,75 @@
 
         if (reachable) {
             $('auth-section').classList.remove('hidden');
-            $('pin-input').focus();
+            updateLoginMethods();
         } else {
             showMessage('Cannot connect to server', true);
             $('auth-section').classList.remove('hidden');
         }
     }
 
+    // Offer passkey login when the server has one registered, and skip the
+    // PIN entirely when this device's client certificate is enough
+    async function updateLoginMethods(autoLogin = true) {
+        let methods = {};
+        try {
+            methods = await api('auth');
+        } catch {
+            // Older servers have no GET /api/auth
+        }
+        if (autoLogin && methods.client_cert === 'bypass') {
+            try {
+                const data = await api('auth', 'POST', { pin: '' });
+                await startSession(data);
+                return;
+            } catch (e) {
+                showMessage(e.message, true);
+            }
+        }
+        const available = !!window.PublicKeyCredential && !!methods.passkeys;
+        $('passkey-login-btn').classList.toggle('hidden', !available);
+        // Ask for a username once the server has user accounts
+        $('username-input').classList.toggle('hidden', !methods.users);
+        (methods.users ? $('username-input') : $('pin-input')).focus();
+    }
+
+    // Hides the actions the session's scopes don't allow; the server
+    // refuses them anyway
+    function applyScopes(scopes) {
+        const admin = !scopes || scopes.includes('admin');
+        const allows = scope => admin || scopes.includes(scope);
+        document.body.classList.toggle('no-wol', !allows('wol'));
+        document.body.classList.toggle('no-unlock', !allows('unlock'));
+        document.body.classList.toggle('no-admin', !admin);
+    }
+
+    async function startSession(data) {
+        token = data.token;
+        applyScopes(data.scopes);
+        $('auth-section').classList.add('hidden');
+        $('pin-input').value = '';
+        $('totp-input').value = '';
+
+        // Show loading while fetching initial status
+        $('loading-section').classList.remove('hidden');
+
+        await loadHosts();
+        await refreshStatus();
+
+        $('loading-section').classList.add('hidden');
+        $('main-section').classList.remove('hidden');
+        updatePasskeyButton();
+
+        // Resume a boot job started before the page was reloaded
+        const jobId = localStorage.getItem(JOB_KEY);
+        if (jobId) {
+            watchJob(jobId);
+        } else {
+            startPolling(10000);
+        }
+        subscribeEvents();
+    }
+
     async function authenticate() {
         const pin = $('pin-input').value;
         if (!pin) {
@@ -250,25 +483,33 @@
             return;
         }
 
+        const body = { pin };
+        const username = $('username-input').value.trim();
+        if (!$('username-input').classList.contains('hidden') && username) {
+            body.username = username;
+        }
+        if (!$('totp-input').classList.contains('hidden')) {
+            body.totp = $('totp-input').value.trim();
+        }
+
         try {
             $('auth-btn').disabled = true;
-            const data = await api('auth', 'POST', { pin });
-            token = data.token;
-            $('auth-section').classList.add('hidden');
-            $('pin-input').value = '';
-
-            // Show loading while fetching initial status
-            $('loading-section').classList.remove('hidden');
-
-            await refreshStatus();
-
-            $('loading-section').classList.add('hidden');
-            $('main-section').classList.remove('hidden');
-
-            // Start slow polling for background updates
-            startPolling(10000);
+            const data = await api('auth', 'POST', body);
+            await startSession(data);
         } catch (e) {
-            showMessage(e.message, true);
+            if (e.data && e.data.totp_required) {
+                // Second factor is enrolled: ask for the code and retry
+                $('totp-input').classList.remove('hidden');
+                $('totp-input').focus();
+                showMessage('Enter the code from your authenticator app');
+            } else if (e.data && e.data.username_required) {
+                $('username-input').classList.remove('hidden');
+                $('username-input').focus();
+                showMessage('Enter your username', true);
+            } else {
+                $('totp-input').value = '';
+                showMessage(e.message, true);
+            }
         } finally {
             $('auth-btn').disabled = false;
         }
@@ -280,7 +521,7 @@
             currentState = STATE.WAKING;
             updateUI(currentState, null);
 
-            await api('wol', 'POST');
+            await api(hostEndpoint('wol'), 'POST');
 
             // Start fast polling to detect when initrd comes up
             startPolling(2000);
@@ -303,6 +544,7 @@
     }
 
     function showUnlockModal() {
+        bootMode = false;
         $('unlock-modal').classList.add('active');
         $('passphrase-input').focus();
     }
@@ -321,8 +563,20 @@
 
         try {
             $('submit-unlock-btn').disabled = true;
-            await api('unlock', 'POST', { passphrase });
+            if (bootMode) {
+                await startBoot(passphrase);
+                return;
+            }
+            const result = await api(hostEndpoint('unlock'), 'POST', { passphrase });
+            if (result.result === 'rejected') {
+                $('passphrase-input').value = '';
+                showMessage('Passphrase rejected, try again', true);
+                return;
+            }
             hideUnlockModal();
+            if (result.result === 'timeout') {
+                showMessage('No response from the server, check its status', true);
+            }
 
             currentState = STATE.BOOTING;
             updateUI(currentState, null);
@@ -345,24 +599,348 @@
         }
     }
 
+    function showBootModal() {
+        showUnlockModal();
+        bootMode = true;
+    }
+
+    async function loadAudit() {
+        const params = new URLSearchParams({ offset: auditOffset, limit: AUDIT_PAGE_SIZE });
+        if ($('audit-action').value) params.set('action', $('audit-action').value);
+        if ($('audit-outcome').value) params.set('outcome', $('audit-outcome').value);
+
+        try {
+            const page = await api('audit?' + params);
+            const list = $('audit-list');
+            list.replaceChildren();
+            for (const entry of page.entries) {
+                const item = document.createElement('li');
+                if (entry.outcome === 'failure') item.className = 'failure';
+
+                const summary = document.createElement('div');
+                summary.textContent = [entry.action, entry.host, entry.outcome, entry.detail]
+                    .filter(Boolean).join(' · ');
+                const meta = document.createElement('div');
+                meta.className = 'audit-meta';
+                meta.textContent = [
+                    new Date(entry.timestamp * 1000).toLocaleString(),
+                    entry.user,
+                    entry.client_ip,
+                    entry.session_id && 'session ' + entry.session_id
+                ].filter(Boolean).join(' · ');
+
+                item.append(summary, meta);
+                list.append(item);
+            }
+            if (page.entries.length === 0) {
+                const item = document.createElement('li');
+                item.textContent = 'No entries';
+                list.append(item);
+            }
+
+            const last = Math.min(page.offset + page.entries.length, page.total);
+            $('audit-page').textContent = page.total ? `${page.offset + 1}–${last} of ${page.total}` : '';
+            $('audit-newer-btn').disabled = page.offset === 0;
+            $('audit-older-btn').disabled = last >= page.total;
+        } catch (e) {
+            showMessage(e.message, true);
+        }
+    }
+
+    function showAudit() {
+        auditOffset = 0;
+        $('audit-modal').classList.add('active');
+        loadAudit();
+    }
+
+    function hideAudit() {
+        $('audit-modal').classList.remove('active');
+    }
+
+    // WebAuthn works on binary buffers; the API exchanges them as base64url
+    function fromBase64url(value) {
+        const base64 = value.replace(/-/g, '+').replace(/_/g, '/');
+        const padded = base64 + '='.repeat((4 - base64.length % 4) % 4);
+        return Uint8Array.from(atob(padded), c => c.charCodeAt(0));
+    }
+
+    function toBase64url(buffer) {
+        return btoa(String.fromCharCode(...new Uint8Array(buffer)))
+            .replace(/\+/g, '-').replace(/\//g, '_').replace(/=+$/, '');
+    }
+
+    async function passkeyLogin() {
+        try {
+            $('passkey-login-btn').disabled = true;
+            const { publicKey } = await api('auth/passkeys/login/start', 'POST');
+            publicKey.challenge = fromBase64url(publicKey.challenge);
+            for (const cred of publicKey.allowCredentials) cred.id = fromBase64url(cred.id);
+
+            const cred = await navigator.credentials.get({ publicKey });
+            const data = await api('auth/passkeys/login/finish', 'POST', {
+                id: cred.id,
+                response: {
+                    clientDataJSON: toBase64url(cred.response.clientDataJSON),
+                    authenticatorData: toBase64url(cred.response.authenticatorData),
+                    signature: toBase64url(cred.response.signature)
+                }
+            });
+            await startSession(data);
+        } catch (e) {
+            // NotAllowedError: the user dismissed the browser prompt
+            if (e.name !== 'NotAllowedError') showMessage(e.message, true);
+        } finally {
+            $('passkey-login-btn').disabled = false;
+        }
+    }
+
+    // The passkey button only shows when the server has passkeys configured
+    async function updatePasskeyButton() {
+        let enabled = false;
+        if (window.PublicKeyCredential) {
+            try {
+                await api('auth/passkeys');
+                enabled = true;
+            } catch {
+                // 404: passkeys are not configured
+            }
+        }
+        $('passkeys-btn').classList.toggle('hidden', !enabled);
+    }
+
+    async function loadPasskeys() {
+        try {
+            const { passkeys } = await api('auth/passkeys');
+            const list = $('passkey-list');
+            list.replaceChildren();
+            for (const passkey of passkeys) {
+                const item = document.createElement('li');
+                const info = document.createElement('div');
+                const name = document.createElement('div');
+                name.textContent = passkey.name;
+                const meta = document.createElement('div');
+                meta.className = 'audit-meta';
+                meta.textContent = [
+                    'added ' + new Date(passkey.created_at * 1000).toLocaleDateString(),
+                    passkey.last_used_at &&
+                        'last used ' + new Date(passkey.last_used_at * 1000).toLocaleString()
+                ].filter(Boolean).join(' · ');
+                info.append(name, meta);
+
+                const remove = document.createElement('button');
+                remove.className = 'btn-cancel';
+                remove.textContent = 'Remove';
+                remove.addEventListener('click', () => removePasskey(passkey));
+
+                item.append(info, remove);
+                list.append(item);
+            }
+            if (passkeys.length === 0) {
+                const item = document.createElement('li');
+                item.textContent = 'No passkeys yet';
+                list.append(item);
+            }
+        } catch (e) {
+            showMessage(e.message, true);
+        }
+    }
+
+    async function addPasskey() {
+        try {
+            $('add-passkey-btn').disabled = true;
+            const { publicKey } = await api('auth/passkeys/register/start', 'POST');
+            publicKey.challenge = fromBase64url(publicKey.challenge);
+            publicKey.user.id = fromBase64url(publicKey.user.id);
+            for (const cred of publicKey.excludeCredentials) cred.id = fromBase64url(cred.id);
+
+            const cred = await navigator.credentials.create({ publicKey });
+            await api('auth/passkeys/register/finish', 'POST', {
+                id: cred.id,
+                name: $('passkey-name-input').value.trim(),
+                response: {
+                    clientDataJSON: toBase64url(cred.response.clientDataJSON),
+                    attestationObject: toBase64url(cred.response.attestationObject)
+                }
+            });
+            $('passkey-name-input').value = '';
+            showMessage('Passkey added');
+            loadPasskeys();
+        } catch (e) {
+            if (e.name !== 'NotAllowedError') showMessage(e.message, true);
+        } finally {
+            $('add-passkey-btn').disabled = false;
+        }
+    }
+
+    async function removePasskey(passkey) {
+        if (!confirm(`Remove passkey "${passkey.name}"?`)) return;
+        try {
+            await api('auth/passkeys/' + encodeURIComponent(passkey.id), 'DELETE');
+            loadPasskeys();
+        } catch (e) {
+            showMessage(e.message, true);
+        }
+    }
+
+    function showPasskeys() {
+        $('passkey-modal').classList.add('active');
+        loadPasskeys();
+    }
+
+    function hidePasskeys() {
+        $('passkey-modal').classList.remove('active');
+    }
+
+    async function loadSessions() {
+        try {
+            const { sessions } = await api('sessions');
+            const list = $('session-list');
+            list.replaceChildren();
+            for (const session of sessions) {
+                const item = document.createElement('li');
+                const info = document.createElement('div');
+                const name = document.createElement('div');
+                name.textContent = (session.user_agent || 'Unknown client') +
+                    (session.current ? ' (this session)' : '');
+                const meta = document.createElement('div');
+                meta.className = 'audit-meta';
+                meta.textContent = [
+                    session.username ? session.username + ' (' + session.role + ')' : session.role,
+                    session.client_ip,
+                    'signed in ' + new Date(session.created_at * 1000).toLocaleString(),
+                    'last used ' + new Date(session.last_used_at * 1000).toLocaleString()
+                ].join(' · ');
+                info.append(name, meta);
+                item.append(info);
+
+                if (!session.current) {
+                    const revoke = document.createElement('button');
+                    revoke.className = 'btn-cancel';
+                    revoke.textContent = 'Revoke';
+                    revoke.addEventListener('click', () => revokeSession(session));
+                    item.append(revoke);
+                }
+                list.append(item);
+            }
+        } catch (e) {
+            showMessage(e.message, true);
+        }
+    }
+
+    async function revokeSession(session) {
+        if (!confirm('Sign out this session?')) return;
+        try {
+            await api('sessions/' + encodeURIComponent(session.id), 'DELETE');
+            loadSessions();
+        } catch (e) {
+            showMessage(e.message, true);
+        }
+    }
+
+    function showSessions() {
+        $('session-modal').classList.add('active');
+        loadSessions();
+    }
+
+    function hideSessions() {
+        $('session-modal').classList.remove('active');
+    }
+
+    function showChangePin() {
+        $('pin-modal').classList.add('active');
+        $('current-pin-input').focus();
+    }
+
+    function hideChangePin() {
+        $('pin-modal').classList.remove('active');
+        for (const id of ['current-pin-input', 'new-pin-input', 'confirm-pin-input', 'pin-totp-input']) {
+            $(id).value = '';
+        }
+        $('pin-totp-input').classList.add('hidden');
+    }
+
+    // Other sessions of this account are signed out by the server
+    async function submitChangePin() {
+        const newPin = $('new-pin-input').value;
+        if (newPin !== $('confirm-pin-input').value) {
+            showMessage('The new PINs do not match', true);
+            return;
+        }
+        try {
+            $('submit-pin-btn').disabled = true;
+            const body = {
+                current_pin: $('current-pin-input').value,
+                new_pin: newPin
+            };
+            if (!$('pin-totp-input').classList.contains('hidden')) {
+                body.totp = $('pin-totp-input').value.trim();
+            }
+            const data = await api('pin', 'POST', body);
+            hideChangePin();
+            showMessage(data.sessions_ended
+                ? 'PIN changed; signed out ' + data.sessions_ended + ' other session(s)'
+                : 'PIN changed');
+        } catch (e) {
+            if (e.data && e.data.totp_required) {
+                // Same as for login: ask for the code and retry
+                $('pin-totp-input').classList.remove('hidden');
+                $('pin-totp-input').focus();
+                showMessage('Enter the code from your authenticator app');
+            } else {
+                $('pin-totp-input').value = '';
+                showMessage(e.message, true);
+            }
+        } finally {
+            $('submit-pin-btn').disabled = false;
+        }
+    }
+
+    // Ends the session on the server too, so the token is useless afterwards
+    async function signOut() {
+        try {
+            await api('logout', 'POST');
+        } catch {
+            // Already expired or revoked
+        }
+        logout();
+    }
+
     function logout() {
         token = null;
+        closeEvents();
         stopPolling();
+        stopJobPolling();
         currentState = STATE.OFFLINE;
         $('auth-section').classList.remove('hidden');
         $('main-section').classList.add('hidden');
         $('online-badge').classList.add('hidden');
+        applyScopes(null);
+        hideAudit();
+        hidePasskeys();
+        hideSessions();
+        hideChangePin();
+        // No automatic login right after logging out
+        updateLoginMethods(false);
     }
 
     // Event listeners
     $('auth-btn').addEventListener('click', authenticate);
+    $('username-input').addEventListener('keypress', e => {
+        if (e.key === 'Enter') $('pin-input').focus();
+    });
     $('pin-input').addEventListener('keypress', e => {
         if (e.key === 'Enter') authenticate();
     });
+    $('totp-input').addEventListener('keypress', e => {
+        if (e.key === 'Enter') authenticate();
+    });
+    $('passkey-login-btn').addEventListener('click', passkeyLogin);
 
     $('wol-btn').addEventListener('click', sendWol);
     $('unlock-btn').addEventListener('click', showUnlockModal);
+    $('boot-btn').addEventListener('click', showBootModal);
     $('refresh-btn').addEventListener('click', refreshStatus);
+    $('host-select').addEventListener('change', selectHost);
 
     $('submit-unlock-btn').addEventListener('click', submitUnlock);
     $('cancel-unlock-btn').addEventListener('click', hideUnlockModal);
@@ -374,6 +952,49 @@
         if (e.target === $('unlock-modal')) hideUnlockModal();
     });
 
+    $('audit-btn').addEventListener('click', showAudit);
+    $('close-audit-btn').addEventListener('click', hideAudit);
+    $('audit-modal').addEventListener('click', e => {
+        if (e.target === $('audit-modal')) hideAudit();
+    });
+    for (const filter of ['audit-action', 'audit-outcome']) {
+        $(filter).addEventListener('change', () => {
+            auditOffset = 0;
+            loadAudit();
+        });
+    }
+    $('audit-newer-btn').addEventListener('click', () => {
+        auditOffset = Math.max(0, auditOffset - AUDIT_PAGE_SIZE);
+        loadAudit();
+    });
+    $('audit-older-btn').addEventListener('click', () => {
+        auditOffset += AUDIT_PAGE_SIZE;
+        loadAudit();
+    });
+
+    $('passkeys-btn').addEventListener('click', showPasskeys);
+    $('close-passkeys-btn').addEventListener('click', hidePasskeys);
+    $('add-passkey-btn').addEventListener('click', addPasskey);
+    $('passkey-modal').addEventListener('click', e => {
+        if (e.target === $('passkey-modal')) hidePasskeys();
+    });
+
+    $('sessions-btn').addEventListener('click', showSessions);
+    $('close-sessions-btn').addEventListener('click', hideSessions);
+    $('pin-btn').addEventListener('click', showChangePin);
+    $('submit-pin-btn').addEventListener('click', submitChangePin);
+    $('cancel-pin-btn').addEventListener('click', hideChangePin);
+    $('confirm-pin-input').addEventListener('keypress', e => {
+        if (e.key === 'Enter') submitChangePin();
+    });
+    $('pin-totp-input').addEventListener('keypress', e => {
+        if (e.key === 'Enter') submitChangePin();
+    });
+    $('session-modal').addEventListener('click', e => {
+        if (e.target === $('session-modal')) hideSessions();
+    });
+    $('logout-btn').addEventListener('click', signOut);
+
     // Initialize
     init();
 })();
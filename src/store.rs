@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::auth::Session;
+use crate::util;
+
+#[derive(Debug, Error)]
+pub enum StoreError {
+    #[error("Failed to read state file {path}: {reason}")]
+    ReadFailed { path: PathBuf, reason: String },
+    #[error("Failed to write state file {path}: {reason}")]
+    WriteFailed { path: PathBuf, reason: String },
+}
+
+/// Failed logins from one client.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FailureRecord {
+    pub count: u32,
+    /// Unix timestamp in seconds
+    pub last_failure: u64,
+    /// Of `count`, the failed PIN logins still in `failures_since_login`
+    #[serde(default)]
+    pub pin_count: u32,
+}
+
+/// Login state that should outlive the process: sessions, failed logins and
+/// the last accepted TOTP steps.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AuthState {
+    /// Keyed by [`hash_token`] of the bearer token, never the token itself
+    #[serde(default)]
+    pub sessions: HashMap<String, Session>,
+    #[serde(default)]
+    pub failures: HashMap<IpAddr, FailureRecord>,
+    /// Unix timestamps in seconds of recent failed PIN logins from any client
+    #[serde(default)]
+    pub pin_failures: Vec<u64>,
+    /// Failed PIN logins towards the lockout. A client that logs in takes
+    /// its own off, so it cannot wipe out those of others.
+    #[serde(default)]
+    pub failures_since_login: u32,
+    /// Last accepted TOTP step of each account, by username and `""` for
+    /// the shared one. All accounts share the secret, so each may use a
+    /// code once without turning away another that logs in at the same time.
+    #[serde(default)]
+    pub last_totp_steps: HashMap<String, u64>,
+}
+
+impl AuthState {
+    /// Drops expired sessions and failures older than `retention` seconds.
+    pub fn compact(&mut self, now: u64, retention: u64) {
+        self.sessions.retain(|_, s| s.expires_at > now);
+        self.failures
+            .retain(|_, record| record.last_failure + retention > now);
+        self.pin_failures.retain(|&t| t + retention > now);
+    }
+}
+
+/// Where [`AuthState`] is kept between restarts.
+pub trait StateStore: Send + Sync {
+    /// The saved state, or an empty one if nothing was saved yet.
+    fn load(&self) -> Result<AuthState, StoreError>;
+    fn save(&self, state: &AuthState) -> Result<(), StoreError>;
+}
+
+/// Keeps nothing; a restart starts from scratch.
+pub struct MemoryStore;
+
+impl StateStore for MemoryStore {
+    fn load(&self) -> Result<AuthState, StoreError> {
+        Ok(AuthState::default())
+    }
+
+    fn save(&self, _state: &AuthState) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
+
+/// A JSON file, replaced atomically on every save.
+pub struct JsonFileStore {
+    path: PathBuf,
+}
+
+impl JsonFileStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        JsonFileStore { path: path.into() }
+    }
+}
+
+impl StateStore for JsonFileStore {
+    fn load(&self) -> Result<AuthState, StoreError> {
+        let read_failed = |reason: String| StoreError::ReadFailed {
+            path: self.path.clone(),
+            reason,
+        };
+        match fs::read(&self.path) {
+            Ok(content) => serde_json::from_slice(&content).map_err(|e| read_failed(e.to_string())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(AuthState::default()),
+            Err(e) => Err(read_failed(e.to_string())),
+        }
+    }
+
+    /// Writes a temporary file readable only by its owner and renames it
+    /// over the old one, so a crash never leaves a half-written file.
+    fn save(&self, state: &AuthState) -> Result<(), StoreError> {
+        let write_failed = |e: io::Error| StoreError::WriteFailed {
+            path: self.path.clone(),
+            reason: e.to_string(),
+        };
+        let content = serde_json::to_vec(state).map_err(|e| write_failed(e.into()))?;
+        util::write_atomic(&self.path, &content, 0o600).map_err(write_failed)
+    }
+}
+
+/// The store for `config.state_path`: a JSON file if set, memory otherwise.
+pub fn open(path: Option<&Path>) -> Box<dyn StateStore> {
+    match path {
+        Some(path) => Box::new(JsonFileStore::new(path)),
+        None => Box::new(MemoryStore),
+    }
+}
+
+/// SHA-256 of a bearer token, hex encoded. Tokens are 32 random bytes, so a
+/// fast unsalted hash is enough to make a leaked state file useless.
+pub fn hash_token(token: &str) -> String {
+    hex::encode(digest::digest(&digest::SHA256, token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::ClientInfo;
+    use crate::users::Role;
+    use std::net::Ipv4Addr;
+
+    fn session(id: &str, expires_at: u64) -> Session {
+        Session {
+            id: id.to_string(),
+            client: ClientInfo {
+                ip: IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10)),
+                user_agent: Some("test".to_string()),
+            },
+            username: Some("alice".to_string()),
+            role: Role::Operator,
+            created_at: 1_000,
+            last_used_at: 1_000,
+            expires_at,
+            max_expires_at: expires_at + 3_600,
+        }
+    }
+
+    #[test]
+    fn test_hash_token() {
+        let hash = hash_token("abc");
+        assert_eq!(
+            hash,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_ne!(hash_token("abd"), hash);
+    }
+
+    #[test]
+    fn test_missing_file_is_empty() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let store = JsonFileStore::new(dir.path().join("state.json"));
+        let state = store.load().unwrap();
+        assert!(state.sessions.is_empty());
+        assert!(state.failures.is_empty());
+        assert!(state.last_totp_steps.is_empty());
+    }
+
+    #[test]
+    fn test_file_round_trip() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("state.json");
+        let store = JsonFileStore::new(&path);
+
+        let mut state = AuthState::default();
+        state
+            .sessions
+            .insert(hash_token("token"), session("0123456789abcdef", 2_000));
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let record = FailureRecord {
+            count: 3,
+            last_failure: 1_010,
+            pin_count: 2,
+        };
+        state.failures.insert(ip, record);
+        state.pin_failures = vec![1_000, 1_010];
+        state.failures_since_login = 3;
+        state.last_totp_steps.insert("alice".to_string(), 42);
+        store.save(&state).unwrap();
+
+        let loaded = store.load().unwrap();
+        let saved = &loaded.sessions[&hash_token("token")];
+        assert_eq!(saved.id, "0123456789abcdef");
+        assert_eq!(saved.expires_at, 2_000);
+        assert_eq!(saved.client.user_agent.as_deref(), Some("test"));
+        assert_eq!(saved.username.as_deref(), Some("alice"));
+        assert_eq!(saved.role, Role::Operator);
+        assert_eq!(loaded.failures[&ip], record);
+        assert_eq!(loaded.pin_failures, vec![1_000, 1_010]);
+        assert_eq!(loaded.failures_since_login, 3);
+        assert_eq!(loaded.last_totp_steps["alice"], 42);
+
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(!content.contains("\"token\""));
+        // Only the file itself, no temporary file
+        assert_eq!(fs::read_dir(path.parent().unwrap()).unwrap().count(), 1);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn test_sessions_from_before_users_are_admin() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("state.json");
+        let old = r#"{"sessions": {"abc": {"id": "0123456789abcdef", "ip": "10.0.0.1",
+            "user_agent": null, "created_at": 1000, "last_used_at": 1000,
+            "expires_at": 2000, "max_expires_at": 5000}}}"#;
+        fs::write(&path, old).unwrap();
+
+        let loaded = JsonFileStore::new(&path).load().unwrap();
+        let session = &loaded.sessions["abc"];
+        assert_eq!(session.username, None);
+        assert_eq!(session.role, Role::Admin);
+    }
+
+    #[test]
+    fn test_corrupt_file_is_an_error() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("state.json");
+        fs::write(&path, "{not json").unwrap();
+        assert!(matches!(
+            JsonFileStore::new(&path).load(),
+            Err(StoreError::ReadFailed { .. })
+        ));
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut state = AuthState::default();
+        state.sessions.insert("a".to_string(), session("a", 999));
+        state.sessions.insert("b".to_string(), session("b", 1_001));
+        let old = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let recent = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        for (ip, last_failure) in [(old, 930), (recent, 990)] {
+            let record = FailureRecord {
+                count: 2,
+                last_failure,
+                pin_count: 0,
+            };
+            state.failures.insert(ip, record);
+        }
+        state.pin_failures = vec![930, 990];
+
+        state.compact(1_000, 60);
+        assert_eq!(state.sessions.len(), 1);
+        assert!(state.sessions.contains_key("b"));
+        assert!(!state.failures.contains_key(&old));
+        assert!(state.failures.contains_key(&recent));
+        assert_eq!(state.pin_failures, vec![990]);
+    }
+}
@@ -0,0 +1,545 @@
+use std::any::Any;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use actix_tls::accept::rustls_0_23::TlsStream;
+use rcgen::{
+    BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    KeyUsagePurpose,
+};
+use ring::digest;
+use rustls::RootCertStore;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use thiserror::Error;
+use tokio::net::TcpStream;
+
+use crate::config::{ClientAuth, TlsConfig};
+use crate::util;
+
+/// How long the client CA created by [`issue_client_cert`] is valid
+const CLIENT_CA_VALIDITY_DAYS: i64 = 20 * 365;
+
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("Failed to read {path}: {reason}")]
+    ReadFailed { path: PathBuf, reason: String },
+    #[error("Failed to write {path}: {reason}")]
+    WriteFailed { path: PathBuf, reason: String },
+    #[error("Invalid certificate or key in {path}: {reason}")]
+    Invalid { path: PathBuf, reason: String },
+    #[error("{0} exists but {1} does not; provide both or neither")]
+    Incomplete(PathBuf, PathBuf),
+    #[error("Failed to generate certificate: {0}")]
+    GenerateFailed(String),
+    #[error("Invalid client name {0:?}: use letters, digits, '-', '_' and '.'")]
+    InvalidName(String),
+}
+
+/// Makes sure a certificate and key exist at the configured paths. If
+/// neither does, a self-signed certificate for `names` (host names or IP
+/// addresses) is generated and saved there. Returns whether one was generated.
+pub fn ensure_certificate(config: &TlsConfig, names: &[String]) -> Result<bool, TlsError> {
+    let exists = |path: &Path| {
+        path.try_exists().map_err(|e| TlsError::ReadFailed {
+            path: path.to_path_buf(),
+            reason: e.to_string(),
+        })
+    };
+    match (exists(&config.cert_path)?, exists(&config.key_path)?) {
+        (true, true) => return Ok(false),
+        (true, false) => {
+            return Err(TlsError::Incomplete(
+                config.cert_path.clone(),
+                config.key_path.clone(),
+            ));
+        }
+        (false, true) => {
+            return Err(TlsError::Incomplete(
+                config.key_path.clone(),
+                config.cert_path.clone(),
+            ));
+        }
+        (false, false) => {}
+    }
+
+    let generated = rcgen::generate_simple_self_signed(names.to_vec())
+        .map_err(|e| TlsError::GenerateFailed(e.to_string()))?;
+    // Key first: a certificate without its key would block the next start
+    write_atomic(&config.key_path, &generated.key_pair.serialize_pem(), 0o600)?;
+    write_atomic(&config.cert_path, &generated.cert.pem(), 0o644)?;
+    Ok(true)
+}
+
+/// [`util::write_atomic`], failing with a [`TlsError`].
+fn write_atomic(path: &Path, content: &str, mode: u32) -> Result<(), TlsError> {
+    util::write_atomic(path, content.as_bytes(), mode).map_err(|e| TlsError::WriteFailed {
+        path: path.to_path_buf(),
+        reason: e.to_string(),
+    })
+}
+
+/// Reads a PEM certificate chain and private key and checks that they match.
+pub fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, TlsError> {
+    let invalid = |path: &Path, reason: String| TlsError::Invalid {
+        path: path.to_path_buf(),
+        reason,
+    };
+    let read = |path: &Path| {
+        fs::read(path).map_err(|e| TlsError::ReadFailed {
+            path: path.to_path_buf(),
+            reason: e.to_string(),
+        })
+    };
+
+    let certs = CertificateDer::pem_slice_iter(&read(cert_path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| invalid(cert_path, e.to_string()))?;
+    if certs.is_empty() {
+        return Err(invalid(cert_path, "no certificate found".to_string()));
+    }
+    let key = PrivateKeyDer::from_pem_slice(&read(key_path)?)
+        .map_err(|e| invalid(key_path, e.to_string()))?;
+
+    CertifiedKey::from_der(certs, key, &provider()).map_err(|e| invalid(key_path, e.to_string()))
+}
+
+fn provider() -> CryptoProvider {
+    rustls::crypto::ring::default_provider()
+}
+
+/// Serves the current certificate and swaps in a new one on [`reload`].
+///
+/// [`reload`]: CertResolver::reload
+#[derive(Debug)]
+pub struct CertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    pub fn load(config: &TlsConfig) -> Result<Self, TlsError> {
+        let key = load_certified_key(&config.cert_path, &config.key_path)?;
+        Ok(CertResolver {
+            cert_path: config.cert_path.clone(),
+            key_path: config.key_path.clone(),
+            current: RwLock::new(Arc::new(key)),
+        })
+    }
+
+    /// Re-reads the certificate and key. On error the old pair stays in use.
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let key = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = Arc::new(key);
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// rustls settings for the HTTPS listener, asking for client certificates
+/// signed by `client_ca_path` unless `client_auth` is off.
+pub fn server_config(
+    resolver: Arc<CertResolver>,
+    config: &TlsConfig,
+) -> Result<rustls::ServerConfig, TlsError> {
+    let provider = Arc::new(provider());
+    let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| TlsError::Invalid {
+            path: resolver.cert_path.clone(),
+            reason: e.to_string(),
+        })?;
+    if config.client_auth == ClientAuth::Off {
+        return Ok(builder.with_no_client_auth().with_cert_resolver(resolver));
+    }
+
+    let invalid = |reason: String| TlsError::Invalid {
+        path: config.client_ca_path.clone(),
+        reason,
+    };
+    let pem = fs::read(&config.client_ca_path).map_err(|e| TlsError::ReadFailed {
+        path: config.client_ca_path.clone(),
+        reason: e.to_string(),
+    })?;
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_slice_iter(&pem) {
+        let cert = cert.map_err(|e| invalid(e.to_string()))?;
+        roots.add(cert).map_err(|e| invalid(e.to_string()))?;
+    }
+    if roots.is_empty() {
+        return Err(invalid("no certificate found".to_string()));
+    }
+
+    let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+    let verifier = match config.client_auth {
+        ClientAuth::Optional => verifier.allow_unauthenticated(),
+        _ => verifier,
+    }
+    .build()
+    .map_err(|e| invalid(e.to_string()))?;
+    Ok(builder
+        .with_client_cert_verifier(verifier)
+        .with_cert_resolver(resolver))
+}
+
+/// A client certificate that passed verification during the TLS handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientCert {
+    /// Common name, as given to `articwake issue-client-cert`
+    pub name: String,
+    /// SHA-256 of the DER certificate, lowercase hex
+    pub fingerprint: String,
+}
+
+impl ClientCert {
+    pub fn from_der(der: &[u8]) -> Self {
+        let name = x509_parser::parse_x509_certificate(der)
+            .ok()
+            .and_then(|(_, cert)| {
+                cert.subject()
+                    .iter_common_name()
+                    .next()
+                    .and_then(|cn| cn.as_str().ok())
+                    .map(String::from)
+            })
+            .unwrap_or_else(|| "unknown".to_string());
+        ClientCert {
+            name,
+            fingerprint: fingerprint(der),
+        }
+    }
+}
+
+/// The verified client certificate of an HTTPS connection, for
+/// `HttpServer::on_connect`. `None` for plain HTTP or without a certificate.
+pub fn peer_client_cert(connection: &dyn Any) -> Option<ClientCert> {
+    let stream = connection.downcast_ref::<TlsStream<TcpStream>>()?;
+    let (_, session) = stream.get_ref();
+    let leaf = session.peer_certificates()?.first()?;
+    Some(ClientCert::from_der(leaf))
+}
+
+/// SHA-256 of a DER certificate, lowercase hex, as `openssl x509 -fingerprint
+/// -sha256` prints it without the colons.
+pub fn fingerprint(der: &[u8]) -> String {
+    hex::encode(digest::digest(&digest::SHA256, der))
+}
+
+/// A client certificate made by [`issue_client_cert`].
+pub struct IssuedCert {
+    pub cert_pem: String,
+    pub key_pem: String,
+    pub fingerprint: String,
+    /// Whether the client CA had to be created first
+    pub created_ca: bool,
+}
+
+/// Issues a client certificate for `name`, valid for `days`, from the CA at
+/// `client_ca_path`. The CA is created first if neither of its files exists.
+pub fn issue_client_cert(
+    config: &TlsConfig,
+    name: &str,
+    days: u32,
+) -> Result<IssuedCert, TlsError> {
+    let valid_name = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid_name {
+        return Err(TlsError::InvalidName(name.to_string()));
+    }
+    let generate_failed = |e: rcgen::Error| TlsError::GenerateFailed(e.to_string());
+
+    let created_ca = ensure_client_ca(config)?;
+    let read = |path: &Path| {
+        fs::read_to_string(path).map_err(|e| TlsError::ReadFailed {
+            path: path.to_path_buf(),
+            reason: e.to_string(),
+        })
+    };
+    let ca_key =
+        KeyPair::from_pem(&read(&config.client_ca_key_path)?).map_err(|e| TlsError::Invalid {
+            path: config.client_ca_key_path.clone(),
+            reason: e.to_string(),
+        })?;
+    // Rebuilt from the saved CA so it can sign; subject and key are unchanged
+    let ca_cert = CertificateParams::from_ca_cert_pem(&read(&config.client_ca_path)?)
+        .and_then(|params| params.self_signed(&ca_key))
+        .map_err(|e| TlsError::Invalid {
+            path: config.client_ca_path.clone(),
+            reason: e.to_string(),
+        })?;
+
+    let now = time::OffsetDateTime::now_utc();
+    let mut params = CertificateParams::new(Vec::<String>::new()).map_err(generate_failed)?;
+    params.distinguished_name.push(DnType::CommonName, name);
+    params.is_ca = IsCa::ExplicitNoCa;
+    params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    params.not_before = now - time::Duration::minutes(5);
+    params.not_after = now + time::Duration::days(days.into());
+    let key = KeyPair::generate().map_err(generate_failed)?;
+    let cert = params
+        .signed_by(&key, &ca_cert, &ca_key)
+        .map_err(generate_failed)?;
+
+    Ok(IssuedCert {
+        fingerprint: fingerprint(cert.der()),
+        cert_pem: cert.pem(),
+        key_pem: key.serialize_pem(),
+        created_ca,
+    })
+}
+
+/// Creates the client CA unless it exists. Returns whether it was created.
+fn ensure_client_ca(config: &TlsConfig) -> Result<bool, TlsError> {
+    let (cert_path, key_path) = (&config.client_ca_path, &config.client_ca_key_path);
+    match (cert_path.exists(), key_path.exists()) {
+        (true, true) => return Ok(false),
+        (true, false) => return Err(TlsError::Incomplete(cert_path.clone(), key_path.clone())),
+        (false, true) => return Err(TlsError::Incomplete(key_path.clone(), cert_path.clone())),
+        (false, false) => {}
+    }
+
+    let generate_failed = |e: rcgen::Error| TlsError::GenerateFailed(e.to_string());
+    let now = time::OffsetDateTime::now_utc();
+    let mut params = CertificateParams::new(Vec::<String>::new()).map_err(generate_failed)?;
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "articwake client CA");
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    params.not_before = now - time::Duration::minutes(5);
+    params.not_after = now + time::Duration::days(CLIENT_CA_VALIDITY_DAYS);
+    let key = KeyPair::generate().map_err(generate_failed)?;
+    let cert = params.self_signed(&key).map_err(generate_failed)?;
+
+    write_atomic(key_path, &key.serialize_pem(), 0o600)?;
+    write_atomic(cert_path, &cert.pem(), 0o644)?;
+    Ok(true)
+}
+
+/// Where a plain HTTP request for `host` and `path` should go on the HTTPS
+/// port. Any port in `host` is replaced.
+pub fn redirect_url(host: &str, https_port: u16, path: &str) -> String {
+    let name = match host.rsplit_once(':') {
+        // Bracketed IPv6 without a port, e.g. `[::1]`
+        Some((_, port)) if port.ends_with(']') => host,
+        Some((name, _)) => name,
+        None => host,
+    };
+    if https_port == 443 {
+        format!("https://{}{}", name, path)
+    } else {
+        format!("https://{}:{}{}", name, https_port, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(dir: &Path) -> TlsConfig {
+        TlsConfig {
+            enabled: true,
+            cert_path: dir.join("tls/cert.pem"),
+            key_path: dir.join("tls/key.pem"),
+            client_ca_path: dir.join("tls/client-ca.pem"),
+            client_ca_key_path: dir.join("tls/client-ca.key"),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_generate_and_load() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let config = config(dir.path());
+        let names = vec!["articwake".to_string(), "192.168.1.5".to_string()];
+
+        assert!(ensure_certificate(&config, &names).unwrap());
+        assert!(load_certified_key(&config.cert_path, &config.key_path).is_ok());
+
+        // The saved pair is reused
+        let cert = fs::read(&config.cert_path).unwrap();
+        assert!(!ensure_certificate(&config, &names).unwrap());
+        assert_eq!(fs::read(&config.cert_path).unwrap(), cert);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&config.key_path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn test_missing_key_is_an_error() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let config = config(dir.path());
+        ensure_certificate(&config, &["articwake".to_string()]).unwrap();
+        fs::remove_file(&config.key_path).unwrap();
+        assert!(matches!(
+            ensure_certificate(&config, &["articwake".to_string()]),
+            Err(TlsError::Incomplete(..))
+        ));
+    }
+
+    #[test]
+    fn test_mismatched_key_is_rejected() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let first = config(dir.path());
+        ensure_certificate(&first, &["articwake".to_string()]).unwrap();
+        let other = TlsConfig {
+            cert_path: dir.path().join("other/cert.pem"),
+            key_path: dir.path().join("other/key.pem"),
+            ..first.clone()
+        };
+        ensure_certificate(&other, &["articwake".to_string()]).unwrap();
+
+        assert!(matches!(
+            load_certified_key(&first.cert_path, &other.key_path),
+            Err(TlsError::Invalid { .. })
+        ));
+        fs::write(&first.cert_path, "not a certificate").unwrap();
+        assert!(load_certified_key(&first.cert_path, &first.key_path).is_err());
+    }
+
+    #[test]
+    fn test_reload_keeps_old_pair_on_error() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let config = config(dir.path());
+        ensure_certificate(&config, &["articwake".to_string()]).unwrap();
+        let resolver = CertResolver::load(&config).unwrap();
+        let before = resolver.current.read().unwrap().clone();
+
+        fs::write(&config.cert_path, "garbage").unwrap();
+        assert!(resolver.reload().is_err());
+        assert!(Arc::ptr_eq(&before, &resolver.current.read().unwrap()));
+
+        fs::remove_file(&config.cert_path).unwrap();
+        fs::remove_file(&config.key_path).unwrap();
+        ensure_certificate(&config, &["articwake".to_string()]).unwrap();
+        resolver.reload().unwrap();
+        assert!(!Arc::ptr_eq(&before, &resolver.current.read().unwrap()));
+    }
+
+    #[test]
+    fn test_server_config() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let config = config(dir.path());
+        ensure_certificate(&config, &["articwake".to_string()]).unwrap();
+        let resolver = Arc::new(CertResolver::load(&config).unwrap());
+        assert!(server_config(resolver, &config).is_ok());
+    }
+
+    #[test]
+    fn test_issue_client_cert() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let config = TlsConfig {
+            client_auth: ClientAuth::Required,
+            ..config(dir.path())
+        };
+
+        let first = issue_client_cert(&config, "phone", 30).unwrap();
+        assert!(first.created_ca);
+        let ca = fs::read(&config.client_ca_path).unwrap();
+        let second = issue_client_cert(&config, "laptop", 30).unwrap();
+        assert!(!second.created_ca);
+        assert_eq!(fs::read(&config.client_ca_path).unwrap(), ca);
+        assert_ne!(first.fingerprint, second.fingerprint);
+
+        let der = CertificateDer::from_pem_slice(second.cert_pem.as_bytes()).unwrap();
+        assert_eq!(
+            ClientCert::from_der(&der),
+            ClientCert {
+                name: "laptop".to_string(),
+                fingerprint: second.fingerprint.clone(),
+            }
+        );
+
+        // Both are accepted by the verifier built from the saved CA
+        let roots = Arc::new({
+            let mut roots = RootCertStore::empty();
+            roots
+                .add(CertificateDer::from_pem_slice(&ca).unwrap())
+                .unwrap();
+            roots
+        });
+        let verifier = WebPkiClientVerifier::builder_with_provider(roots, Arc::new(provider()))
+            .build()
+            .unwrap();
+        for issued in [&first, &second] {
+            let der = CertificateDer::from_pem_slice(issued.cert_pem.as_bytes()).unwrap();
+            verifier
+                .verify_client_cert(&der, &[], rustls::pki_types::UnixTime::now())
+                .unwrap();
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&config.client_ca_key_path)
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn test_issue_client_cert_rejects_bad_names() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let config = config(dir.path());
+        for name in ["", "../phone", ".hidden", "my phone", "a/b"] {
+            assert!(matches!(
+                issue_client_cert(&config, name, 30),
+                Err(TlsError::InvalidName(_))
+            ));
+        }
+        // Nothing was created for them
+        assert!(!config.client_ca_path.exists());
+    }
+
+    #[test]
+    fn test_server_config_needs_client_ca() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let config = TlsConfig {
+            client_auth: ClientAuth::Optional,
+            ..config(dir.path())
+        };
+        ensure_certificate(&config, &["articwake".to_string()]).unwrap();
+        let resolver = Arc::new(CertResolver::load(&config).unwrap());
+        assert!(matches!(
+            server_config(resolver.clone(), &config),
+            Err(TlsError::ReadFailed { .. })
+        ));
+
+        issue_client_cert(&config, "phone", 30).unwrap();
+        assert!(server_config(resolver, &config).is_ok());
+    }
+
+    #[test]
+    fn test_redirect_url() {
+        assert_eq!(
+            redirect_url("pi.local", 443, "/api/status?x=1"),
+            "https://pi.local/api/status?x=1"
+        );
+        assert_eq!(
+            redirect_url("pi.local:80", 8443, "/"),
+            "https://pi.local:8443/"
+        );
+        assert_eq!(redirect_url("[::1]:80", 443, "/"), "https://[::1]/");
+        assert_eq!(redirect_url("[::1]", 443, "/"), "https://[::1]/");
+    }
+}
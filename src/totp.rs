@@ -0,0 +1,220 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+use crate::util;
+
+/// RFC 6238 defaults, which every authenticator app supports
+pub const STEP_SECS: u64 = 30;
+pub const DIGITS: u32 = 6;
+const SECRET_LEN: usize = 20;
+
+/// Steps accepted on either side of the current one, for clock drift
+const SKEW_STEPS: u64 = 1;
+
+pub fn generate_secret() -> Vec<u8> {
+    util::random_bytes::<SECRET_LEN>().to_vec()
+}
+
+pub fn encode_secret(secret: &[u8]) -> String {
+    BASE32_NOPAD.encode(secret)
+}
+
+/// Decodes a base32 secret, ignoring case, spaces and padding.
+pub fn decode_secret(encoded: &str) -> Option<Vec<u8>> {
+    let normalized: String = encoded
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '=')
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    BASE32_NOPAD
+        .decode(normalized.as_bytes())
+        .ok()
+        .filter(|secret| !secret.is_empty())
+}
+
+/// Reads the enrolled secret. `Ok(None)` means TOTP is not enrolled.
+pub fn read_secret(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    match fs::read_to_string(path) {
+        Ok(content) => decode_secret(&content).map(Some).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "invalid base32 TOTP secret")
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Writes a new secret atomically, readable only by its owner. Fails with
+/// `AlreadyExists` if one exists, unless `overwrite`.
+pub fn write_secret(path: &Path, secret: &[u8], overwrite: bool) -> io::Result<()> {
+    if !overwrite && path.try_exists()? {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "TOTP secret already exists",
+        ));
+    }
+    util::write_atomic(
+        path,
+        format!("{}\n", encode_secret(secret)).as_bytes(),
+        0o600,
+    )
+}
+
+/// `otpauth://` URI for enrolling the secret in an authenticator app.
+pub fn otpauth_uri(secret: &[u8], issuer: &str, account: &str) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={STEP_SECS}",
+        issuer = percent_encode(issuer),
+        account = percent_encode(account),
+        secret = encode_secret(secret),
+    )
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// HOTP value (RFC 4226) for `counter`, truncated to [`DIGITS`] digits.
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    value % 10u32.pow(DIGITS)
+}
+
+/// The code an authenticator app shows during `step`.
+pub fn code(secret: &[u8], step: u64) -> String {
+    format!("{:0width$}", hotp(secret, step), width = DIGITS as usize)
+}
+
+pub fn current_step() -> u64 {
+    util::unix_now() / STEP_SECS
+}
+
+/// Checks `code` against the steps around `step`. Returns the matching step
+/// so the caller can refuse to accept it (or an earlier one) again.
+pub fn verify(secret: &[u8], code: &str, step: u64) -> Option<u64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+
+    (step.saturating_sub(SKEW_STEPS)..=step + SKEW_STEPS)
+        .find(|&candidate| hotp(secret, candidate) == code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Secret of the RFC 6238 SHA-1 test vectors
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn test_rfc6238_vectors() {
+        // The RFC lists 8 digit codes; ours are the last 6 digits
+        for (time, code) in [
+            (59, 287082),
+            (1111111109, 81804),
+            (1111111111, 50471),
+            (1234567890, 5924),
+            (2000000000, 279037),
+        ] {
+            assert_eq!(hotp(RFC_SECRET, time / STEP_SECS), code, "t={}", time);
+        }
+    }
+
+    #[test]
+    fn test_code_is_zero_padded() {
+        assert_eq!(code(RFC_SECRET, 1234567890 / STEP_SECS), "005924");
+    }
+
+    #[test]
+    fn test_verify_window() {
+        let step = 1234567890 / STEP_SECS;
+        assert_eq!(verify(RFC_SECRET, "005924", step), Some(step));
+        assert_eq!(verify(RFC_SECRET, " 005924\n", step), Some(step));
+        // One step of drift either way is accepted
+        assert_eq!(verify(RFC_SECRET, "005924", step + 1), Some(step));
+        assert_eq!(verify(RFC_SECRET, "005924", step - 1), Some(step));
+        assert_eq!(verify(RFC_SECRET, "005924", step + 2), None);
+    }
+
+    #[test]
+    fn test_verify_rejects_malformed_codes() {
+        let step = 1234567890 / STEP_SECS;
+        assert_eq!(verify(RFC_SECRET, "5924", step), None);
+        assert_eq!(verify(RFC_SECRET, "+05924", step), None);
+        assert_eq!(verify(RFC_SECRET, "0059245", step), None);
+        assert_eq!(verify(RFC_SECRET, "", step), None);
+    }
+
+    #[test]
+    fn test_secret_encoding() {
+        let encoded = encode_secret(RFC_SECRET);
+        assert_eq!(encoded, "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+        assert_eq!(decode_secret(&encoded).unwrap(), RFC_SECRET);
+        assert_eq!(
+            decode_secret("gezd gnbv gy3t qojq gezd gnbv gy3t qojq\n").unwrap(),
+            RFC_SECRET
+        );
+        assert!(decode_secret("not base32!").is_none());
+        assert!(decode_secret("").is_none());
+    }
+
+    #[test]
+    fn test_secret_file() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("totp.secret");
+        assert!(read_secret(&path).unwrap().is_none());
+
+        let secret = generate_secret();
+        write_secret(&path, &secret, false).unwrap();
+        assert_eq!(read_secret(&path).unwrap().unwrap(), secret);
+
+        // Enrolling again must be explicit
+        let err = write_secret(&path, &generate_secret(), false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(read_secret(&path).unwrap().unwrap(), secret);
+        write_secret(&path, RFC_SECRET, true).unwrap();
+        assert_eq!(read_secret(&path).unwrap().unwrap(), RFC_SECRET);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn test_otpauth_uri() {
+        let uri = otpauth_uri(RFC_SECRET, "articwake", "pi@home");
+        assert_eq!(
+            uri,
+            "otpauth://totp/articwake:pi%40home?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&issuer=articwake&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+}
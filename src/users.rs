@@ -0,0 +1,474 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use argon2::PasswordHash;
+use argon2::password_hash::{SaltString, rand_core::OsRng};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
+
+use crate::apikeys::Scope;
+use crate::config::Argon2Config;
+use crate::pin::{self, PinError};
+use crate::util::{self, FileCache, unix_now};
+
+pub const MAX_USERNAME_LEN: usize = 32;
+
+#[derive(Debug, Error)]
+pub enum UserError {
+    #[error("Failed to read {0}: {1}")]
+    ReadFailed(PathBuf, String),
+    #[error("Failed to write {0}: {1}")]
+    WriteFailed(PathBuf, String),
+    #[error("Unknown role {0:?} (expected viewer, operator, unlocker or admin)")]
+    UnknownRole(String),
+    #[error(
+        "Invalid username {0:?}: 1 to 32 lowercase letters, digits, '.', '_' or '-', starting with a letter or digit"
+    )]
+    InvalidUsername(String),
+    #[error("User {0} already exists")]
+    Exists(String),
+    #[error("No user named {0}")]
+    NotFound(String),
+    #[error("PIN cannot be empty")]
+    EmptyPin,
+    #[error(transparent)]
+    Pin(#[from] PinError),
+}
+
+/// What a user may do. Each role includes the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Role {
+    /// Host list and status
+    Viewer,
+    /// Also wakes hosts
+    Operator,
+    /// Also sends the passphrase
+    Unlocker,
+    /// Everything, including sessions, passkeys and the audit log
+    Admin,
+}
+
+impl Role {
+    pub const ALL: [Role; 4] = [Role::Viewer, Role::Operator, Role::Unlocker, Role::Admin];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Operator => "operator",
+            Role::Unlocker => "unlocker",
+            Role::Admin => "admin",
+        }
+    }
+
+    /// The scopes a session with this role holds.
+    pub fn scopes(self) -> Vec<Scope> {
+        match self {
+            Role::Viewer => vec![Scope::StatusRead],
+            Role::Operator => vec![Scope::StatusRead, Scope::Wol],
+            Role::Unlocker => vec![Scope::StatusRead, Scope::Wol, Scope::Unlock],
+            Role::Admin => vec![Scope::Admin],
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Role {
+    type Err = UserError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Role::ALL
+            .into_iter()
+            .find(|role| role.as_str() == s)
+            .ok_or_else(|| UserError::UnknownRole(s.to_string()))
+    }
+}
+
+impl Serialize for Role {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Role {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// A stored user. Only the Argon2 hash of the PIN is kept.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    pub username: String,
+    pub role: Role,
+    /// Unix timestamp in seconds
+    pub created_at: u64,
+    hash: String,
+}
+
+impl User {
+    fn verify_pin(&self, pin: &str) -> bool {
+        matches_hash(&self.hash, pin)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct UserFile {
+    users: Vec<User>,
+}
+
+/// User accounts in a JSON file. The accounts are cached, and read again
+/// when the file changes, so users added or changed with `articwake user`
+/// take effect within a second, without a restart.
+pub struct Users {
+    file: FileCache<UserFile>,
+    /// Settings new PIN hashes are made with
+    argon2: Argon2Config,
+    /// Hash of a random PIN, checked against when the username is unknown.
+    /// Made up front so the first such login takes no longer than the rest.
+    dummy_hash: String,
+    /// Serializes read-modify-write cycles of the file
+    writes: Mutex<()>,
+}
+
+impl Users {
+    pub fn new(path: PathBuf, argon2: Argon2Config) -> Self {
+        let salt = SaltString::generate(&mut OsRng);
+        let dummy_hash = pin::hash(salt.as_str(), &argon2).unwrap_or_default();
+        Users {
+            file: FileCache::new(path),
+            argon2,
+            dummy_hash,
+            writes: Mutex::new(()),
+        }
+    }
+
+    pub fn list(&self) -> Result<Vec<User>, UserError> {
+        Ok(self.load()?.users.clone())
+    }
+
+    pub fn find(&self, username: &str) -> Result<Option<User>, UserError> {
+        Ok(self
+            .load()?
+            .users
+            .iter()
+            .find(|user| user.username == username)
+            .cloned())
+    }
+
+    pub fn add(&self, username: &str, role: Role, pin: &str) -> Result<User, UserError> {
+        if !valid_username(username) {
+            return Err(UserError::InvalidUsername(username.to_string()));
+        }
+        let _guard = self.writes.lock().unwrap();
+        let mut file = UserFile::clone(&*self.load()?);
+        if file.users.iter().any(|user| user.username == username) {
+            return Err(UserError::Exists(username.to_string()));
+        }
+        let user = User {
+            username: username.to_string(),
+            role,
+            created_at: unix_now(),
+            hash: self.hash_pin(pin)?,
+        };
+        file.users.push(user.clone());
+        self.save(&file)?;
+        Ok(user)
+    }
+
+    pub fn remove(&self, username: &str) -> Result<User, UserError> {
+        let _guard = self.writes.lock().unwrap();
+        let mut file = UserFile::clone(&*self.load()?);
+        let index = file
+            .users
+            .iter()
+            .position(|user| user.username == username)
+            .ok_or_else(|| UserError::NotFound(username.to_string()))?;
+        let removed = file.users.remove(index);
+        self.save(&file)?;
+        Ok(removed)
+    }
+
+    pub fn set_role(&self, username: &str, role: Role) -> Result<(), UserError> {
+        self.update(username, |user| {
+            user.role = role;
+            Ok(())
+        })
+    }
+
+    pub fn set_pin(&self, username: &str, pin: &str) -> Result<(), UserError> {
+        self.update(username, |user| {
+            user.hash = self.hash_pin(pin)?;
+            Ok(())
+        })
+    }
+
+    /// The user if `pin` is theirs. An unknown username takes as long to
+    /// check as a wrong PIN, so the answer does not reveal which names exist.
+    pub fn verify(&self, username: &str, pin: &str) -> Result<Option<User>, UserError> {
+        Ok(match self.find(username)? {
+            Some(user) if user.verify_pin(pin) => Some(user),
+            Some(_) => None,
+            None => {
+                matches_hash(&self.dummy_hash, pin);
+                None
+            }
+        })
+    }
+
+    /// Stores `pin`, just verified against `verified`, again if that hash
+    /// was made with weaker settings than configured. Returns whether it
+    /// was. Nothing is written if the PIN changed since, so a login never
+    /// brings back a PIN that was replaced while it was being checked.
+    pub fn rehash_if_weaker(&self, verified: &User, pin: &str) -> Result<bool, UserError> {
+        let _guard = self.writes.lock().unwrap();
+        let mut file = UserFile::clone(&*self.load()?);
+        let Some(user) = file
+            .users
+            .iter_mut()
+            .find(|user| user.username == verified.username && user.hash == verified.hash)
+        else {
+            return Ok(false);
+        };
+        let weaker =
+            PasswordHash::new(&user.hash).is_ok_and(|hash| pin::needs_rehash(&hash, &self.argon2));
+        if !weaker {
+            return Ok(false);
+        }
+        user.hash = pin::hash(pin, &self.argon2)?;
+        self.save(&file)?;
+        Ok(true)
+    }
+
+    fn hash_pin(&self, pin: &str) -> Result<String, UserError> {
+        if pin.trim().is_empty() {
+            return Err(UserError::EmptyPin);
+        }
+        Ok(pin::hash(pin, &self.argon2)?)
+    }
+
+    fn update(
+        &self,
+        username: &str,
+        change: impl FnOnce(&mut User) -> Result<(), UserError>,
+    ) -> Result<(), UserError> {
+        let _guard = self.writes.lock().unwrap();
+        let mut file = UserFile::clone(&*self.load()?);
+        let user = file
+            .users
+            .iter_mut()
+            .find(|user| user.username == username)
+            .ok_or_else(|| UserError::NotFound(username.to_string()))?;
+        change(user)?;
+        self.save(&file)
+    }
+
+    /// Reads the file again on the next use, e.g. on SIGHUP.
+    pub fn reload(&self) {
+        self.file.invalidate();
+    }
+
+    fn load(&self) -> Result<Arc<UserFile>, UserError> {
+        self.file.get(|path| {
+            let read_failed = |e: String| UserError::ReadFailed(path.to_path_buf(), e);
+            match fs::read(path) {
+                Ok(content) => {
+                    serde_json::from_slice(&content).map_err(|e| read_failed(e.to_string()))
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(UserFile::default()),
+                Err(e) => Err(read_failed(e.to_string())),
+            }
+        })
+    }
+
+    /// Replaces the file atomically, readable only by its owner.
+    fn save(&self, file: &UserFile) -> Result<(), UserError> {
+        let path = self.file.path();
+        let write_failed = |e: io::Error| UserError::WriteFailed(path.to_path_buf(), e.to_string());
+        let content = serde_json::to_vec_pretty(file).map_err(|e| write_failed(e.into()))?;
+        let saved = util::write_atomic(path, &content, 0o600).map_err(write_failed);
+        self.file.invalidate();
+        saved
+    }
+}
+
+/// Lowercase ASCII letters, digits, `.`, `_` and `-`, starting with a letter
+/// or digit.
+pub fn valid_username(username: &str) -> bool {
+    let mut chars = username.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && username.len() <= MAX_USERNAME_LEN
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "._-".contains(c))
+}
+
+fn matches_hash(hash: &str, pin: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| pin::verify(&hash, pin))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn users(dir: &tempfile::TempDir) -> Users {
+        Users::new(dir.path().join("users.json"), Argon2Config::default())
+    }
+
+    #[test]
+    fn test_add_verify_remove() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let users = users(&dir);
+        assert!(users.list().unwrap().is_empty());
+
+        users.add("alice", Role::Admin, "1234").unwrap();
+        users.add("guest", Role::Operator, "5678").unwrap();
+        assert!(matches!(
+            users.add("alice", Role::Viewer, "0000"),
+            Err(UserError::Exists(_))
+        ));
+
+        assert_eq!(
+            users.verify("alice", "1234").unwrap().unwrap().role,
+            Role::Admin
+        );
+        assert!(users.verify("alice", "5678").unwrap().is_none());
+        assert!(users.verify("bob", "1234").unwrap().is_none());
+
+        // Only the hash is on disk
+        let content = fs::read_to_string(dir.path().join("users.json")).unwrap();
+        assert!(content.contains("$argon2"));
+        assert!(content.contains("\"operator\""));
+
+        assert_eq!(users.remove("guest").unwrap().username, "guest");
+        assert!(users.verify("guest", "5678").unwrap().is_none());
+        assert!(matches!(users.remove("guest"), Err(UserError::NotFound(_))));
+    }
+
+    #[test]
+    fn test_set_role_and_pin() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let users = users(&dir);
+        users.add("guest", Role::Viewer, "1234").unwrap();
+
+        users.set_role("guest", Role::Unlocker).unwrap();
+        users.set_pin("guest", "4321").unwrap();
+        assert!(users.verify("guest", "1234").unwrap().is_none());
+        let user = users.verify("guest", "4321").unwrap().unwrap();
+        assert_eq!(user.role, Role::Unlocker);
+
+        assert!(matches!(
+            users.set_role("nobody", Role::Admin),
+            Err(UserError::NotFound(_))
+        ));
+        assert!(matches!(
+            users.set_pin("guest", "  "),
+            Err(UserError::EmptyPin)
+        ));
+    }
+
+    #[test]
+    fn test_rehash_if_weaker() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("users.json");
+        let weak = Argon2Config {
+            m_cost: 64,
+            t_cost: 1,
+            ..Argon2Config::default()
+        };
+        let guest = Users::new(path.clone(), weak.clone())
+            .add("guest", Role::Viewer, "1234")
+            .unwrap();
+
+        let same = Users::new(path.clone(), weak.clone());
+        assert!(!same.rehash_if_weaker(&guest, "1234").unwrap());
+
+        let stronger = Users::new(path.clone(), Argon2Config { t_cost: 2, ..weak });
+        assert!(stronger.rehash_if_weaker(&guest, "1234").unwrap());
+        let rehashed = stronger.verify("guest", "1234").unwrap().unwrap();
+        assert!(!stronger.rehash_if_weaker(&rehashed, "1234").unwrap());
+        assert!(fs::read_to_string(&path).unwrap().contains("m=64,t=2,p=1"));
+
+        // The PIN changed after the old one was verified: the old one is not
+        // brought back
+        let weaker = Users::new(path.clone(), weak);
+        let other = weaker.add("other", Role::Viewer, "1234").unwrap();
+        weaker.set_pin("other", "5678").unwrap();
+        stronger.reload();
+        assert!(!stronger.rehash_if_weaker(&other, "1234").unwrap());
+        assert!(stronger.verify("other", "1234").unwrap().is_none());
+        assert!(stronger.verify("other", "5678").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_dummy_hash_made_up_front() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let users = users(&dir);
+        assert!(users.dummy_hash.starts_with("$argon2id$"));
+        assert!(users.verify("nobody", "1234").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_sees_users_from_other_processes() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let server = users(&dir);
+        assert!(server.find("alice").unwrap().is_none());
+
+        // `articwake user add` writes the file behind the server's back
+        users(&dir).add("alice", Role::Viewer, "1234").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        assert_eq!(server.find("alice").unwrap().unwrap().role, Role::Viewer);
+
+        users(&dir).set_role("alice", Role::Admin).unwrap();
+        server.reload();
+        assert_eq!(server.find("alice").unwrap().unwrap().role, Role::Admin);
+    }
+
+    #[test]
+    fn test_roles() {
+        assert_eq!("unlocker".parse::<Role>().unwrap(), Role::Unlocker);
+        assert!(matches!(
+            "owner".parse::<Role>(),
+            Err(UserError::UnknownRole(_))
+        ));
+        assert_eq!(Role::Viewer.scopes(), vec![Scope::StatusRead]);
+        assert!(!Role::Operator.scopes().contains(&Scope::Unlock));
+        assert!(Role::Unlocker.scopes().contains(&Scope::Unlock));
+        assert!(!Role::Unlocker.scopes().contains(&Scope::Admin));
+    }
+
+    #[test]
+    fn test_valid_username() {
+        assert!(valid_username("alice"));
+        assert!(valid_username("guest-2.x_y"));
+        assert!(!valid_username(""));
+        assert!(!valid_username("Alice"));
+        assert!(!valid_username("-alice"));
+        assert!(!valid_username("a b"));
+        assert!(!valid_username(&"a".repeat(MAX_USERNAME_LEN + 1)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_file_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::TempDir::new().unwrap();
+        let users = users(&dir);
+        users.add("alice", Role::Admin, "1234").unwrap();
+        let mode = fs::metadata(dir.path().join("users.json"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
@@ -0,0 +1,226 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use rand::Rng;
+
+/// Seconds since the Unix epoch, the unit of every timestamp that is saved
+/// or sent to clients.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    rand::thread_rng().fill(&mut bytes[..]);
+    bytes
+}
+
+/// Writes a temporary file with permissions `mode` and renames it over
+/// `path`, so a crash never leaves a half-written file. Creates the parent
+/// directory if needed. Each call has its own temporary file, so concurrent
+/// writers never rename each other's half-written content into place.
+pub fn write_atomic(path: &Path, content: &[u8], mode: u32) -> io::Result<()> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(format!(".{}.tmp", hex::encode(random_bytes::<8>())));
+    let tmp = path.with_file_name(tmp_name);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(mode);
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    let written = options.open(&tmp).and_then(|mut file| {
+        file.write_all(content)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    });
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    written
+}
+
+/// How often [`FileCache`] looks at its file for changes
+const CHANGE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What tells one version of a file from the next. Files are replaced by
+/// renaming, so the inode changes even when the time and size do not.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FileStamp {
+    modified: Option<SystemTime>,
+    len: u64,
+    inode: u64,
+}
+
+impl FileStamp {
+    /// `None` if the file does not exist.
+    fn of(path: &Path) -> io::Result<Option<Self>> {
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(&metadata);
+        #[cfg(not(unix))]
+        let inode = 0;
+        Ok(Some(FileStamp {
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+            inode,
+        }))
+    }
+}
+
+struct Cached<T> {
+    value: Arc<T>,
+    stamp: Option<FileStamp>,
+    checked_at: Instant,
+}
+
+/// The parsed contents of a file that the `articwake` CLI may replace while
+/// the server runs. The file is only read again once it changed, and looked
+/// at no more than once a second, so requests don't each read and parse it.
+pub struct FileCache<T> {
+    path: PathBuf,
+    cached: Mutex<Option<Cached<T>>>,
+}
+
+impl<T> FileCache<T> {
+    pub fn new(path: PathBuf) -> Self {
+        FileCache {
+            path,
+            cached: Mutex::new(None),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The cached contents, or those `load` reads from the file if it
+    /// changed since. A failed load is returned and not cached.
+    pub fn get<E>(&self, load: impl FnOnce(&Path) -> Result<T, E>) -> Result<Arc<T>, E> {
+        let mut cached = self.cached.lock().unwrap();
+        let now = Instant::now();
+        if let Some(c) = cached.as_mut() {
+            if now < c.checked_at + CHANGE_CHECK_INTERVAL {
+                return Ok(c.value.clone());
+            }
+            // If the file cannot be looked at, `load` reports why
+            if FileStamp::of(&self.path).is_ok_and(|stamp| stamp == c.stamp) {
+                c.checked_at = now;
+                return Ok(c.value.clone());
+            }
+        }
+
+        // Stamped before reading, so a change during the read is seen next time
+        let stamp = FileStamp::of(&self.path).ok().flatten();
+        let value = Arc::new(load(&self.path)?);
+        *cached = Some(Cached {
+            value: value.clone(),
+            stamp,
+            checked_at: now,
+        });
+        Ok(value)
+    }
+
+    /// Forgets the contents, so the next [`Self::get`] reads the file. For
+    /// after writing it, or on SIGHUP.
+    pub fn invalidate(&self) {
+        *self.cached.lock().unwrap() = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_atomic() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("state").join("file.json");
+
+        write_atomic(&path, b"first", 0o600).unwrap();
+        write_atomic(&path, b"second", 0o600).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second");
+        // No temporary files are left behind
+        let names: Vec<_> = fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["file.json"]);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn test_concurrent_write_atomic() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("file.json");
+        let contents: Vec<String> = (0..8).map(|i| i.to_string().repeat(64 * 1024)).collect();
+
+        std::thread::scope(|scope| {
+            for content in &contents {
+                scope.spawn(|| write_atomic(&path, content.as_bytes(), 0o600).unwrap());
+            }
+        });
+        // One writer's content in full, not a mix of several
+        assert!(contents.contains(&fs::read_to_string(&path).unwrap()));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_file_cache() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("data.txt");
+        let cache = FileCache::new(path.clone());
+        let reads = std::cell::Cell::new(0);
+        let load = |path: &Path| -> io::Result<String> {
+            reads.set(reads.get() + 1);
+            match fs::read_to_string(path) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+                other => other,
+            }
+        };
+
+        assert_eq!(*cache.get(load).unwrap(), "");
+        write_atomic(&path, b"first", 0o600).unwrap();
+        // Not looked at again within the check interval
+        assert_eq!(*cache.get(load).unwrap(), "");
+        assert_eq!(reads.get(), 1);
+
+        cache.invalidate();
+        assert_eq!(*cache.get(load).unwrap(), "first");
+        assert_eq!(reads.get(), 2);
+
+        // Changed by someone else: read once the interval has passed
+        write_atomic(&path, b"other", 0o600).unwrap();
+        std::thread::sleep(CHANGE_CHECK_INTERVAL);
+        assert_eq!(*cache.get(load).unwrap(), "other");
+        assert_eq!(reads.get(), 3);
+
+        // Unchanged: the stamp matches, nothing is read
+        std::thread::sleep(CHANGE_CHECK_INTERVAL);
+        assert_eq!(*cache.get(load).unwrap(), "other");
+        assert_eq!(reads.get(), 3);
+    }
+}
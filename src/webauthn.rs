@@ -0,0 +1,888 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use ciborium::Value;
+use data_encoding::BASE64URL_NOPAD;
+use ring::digest::{SHA256, digest};
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::config::WebauthnConfig;
+use crate::util::{self, random_bytes, unix_now};
+
+/// How long the browser has to complete a ceremony
+pub const CEREMONY_TIMEOUT: Duration = Duration::from_secs(120);
+/// Unfinished ceremonies kept at once; the oldest is dropped beyond this
+const MAX_PENDING: usize = 32;
+const CHALLENGE_LEN: usize = 32;
+const USER_ID_LEN: usize = 16;
+pub const MAX_NAME_LEN: usize = 64;
+
+const RP_NAME: &str = "articwake";
+/// Shown for passkeys of the shared account, which has no username
+const USER_NAME: &str = "articwake";
+
+/// COSE algorithm identifiers (RFC 9053)
+const COSE_ES256: i64 = -7;
+const COSE_RS256: i64 = -257;
+
+/// Authenticator data flags
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL: u8 = 0x40;
+
+#[derive(Debug, Error)]
+pub enum WebauthnError {
+    #[error("Passkeys are not configured")]
+    Disabled,
+    #[error("No passkeys registered")]
+    NoCredentials,
+    #[error("Unknown or expired challenge")]
+    UnknownChallenge,
+    #[error("Unexpected origin: {0}")]
+    OriginMismatch(String),
+    #[error("Credential is for another relying party")]
+    RpIdMismatch,
+    #[error("Authenticator did not verify the user")]
+    UserNotVerified,
+    #[error("Unknown credential")]
+    UnknownCredential,
+    #[error("Credential is already registered")]
+    DuplicateCredential,
+    #[error("Unsupported public key algorithm: {0}")]
+    UnsupportedAlgorithm(i64),
+    #[error("Invalid signature")]
+    InvalidSignature,
+    #[error("Signature counter did not increase; the authenticator may be cloned")]
+    CounterRegressed,
+    #[error("Malformed response: {0}")]
+    Malformed(String),
+    #[error("Failed to access credential store: {0}")]
+    Storage(String),
+}
+
+/// `PublicKeyCredential.toJSON()` of a `navigator.credentials.create()` call.
+#[derive(Debug, Deserialize)]
+pub struct RegistrationResponse {
+    /// Base64url credential ID
+    pub id: String,
+    pub response: AttestationResponse,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "attestationObject")]
+    pub attestation_object: String,
+}
+
+/// `PublicKeyCredential.toJSON()` of a `navigator.credentials.get()` call.
+#[derive(Debug, Deserialize)]
+pub struct LoginResponse {
+    pub id: String,
+    pub response: AssertionResponse,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "authenticatorData")]
+    pub authenticator_data: String,
+    pub signature: String,
+}
+
+/// A registered passkey as shown to clients. The public key stays on disk.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CredentialInfo {
+    pub id: String,
+    pub name: String,
+    /// The account it logs in as, `None` for the shared one
+    pub username: Option<String>,
+    /// Unix timestamps in seconds
+    pub created_at: u64,
+    pub last_used_at: Option<u64>,
+}
+
+/// Contents of the credential state file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CredentialState {
+    /// Random WebAuthn user handle of the shared account, created with its
+    /// first passkey
+    user_id: String,
+    /// Handles of user accounts by username, so their passkeys don't
+    /// replace each other on an authenticator they share
+    #[serde(default)]
+    user_ids: BTreeMap<String, String>,
+    credentials: Vec<StoredCredential>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredCredential {
+    id: String,
+    name: String,
+    /// Who registered it. Missing for passkeys of the shared account and
+    /// those registered before user accounts existed.
+    #[serde(default)]
+    username: Option<String>,
+    /// Base64url COSE key from the attested credential data
+    public_key: String,
+    sign_count: u32,
+    created_at: u64,
+    last_used_at: Option<u64>,
+}
+
+impl StoredCredential {
+    fn info(&self) -> CredentialInfo {
+        CredentialInfo {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            username: self.username.clone(),
+            created_at: self.created_at,
+            last_used_at: self.last_used_at,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+enum Ceremony {
+    /// Adding a passkey from an authenticated session, for its user
+    Registration {
+        session_id: String,
+        username: Option<String>,
+        user_id: String,
+    },
+    Login,
+}
+
+struct Pending {
+    ceremony: Ceremony,
+    expires_at: Instant,
+}
+
+/// WebAuthn relying party for articwake's accounts. Passkeys are kept
+/// in a JSON state file; challenges only live in memory.
+pub struct Webauthn {
+    origin: Option<String>,
+    rp_id: Option<String>,
+    path: PathBuf,
+    pending: Mutex<HashMap<String, Pending>>,
+    /// Serializes read-modify-write cycles of the state file
+    store: Mutex<()>,
+}
+
+impl Webauthn {
+    pub fn new(config: &WebauthnConfig) -> Self {
+        Webauthn {
+            origin: config.origin.clone(),
+            rp_id: config.rp_id().map(str::to_string),
+            path: config.credentials_path.clone(),
+            pending: Mutex::new(HashMap::new()),
+            store: Mutex::new(()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.origin.is_some()
+    }
+
+    /// Whether a passkey login could succeed: enabled and one is registered.
+    pub fn login_available(&self) -> bool {
+        self.credentials().is_ok_and(|c| !c.is_empty())
+    }
+
+    fn relying_party(&self) -> Result<(&str, &str), WebauthnError> {
+        match (&self.origin, &self.rp_id) {
+            (Some(origin), Some(rp_id)) => Ok((origin, rp_id)),
+            _ => Err(WebauthnError::Disabled),
+        }
+    }
+
+    pub fn credentials(&self) -> Result<Vec<CredentialInfo>, WebauthnError> {
+        self.relying_party()?;
+        let _guard = self.store.lock().unwrap();
+        let state = load_state(&self.path)?;
+        Ok(state
+            .credentials
+            .iter()
+            .map(StoredCredential::info)
+            .collect())
+    }
+
+    /// Removes a passkey. Returns `None` if no passkey has that ID.
+    pub fn remove(&self, id: &str) -> Result<Option<CredentialInfo>, WebauthnError> {
+        self.relying_party()?;
+        let _guard = self.store.lock().unwrap();
+        let mut state = load_state(&self.path)?;
+        let Some(index) = state.credentials.iter().position(|c| c.id == id) else {
+            return Ok(None);
+        };
+        let removed = state.credentials.remove(index);
+        save_state(&self.path, &state)?;
+        Ok(Some(removed.info()))
+    }
+
+    /// Options for `navigator.credentials.create()`, bound to `session_id`.
+    /// The passkey will log in as `username`, or the shared account.
+    pub fn start_registration(
+        &self,
+        session_id: &str,
+        username: Option<&str>,
+    ) -> Result<serde_json::Value, WebauthnError> {
+        let (_, rp_id) = self.relying_party()?;
+        let state = {
+            let _guard = self.store.lock().unwrap();
+            load_state(&self.path)?
+        };
+        let user_id = match username {
+            Some(username) => state.user_ids.get(username).cloned(),
+            None => Some(state.user_id.clone()).filter(|id| !id.is_empty()),
+        }
+        .unwrap_or_else(|| BASE64URL_NOPAD.encode(&random_bytes::<USER_ID_LEN>()));
+
+        let challenge = self.new_challenge(Ceremony::Registration {
+            session_id: session_id.to_string(),
+            username: username.map(str::to_string),
+            user_id: user_id.clone(),
+        });
+        let user_name = username.unwrap_or(USER_NAME);
+        Ok(serde_json::json!({
+            "challenge": challenge,
+            "rp": { "id": rp_id, "name": RP_NAME },
+            "user": { "id": user_id, "name": user_name, "displayName": user_name },
+            "pubKeyCredParams": [
+                { "type": "public-key", "alg": COSE_ES256 },
+                { "type": "public-key", "alg": COSE_RS256 },
+            ],
+            "timeout": CEREMONY_TIMEOUT.as_millis(),
+            "excludeCredentials": descriptors(&state.credentials),
+            "authenticatorSelection": {
+                "residentKey": "preferred",
+                "userVerification": "required",
+            },
+            "attestation": "none",
+        }))
+    }
+
+    /// Verifies a new credential and stores it under `name`.
+    pub fn finish_registration(
+        &self,
+        session_id: &str,
+        response: &RegistrationResponse,
+        name: &str,
+    ) -> Result<CredentialInfo, WebauthnError> {
+        let (origin, rp_id) = self.relying_party()?;
+        let client_data = decode(&response.response.client_data_json, "clientDataJSON")?;
+        let client = parse_client_data(&client_data, "webauthn.create", origin)?;
+        let (username, user_id) = match self.take_challenge(&client.challenge)? {
+            Ceremony::Registration {
+                session_id: owner,
+                username,
+                user_id,
+            } if owner == session_id => (username, user_id),
+            _ => return Err(WebauthnError::UnknownChallenge),
+        };
+
+        // Attestation statements are not checked: we ask for "none" and trust
+        // whatever authenticator the logged-in user picked
+        let attestation = decode(&response.response.attestation_object, "attestationObject")?;
+        let attestation: Value = ciborium::from_reader(attestation.as_slice())
+            .map_err(|e| WebauthnError::Malformed(format!("attestationObject: {}", e)))?;
+        let auth_data = map_get(&attestation, &Value::from("authData"))
+            .and_then(Value::as_bytes)
+            .ok_or_else(|| WebauthnError::Malformed("attestationObject: no authData".into()))?;
+        let auth_data = AuthenticatorData::parse(auth_data)?;
+        auth_data.check(rp_id)?;
+        let (credential_id, public_key) = auth_data
+            .credential
+            .ok_or_else(|| WebauthnError::Malformed("no attested credential".into()))?;
+        PublicKey::from_cose(public_key)?;
+
+        let id = BASE64URL_NOPAD.encode(credential_id);
+        if id != response.id {
+            return Err(WebauthnError::Malformed("credential ID mismatch".into()));
+        }
+
+        let _guard = self.store.lock().unwrap();
+        let mut state = load_state(&self.path)?;
+        if state.credentials.iter().any(|c| c.id == id) {
+            return Err(WebauthnError::DuplicateCredential);
+        }
+        match &username {
+            Some(username) => {
+                state.user_ids.entry(username.clone()).or_insert(user_id);
+            }
+            None if state.user_id.is_empty() => state.user_id = user_id,
+            None => {}
+        }
+        let credential = StoredCredential {
+            id,
+            name: name.to_string(),
+            username,
+            public_key: BASE64URL_NOPAD.encode(public_key),
+            sign_count: auth_data.sign_count,
+            created_at: unix_now(),
+            last_used_at: None,
+        };
+        state.credentials.push(credential.clone());
+        save_state(&self.path, &state)?;
+        Ok(credential.info())
+    }
+
+    /// Options for `navigator.credentials.get()`.
+    pub fn start_login(&self) -> Result<serde_json::Value, WebauthnError> {
+        let (_, rp_id) = self.relying_party()?;
+        let state = {
+            let _guard = self.store.lock().unwrap();
+            load_state(&self.path)?
+        };
+        if state.credentials.is_empty() {
+            return Err(WebauthnError::NoCredentials);
+        }
+
+        let challenge = self.new_challenge(Ceremony::Login);
+        Ok(serde_json::json!({
+            "challenge": challenge,
+            "rpId": rp_id,
+            "allowCredentials": descriptors(&state.credentials),
+            "timeout": CEREMONY_TIMEOUT.as_millis(),
+            "userVerification": "required",
+        }))
+    }
+
+    /// Verifies an assertion and returns the passkey that signed it.
+    pub fn finish_login(&self, response: &LoginResponse) -> Result<CredentialInfo, WebauthnError> {
+        let (origin, rp_id) = self.relying_party()?;
+        let client_data = decode(&response.response.client_data_json, "clientDataJSON")?;
+        let client = parse_client_data(&client_data, "webauthn.get", origin)?;
+        if !matches!(self.take_challenge(&client.challenge)?, Ceremony::Login) {
+            return Err(WebauthnError::UnknownChallenge);
+        }
+
+        let auth_data_bytes = decode(&response.response.authenticator_data, "authenticatorData")?;
+        let signature = decode(&response.response.signature, "signature")?;
+        let auth_data = AuthenticatorData::parse(&auth_data_bytes)?;
+        auth_data.check(rp_id)?;
+
+        let _guard = self.store.lock().unwrap();
+        let mut state = load_state(&self.path)?;
+        let credential = state
+            .credentials
+            .iter_mut()
+            .find(|c| c.id == response.id)
+            .ok_or(WebauthnError::UnknownCredential)?;
+
+        let public_key = decode(&credential.public_key, "stored public key")?;
+        let mut signed = auth_data_bytes.clone();
+        signed.extend_from_slice(digest(&SHA256, &client_data).as_ref());
+        PublicKey::from_cose(&public_key)?.verify(&signed, &signature)?;
+
+        // Authenticators without a counter always report 0
+        let count = auth_data.sign_count;
+        if (count != 0 || credential.sign_count != 0) && count <= credential.sign_count {
+            return Err(WebauthnError::CounterRegressed);
+        }
+        credential.sign_count = count;
+        credential.last_used_at = Some(unix_now());
+        let info = credential.info();
+        save_state(&self.path, &state)?;
+        Ok(info)
+    }
+
+    fn new_challenge(&self, ceremony: Ceremony) -> String {
+        let challenge = BASE64URL_NOPAD.encode(&random_bytes::<CHALLENGE_LEN>());
+        let now = Instant::now();
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, p| p.expires_at > now);
+        if pending.len() >= MAX_PENDING {
+            let oldest = pending
+                .iter()
+                .min_by_key(|(_, p)| p.expires_at)
+                .map(|(c, _)| c.clone());
+            if let Some(oldest) = oldest {
+                pending.remove(&oldest);
+            }
+        }
+        pending.insert(
+            challenge.clone(),
+            Pending {
+                ceremony,
+                expires_at: now + CEREMONY_TIMEOUT,
+            },
+        );
+        challenge
+    }
+
+    /// Removes a challenge so it can only be answered once.
+    fn take_challenge(&self, challenge: &str) -> Result<Ceremony, WebauthnError> {
+        let mut pending = self.pending.lock().unwrap();
+        match pending.remove(challenge) {
+            Some(p) if p.expires_at > Instant::now() => Ok(p.ceremony),
+            _ => Err(WebauthnError::UnknownChallenge),
+        }
+    }
+}
+
+fn descriptors(credentials: &[StoredCredential]) -> Vec<serde_json::Value> {
+    credentials
+        .iter()
+        .map(|c| serde_json::json!({ "type": "public-key", "id": c.id }))
+        .collect()
+}
+
+fn parse_client_data(bytes: &[u8], kind: &str, origin: &str) -> Result<ClientData, WebauthnError> {
+    let client: ClientData = serde_json::from_slice(bytes)
+        .map_err(|e| WebauthnError::Malformed(format!("clientDataJSON: {}", e)))?;
+    if client.kind != kind {
+        return Err(WebauthnError::Malformed(format!(
+            "expected {}, got {}",
+            kind, client.kind
+        )));
+    }
+    if client.origin != origin {
+        return Err(WebauthnError::OriginMismatch(client.origin));
+    }
+    Ok(client)
+}
+
+/// The parts of the authenticator data (WebAuthn §6.1) articwake checks.
+struct AuthenticatorData<'a> {
+    rp_id_hash: &'a [u8],
+    flags: u8,
+    sign_count: u32,
+    /// Credential ID and COSE public key, present during registration
+    credential: Option<(&'a [u8], &'a [u8])>,
+}
+
+impl<'a> AuthenticatorData<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self, WebauthnError> {
+        let short = || WebauthnError::Malformed("authenticator data too short".into());
+        if data.len() < 37 {
+            return Err(short());
+        }
+        let flags = data[32];
+        let sign_count = u32::from_be_bytes([data[33], data[34], data[35], data[36]]);
+
+        let credential = if flags & FLAG_ATTESTED_CREDENTIAL != 0 {
+            // AAGUID (16 bytes), then a length-prefixed credential ID
+            let rest = data.get(37 + 16..).ok_or_else(short)?;
+            let id_len = u16::from_be_bytes([
+                *rest.first().ok_or_else(short)?,
+                *rest.get(1).ok_or_else(short)?,
+            ]) as usize;
+            let id = rest.get(2..2 + id_len).ok_or_else(short)?;
+
+            // The COSE key is followed by optional extensions, so its length
+            // is only known once it has been decoded
+            let key_and_rest = &rest[2 + id_len..];
+            let mut reader = key_and_rest;
+            ciborium::from_reader::<Value, _>(&mut reader)
+                .map_err(|e| WebauthnError::Malformed(format!("credential public key: {}", e)))?;
+            let key = &key_and_rest[..key_and_rest.len() - reader.len()];
+            Some((id, key))
+        } else {
+            None
+        };
+
+        Ok(AuthenticatorData {
+            rp_id_hash: &data[..32],
+            flags,
+            sign_count,
+            credential,
+        })
+    }
+
+    fn check(&self, rp_id: &str) -> Result<(), WebauthnError> {
+        if self.rp_id_hash != digest(&SHA256, rp_id.as_bytes()).as_ref() {
+            return Err(WebauthnError::RpIdMismatch);
+        }
+        // The passkey replaces the PIN, so the authenticator must have
+        // verified the user (biometrics or device PIN), not just a tap
+        let required = FLAG_USER_PRESENT | FLAG_USER_VERIFIED;
+        if self.flags & required != required {
+            return Err(WebauthnError::UserNotVerified);
+        }
+        Ok(())
+    }
+}
+
+enum PublicKey {
+    /// Uncompressed P-256 point
+    Es256(Vec<u8>),
+    Rs256 {
+        n: Vec<u8>,
+        e: Vec<u8>,
+    },
+}
+
+impl PublicKey {
+    fn from_cose(bytes: &[u8]) -> Result<Self, WebauthnError> {
+        let key: Value = ciborium::from_reader(bytes)
+            .map_err(|e| WebauthnError::Malformed(format!("COSE key: {}", e)))?;
+        let int = |label: i64| map_get(&key, &Value::from(label)).and_then(cose_int);
+        let bytes = |label: i64| {
+            map_get(&key, &Value::from(label))
+                .and_then(Value::as_bytes)
+                .ok_or_else(|| WebauthnError::Malformed(format!("COSE key: missing {}", label)))
+        };
+
+        let alg = int(3).ok_or_else(|| WebauthnError::Malformed("COSE key: no alg".into()))?;
+        match (int(1), alg) {
+            // EC2 key on P-256
+            (Some(2), COSE_ES256) if int(-1) == Some(1) => {
+                let (x, y) = (bytes(-2)?, bytes(-3)?);
+                if x.len() != 32 || y.len() != 32 {
+                    return Err(WebauthnError::Malformed("COSE key: bad point".into()));
+                }
+                let mut point = vec![0x04];
+                point.extend_from_slice(x);
+                point.extend_from_slice(y);
+                Ok(PublicKey::Es256(point))
+            }
+            (Some(3), COSE_RS256) => Ok(PublicKey::Rs256 {
+                n: bytes(-1)?.clone(),
+                e: bytes(-2)?.clone(),
+            }),
+            _ => Err(WebauthnError::UnsupportedAlgorithm(alg)),
+        }
+    }
+
+    fn verify(&self, message: &[u8], sig: &[u8]) -> Result<(), WebauthnError> {
+        let result = match self {
+            PublicKey::Es256(point) => {
+                UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_ASN1, point)
+                    .verify(message, sig)
+            }
+            PublicKey::Rs256 { n, e } => RsaPublicKeyComponents { n, e }.verify(
+                &signature::RSA_PKCS1_2048_8192_SHA256,
+                message,
+                sig,
+            ),
+        };
+        result.map_err(|_| WebauthnError::InvalidSignature)
+    }
+}
+
+fn map_get<'a>(map: &'a Value, key: &Value) -> Option<&'a Value> {
+    map.as_map()?.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+}
+
+fn cose_int(value: &Value) -> Option<i64> {
+    value.as_integer().and_then(|i| i64::try_from(i).ok())
+}
+
+fn decode(value: &str, what: &str) -> Result<Vec<u8>, WebauthnError> {
+    BASE64URL_NOPAD
+        .decode(value.trim_end_matches('=').as_bytes())
+        .map_err(|_| WebauthnError::Malformed(format!("{} is not base64url", what)))
+}
+
+fn load_state(path: &Path) -> Result<CredentialState, WebauthnError> {
+    match fs::read(path) {
+        Ok(content) => serde_json::from_slice(&content)
+            .map_err(|e| WebauthnError::Storage(format!("{}: {}", path.display(), e))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(CredentialState::default()),
+        Err(e) => Err(WebauthnError::Storage(format!("{}: {}", path.display(), e))),
+    }
+}
+
+/// Replaces the state file atomically, readable only by its owner.
+fn save_state(path: &Path, state: &CredentialState) -> Result<(), WebauthnError> {
+    let storage = |e: io::Error| WebauthnError::Storage(format!("{}: {}", path.display(), e));
+    let content = serde_json::to_vec_pretty(state).map_err(|e| storage(e.into()))?;
+    util::write_atomic(path, &content, 0o600).map_err(storage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{ECDSA_P256_SHA256_ASN1_SIGNING, EcdsaKeyPair, KeyPair};
+
+    const ORIGIN: &str = "https://pi.lan";
+
+    fn webauthn(dir: &tempfile::TempDir) -> Webauthn {
+        Webauthn::new(&WebauthnConfig {
+            origin: Some(ORIGIN.to_string()),
+            credentials_path: dir.path().join("webauthn.json"),
+        })
+    }
+
+    fn key_pair() -> EcdsaKeyPair {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng).unwrap()
+    }
+
+    fn cose_key(key: &EcdsaKeyPair) -> Vec<u8> {
+        let point = key.public_key().as_ref();
+        let cose = Value::Map(vec![
+            (1.into(), 2.into()),
+            (3.into(), COSE_ES256.into()),
+            ((-1).into(), 1.into()),
+            ((-2).into(), point[1..33].into()),
+            ((-3).into(), point[33..].into()),
+        ]);
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&cose, &mut bytes).unwrap();
+        bytes
+    }
+
+    fn auth_data(
+        rp_id: &str,
+        flags: u8,
+        count: u32,
+        credential: Option<(&[u8], &[u8])>,
+    ) -> Vec<u8> {
+        let mut data = digest(&SHA256, rp_id.as_bytes()).as_ref().to_vec();
+        data.push(flags);
+        data.extend_from_slice(&count.to_be_bytes());
+        if let Some((id, key)) = credential {
+            data.extend_from_slice(&[0; 16]);
+            data.extend_from_slice(&(id.len() as u16).to_be_bytes());
+            data.extend_from_slice(id);
+            data.extend_from_slice(key);
+        }
+        data
+    }
+
+    fn client_data(kind: &str, challenge: &str, origin: &str) -> String {
+        let json = serde_json::json!({ "type": kind, "challenge": challenge, "origin": origin });
+        BASE64URL_NOPAD.encode(json.to_string().as_bytes())
+    }
+
+    fn register(
+        webauthn: &Webauthn,
+        key: &EcdsaKeyPair,
+        id: &[u8],
+        username: Option<&str>,
+    ) -> CredentialInfo {
+        let options = webauthn.start_registration("session", username).unwrap();
+        let challenge = options["challenge"].as_str().unwrap();
+        let data = auth_data("pi.lan", 0x45, 0, Some((id, &cose_key(key))));
+        let attestation = Value::Map(vec![
+            ("fmt".into(), "none".into()),
+            ("attStmt".into(), Value::Map(vec![])),
+            ("authData".into(), data.into()),
+        ]);
+        let mut attestation_object = Vec::new();
+        ciborium::into_writer(&attestation, &mut attestation_object).unwrap();
+
+        let response = RegistrationResponse {
+            id: BASE64URL_NOPAD.encode(id),
+            response: AttestationResponse {
+                client_data_json: client_data("webauthn.create", challenge, ORIGIN),
+                attestation_object: BASE64URL_NOPAD.encode(&attestation_object),
+            },
+        };
+        webauthn
+            .finish_registration("session", &response, "Phone")
+            .unwrap()
+    }
+
+    fn assertion(webauthn: &Webauthn, key: &EcdsaKeyPair, id: &[u8], count: u32) -> LoginResponse {
+        let options = webauthn.start_login().unwrap();
+        let client = client_data(
+            "webauthn.get",
+            options["challenge"].as_str().unwrap(),
+            ORIGIN,
+        );
+        let data = auth_data("pi.lan", 0x05, count, None);
+        let mut signed = data.clone();
+        signed.extend_from_slice(
+            digest(&SHA256, &BASE64URL_NOPAD.decode(client.as_bytes()).unwrap()).as_ref(),
+        );
+        let signature = key.sign(&SystemRandom::new(), &signed).unwrap();
+        LoginResponse {
+            id: BASE64URL_NOPAD.encode(id),
+            response: AssertionResponse {
+                client_data_json: client,
+                authenticator_data: BASE64URL_NOPAD.encode(&data),
+                signature: BASE64URL_NOPAD.encode(signature.as_ref()),
+            },
+        }
+    }
+
+    #[test]
+    fn test_disabled_without_origin() {
+        let webauthn = Webauthn::new(&WebauthnConfig::default());
+        assert!(!webauthn.is_enabled());
+        assert!(!webauthn.login_available());
+        assert!(matches!(
+            webauthn.start_login(),
+            Err(WebauthnError::Disabled)
+        ));
+    }
+
+    #[test]
+    fn test_login_and_sign_counter() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let webauthn = webauthn(&dir);
+        let key = key_pair();
+        register(&webauthn, &key, b"credential-1", None);
+        assert!(webauthn.login_available());
+
+        let info = webauthn
+            .finish_login(&assertion(&webauthn, &key, b"credential-1", 5))
+            .unwrap();
+        assert_eq!(info.name, "Phone");
+        assert!(info.last_used_at.is_some());
+
+        // A counter that does not increase points at a cloned authenticator
+        let result = webauthn.finish_login(&assertion(&webauthn, &key, b"credential-1", 5));
+        assert!(matches!(result, Err(WebauthnError::CounterRegressed)));
+
+        // Someone else's key cannot sign for this credential
+        let result = webauthn.finish_login(&assertion(&webauthn, &key_pair(), b"credential-1", 6));
+        assert!(matches!(result, Err(WebauthnError::InvalidSignature)));
+    }
+
+    #[test]
+    fn test_registration_bound_to_session() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let webauthn = webauthn(&dir);
+        let options = webauthn.start_registration("other-session", None).unwrap();
+        let key = key_pair();
+        let data = auth_data("pi.lan", 0x45, 0, Some((b"id", &cose_key(&key))));
+        let mut attestation_object = Vec::new();
+        ciborium::into_writer(
+            &Value::Map(vec![("authData".into(), data.into())]),
+            &mut attestation_object,
+        )
+        .unwrap();
+        let response = RegistrationResponse {
+            id: BASE64URL_NOPAD.encode(b"id"),
+            response: AttestationResponse {
+                client_data_json: client_data(
+                    "webauthn.create",
+                    options["challenge"].as_str().unwrap(),
+                    ORIGIN,
+                ),
+                attestation_object: BASE64URL_NOPAD.encode(&attestation_object),
+            },
+        };
+        assert!(matches!(
+            webauthn.finish_registration("session", &response, "Phone"),
+            Err(WebauthnError::UnknownChallenge)
+        ));
+    }
+
+    #[test]
+    fn test_authenticator_data_checks() {
+        let key = key_pair();
+        let cose = cose_key(&key);
+
+        // Extensions after the COSE key are not part of it
+        let mut data = auth_data("pi.lan", 0xc5, 7, Some((b"id", &cose)));
+        data.extend_from_slice(&[0xa0]);
+        let parsed = AuthenticatorData::parse(&data).unwrap();
+        assert_eq!(parsed.sign_count, 7);
+        assert_eq!(parsed.credential, Some((&b"id"[..], &cose[..])));
+        assert!(parsed.check("pi.lan").is_ok());
+        assert!(matches!(
+            parsed.check("evil.lan"),
+            Err(WebauthnError::RpIdMismatch)
+        ));
+
+        // Presence alone is not enough
+        let data = auth_data("pi.lan", 0x01, 0, None);
+        assert!(matches!(
+            AuthenticatorData::parse(&data).unwrap().check("pi.lan"),
+            Err(WebauthnError::UserNotVerified)
+        ));
+
+        assert!(AuthenticatorData::parse(&data[..36]).is_err());
+        let truncated = auth_data("pi.lan", 0x45, 0, Some((b"id", &cose[..10])));
+        assert!(AuthenticatorData::parse(&truncated).is_err());
+    }
+
+    #[test]
+    fn test_cose_keys() {
+        assert!(matches!(
+            PublicKey::from_cose(&cose_key(&key_pair())),
+            Ok(PublicKey::Es256(point)) if point.len() == 65
+        ));
+
+        let cbor = |value: Value| {
+            let mut bytes = Vec::new();
+            ciborium::into_writer(&value, &mut bytes).unwrap();
+            bytes
+        };
+        let rsa = cbor(Value::Map(vec![
+            (1.into(), 3.into()),
+            (3.into(), COSE_RS256.into()),
+            ((-1).into(), vec![0xc5; 256].into()),
+            ((-2).into(), vec![1, 0, 1].into()),
+        ]));
+        assert!(matches!(
+            PublicKey::from_cose(&rsa),
+            Ok(PublicKey::Rs256 { .. })
+        ));
+
+        // Ed25519 (OKP, EdDSA) is not offered in pubKeyCredParams
+        let eddsa = cbor(Value::Map(vec![
+            (1.into(), 1.into()),
+            (3.into(), (-8).into()),
+            ((-1).into(), 6.into()),
+            ((-2).into(), vec![0; 32].into()),
+        ]));
+        assert!(matches!(
+            PublicKey::from_cose(&eddsa),
+            Err(WebauthnError::UnsupportedAlgorithm(-8))
+        ));
+    }
+
+    #[test]
+    fn test_state_file() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let webauthn = webauthn(&dir);
+        assert!(webauthn.credentials().unwrap().is_empty());
+
+        let info = register(&webauthn, &key_pair(), b"credential-1", None);
+        let path = dir.path().join("webauthn.json");
+        let state = load_state(&path).unwrap();
+        assert_eq!(state.credentials.len(), 1);
+        assert!(!state.user_id.is_empty());
+
+        // The user handle stays the same for later passkeys
+        let options = webauthn.start_registration("session", None).unwrap();
+        assert_eq!(options["user"]["id"], state.user_id);
+        assert_eq!(options["excludeCredentials"][0]["id"], info.id);
+
+        // A user's passkey logs in as them, under a handle of their own
+        let alice = register(&webauthn, &key_pair(), b"credential-2", Some("alice"));
+        assert_eq!(alice.username.as_deref(), Some("alice"));
+        let state = load_state(&path).unwrap();
+        assert_ne!(state.user_ids["alice"], state.user_id);
+        let options = webauthn
+            .start_registration("session", Some("alice"))
+            .unwrap();
+        assert_eq!(options["user"]["id"], state.user_ids["alice"]);
+        assert_eq!(options["user"]["name"], "alice");
+        assert_eq!(webauthn.remove(&alice.id).unwrap(), Some(alice));
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        assert_eq!(webauthn.remove(&info.id).unwrap(), Some(info));
+        assert_eq!(webauthn.remove("missing").unwrap(), None);
+        assert!(webauthn.credentials().unwrap().is_empty());
+    }
+}
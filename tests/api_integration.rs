@@ -1,7 +1,7 @@
 use actix_web::{App, test, web};
-use articwake::api;
 use articwake::auth::AppState;
-use articwake::config::Config;
+use articwake::config::{Config, HostConfig, WebauthnConfig};
+use articwake::{api, tls, totp};
 use std::io::Write;
 use std::path::PathBuf;
 use tempfile::NamedTempFile;
@@ -10,12 +10,31 @@ fn create_test_config(pin_hash_path: PathBuf) -> Config {
     Config {
         bind_host: "127.0.0.1".to_string(),
         port: 8080,
-        homelab_mac: "aa:bb:cc:dd:ee:ff".to_string(),
-        homelab_ip: "127.0.0.1".to_string(),
-        homelab_broadcast: "255.255.255.255".to_string(),
-        ssh_port: 2222,
-        ssh_key_path: PathBuf::from("/tmp/nonexistent-key"),
+        hosts: vec![
+            HostConfig {
+                id: "homelab".to_string(),
+                mac: "aa:bb:cc:dd:ee:ff".to_string(),
+                ip: "127.0.0.1".to_string(),
+                broadcast: "255.255.255.255".to_string(),
+                ssh_port: 2222,
+                ssh_key_path: PathBuf::from("/tmp/nonexistent-key"),
+                ..Default::default()
+            },
+            HostConfig {
+                id: "nas".to_string(),
+                mac: "aa:bb:cc:dd:ee:01".to_string(),
+                ip: "127.0.0.1".to_string(),
+                broadcast: "127.255.255.255".to_string(),
+                ssh_port: 2223,
+                ssh_key_path: PathBuf::from("/tmp/nonexistent-key"),
+                ..Default::default()
+            },
+        ],
         pin_hash_path,
+        totp_secret_path: PathBuf::from("/nonexistent/totp.secret"),
+        users_path: PathBuf::from("/nonexistent/users.json"),
+        audit_log_path: None,
+        ..Default::default()
     }
 }
 
@@ -46,10 +65,84 @@ fn create_test_app(
     >,
 > {
     App::new()
+        .wrap(actix_web::middleware::from_fn(api::access::enforce))
         .app_data(state)
+        .route("/api/auth", web::get().to(api::auth::auth_methods))
         .route("/api/auth", web::post().to(api::auth::authenticate))
+        .route(
+            "/api/auth/passkeys",
+            web::get().to(api::auth::list_passkeys),
+        )
+        .route(
+            "/api/auth/passkeys/{id}",
+            web::delete().to(api::auth::delete_passkey),
+        )
+        .route(
+            "/api/auth/passkeys/register/start",
+            web::post().to(api::auth::start_passkey_registration),
+        )
+        .route(
+            "/api/auth/passkeys/register/finish",
+            web::post().to(api::auth::finish_passkey_registration),
+        )
+        .route(
+            "/api/auth/passkeys/login/start",
+            web::post().to(api::auth::start_passkey_login),
+        )
+        .route(
+            "/api/auth/passkeys/login/finish",
+            web::post().to(api::auth::finish_passkey_login),
+        )
+        .route("/api/pin", web::post().to(api::auth::change_pin))
+        .route("/api/logout", web::post().to(api::sessions::logout))
+        .route("/api/refresh", web::post().to(api::sessions::refresh))
+        .route("/api/sessions", web::get().to(api::sessions::list_sessions))
+        .route(
+            "/api/sessions/{id}",
+            web::delete().to(api::sessions::delete_session),
+        )
         .route("/api/status", web::get().to(api::status::get_status))
         .route("/api/wol", web::post().to(api::wol::send_wol))
+        .route("/api/events", web::get().to(api::events::status_events))
+        .route(
+            "/api/events/ticket",
+            web::post().to(api::events::events_ticket),
+        )
+        .route("/api/hosts", web::get().to(api::hosts::list_hosts))
+        .route(
+            "/api/hosts/{id}/status",
+            web::get().to(api::status::get_host_status),
+        )
+        .route(
+            "/api/hosts/{id}/wol",
+            web::post().to(api::wol::send_host_wol),
+        )
+        .route(
+            "/api/hosts/{id}/unlock",
+            web::post().to(api::unlock::unlock_host_by_id),
+        )
+        .route("/api/boot", web::post().to(api::boot::start_boot))
+        .route("/api/jobs/{id}", web::get().to(api::boot::get_job))
+        .route("/api/audit", web::get().to(api::audit::get_audit))
+        .route("/api/access", web::get().to(api::access::get_access))
+}
+
+async fn login<S, B>(app: &S) -> String
+where
+    S: actix_web::dev::Service<
+            actix_http::Request,
+            Response = actix_web::dev::ServiceResponse<B>,
+            Error = actix_web::Error,
+        >,
+    B: actix_web::body::MessageBody,
+{
+    let req = test::TestRequest::post()
+        .uri("/api/auth")
+        .set_json(serde_json::json!({"pin": "1234"}))
+        .to_request();
+
+    let body: serde_json::Value = test::call_and_read_body_json(app, req).await;
+    body["token"].as_str().unwrap().to_string()
 }
 
 #[actix_rt::test]
@@ -71,6 +164,7 @@ async fn test_auth_success() {
     let body: serde_json::Value = test::read_body_json(resp).await;
     assert!(body.get("token").is_some());
     assert_eq!(body["token"].as_str().unwrap().len(), 64);
+    assert_eq!(body["expires_in"], 900);
 }
 
 #[actix_rt::test]
@@ -152,6 +246,8 @@ async fn test_status_with_valid_token() {
     assert!(status_body.get("reachable").is_some());
     assert!(status_body.get("initrd_ssh_open").is_some());
     assert!(status_body.get("system_ssh_open").is_some());
+    // Hosts without probes get ping plus both SSH ports
+    assert_eq!(status_body["probes"].as_array().unwrap().len(), 3);
 }
 
 #[actix_rt::test]
@@ -224,8 +320,8 @@ async fn test_rate_limiting() {
 
     let app = test::init_service(create_test_app(state)).await;
 
-    // Make 10 failed attempts (the limit)
-    for _ in 0..10 {
+    // 5 failed attempts are free
+    for _ in 0..5 {
         let req = test::TestRequest::post()
             .uri("/api/auth")
             .set_json(serde_json::json!({"pin": "wrong"}))
@@ -235,7 +331,7 @@ async fn test_rate_limiting() {
         assert_eq!(resp.status(), 401);
     }
 
-    // 11th attempt should be rate limited
+    // The 6th has to wait
     let req = test::TestRequest::post()
         .uri("/api/auth")
         .set_json(serde_json::json!({"pin": "wrong"}))
@@ -243,4 +339,1878 @@ async fn test_rate_limiting() {
 
     let resp = test::call_service(&app, req).await;
     assert_eq!(resp.status(), 429); // Too Many Requests
+    assert_eq!(resp.headers().get("Retry-After").unwrap(), "30");
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["retry_after"], 30);
+}
+
+#[actix_rt::test]
+async fn test_login_busy() {
+    let hash_file = create_pin_hash("1234");
+    let dir = tempfile::TempDir::new().unwrap();
+    let lockout_path = dir.path().join("lockout");
+    let audit_path = dir.path().join("audit.log");
+    let mut config = create_test_config(hash_file.path().to_path_buf());
+    config.login.max_concurrent_hashes = 1;
+    config.login.lockout_path = lockout_path.clone();
+    config.audit_log_path = Some(audit_path.clone());
+    let (_totp_dir, secret) = enroll_totp(&mut config);
+    let state = web::Data::new(AppState::new(config));
+    let app = test::init_service(create_test_app(state.clone())).await;
+    let login = |body: serde_json::Value| {
+        test::TestRequest::post()
+            .uri("/api/auth")
+            .set_json(body)
+            .to_request()
+    };
+
+    // Every hashing slot is taken
+    let permit = state.reserve_hash().unwrap();
+    for _ in 0..6 {
+        let body = serde_json::json!({"pin": "1234", "totp": "000000"});
+        let resp = test::call_service(&app, login(body)).await;
+        assert_eq!(resp.status(), 503);
+        assert_eq!(resp.headers().get("Retry-After").unwrap(), "1");
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["retry_after"], 1);
+    }
+    // Nothing was checked, so nothing is audited
+    assert_eq!(std::fs::read_to_string(&audit_path).unwrap(), "");
+
+    // Refusals that need no hashing come first
+    let resp = test::call_service(&app, login(serde_json::json!({"pin": "1234"}))).await;
+    assert_eq!(resp.status(), 401);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["totp_required"], true);
+    articwake::lockout::lock(&lockout_path, "test").unwrap();
+    let body = serde_json::json!({"pin": "1234", "totp": "000000"});
+    let resp = test::call_service(&app, login(body)).await;
+    assert_eq!(resp.status(), 403);
+    std::fs::remove_file(&lockout_path).unwrap();
+
+    // Turned away requests are not failed logins, so there is no backoff
+    drop(permit);
+    let code = totp::code(&secret, totp::current_step());
+    let body = serde_json::json!({"pin": "1234", "totp": code});
+    let resp = test::call_service(&app, login(body)).await;
+    assert_eq!(resp.status(), 200);
+}
+
+#[actix_rt::test]
+async fn test_login_does_not_block_other_requests() {
+    let hash_file = create_pin_hash("1234");
+    let config = create_test_config(hash_file.path().to_path_buf());
+    let state = web::Data::new(AppState::new(config));
+    let app = test::init_service(create_test_app(state)).await;
+
+    use actix_web::http::StatusCode;
+
+    // Each request reports here when it is answered
+    let (done, mut answered) = tokio::sync::mpsc::unbounded_channel();
+    let login = async {
+        let req = test::TestRequest::post()
+            .uri("/api/auth")
+            .set_json(serde_json::json!({"pin": "1234"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        done.send(("login", resp.status())).unwrap();
+    };
+    let methods = async {
+        let req = test::TestRequest::get().uri("/api/auth").to_request();
+        let resp = test::call_service(&app, req).await;
+        done.send(("methods", resp.status())).unwrap();
+    };
+    // The test runtime has one thread and the login is started first: the
+    // second request is only answered first if the hash runs elsewhere
+    tokio::join!(login, methods);
+    assert_eq!(answered.recv().await.unwrap(), ("methods", StatusCode::OK));
+    assert_eq!(answered.recv().await.unwrap(), ("login", StatusCode::OK));
+}
+
+fn forwarded_login(from: &str, pin: &str) -> actix_http::Request {
+    test::TestRequest::post()
+        .uri("/api/auth")
+        .insert_header(("X-Forwarded-For", from))
+        .set_json(serde_json::json!({"pin": pin}))
+        .to_request()
+}
+
+#[actix_rt::test]
+async fn test_trusted_proxy_client_ip() {
+    let hash_file = create_pin_hash("1234");
+    let mut config = create_test_config(hash_file.path().to_path_buf());
+    config.trusted_proxies = vec!["127.0.0.1/32".parse().unwrap()];
+    let state = web::Data::new(AppState::new(config));
+
+    let app = test::init_service(create_test_app(state)).await;
+
+    for _ in 0..5 {
+        let resp = test::call_service(&app, forwarded_login("203.0.113.9", "wrong")).await;
+        assert_eq!(resp.status(), 401);
+    }
+    let resp = test::call_service(&app, forwarded_login("203.0.113.9", "wrong")).await;
+    assert_eq!(resp.status(), 429);
+
+    // Another client behind the same proxy has its own budget
+    let resp = test::call_service(&app, forwarded_login("203.0.113.10", "1234")).await;
+    assert_eq!(resp.status(), 200);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    let token = body["token"].as_str().unwrap();
+
+    let req = test::TestRequest::get()
+        .uri("/api/sessions")
+        .insert_header(("Authorization", format!("Bearer {}", token)))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["sessions"][0]["client_ip"], "203.0.113.10");
+}
+
+fn get_from(uri: &str, from: &str) -> test::TestRequest {
+    test::TestRequest::get()
+        .uri(uri)
+        .peer_addr(format!("{}:40000", from).parse().unwrap())
+}
+
+#[actix_rt::test]
+async fn test_access_rules() {
+    let hash_file = create_pin_hash("1234");
+    let mut config = create_test_config(hash_file.path().to_path_buf());
+    config.access.api.allow = vec!["192.0.2.0/24".parse().unwrap()];
+    config.access.ui.deny = vec!["203.0.113.0/24".parse().unwrap()];
+    config.trusted_proxies = vec!["127.0.0.1/32".parse().unwrap()];
+    let state = web::Data::new(AppState::new(config));
+
+    let app = test::init_service(create_test_app(state)).await;
+
+    // Refused before the handler, so no 401
+    let resp = test::call_service(&app, get_from("/api/status", "198.51.100.1").to_request()).await;
+    assert_eq!(resp.status(), 403);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"], "Access from this address is not allowed");
+    let resp = test::call_service(&app, get_from("/api/status", "192.0.2.5").to_request()).await;
+    assert_eq!(resp.status(), 401);
+
+    // The UI has its own rules: 404 here means the request got through
+    let resp = test::call_service(&app, get_from("/app.js", "198.51.100.1").to_request()).await;
+    assert_eq!(resp.status(), 404);
+    let resp = test::call_service(&app, get_from("/app.js", "203.0.113.7").to_request()).await;
+    assert_eq!(resp.status(), 403);
+
+    // Rules apply to the client behind a trusted proxy
+    let req = get_from("/api/status", "127.0.0.1")
+        .insert_header(("X-Forwarded-For", "198.51.100.1"))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 403);
+
+    let req = test::TestRequest::post()
+        .uri("/api/auth")
+        .peer_addr("192.0.2.5:40000".parse().unwrap())
+        .set_json(serde_json::json!({"pin": "1234"}))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let token = body["token"].as_str().unwrap();
+
+    let req = get_from("/api/access", "192.0.2.5")
+        .insert_header(("Authorization", format!("Bearer {}", token)))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["api"]["allow"], serde_json::json!(["192.0.2.0/24"]));
+    assert_eq!(body["api"]["blocked"], 2);
+    assert_eq!(body["ui"]["deny"], serde_json::json!(["203.0.113.0/24"]));
+    assert_eq!(body["ui"]["blocked"], 1);
+
+    // Encoding `/api` reaches the same handlers, so it gets the same rules
+    let resp =
+        test::call_service(&app, get_from("/%61pi/status", "198.51.100.1").to_request()).await;
+    assert_eq!(resp.status(), 403);
+    let resp = test::call_service(&app, get_from("/%61pi/status", "192.0.2.5").to_request()).await;
+    assert_eq!(resp.status(), 401);
+}
+
+#[actix_rt::test]
+async fn test_untrusted_forwarded_header_ignored() {
+    let hash_file = create_pin_hash("1234");
+    let config = create_test_config(hash_file.path().to_path_buf());
+    let state = web::Data::new(AppState::new(config));
+
+    let app = test::init_service(create_test_app(state)).await;
+
+    for i in 0..5 {
+        let from = format!("203.0.113.{}", i);
+        let resp = test::call_service(&app, forwarded_login(&from, "wrong")).await;
+        assert_eq!(resp.status(), 401);
+    }
+    // Changing the header does not get a fresh budget
+    let resp = test::call_service(&app, forwarded_login("198.51.100.1", "wrong")).await;
+    assert_eq!(resp.status(), 429);
+}
+
+#[actix_rt::test]
+async fn test_successful_logins_not_rate_limited() {
+    let hash_file = create_pin_hash("1234");
+    let config = create_test_config(hash_file.path().to_path_buf());
+    let state = web::Data::new(AppState::new(config));
+
+    let app = test::init_service(create_test_app(state)).await;
+
+    for _ in 0..7 {
+        login(&app).await;
+    }
+
+    // A success wipes earlier failures
+    for _ in 0..4 {
+        let req = test::TestRequest::post()
+            .uri("/api/auth")
+            .set_json(serde_json::json!({"pin": "wrong"}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 401);
+    }
+    login(&app).await;
+    for _ in 0..4 {
+        let req = test::TestRequest::post()
+            .uri("/api/auth")
+            .set_json(serde_json::json!({"pin": "wrong"}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 401);
+    }
+}
+
+#[actix_rt::test]
+async fn test_lockout() {
+    let hash_file = create_pin_hash("1234");
+    let dir = tempfile::TempDir::new().unwrap();
+    let lockout_path = dir.path().join("lockout");
+    let mut config = create_test_config(hash_file.path().to_path_buf());
+    config.login.lockout_after = Some(2);
+    config.login.lockout_path = lockout_path.clone();
+    let state = web::Data::new(AppState::new(config));
+
+    let app = test::init_service(create_test_app(state)).await;
+
+    for _ in 0..2 {
+        let req = test::TestRequest::post()
+            .uri("/api/auth")
+            .set_json(serde_json::json!({"pin": "wrong"}))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 401);
+    }
+
+    let req = test::TestRequest::post()
+        .uri("/api/auth")
+        .set_json(serde_json::json!({"pin": "1234"}))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 403);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["locked_out"], true);
+
+    std::fs::remove_file(&lockout_path).unwrap();
+    login(&app).await;
+}
+
+/// Enrolls a fresh TOTP secret; keep the directory alive for the test.
+fn enroll_totp(config: &mut Config) -> (tempfile::TempDir, Vec<u8>) {
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("totp.secret");
+    let secret = totp::generate_secret();
+    totp::write_secret(&path, &secret, false).unwrap();
+    config.totp_secret_path = path;
+    (dir, secret)
+}
+
+#[actix_rt::test]
+async fn test_change_pin_totp() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let hash_path = dir.path().join("pin.hash");
+    articwake::pin::write_hash(
+        &hash_path,
+        &articwake::pin::hash("1234", &Default::default()).unwrap(),
+    )
+    .unwrap();
+    let mut config = create_test_config(hash_path);
+    let (_totp_dir, secret) = enroll_totp(&mut config);
+    let state = web::Data::new(AppState::new(config));
+    let app = test::init_service(create_test_app(state)).await;
+
+    let code = totp::code(&secret, totp::current_step());
+    let (_, body) = post_json(
+        &app,
+        "/api/auth",
+        None,
+        serde_json::json!({"pin": "1234", "totp": code}),
+    )
+    .await;
+    let token = body["token"].as_str().unwrap();
+
+    let change = serde_json::json!({"current_pin": "1234", "new_pin": "270418"});
+    let (status, body) = post_json(&app, "/api/pin", Some(token), change.clone()).await;
+    assert_eq!(status, 401);
+    assert_eq!(body["totp_required"], true);
+
+    let mut change = change;
+    change["totp"] = totp::code(&secret, totp::current_step() + 1).into();
+    let (status, _) = post_json(&app, "/api/pin", Some(token), change).await;
+    assert_eq!(status, 200);
+}
+
+#[actix_rt::test]
+async fn test_auth_totp() {
+    let hash_file = create_pin_hash("1234");
+    let mut config = create_test_config(hash_file.path().to_path_buf());
+    let (_dir, secret) = enroll_totp(&mut config);
+    let state = web::Data::new(AppState::new(config));
+
+    let app = test::init_service(create_test_app(state)).await;
+
+    let auth = |body: serde_json::Value| {
+        test::TestRequest::post()
+            .uri("/api/auth")
+            .set_json(body)
+            .to_request()
+    };
+
+    let resp = test::call_service(&app, auth(serde_json::json!({"pin": "1234"}))).await;
+    assert_eq!(resp.status(), 401);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["totp_required"], true);
+
+    // A wrong PIN and a wrong code get the same answer
+    let code = totp::code(&secret, totp::current_step());
+    let resp =
+        test::call_service(&app, auth(serde_json::json!({"pin": "0000", "totp": code}))).await;
+    assert_eq!(resp.status(), 401);
+    let wrong_pin: serde_json::Value = test::read_body_json(resp).await;
+    let resp = test::call_service(
+        &app,
+        auth(serde_json::json!({"pin": "1234", "totp": "000000x"})),
+    )
+    .await;
+    assert_eq!(resp.status(), 401);
+    let wrong_code: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(wrong_pin, wrong_code);
+
+    let resp =
+        test::call_service(&app, auth(serde_json::json!({"pin": "1234", "totp": code}))).await;
+    assert_eq!(resp.status(), 200);
+
+    // The same code cannot be used twice
+    let resp =
+        test::call_service(&app, auth(serde_json::json!({"pin": "1234", "totp": code}))).await;
+    assert_eq!(resp.status(), 401);
+}
+
+#[actix_rt::test]
+async fn test_rate_limiting_counts_bad_totp() {
+    let hash_file = create_pin_hash("1234");
+    let mut config = create_test_config(hash_file.path().to_path_buf());
+    let (_dir, secret) = enroll_totp(&mut config);
+    let state = web::Data::new(AppState::new(config));
+
+    let app = test::init_service(create_test_app(state)).await;
+
+    for _ in 0..5 {
+        let req = test::TestRequest::post()
+            .uri("/api/auth")
+            .set_json(serde_json::json!({"pin": "1234", "totp": "123456"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 401);
+    }
+
+    // Even the right code is refused once the limit is reached
+    let code = totp::code(&secret, totp::current_step());
+    let req = test::TestRequest::post()
+        .uri("/api/auth")
+        .set_json(serde_json::json!({"pin": "1234", "totp": code}))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 429);
+}
+
+#[actix_rt::test]
+async fn test_hosts_unauthorized() {
+    let hash_file = create_pin_hash("1234");
+    let config = create_test_config(hash_file.path().to_path_buf());
+    let state = web::Data::new(AppState::new(config));
+
+    let app = test::init_service(create_test_app(state)).await;
+
+    let req = test::TestRequest::get().uri("/api/hosts").to_request();
+
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 401);
+}
+
+#[actix_rt::test]
+async fn test_hosts_list() {
+    let hash_file = create_pin_hash("1234");
+    let config = create_test_config(hash_file.path().to_path_buf());
+    let state = web::Data::new(AppState::new(config));
+
+    let app = test::init_service(create_test_app(state)).await;
+    let token = login(&app).await;
+
+    let req = test::TestRequest::get()
+        .uri("/api/hosts")
+        .insert_header(("Authorization", format!("Bearer {}", token)))
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    let hosts = body["hosts"].as_array().unwrap();
+    assert_eq!(hosts.len(), 2);
+    assert_eq!(hosts[0]["id"], "homelab");
+    assert_eq!(hosts[1]["id"], "nas");
+    assert_eq!(hosts[1]["initrd_ssh_port"], 2223);
+    assert!(hosts[1]["status"].get("initrd_ssh_open").is_some());
+}
+
+#[actix_rt::test]
+async fn test_host_status_by_id() {
+    let hash_file = create_pin_hash("1234");
+    let config = create_test_config(hash_file.path().to_path_buf());
+    let state = web::Data::new(AppState::new(config));
+
+    let app = test::init_service(create_test_app(state)).await;
+    let token = login(&app).await;
+
+    let req = test::TestRequest::get()
+        .uri("/api/hosts/nas/status")
+        .insert_header(("Authorization", format!("Bearer {}", token)))
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["host"], "nas");
+    assert_eq!(body["initrd_ssh_port"], 2223);
+}
+
+#[actix_rt::test]
+async fn test_host_wol_by_id() {
+    let hash_file = create_pin_hash("1234");
+    let config = create_test_config(hash_file.path().to_path_buf());
+    let state = web::Data::new(AppState::new(config));
+
+    let app = test::init_service(create_test_app(state)).await;
+    let token = login(&app).await;
+
+    let req = test::TestRequest::post()
+        .uri("/api/hosts/nas/wol")
+        .insert_header(("Authorization", format!("Bearer {}", token)))
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+}
+
+#[actix_rt::test]
+async fn test_unknown_host() {
+    let hash_file = create_pin_hash("1234");
+    let config = create_test_config(hash_file.path().to_path_buf());
+    let state = web::Data::new(AppState::new(config));
+
+    let app = test::init_service(create_test_app(state)).await;
+    let token = login(&app).await;
+
+    for (method, uri) in [
+        ("GET", "/api/hosts/backup/status"),
+        ("POST", "/api/hosts/backup/wol"),
+    ] {
+        let req = match method {
+            "GET" => test::TestRequest::get(),
+            _ => test::TestRequest::post(),
+        }
+        .uri(uri)
+        .insert_header(("Authorization", format!("Bearer {}", token)))
+        .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 404, "{} {}", method, uri);
+    }
+
+    let req = test::TestRequest::post()
+        .uri("/api/hosts/backup/unlock")
+        .insert_header(("Authorization", format!("Bearer {}", token)))
+        .set_json(serde_json::json!({"passphrase": "secret"}))
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 404);
+}
+
+#[actix_rt::test]
+async fn test_boot_unauthorized() {
+    let hash_file = create_pin_hash("1234");
+    let config = create_test_config(hash_file.path().to_path_buf());
+    let state = web::Data::new(AppState::new(config));
+
+    let app = test::init_service(create_test_app(state)).await;
+
+    let req = test::TestRequest::post()
+        .uri("/api/boot")
+        .set_json(serde_json::json!({"passphrase": "secret"}))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 401);
+
+    let req = test::TestRequest::get().uri("/api/jobs/abc").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 401);
+}
+
+#[actix_rt::test]
+async fn test_boot_rejects_bad_requests() {
+    let hash_file = create_pin_hash("1234");
+    let config = create_test_config(hash_file.path().to_path_buf());
+    let state = web::Data::new(AppState::new(config));
+
+    let app = test::init_service(create_test_app(state)).await;
+    let token = login(&app).await;
+
+    let req = test::TestRequest::post()
+        .uri("/api/boot")
+        .insert_header(("Authorization", format!("Bearer {}", token)))
+        .set_json(serde_json::json!({"passphrase": ""}))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 400);
+
+    let req = test::TestRequest::post()
+        .uri("/api/boot")
+        .insert_header(("Authorization", format!("Bearer {}", token)))
+        .set_json(serde_json::json!({"passphrase": "secret", "host": "backup"}))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 404);
+
+    let req = test::TestRequest::get()
+        .uri("/api/jobs/0123456789abcdef")
+        .insert_header(("Authorization", format!("Bearer {}", token)))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 404);
+}
+
+#[actix_rt::test]
+async fn test_boot_job_lifecycle() {
+    let hash_file = create_pin_hash("1234");
+    let config = create_test_config(hash_file.path().to_path_buf());
+    let state = web::Data::new(AppState::new(config));
+
+    let app = test::init_service(create_test_app(state)).await;
+    let token = login(&app).await;
+
+    let req = test::TestRequest::post()
+        .uri("/api/boot")
+        .insert_header(("Authorization", format!("Bearer {}", token)))
+        .set_json(serde_json::json!({"passphrase": "secret", "host": "nas"}))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 202);
+
+    let job: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(job["host"], "nas");
+    assert_eq!(job["phase"], "waking");
+    let id = job["id"].as_str().unwrap();
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/api/jobs/{}", id))
+        .insert_header(("Authorization", format!("Bearer {}", token)))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["id"], id);
+    assert!(body["phase"].is_string());
+}
+
+#[actix_rt::test]
+async fn test_events_unauthorized() {
+    let hash_file = create_pin_hash("1234");
+    let config = create_test_config(hash_file.path().to_path_buf());
+    let state = web::Data::new(AppState::new(config));
+
+    let app = test::init_service(create_test_app(state)).await;
+
+    for uri in [
+        "/api/events",
+        "/api/events?token=invalid",
+        "/api/events?ticket=invalid",
+    ] {
+        let req = test::TestRequest::get().uri(uri).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 401, "{}", uri);
+    }
+}
+
+#[actix_rt::test]
+async fn test_events_stream_snapshot() {
+    use actix_web::body::MessageBody;
+    use articwake::config::HostPhase;
+    use articwake::services::network::{HostStatus, ProbeResult};
+
+    let hash_file = create_pin_hash("1234");
+    let config = create_test_config(hash_file.path().to_path_buf());
+    let state = web::Data::new(AppState::new(config));
+    state.monitor.record(
+        "nas",
+        HostStatus::from_probes(vec![ProbeResult {
+            name: "initrd_ssh".to_string(),
+            kind: "tcp",
+            phase: Some(HostPhase::Initrd),
+            ok: true,
+            latency_ms: Some(4),
+            error: None,
+            responded: true,
+        }]),
+    );
+
+    let app = test::init_service(create_test_app(state)).await;
+    let token = login(&app).await;
+
+    let req = test::TestRequest::get()
+        .uri(&format!(
+            "/api/events?ticket={}&host=nas",
+            events_ticket(&app, &token).await
+        ))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+    assert_eq!(
+        resp.headers().get("content-type").unwrap(),
+        "text/event-stream"
+    );
+
+    let mut body = std::pin::pin!(resp.into_body());
+    let chunk = futures_util::future::poll_fn(|cx| body.as_mut().poll_next(cx))
+        .await
+        .and_then(Result::ok)
+        .expect("expected a snapshot event");
+    let chunk = String::from_utf8(chunk.to_vec()).unwrap();
+    assert!(chunk.starts_with("event: snapshot\n"));
+    assert!(chunk.contains("\"phase\":\"initrd\""));
+
+    let req = test::TestRequest::get()
+        .uri(&format!(
+            "/api/events?ticket={}&host=backup",
+            events_ticket(&app, &token).await
+        ))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 404);
+}
+
+async fn events_ticket<S, B>(app: &S, token: &str) -> String
+where
+    S: actix_web::dev::Service<
+            actix_http::Request,
+            Response = actix_web::dev::ServiceResponse<B>,
+            Error = actix_web::Error,
+        >,
+    B: actix_web::body::MessageBody,
+{
+    let (status, body) = post_json(
+        app,
+        "/api/events/ticket",
+        Some(token),
+        serde_json::json!({}),
+    )
+    .await;
+    assert_eq!(status, 200, "{}", body);
+    body["ticket"].as_str().unwrap().to_string()
+}
+
+#[actix_rt::test]
+async fn test_events_ticket_single_use() {
+    use articwake::apikeys::Scope;
+
+    let dir = tempfile::TempDir::new().unwrap();
+    let hash_file = create_pin_hash("1234");
+    let mut config = create_test_config(hash_file.path().to_path_buf());
+    config.api_keys_path = dir.path().join("api-keys.json");
+    let state = web::Data::new(AppState::new(config));
+    let (_, key) = state
+        .api_keys
+        .create("script", vec![Scope::StatusRead], None)
+        .unwrap();
+
+    let app = test::init_service(create_test_app(state)).await;
+    let token = login(&app).await;
+
+    // Session tokens are no longer taken from the URL
+    let req = test::TestRequest::get()
+        .uri(&format!("/api/events?token={}", token))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 401);
+
+    let ticket = events_ticket(&app, &token).await;
+    let uri = format!("/api/events?ticket={}", ticket);
+    let req = test::TestRequest::get().uri(&uri).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 200);
+    let req = test::TestRequest::get().uri(&uri).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 401);
+
+    // A ticket dies with its session
+    let ticket = events_ticket(&app, &token).await;
+    let (status, _) = post_json(&app, "/api/logout", Some(&token), serde_json::json!({})).await;
+    assert_eq!(status, 204);
+    let req = test::TestRequest::get()
+        .uri(&format!("/api/events?ticket={}", ticket))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 401);
+
+    // API keys use the Authorization header instead
+    let (status, _) = post_json(
+        &app,
+        "/api/events/ticket",
+        Some(&key),
+        serde_json::json!({}),
+    )
+    .await;
+    assert_eq!(status, 403);
+    let req = test::TestRequest::get()
+        .uri("/api/events")
+        .insert_header(("Authorization", format!("Bearer {}", key)))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 200);
+}
+
+#[actix_rt::test]
+async fn test_events_with_api_key() {
+    use actix_web::body::MessageBody;
+    use articwake::apikeys::Scope;
+    use articwake::config::HostPhase;
+    use articwake::services::network::{HostStatus, ProbeResult};
+
+    let dir = tempfile::TempDir::new().unwrap();
+    let mut config = create_test_config(PathBuf::from("/nonexistent/pin.hash"));
+    config.api_keys_path = dir.path().join("api-keys.json");
+    let state = web::Data::new(AppState::new(config));
+    let (_, key) = state
+        .api_keys
+        .create("home-assistant", vec![Scope::StatusRead], None)
+        .unwrap();
+    let app = test::init_service(create_test_app(state.clone())).await;
+
+    let req = test::TestRequest::get()
+        .uri("/api/events")
+        .insert_header(("Authorization", format!("Bearer {}", key)))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    let mut body = std::pin::pin!(resp.into_body());
+    let mut next_chunk = async || {
+        let chunk = futures_util::future::poll_fn(|cx| body.as_mut().poll_next(cx))
+            .await
+            .and_then(Result::ok)
+            .expect("expected the stream to go on");
+        String::from_utf8(chunk.to_vec()).unwrap()
+    };
+
+    // Nothing to report yet: the first keep-alive re-checks the key, which
+    // must not end the stream
+    assert_eq!(next_chunk().await, ": keep-alive\n\n");
+
+    state.monitor.record(
+        "nas",
+        HostStatus::from_probes(vec![ProbeResult {
+            name: "initrd_ssh".to_string(),
+            kind: "tcp",
+            phase: Some(HostPhase::Initrd),
+            ok: true,
+            latency_ms: Some(4),
+            error: None,
+            responded: true,
+        }]),
+    );
+    let chunk = next_chunk().await;
+    assert!(chunk.starts_with("event: status\n"));
+    assert!(chunk.contains("\"host\":\"nas\""));
+}
+
+#[actix_rt::test]
+async fn test_audit_unauthorized() {
+    let hash_file = create_pin_hash("1234");
+    let config = create_test_config(hash_file.path().to_path_buf());
+    let state = web::Data::new(AppState::new(config));
+
+    let app = test::init_service(create_test_app(state)).await;
+
+    let req = test::TestRequest::get().uri("/api/audit").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 401);
+}
+
+#[actix_rt::test]
+async fn test_audit_disabled() {
+    let hash_file = create_pin_hash("1234");
+    let config = create_test_config(hash_file.path().to_path_buf());
+    let state = web::Data::new(AppState::new(config));
+
+    let app = test::init_service(create_test_app(state)).await;
+    let token = login(&app).await;
+
+    let req = test::TestRequest::get()
+        .uri("/api/audit")
+        .insert_header(("Authorization", format!("Bearer {}", token)))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 404);
+}
+
+#[actix_rt::test]
+async fn test_audit_records_actions() {
+    let hash_file = create_pin_hash("1234");
+    let audit_dir = tempfile::TempDir::new().unwrap();
+    let audit_path = audit_dir.path().join("audit.log");
+    let mut config = create_test_config(hash_file.path().to_path_buf());
+    config.audit_log_path = Some(audit_path.clone());
+    let state = web::Data::new(AppState::new(config));
+
+    let app = test::init_service(create_test_app(state)).await;
+
+    let req = test::TestRequest::post()
+        .uri("/api/auth")
+        .set_json(serde_json::json!({"pin": "0000"}))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 401);
+    let token = login(&app).await;
+    let auth = ("Authorization", format!("Bearer {}", token));
+
+    let req = test::TestRequest::post()
+        .uri("/api/hosts/nas/wol")
+        .insert_header(auth.clone())
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+
+    let req = test::TestRequest::post()
+        .uri("/api/boot")
+        .insert_header(auth.clone())
+        .set_json(serde_json::json!({"passphrase": "correct-horse-battery", "host": "nas"}))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 202);
+
+    let get = |uri: &str| {
+        test::TestRequest::get()
+            .uri(uri)
+            .insert_header(auth.clone())
+            .to_request()
+    };
+
+    let page: serde_json::Value =
+        test::call_and_read_body_json(&app, get("/api/audit?action=login")).await;
+    assert_eq!(page["total"], 2);
+    let entries = page["entries"].as_array().unwrap();
+    assert_eq!(entries[0]["outcome"], "success");
+    assert!(entries[0]["session_id"].is_string());
+    assert_eq!(entries[1]["outcome"], "failure");
+    assert_eq!(entries[1]["detail"], "Invalid PIN");
+    assert!(entries[1]["session_id"].is_null());
+
+    let page: serde_json::Value =
+        test::call_and_read_body_json(&app, get("/api/audit?action=wol&host=nas")).await;
+    let wol = &page["entries"][0];
+    assert_eq!(wol["outcome"], "success");
+    assert_eq!(wol["client_ip"], "127.0.0.1");
+    assert_eq!(wol["session_id"], entries[0]["session_id"]);
+
+    let page: serde_json::Value =
+        test::call_and_read_body_json(&app, get("/api/audit?action=boot&limit=1")).await;
+    assert_eq!(page["limit"], 1);
+    assert!(
+        page["entries"][0]["detail"]
+            .as_str()
+            .unwrap()
+            .starts_with("Job ")
+    );
+
+    let req = get("/api/audit?action=reboot");
+    assert_eq!(test::call_service(&app, req).await.status(), 400);
+
+    let log = std::fs::read_to_string(&audit_path).unwrap();
+    assert!(!log.contains("correct-horse-battery"));
+    assert!(!log.contains(&token));
+}
+
+const PASSKEY_ORIGIN: &str = "https://articwake.test";
+
+/// Software WebAuthn authenticator: an ES256 key answering ceremonies the
+/// way a browser and platform authenticator would.
+struct SoftAuthenticator {
+    key: ring::signature::EcdsaKeyPair,
+    credential_id: Vec<u8>,
+    sign_count: u32,
+    origin: String,
+}
+
+impl SoftAuthenticator {
+    fn new(origin: &str) -> Self {
+        use ring::signature::{ECDSA_P256_SHA256_ASN1_SIGNING, EcdsaKeyPair};
+
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng)
+            .unwrap();
+        SoftAuthenticator {
+            key,
+            credential_id: rand::random::<[u8; 16]>().to_vec(),
+            sign_count: 0,
+            origin: origin.to_string(),
+        }
+    }
+
+    fn client_data(&self, kind: &str, options: &serde_json::Value) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({
+            "type": kind,
+            "challenge": options["publicKey"]["challenge"],
+            "origin": self.origin,
+        }))
+        .unwrap()
+    }
+
+    /// Authenticator data with user presence and verification flags set.
+    fn auth_data(&mut self, rp_id: &str, attested: bool) -> Vec<u8> {
+        use ring::signature::KeyPair;
+
+        self.sign_count += 1;
+        let mut data = ring::digest::digest(&ring::digest::SHA256, rp_id.as_bytes())
+            .as_ref()
+            .to_vec();
+        data.push(if attested { 0x45 } else { 0x05 });
+        data.extend_from_slice(&self.sign_count.to_be_bytes());
+        if attested {
+            let point = self.key.public_key().as_ref();
+            let cose = ciborium::Value::Map(vec![
+                (1.into(), 2.into()),
+                (3.into(), (-7).into()),
+                ((-1).into(), 1.into()),
+                ((-2).into(), point[1..33].into()),
+                ((-3).into(), point[33..].into()),
+            ]);
+            data.extend_from_slice(&[0; 16]);
+            data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+            data.extend_from_slice(&self.credential_id);
+            ciborium::into_writer(&cose, &mut data).unwrap();
+        }
+        data
+    }
+
+    fn register(&mut self, options: &serde_json::Value) -> serde_json::Value {
+        let rp_id = options["publicKey"]["rp"]["id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let attestation = ciborium::Value::Map(vec![
+            ("fmt".into(), "none".into()),
+            ("attStmt".into(), ciborium::Value::Map(vec![])),
+            ("authData".into(), self.auth_data(&rp_id, true).into()),
+        ]);
+        let mut attestation_object = Vec::new();
+        ciborium::into_writer(&attestation, &mut attestation_object).unwrap();
+
+        let b64 = |bytes: &[u8]| data_encoding::BASE64URL_NOPAD.encode(bytes);
+        serde_json::json!({
+            "id": b64(&self.credential_id),
+            "type": "public-key",
+            "response": {
+                "clientDataJSON": b64(&self.client_data("webauthn.create", options)),
+                "attestationObject": b64(&attestation_object),
+            },
+        })
+    }
+
+    fn login(&mut self, options: &serde_json::Value) -> serde_json::Value {
+        let rp_id = options["publicKey"]["rpId"].as_str().unwrap().to_string();
+        let auth_data = self.auth_data(&rp_id, false);
+        let client_data = self.client_data("webauthn.get", options);
+
+        let mut signed = auth_data.clone();
+        signed
+            .extend_from_slice(ring::digest::digest(&ring::digest::SHA256, &client_data).as_ref());
+        let signature = self
+            .key
+            .sign(&ring::rand::SystemRandom::new(), &signed)
+            .unwrap();
+
+        let b64 = |bytes: &[u8]| data_encoding::BASE64URL_NOPAD.encode(bytes);
+        serde_json::json!({
+            "id": b64(&self.credential_id),
+            "type": "public-key",
+            "response": {
+                "clientDataJSON": b64(&client_data),
+                "authenticatorData": b64(&auth_data),
+                "signature": b64(signature.as_ref()),
+            },
+        })
+    }
+}
+
+/// Enables passkeys with a credential store in a fresh directory.
+fn enable_passkeys(config: &mut Config) -> tempfile::TempDir {
+    let dir = tempfile::TempDir::new().unwrap();
+    config.webauthn = WebauthnConfig {
+        origin: Some(PASSKEY_ORIGIN.to_string()),
+        credentials_path: dir.path().join("webauthn.json"),
+    };
+    dir
+}
+
+async fn post_json<S, B>(
+    app: &S,
+    uri: &str,
+    token: Option<&str>,
+    body: serde_json::Value,
+) -> (u16, serde_json::Value)
+where
+    S: actix_web::dev::Service<
+            actix_http::Request,
+            Response = actix_web::dev::ServiceResponse<B>,
+            Error = actix_web::Error,
+        >,
+    B: actix_web::body::MessageBody,
+{
+    let mut req = test::TestRequest::post().uri(uri).set_json(body);
+    if let Some(token) = token {
+        req = req.insert_header(("Authorization", format!("Bearer {}", token)));
+    }
+    let resp = test::call_service(app, req.to_request()).await;
+    let status = resp.status().as_u16();
+    let body = test::read_body(resp).await;
+    (status, serde_json::from_slice(&body).unwrap_or_default())
+}
+
+#[actix_rt::test]
+async fn test_passkeys_disabled() {
+    let hash_file = create_pin_hash("1234");
+    let config = create_test_config(hash_file.path().to_path_buf());
+    let state = web::Data::new(AppState::new(config));
+
+    let app = test::init_service(create_test_app(state)).await;
+
+    let req = test::TestRequest::get().uri("/api/auth").to_request();
+    let methods: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(methods["passkeys"], false);
+    assert_eq!(methods["totp"], false);
+
+    let (status, _) = post_json(
+        &app,
+        "/api/auth/passkeys/login/start",
+        None,
+        serde_json::json!({}),
+    )
+    .await;
+    assert_eq!(status, 404);
+
+    let token = login(&app).await;
+    let (status, body) = post_json(
+        &app,
+        "/api/auth/passkeys/register/start",
+        Some(&token),
+        serde_json::json!({}),
+    )
+    .await;
+    assert_eq!(status, 404);
+    assert_eq!(body["error"], "Passkeys are not configured");
+}
+
+#[actix_rt::test]
+async fn test_passkey_register_and_login() {
+    let hash_file = create_pin_hash("1234");
+    let mut config = create_test_config(hash_file.path().to_path_buf());
+    let dir = enable_passkeys(&mut config);
+    let state = web::Data::new(AppState::new(config));
+
+    let app = test::init_service(create_test_app(state)).await;
+    let mut authenticator = SoftAuthenticator::new(PASSKEY_ORIGIN);
+
+    // Nothing registered yet
+    let (status, _) = post_json(
+        &app,
+        "/api/auth/passkeys/login/start",
+        None,
+        serde_json::json!({}),
+    )
+    .await;
+    assert_eq!(status, 404);
+
+    // Registration needs a PIN session
+    let (status, _) = post_json(
+        &app,
+        "/api/auth/passkeys/register/start",
+        None,
+        serde_json::json!({}),
+    )
+    .await;
+    assert_eq!(status, 401);
+
+    let token = login(&app).await;
+    let (status, options) = post_json(
+        &app,
+        "/api/auth/passkeys/register/start",
+        Some(&token),
+        serde_json::json!({}),
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(options["publicKey"]["rp"]["id"], "articwake.test");
+
+    let mut credential = authenticator.register(&options);
+    credential["name"] = "Phone".into();
+    let (status, passkey) = post_json(
+        &app,
+        "/api/auth/passkeys/register/finish",
+        Some(&token),
+        credential.clone(),
+    )
+    .await;
+    assert_eq!(status, 201);
+    assert_eq!(passkey["name"], "Phone");
+    assert!(dir.path().join("webauthn.json").exists());
+
+    // The challenge was used up
+    let (status, _) = post_json(
+        &app,
+        "/api/auth/passkeys/register/finish",
+        Some(&token),
+        credential,
+    )
+    .await;
+    assert_eq!(status, 400);
+
+    let req = test::TestRequest::get().uri("/api/auth").to_request();
+    let methods: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(methods["passkeys"], true);
+
+    let (status, options) = post_json(
+        &app,
+        "/api/auth/passkeys/login/start",
+        None,
+        serde_json::json!({}),
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        options["publicKey"]["allowCredentials"][0]["id"],
+        passkey["id"]
+    );
+
+    let assertion = authenticator.login(&options);
+    let (status, body) = post_json(
+        &app,
+        "/api/auth/passkeys/login/finish",
+        None,
+        assertion.clone(),
+    )
+    .await;
+    assert_eq!(status, 200);
+    let passkey_token = body["token"].as_str().unwrap();
+
+    // The passkey session works like a PIN session
+    let req = test::TestRequest::get()
+        .uri("/api/auth/passkeys")
+        .insert_header(("Authorization", format!("Bearer {}", passkey_token)))
+        .to_request();
+    let list: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(list["passkeys"][0]["name"], "Phone");
+    assert!(list["passkeys"][0]["last_used_at"].is_u64());
+
+    // Replaying the assertion fails
+    let (status, body) = post_json(&app, "/api/auth/passkeys/login/finish", None, assertion).await;
+    assert_eq!(status, 401);
+    assert_eq!(body["error"], "Passkey login failed");
+}
+
+#[actix_rt::test]
+async fn test_passkey_of_user() {
+    use articwake::users::Role;
+
+    let dir = tempfile::TempDir::new().unwrap();
+    let mut config = create_test_config(dir.path().join("pin.hash"));
+    config.users_path = dir.path().join("users.json");
+    let _passkeys = enable_passkeys(&mut config);
+    let state = web::Data::new(AppState::new(config));
+    state.users.add("alice", Role::Admin, "1234").unwrap();
+
+    let app = test::init_service(create_test_app(state.clone())).await;
+    let mut authenticator = SoftAuthenticator::new(PASSKEY_ORIGIN);
+
+    let (_, body) = post_json(
+        &app,
+        "/api/auth",
+        None,
+        serde_json::json!({"username": "alice", "pin": "1234"}),
+    )
+    .await;
+    let token = body["token"].as_str().unwrap().to_string();
+    let (_, options) = post_json(
+        &app,
+        "/api/auth/passkeys/register/start",
+        Some(&token),
+        serde_json::json!({}),
+    )
+    .await;
+    assert_eq!(options["publicKey"]["user"]["name"], "alice");
+    let (status, passkey) = post_json(
+        &app,
+        "/api/auth/passkeys/register/finish",
+        Some(&token),
+        authenticator.register(&options),
+    )
+    .await;
+    assert_eq!(status, 201);
+    assert_eq!(passkey["username"], "alice");
+
+    // The passkey logs in as alice, not as the shared account
+    let (_, options) = post_json(
+        &app,
+        "/api/auth/passkeys/login/start",
+        None,
+        serde_json::json!({}),
+    )
+    .await;
+    let (status, body) = post_json(
+        &app,
+        "/api/auth/passkeys/login/finish",
+        None,
+        authenticator.login(&options),
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body["username"], "alice");
+
+    // Once alice is gone, so is her passkey login
+    state.users.remove("alice").unwrap();
+    let (_, options) = post_json(
+        &app,
+        "/api/auth/passkeys/login/start",
+        None,
+        serde_json::json!({}),
+    )
+    .await;
+    let (status, _) = post_json(
+        &app,
+        "/api/auth/passkeys/login/finish",
+        None,
+        authenticator.login(&options),
+    )
+    .await;
+    assert_eq!(status, 401);
+}
+
+#[actix_rt::test]
+async fn test_passkey_login_failures() {
+    let hash_file = create_pin_hash("1234");
+    let mut config = create_test_config(hash_file.path().to_path_buf());
+    let _dir = enable_passkeys(&mut config);
+    let state = web::Data::new(AppState::new(config));
+
+    let app = test::init_service(create_test_app(state)).await;
+    let mut authenticator = SoftAuthenticator::new(PASSKEY_ORIGIN);
+
+    let token = login(&app).await;
+    let (_, options) = post_json(
+        &app,
+        "/api/auth/passkeys/register/start",
+        Some(&token),
+        serde_json::json!({}),
+    )
+    .await;
+    let (status, passkey) = post_json(
+        &app,
+        "/api/auth/passkeys/register/finish",
+        Some(&token),
+        authenticator.register(&options),
+    )
+    .await;
+    assert_eq!(status, 201);
+    assert_eq!(passkey["name"], "Passkey");
+
+    let login_start = || {
+        post_json(
+            &app,
+            "/api/auth/passkeys/login/start",
+            None,
+            serde_json::json!({}),
+        )
+    };
+
+    // Signed for another site
+    let mut phishing = SoftAuthenticator::new("https://articwake.example");
+    phishing.key = authenticator.key;
+    phishing.credential_id = authenticator.credential_id.clone();
+    let (_, options) = login_start().await;
+    let (status, _) = post_json(
+        &app,
+        "/api/auth/passkeys/login/finish",
+        None,
+        phishing.login(&options),
+    )
+    .await;
+    assert_eq!(status, 401);
+
+    // A credential that was never registered
+    let (_, options) = login_start().await;
+    let mut stranger = SoftAuthenticator::new(PASSKEY_ORIGIN);
+    let (status, _) = post_json(
+        &app,
+        "/api/auth/passkeys/login/finish",
+        None,
+        stranger.login(&options),
+    )
+    .await;
+    assert_eq!(status, 401);
+
+    // Once removed, the passkey no longer signs in
+    let req = test::TestRequest::delete()
+        .uri(&format!(
+            "/api/auth/passkeys/{}",
+            passkey["id"].as_str().unwrap()
+        ))
+        .insert_header(("Authorization", format!("Bearer {}", token)))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 204);
+    let (status, _) = login_start().await;
+    assert_eq!(status, 404);
+}
+
+#[actix_rt::test]
+async fn test_sessions_unauthorized() {
+    let hash_file = create_pin_hash("1234");
+    let config = create_test_config(hash_file.path().to_path_buf());
+    let state = web::Data::new(AppState::new(config));
+
+    let app = test::init_service(create_test_app(state)).await;
+
+    for req in [
+        test::TestRequest::post().uri("/api/logout").to_request(),
+        test::TestRequest::post().uri("/api/refresh").to_request(),
+        test::TestRequest::get().uri("/api/sessions").to_request(),
+        test::TestRequest::delete()
+            .uri("/api/sessions/0123456789abcdef")
+            .to_request(),
+    ] {
+        assert_eq!(test::call_service(&app, req).await.status(), 401);
+    }
+}
+
+#[actix_rt::test]
+async fn test_logout_invalidates_token() {
+    let hash_file = create_pin_hash("1234");
+    let config = create_test_config(hash_file.path().to_path_buf());
+    let state = web::Data::new(AppState::new(config));
+
+    let app = test::init_service(create_test_app(state)).await;
+    let token = login(&app).await;
+
+    let (status, _) = post_json(&app, "/api/logout", Some(&token), serde_json::json!({})).await;
+    assert_eq!(status, 204);
+
+    let req = test::TestRequest::get()
+        .uri("/api/status")
+        .insert_header(("Authorization", format!("Bearer {}", token)))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 401);
+
+    let (status, _) = post_json(&app, "/api/logout", Some(&token), serde_json::json!({})).await;
+    assert_eq!(status, 401);
+}
+
+#[actix_rt::test]
+async fn test_list_and_revoke_sessions() {
+    let hash_file = create_pin_hash("1234");
+    let audit_dir = tempfile::TempDir::new().unwrap();
+    let mut config = create_test_config(hash_file.path().to_path_buf());
+    config.audit_log_path = Some(audit_dir.path().join("audit.log"));
+    let state = web::Data::new(AppState::new(config));
+
+    let app = test::init_service(create_test_app(state)).await;
+
+    let req = test::TestRequest::post()
+        .uri("/api/auth")
+        .insert_header(("User-Agent", "Laptop Browser"))
+        .set_json(serde_json::json!({"pin": "1234"}))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let laptop = body["token"].as_str().unwrap().to_string();
+    let phone = login(&app).await;
+
+    let get = |uri: &str, token: &str| {
+        test::TestRequest::get()
+            .uri(uri)
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_request()
+    };
+
+    let body: serde_json::Value =
+        test::call_and_read_body_json(&app, get("/api/sessions", &phone)).await;
+    let sessions = body["sessions"].as_array().unwrap();
+    assert_eq!(sessions.len(), 2);
+    let other = sessions.iter().find(|s| s["current"] == false).unwrap();
+    assert_eq!(other["user_agent"], "Laptop Browser");
+    assert_eq!(other["client_ip"], "127.0.0.1");
+    assert!(other["created_at"].as_u64().unwrap() > 0);
+    assert!(other["expires_at"].as_u64() > other["last_used_at"].as_u64());
+    assert!(!body.to_string().contains(&laptop));
+
+    let delete = |id: &str| {
+        test::TestRequest::delete()
+            .uri(&format!("/api/sessions/{}", id))
+            .insert_header(("Authorization", format!("Bearer {}", phone)))
+            .to_request()
+    };
+    let id = other["id"].as_str().unwrap();
+    assert_eq!(test::call_service(&app, delete(id)).await.status(), 204);
+    assert_eq!(test::call_service(&app, delete(id)).await.status(), 404);
+
+    let resp = test::call_service(&app, get("/api/status", &laptop)).await;
+    assert_eq!(resp.status(), 401);
+
+    let page: serde_json::Value =
+        test::call_and_read_body_json(&app, get("/api/audit?action=logout", &phone)).await;
+    assert_eq!(page["total"], 1);
+    assert_eq!(
+        page["entries"][0]["detail"],
+        format!("Revoked session {}", id)
+    );
+}
+
+#[actix_rt::test]
+async fn test_refresh_rotates_token() {
+    let hash_file = create_pin_hash("1234");
+    let config = create_test_config(hash_file.path().to_path_buf());
+    let state = web::Data::new(AppState::new(config));
+
+    let app = test::init_service(create_test_app(state)).await;
+    let token = login(&app).await;
+
+    let (status, body) = post_json(&app, "/api/refresh", Some(&token), serde_json::json!({})).await;
+    assert_eq!(status, 200);
+    assert_eq!(body["expires_in"], 900);
+    let refreshed = body["token"].as_str().unwrap();
+    assert_ne!(refreshed, token);
+
+    let status_of = |token: &str| {
+        test::TestRequest::get()
+            .uri("/api/status")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_request()
+    };
+    assert_eq!(
+        test::call_service(&app, status_of(&token)).await.status(),
+        401
+    );
+    assert!(
+        test::call_service(&app, status_of(refreshed))
+            .await
+            .status()
+            .is_success()
+    );
+}
+
+/// Sends one request over HTTPS to `addr`, presenting `client` if given,
+/// and returns the status and JSON body.
+async fn https_request(
+    addr: std::net::SocketAddr,
+    server_cert: &std::path::Path,
+    client: Option<&tls::IssuedCert>,
+    request: &str,
+) -> (u16, serde_json::Value) {
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(server_cert).unwrap() {
+        roots.add(cert.unwrap()).unwrap();
+    }
+    let builder = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_root_certificates(roots);
+    let config = match client {
+        Some(issued) => builder
+            .with_client_auth_cert(
+                vec![CertificateDer::from_pem_slice(issued.cert_pem.as_bytes()).unwrap()],
+                PrivateKeyDer::from_pem_slice(issued.key_pem.as_bytes()).unwrap(),
+            )
+            .unwrap(),
+        None => builder.with_no_client_auth(),
+    };
+
+    let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let mut stream = tokio_rustls::TlsConnector::from(Arc::new(config))
+        .connect(ServerName::try_from("localhost").unwrap(), tcp)
+        .await
+        .unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    // The server may close without close_notify after `Connection: close`
+    let _ = stream.read_to_end(&mut response).await;
+
+    let response = String::from_utf8(response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    (status, serde_json::from_str(body).unwrap_or_default())
+}
+
+fn https_get(path: &str, token: Option<&str>) -> String {
+    let auth = token
+        .map(|token| format!("Authorization: Bearer {}\r\n", token))
+        .unwrap_or_default();
+    format!(
+        "GET {} HTTP/1.1\r\nHost: localhost\r\n{}Connection: close\r\n\r\n",
+        path, auth
+    )
+}
+
+fn https_login(pin: &str) -> String {
+    let body = serde_json::json!({ "pin": pin }).to_string();
+    format!(
+        "POST /api/auth HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )
+}
+
+#[actix_rt::test]
+async fn test_client_certificates() {
+    use articwake::config::{CertPolicy, CertRoute, ClientAuth};
+
+    let dir = tempfile::TempDir::new().unwrap();
+    let hash_file = create_pin_hash("1234");
+    let mut config = create_test_config(hash_file.path().to_path_buf());
+    config.tls.enabled = true;
+    config.tls.cert_path = dir.path().join("cert.pem");
+    config.tls.key_path = dir.path().join("key.pem");
+    config.tls.client_auth = ClientAuth::Optional;
+    config.tls.client_ca_path = dir.path().join("client-ca.pem");
+    config.tls.client_ca_key_path = dir.path().join("client-ca.key");
+    config.tls.client_cert_routes = vec![
+        CertRoute {
+            prefix: "/api/auth".to_string(),
+            policy: CertPolicy::Bypass,
+        },
+        CertRoute {
+            prefix: "/api/hosts".to_string(),
+            policy: CertPolicy::Bypass,
+        },
+        CertRoute {
+            prefix: "/api/sessions".to_string(),
+            policy: CertPolicy::Factor,
+        },
+    ];
+    tls::ensure_certificate(&config.tls, &["localhost".to_string()]).unwrap();
+    let phone = tls::issue_client_cert(&config.tls, "phone", 30).unwrap();
+    let stolen = tls::issue_client_cert(&config.tls, "stolen", 30).unwrap();
+    config.tls.revoked_client_certs = vec![stolen.fingerprint.clone()];
+
+    let resolver = std::sync::Arc::new(tls::CertResolver::load(&config.tls).unwrap());
+    let server_config = tls::server_config(resolver, &config.tls).unwrap();
+    let server_cert = config.tls.cert_path.clone();
+    let state = web::Data::new(AppState::new(config));
+    let server = actix_web::HttpServer::new(move || create_test_app(state.clone()))
+        .on_connect(|connection, data| {
+            if let Some(cert) = tls::peer_client_cert(connection) {
+                data.insert(cert);
+            }
+        })
+        .workers(1)
+        .bind_rustls_0_23("127.0.0.1:0", server_config)
+        .unwrap();
+    let addr = server.addrs()[0];
+    let server = server.run();
+    let handle = server.handle();
+    actix_rt::spawn(server);
+
+    let send = |client, request: String| {
+        let server_cert = server_cert.clone();
+        async move { https_request(addr, &server_cert, client, &request).await }
+    };
+
+    // Bypass: the certificate replaces the PIN and the token
+    let (status, _) = send(Some(&phone), https_get("/api/hosts", None)).await;
+    assert_eq!(status, 200);
+    let (status, body) = send(Some(&phone), https_login("")).await;
+    assert_eq!(status, 200);
+    let token = body["token"].as_str().unwrap().to_string();
+    let (status, methods) = send(Some(&phone), https_get("/api/auth", None)).await;
+    assert_eq!(status, 200);
+    assert_eq!(methods["client_cert"], "bypass");
+
+    // Factor: the certificate is needed on top of the token
+    let (status, _) = send(Some(&phone), https_get("/api/sessions", Some(&token))).await;
+    assert_eq!(status, 200);
+    let (status, _) = send(None, https_get("/api/sessions", Some(&token))).await;
+    assert_eq!(status, 403);
+    // The router decodes the path, so the policy must match the decoded one
+    let (status, _) = send(None, https_get("/api/%73essions", Some(&token))).await;
+    assert_eq!(status, 403);
+    let (status, _) = send(Some(&phone), https_get("/api/%68osts", None)).await;
+    assert_eq!(status, 200);
+
+    // Without a certificate the PIN is needed as usual
+    let (status, _) = send(None, https_get("/api/hosts", None)).await;
+    assert_eq!(status, 401);
+    let (status, methods) = send(None, https_get("/api/auth", None)).await;
+    assert_eq!(status, 200);
+    assert!(methods["client_cert"].is_null());
+    let (status, _) = send(None, https_login("")).await;
+    assert_eq!(status, 401);
+    let (status, _) = send(None, https_login("1234")).await;
+    assert_eq!(status, 200);
+
+    // A revoked certificate counts for nothing
+    let (status, _) = send(Some(&stolen), https_get("/api/hosts", None)).await;
+    assert_eq!(status, 401);
+    let (status, _) = send(Some(&stolen), https_login("")).await;
+    assert_eq!(status, 401);
+
+    handle.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn test_api_key_scopes() {
+    use articwake::apikeys::Scope;
+
+    let dir = tempfile::TempDir::new().unwrap();
+    let mut config = create_test_config(PathBuf::from("/nonexistent/pin.hash"));
+    config.api_keys_path = dir.path().join("api-keys.json");
+    let state = web::Data::new(AppState::new(config));
+    let (entry, key) = state
+        .api_keys
+        .create("home-assistant", vec![Scope::StatusRead], None)
+        .unwrap();
+
+    let app = test::init_service(create_test_app(state.clone())).await;
+    let with_key = |req: test::TestRequest| {
+        req.insert_header(("Authorization", format!("Bearer {}", key)))
+            .to_request()
+    };
+
+    let resp = test::call_service(&app, with_key(test::TestRequest::get().uri("/api/hosts"))).await;
+    assert_eq!(resp.status(), 200);
+
+    let resp = test::call_service(&app, with_key(test::TestRequest::post().uri("/api/wol"))).await;
+    assert_eq!(resp.status(), 403);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["scope"], "wol");
+
+    let resp = test::call_service(
+        &app,
+        with_key(test::TestRequest::get().uri("/api/sessions")),
+    )
+    .await;
+    assert_eq!(resp.status(), 403);
+
+    // Revoking takes effect at once
+    state.api_keys.revoke(&entry.id).unwrap();
+    let resp = test::call_service(&app, with_key(test::TestRequest::get().uri("/api/hosts"))).await;
+    assert_eq!(resp.status(), 401);
+}
+
+#[actix_rt::test]
+async fn test_api_key_actions_audited() {
+    use articwake::apikeys::Scope;
+
+    let dir = tempfile::TempDir::new().unwrap();
+    let mut config = create_test_config(PathBuf::from("/nonexistent/pin.hash"));
+    config.api_keys_path = dir.path().join("api-keys.json");
+    config.audit_log_path = Some(dir.path().join("audit.log"));
+    let state = web::Data::new(AppState::new(config));
+    let (entry, key) = state
+        .api_keys
+        .create("script", vec![Scope::Wol, Scope::Admin], None)
+        .unwrap();
+
+    let app = test::init_service(create_test_app(state)).await;
+    let req = test::TestRequest::post()
+        .uri("/api/wol")
+        .insert_header(("Authorization", format!("Bearer {}", key)))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+    let req = test::TestRequest::get()
+        .uri("/api/audit?action=wol")
+        .insert_header(("Authorization", format!("Bearer {}", key)))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        body["entries"][0]["session_id"],
+        format!("key:{}", entry.id)
+    );
+}
+
+#[actix_rt::test]
+async fn test_user_roles() {
+    use articwake::users::Role;
+
+    let dir = tempfile::TempDir::new().unwrap();
+    let mut config = create_test_config(dir.path().join("pin.hash"));
+    config.users_path = dir.path().join("users.json");
+    config.audit_log_path = Some(dir.path().join("audit.log"));
+    let state = web::Data::new(AppState::new(config));
+    state.users.add("guest", Role::Operator, "5678").unwrap();
+    state.users.add("alice", Role::Admin, "1234").unwrap();
+    let app = test::init_service(create_test_app(state)).await;
+
+    let req = test::TestRequest::get().uri("/api/auth").to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["users"], true);
+
+    // Without a shared PIN, logins need a username
+    let (status, body) =
+        post_json(&app, "/api/auth", None, serde_json::json!({"pin": "1234"})).await;
+    assert_eq!(status, 401);
+    assert_eq!(body["username_required"], true);
+
+    let (status, body) = post_json(
+        &app,
+        "/api/auth",
+        None,
+        serde_json::json!({"username": "guest", "pin": "1234"}),
+    )
+    .await;
+    assert_eq!(status, 401);
+    assert_eq!(body["error"], "Invalid username or PIN");
+
+    let (status, body) = post_json(
+        &app,
+        "/api/auth",
+        None,
+        serde_json::json!({"username": "Guest", "pin": "5678"}),
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(body["username"], "guest");
+    assert_eq!(body["scopes"], serde_json::json!(["status:read", "wol"]));
+    let guest = body["token"].as_str().unwrap().to_string();
+
+    let (status, _) = post_json(&app, "/api/wol", Some(&guest), serde_json::json!({})).await;
+    assert_eq!(status, 200);
+    let (status, body) = post_json(
+        &app,
+        "/api/boot",
+        Some(&guest),
+        serde_json::json!({"host": "homelab", "passphrase": "secret"}),
+    )
+    .await;
+    assert_eq!(status, 403);
+    assert_eq!(body["scope"], "unlock");
+
+    let (status, body) = post_json(
+        &app,
+        "/api/auth",
+        None,
+        serde_json::json!({"username": "alice", "pin": "1234"}),
+    )
+    .await;
+    assert_eq!(status, 200);
+    let alice = body["token"].as_str().unwrap().to_string();
+
+    // Each user's actions are audited under their name
+    let req = test::TestRequest::get()
+        .uri("/api/audit?action=wol")
+        .insert_header(("Authorization", format!("Bearer {}", alice)))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["entries"][0]["user"], "guest");
+
+    let req = test::TestRequest::get()
+        .uri("/api/audit?action=login&user=guest")
+        .insert_header(("Authorization", format!("Bearer {}", alice)))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let outcomes: Vec<&str> = body["entries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry["outcome"].as_str().unwrap())
+        .collect();
+    assert_eq!(outcomes.len(), 2);
+    assert!(outcomes.contains(&"success") && outcomes.contains(&"failure"));
+
+    let req = test::TestRequest::get()
+        .uri("/api/sessions")
+        .insert_header(("Authorization", format!("Bearer {}", alice)))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let sessions = body["sessions"].as_array().unwrap();
+    assert!(
+        sessions
+            .iter()
+            .any(|s| s["username"] == "guest" && s["role"] == "operator")
+    );
+}
+
+#[actix_rt::test]
+async fn test_change_pin() {
+    use articwake::apikeys::Scope;
+
+    let dir = tempfile::TempDir::new().unwrap();
+    let hash_path = dir.path().join("pin.hash");
+    articwake::pin::write_hash(
+        &hash_path,
+        &articwake::pin::hash("1234", &Default::default()).unwrap(),
+    )
+    .unwrap();
+    let mut config = create_test_config(hash_path);
+    config.api_keys_path = dir.path().join("api-keys.json");
+    config.audit_log_path = Some(dir.path().join("audit.log"));
+    let state = web::Data::new(AppState::new(config));
+    let (_, key) = state
+        .api_keys
+        .create("script", vec![Scope::Admin], None)
+        .unwrap();
+    let app = test::init_service(create_test_app(state)).await;
+
+    let token = login(&app).await;
+    let other = login(&app).await;
+    let change =
+        |current: &str, new: &str| serde_json::json!({"current_pin": current, "new_pin": new});
+
+    let (status, _) = post_json(&app, "/api/pin", None, change("1234", "270418")).await;
+    assert_eq!(status, 401);
+    let (status, _) = post_json(&app, "/api/pin", Some(&key), change("1234", "270418")).await;
+    assert_eq!(status, 403);
+
+    let (status, body) = post_json(&app, "/api/pin", Some(&token), change("1234", "42")).await;
+    assert_eq!(status, 400);
+    assert_eq!(body["error"], "PIN must be at least 6 characters");
+    let (status, _) = post_json(&app, "/api/pin", Some(&token), change("9999", "270418")).await;
+    assert_eq!(status, 401);
+
+    let (status, body) = post_json(&app, "/api/pin", Some(&token), change("1234", "270418")).await;
+    assert_eq!(status, 200);
+    assert_eq!(body["sessions_ended"], 1);
+
+    // The other session is gone, this one still works
+    let (status, _) = post_json(&app, "/api/refresh", Some(&other), serde_json::json!({})).await;
+    assert_eq!(status, 401);
+    let (status, _) = post_json(&app, "/api/refresh", Some(&token), serde_json::json!({})).await;
+    assert_eq!(status, 200);
+
+    let (status, _) = post_json(&app, "/api/auth", None, serde_json::json!({"pin": "1234"})).await;
+    assert_eq!(status, 401);
+    let (status, _) = post_json(
+        &app,
+        "/api/auth",
+        None,
+        serde_json::json!({"pin": "270418"}),
+    )
+    .await;
+    assert_eq!(status, 200);
+
+    let req = test::TestRequest::get()
+        .uri("/api/audit?action=pin")
+        .insert_header(("Authorization", format!("Bearer {}", key)))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["entries"][0]["outcome"], "success");
+    assert_eq!(body["total"], 3);
 }